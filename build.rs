@@ -65,7 +65,9 @@ fn compile_runner(starter_dir: &Path, target: &str, out_dir: &str) -> bool {
     let profile = var("PROFILE").unwrap();
     let native_target = var("TARGET").unwrap();
     let cargo = PathBuf::from(var("CARGO").unwrap()).canonicalize().unwrap();
-    let use_cross = var(USE_CROSS_ENV) == Ok("true".into()) || var(USE_CROSS_ENV) == Ok("1".into());
+    let enabled = |name: String| var(name).is_ok_and(|value| value == "true" || value == "1");
+    let use_cross = enabled(USE_CROSS_ENV.to_string())
+        || enabled(format!("WRAPPE_TARGET_USE_CROSS_{}", target));
     let mut command = if target == native_target || !use_cross {
         Command::new(cargo)
     } else {
@@ -120,6 +122,15 @@ fn compile_runner(starter_dir: &Path, target: &str, out_dir: &str) -> bool {
     if profile == "release" {
         command.arg("--release");
     }
+    // the packer unwinds to recover from panics of the PE parser, the runner keeps aborting
+    command.arg("--config").arg(format!(
+        "profile.{}.panic=\"abort\"",
+        if profile == "release" {
+            "release"
+        } else {
+            "dev"
+        }
+    ));
     eprintln!("running {:?}", command);
     let status = command
         .status()
@@ -0,0 +1,4 @@
+#!/bin/sh
+cat "$WRAPPE_UNPACK_DIR/link.txt"
+if [ -n "$WRAPPE_SECTIONS_DIR" ]; then cat "$WRAPPE_SECTIONS_DIR/license"; fi
+printf "%s\n" "$@"
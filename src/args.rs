@@ -1,16 +1,35 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
-    path::{Path, PathBuf},
+    fmt::{Display, Formatter},
+    hash::Hasher,
+    io::{IsTerminal, Read},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use console::style;
+use console::{Emoji, style};
+use ed25519_dalek::SigningKey;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use rand::{
     distributions::{Alphanumeric, Distribution},
     thread_rng,
 };
+use serde_json::Value;
 use staticfilemap::StaticFileMap;
+use twox_hash::XxHash64;
 
-use crate::types::{ARGS_SIZE, NAME_SIZE};
+use crate::{
+    filter::{ContentFilters, FILTER_NAMES, Filter},
+    payload::executable_target,
+    shebang::ShebangMode,
+    signing::read_signing_key,
+    statistics::STATISTICS_SECTION,
+    types::{
+        DESKTOP_ENTRY_FILE, DESKTOP_ICON_NAME, NAME_SIZE, RUNNER_MARKER, WRAPPE_DIRECTORY,
+        WRAPPE_FORMAT, encode_arguments, encode_field, is_valid_section_name, write_extra_section,
+    },
+};
 
 #[derive(StaticFileMap)]
 #[parse("env")]
@@ -20,392 +39,1082 @@ use crate::types::{ARGS_SIZE, NAME_SIZE};
 #[algorithm("zstd")]
 struct StarterMap;
 
-pub fn list_runners() {
-    println!("{}:", style("available runners").blue().bright());
-    println!(
-        "  {} {}",
-        StarterMap::keys()[0],
-        style("(default)").bold().dim()
-    );
-    for runner in &StarterMap::keys()[1..] {
-        println!("  {}", runner);
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Switch to plain output without colors, emoji and progress bars if `plain` is set,
+/// the `NO_COLOR` environment variable is set or stdout is not a terminal.
+pub fn set_plain(plain: bool) {
+    let plain = plain
+        || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+        || !std::io::stdout().is_terminal();
+    PLAIN.store(plain, Ordering::Relaxed);
+    if plain {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
     }
 }
 
-pub fn get_runner_name(name: &str) -> &'static str {
-    if name == "native" || name == "default" {
-        return StarterMap::keys()[0];
-    }
-    StarterMap::get_match_index(name)
-        .map(|id| StarterMap::keys()[id])
-        .unwrap_or_else(|| {
-            println!(
-                "{}: {}",
-                style("not a valid runner").red(),
-                style(name).red()
-            );
-            list_runners();
-            std::process::exit(-1);
-        })
+pub fn is_plain() -> bool { PLAIN.load(Ordering::Relaxed) }
+
+/// Emoji that is replaced with its fallback in plain output.
+pub fn emoji<'a>(emoji: &'a str, fallback: &'a str) -> Emoji<'a, 'a> {
+    Emoji(if is_plain() { fallback } else { emoji }, fallback)
 }
 
-pub fn get_runner(name: &str) -> &'static [u8] {
-    let runner_name = if name == "native" || name == "default" {
-        StarterMap::keys()[0]
-    } else {
-        name
+/// Directory runners for additional targets are loaded from, named by their target triple.
+pub fn runner_directory() -> Option<PathBuf> {
+    dirs::config_dir().map(|config| config.join("wrappe").join("runners"))
+}
+
+/// Names and paths of the runners in the runner directory that are not included in wrappe.
+fn plugin_runners() -> Vec<(String, PathBuf)> {
+    let Some(entries) = runner_directory().and_then(|directory| directory.read_dir().ok()) else {
+        return Vec::new();
     };
-    StarterMap::get_match(runner_name).unwrap_or_else(|| {
+    let mut runners = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let name = name.strip_suffix(".exe").unwrap_or(name).to_string();
+            (path.is_file() && !name.starts_with('.') && !StarterMap::keys().contains(&&*name))
+                .then_some((name, path))
+        })
+        .collect::<Vec<_>>();
+    runners.sort_unstable();
+    runners
+}
+
+/// Whether wrappe is running on an ARM64 Windows host, including under x64 emulation where the
+/// processor architecture of the process is reported as AMD64.
+#[cfg(windows)]
+fn is_windows_arm64() -> bool {
+    let var = |name: &str| std::env::var(name).unwrap_or_default();
+    var("PROCESSOR_ARCHITECTURE") == "ARM64"
+        || var("PROCESSOR_ARCHITEW6432") == "ARM64"
+        || var("PROCESSOR_IDENTIFIER").starts_with("ARMv8")
+}
+
+/// Index of the runner for the current platform, which is the runner for the target wrappe was
+/// compiled for, or the ARM64 Windows runner on ARM64 Windows hosts if it is included.
+fn native_runner() -> usize {
+    #[cfg(windows)]
+    if is_windows_arm64() {
+        if let Some(index) = StarterMap::keys()
+            .iter()
+            .position(|key| key.starts_with("aarch64-pc-windows"))
+        {
+            return index;
+        }
+    }
+    0
+}
+
+pub fn list_runners() {
+    println!("{}:", style("available runners").blue().bright());
+    let native = native_runner();
+    for (index, runner) in StarterMap::keys().iter().enumerate() {
+        if index == native {
+            println!("  {} {}", runner, style("(default)").bold().dim());
+        } else {
+            println!("  {}", runner);
+        }
+    }
+    for (runner, path) in plugin_runners() {
         println!(
-            "{}: {}",
-            style("not a valid runner").red(),
-            style(runner_name).red()
+            "  {} {}",
+            runner,
+            style(format!("({})", path.display())).dim()
         );
-        list_runners();
-        std::process::exit(-1);
-    })
+    }
 }
 
-pub fn get_unpack_target(directory: &str) -> u8 {
-    match directory.to_lowercase().as_str() {
-        "temp" => 0,
-        "default" => 0,
-        "local" => 1,
-        "cwd" => 2,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid target directory").red(),
-                style(directory).red(),
-            );
-            println!(
-                "{}: temp {}, local, cwd",
-                style("available target directories").blue().bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
+/// Index of the included runner or name and path of the runner in the runner directory that
+/// `name` is part of, if it matches exactly one runner.
+fn find_runner(name: &str) -> Option<Result<usize, (String, PathBuf)>> {
+    if name == "native" || name == "default" {
+        return Some(Ok(native_runner()));
+    }
+    let plugins = plugin_runners();
+    if let Some(index) = StarterMap::keys().iter().position(|key| *key == name) {
+        return Some(Ok(index));
+    }
+    if let Some(plugin) = plugins.iter().find(|(key, _)| key == name) {
+        return Some(Err(plugin.clone()));
+    }
+    let included = StarterMap::keys().iter().filter(|key| key.contains(name));
+    let mut plugins = plugins.into_iter().filter(|(key, _)| key.contains(name));
+    match (included.count(), plugins.next(), plugins.next()) {
+        (1, None, _) => StarterMap::get_match_index(name).map(Ok),
+        (0, Some(plugin), None) => Some(Err(plugin)),
+        _ => None,
+    }
+}
+
+/// Error of an invalid option, input or failed command, printed by the command line interface
+/// with [`ArgsError::exit`].
+#[derive(Debug)]
+pub enum ArgsError {
+    /// `value` isn't one of the `values` of the option described by `kind`.
+    InvalidValue {
+        kind:    &'static str,
+        value:   String,
+        values:  &'static [&'static str],
+        default: Option<&'static str>,
+    },
+    /// The runner name doesn't match exactly one runner.
+    InvalidRunner(String),
+    /// The runner was built for the payload `format` of another version of wrappe.
+    RunnerFormat(u8),
+    /// Invalid option or input described by `message`, with the value or cause in `detail`.
+    Invalid {
+        message: &'static str,
+        detail:  Option<String>,
+    },
+}
+
+impl ArgsError {
+    pub fn invalid(message: &'static str, detail: impl Display) -> Self {
+        ArgsError::Invalid {
+            message,
+            detail: Some(detail.to_string()),
+        }
+    }
+
+    fn message(message: &'static str) -> Self {
+        ArgsError::Invalid {
+            message,
+            detail: None,
         }
     }
+
+    fn invalid_value(
+        kind: &'static str, value: &str, values: &'static [&'static str],
+        default: Option<&'static str>,
+    ) -> Self {
+        ArgsError::InvalidValue {
+            kind,
+            value: value.to_string(),
+            values,
+            default,
+        }
+    }
+
+    /// Print the error with the available values if there are any and exit.
+    pub fn exit(&self) -> ! {
+        match self {
+            ArgsError::InvalidValue {
+                kind,
+                value,
+                values,
+                default,
+            } => {
+                println!(
+                    "{}: {}",
+                    style(format!("not a valid {}", kind)).red(),
+                    style(value).red()
+                );
+                let values = values
+                    .iter()
+                    .map(|value| {
+                        if Some(*value) == *default {
+                            format!("{} {}", value, style("(default)").bold().dim())
+                        } else {
+                            value.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                // plural of the kind, all kinds end in a consonant or y
+                let kinds = match kind.strip_suffix('y') {
+                    Some(stem) => format!("{}ies", stem),
+                    None => format!("{}s", kind),
+                };
+                println!(
+                    "{}: {}",
+                    style(format!("available {}", kinds)).blue().bright(),
+                    values.join(", ")
+                );
+            }
+            ArgsError::InvalidRunner(name) => {
+                println!(
+                    "{}: {}",
+                    style("not a valid runner").red(),
+                    style(name).red()
+                );
+                list_runners();
+            }
+            ArgsError::RunnerFormat(format) => println!(
+                "{}: {} {}",
+                style("runner format differs from the format of wrappe").red(),
+                style(format).red(),
+                style(format!("(expected {})", WRAPPE_FORMAT)).dim()
+            ),
+            ArgsError::Invalid {
+                message,
+                detail: Some(detail),
+            } => println!("{}: {}", style(message).red(), detail),
+            ArgsError::Invalid {
+                message,
+                detail: None,
+            } => println!("{}", style(message).red()),
+        }
+        std::process::exit(1);
+    }
 }
 
-pub fn get_versioning(versioning: &str) -> u8 {
-    match versioning.to_lowercase().as_str() {
-        "sidebyside" => 0,
-        "default" => 0,
-        "replace" => 1,
-        "none" => 2,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid versioning strategy").red(),
-                style(versioning).red(),
-            );
-            println!(
-                "{}: sidebyside {}, replace",
-                style("available versioning strategies").blue().bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
+impl Display for ArgsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgsError::InvalidValue {
+                kind,
+                value,
+                values,
+                ..
+            } => write!(
+                f,
+                "not a valid {}: {} (expected one of {})",
+                kind,
+                value,
+                values.join(", ")
+            ),
+            ArgsError::InvalidRunner(name) => write!(f, "not a valid runner: {}", name),
+            ArgsError::RunnerFormat(format) => write!(
+                f,
+                "runner format differs from the format of wrappe: {} (expected {})",
+                format, WRAPPE_FORMAT
+            ),
+            ArgsError::Invalid {
+                message,
+                detail: Some(detail),
+            } => write!(f, "{}: {}", message, detail),
+            ArgsError::Invalid {
+                message,
+                detail: None,
+            } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+/// Exit with the printed error of a failed result, used by the command line interface.
+pub trait OrExit<T> {
+    fn or_exit(self) -> T;
+}
+
+impl<T> OrExit<T> for Result<T, ArgsError> {
+    fn or_exit(self) -> T { self.unwrap_or_else(|e| e.exit()) }
+}
+
+pub fn get_runner_name(name: &str) -> Result<String, ArgsError> {
+    match find_runner(name) {
+        Some(Ok(index)) => Ok(StarterMap::keys()[index].to_string()),
+        Some(Err((name, _))) => Ok(name),
+        None => Err(ArgsError::InvalidRunner(name.to_string())),
+    }
+}
+
+/// Decompressed included runner or the runner from the runner directory matching `name`.
+pub fn get_runner(name: &str) -> Result<Vec<u8>, ArgsError> {
+    match find_runner(name) {
+        Some(Ok(index)) => {
+            let mut runner = Vec::new();
+            zstd::stream::copy_decode(StarterMap::data()[index], &mut runner).unwrap();
+            Ok(runner)
+        }
+        Some(Err((_, path))) => Ok(get_runner_path(&path)?.0),
+        None => Err(ArgsError::InvalidRunner(name.to_string())),
+    }
+}
+
+/// Decompressed runners matching `names` with their names, where `all` selects all included
+/// runners. Runners selected more than once are only returned once.
+pub fn get_runners(names: &[String]) -> Result<Vec<(Vec<u8>, String)>, ArgsError> {
+    let mut runner_names = Vec::<String>::new();
+    for name in names {
+        let selected = if name == "all" {
+            StarterMap::keys()
+                .iter()
+                .map(|key| key.to_string())
+                .collect()
+        } else {
+            vec![get_runner_name(name)?]
+        };
+        for name in selected {
+            if !runner_names.contains(&name) {
+                runner_names.push(name);
+            }
         }
     }
+    runner_names
+        .into_iter()
+        .map(|name| Ok((get_runner(&name)?, name)))
+        .collect()
 }
 
-pub fn get_version(version: Option<&str>) -> String {
+/// Check that `runner` from `source` contains the runner marker with a matching payload format.
+pub fn check_runner(runner: &[u8], source: &dyn Display) -> Result<(), ArgsError> {
+    let format = memchr::memmem::find(runner, RUNNER_MARKER)
+        .and_then(|position| runner.get(position + RUNNER_MARKER.len()).copied());
+    match format {
+        Some(WRAPPE_FORMAT) => Ok(()),
+        Some(format) => Err(ArgsError::RunnerFormat(format)),
+        None => Err(ArgsError::invalid(
+            "not a runner built from this version of wrappe",
+            source,
+        )),
+    }
+}
+
+/// Read the runner binary at `path` and return it with its target detected from its executable
+/// header.
+pub fn get_runner_path(path: &Path) -> Result<(Vec<u8>, String), ArgsError> {
+    let runner = std::fs::read(path).map_err(|e| {
+        ArgsError::invalid("couldn't read runner", format!("{}: {}", path.display(), e))
+    })?;
+    check_runner(&runner, &path.display())?;
+    let target = executable_target(&runner[..runner.len().min(4096)]);
+    Ok((runner, target))
+}
+
+pub fn get_algorithm(algorithm: &str) -> Result<u8, ArgsError> {
+    match algorithm.to_lowercase().as_str() {
+        "zstd" => Ok(0),
+        "default" => Ok(0),
+        "xz" => Ok(1),
+        "brotli" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "compression algorithm",
+            algorithm,
+            &["zstd", "xz", "brotli"],
+            Some("zstd"),
+        )),
+    }
+}
+
+pub fn get_unpack_target(directory: &str) -> Result<u8, ArgsError> {
+    match directory.to_lowercase().as_str() {
+        "temp" => Ok(0),
+        "default" => Ok(0),
+        "local" => Ok(1),
+        "cwd" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "target directory",
+            directory,
+            &["temp", "local", "cwd"],
+            Some("temp"),
+        )),
+    }
+}
+
+pub fn get_unpack_subdir(unpack_subdir: &str) -> Result<u8, ArgsError> {
+    match unpack_subdir.to_lowercase().as_str() {
+        "none" => Ok(0),
+        "path" => Ok(1),
+        "content" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "unpack subdirectory",
+            unpack_subdir,
+            &["none", "path", "content"],
+            Some("none"),
+        )),
+    }
+}
+
+pub fn get_shebang_mode(shebangs: &str) -> Result<ShebangMode, ArgsError> {
+    match shebangs.to_lowercase().as_str() {
+        "keep" => Ok(ShebangMode::Keep),
+        "env" => Ok(ShebangMode::Env),
+        "unpack" => Ok(ShebangMode::Unpack),
+        _ => Err(ArgsError::invalid_value(
+            "shebang mode",
+            shebangs,
+            &["keep", "env", "unpack"],
+            Some("keep"),
+        )),
+    }
+}
+
+pub fn check_venv<T: AsRef<Path>>(sources: &[T]) -> Result<(), ArgsError> {
+    match sources {
+        [source] if source.as_ref().join("pyvenv.cfg").is_file() => Ok(()),
+        [source] => Err(ArgsError::invalid(
+            "no pyvenv.cfg in the Python virtual environment",
+            source.as_ref().display().to_string(),
+        )),
+        _ => Err(ArgsError::message(
+            "a Python virtual environment has to be packed as the only input",
+        )),
+    }
+}
+
+pub fn get_versioning(versioning: &str) -> Result<u8, ArgsError> {
+    match versioning.to_lowercase().as_str() {
+        "sidebyside" => Ok(0),
+        "default" => Ok(0),
+        "replace" => Ok(1),
+        "none" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "versioning strategy",
+            versioning,
+            &["sidebyside", "replace", "none"],
+            Some("sidebyside"),
+        )),
+    }
+}
+
+pub fn get_version(version: Option<&str>) -> Result<String, ArgsError> {
     let mut version = if let Some(version) = version {
         if version.len() > 16 {
-            println!(
-                "{}",
-                style("version specifier is longer than 16 characters").red(),
-            );
-            std::process::exit(-1);
+            return Err(ArgsError::message(
+                "version specifier is longer than 16 bytes",
+            ));
         }
         if version.is_empty() {
-            println!("{}", style("version specifier is empty").red());
-            std::process::exit(-1);
+            return Err(ArgsError::message("version specifier is empty"));
         }
-        version.chars().collect::<Vec<_>>()
+        version.to_string()
     } else {
         Alphanumeric
             .sample_iter(thread_rng())
             .map(char::from)
             .take(8)
-            .collect::<Vec<_>>()
+            .collect::<String>()
+    };
+    // pad to the size of the field in bytes, multi-byte characters take up more than one
+    while version.len() < 16 {
+        version.push('\0');
+    }
+    Ok(version)
+}
+
+pub fn get_cache(cache: Option<&Path>) -> Result<PathBuf, ArgsError> {
+    let cache = match cache {
+        Some(cache) => cache.to_owned(),
+        None => dirs::cache_dir()
+            .ok_or_else(|| ArgsError::message("couldn't get cache directory"))?
+            .join("wrappe"),
+    };
+    std::fs::create_dir_all(&cache).map_err(|e| {
+        ArgsError::invalid(
+            "couldn't create cache directory",
+            format!("{}: {}", cache.display(), e),
+        )
+    })?;
+    Ok(cache)
+}
+
+pub fn get_dictionary_file(dictionary_file: Option<&Path>) -> Result<Option<Vec<u8>>, ArgsError> {
+    let Some(dictionary_file) = dictionary_file else {
+        return Ok(None);
+    };
+    let dictionary = std::fs::read(dictionary_file).map_err(|e| {
+        ArgsError::invalid(
+            "couldn't read dictionary file",
+            format!("{}: {}", dictionary_file.display(), e),
+        )
+    })?;
+    if dictionary.is_empty() {
+        return Err(ArgsError::invalid(
+            "dictionary file is empty",
+            dictionary_file.display(),
+        ));
+    }
+    Ok(Some(dictionary))
+}
+
+pub fn get_signing_key(sign_key: Option<&Path>) -> Result<Option<SigningKey>, ArgsError> {
+    let Some(sign_key) = sign_key else {
+        return Ok(None);
+    };
+    read_signing_key(sign_key)
+        .map(Some)
+        .map_err(|e| ArgsError::invalid("couldn't read signing key", e))
+}
+
+pub fn get_reproducible(reproducible: bool) -> Result<Option<u64>, ArgsError> {
+    if !reproducible {
+        return Ok(None);
+    }
+    // timestamps are zeroed if no source date is given
+    let timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(timestamp) => timestamp,
+        Err(_) => return Ok(Some(0)),
     };
-    version.resize(16, 0 as char);
-    version.iter().collect()
+    timestamp
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| ArgsError::invalid("SOURCE_DATE_EPOCH is not a valid timestamp", &timestamp))
 }
 
-pub fn get_verification(verification: &str) -> u8 {
+pub fn get_once(once: Option<&str>) -> Result<u8, ArgsError> {
+    match once.map(|once| once.to_lowercase()).as_deref() {
+        None => Ok(0),
+        Some("default") => Ok(1),
+        Some("scan") => Ok(1),
+        Some("lock") => Ok(2),
+        Some("wait") => Ok(3),
+        Some("app") => Ok(4),
+        Some(once) => Err(ArgsError::invalid_value(
+            "once strategy",
+            once,
+            &["scan", "lock", "wait", "app"],
+            Some("scan"),
+        )),
+    }
+}
+
+pub fn get_verification(verification: &str) -> Result<u8, ArgsError> {
     match verification.to_lowercase().as_str() {
-        "none" => 0,
-        "default" => 1,
-        "existence" => 1,
-        "checksum" => 2,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid verification option").red(),
-                style(verification).red(),
-            );
-            println!(
-                "{}: none, existence {}, checksum",
-                style("available verification options").blue().bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
-        }
+        "none" => Ok(0),
+        "default" => Ok(1),
+        "existence" => Ok(1),
+        "checksum" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "verification option",
+            verification,
+            &["none", "existence", "checksum"],
+            Some("existence"),
+        )),
     }
 }
 
-pub fn get_show_information(show_information: &str) -> u8 {
+pub fn get_show_information(show_information: &str) -> Result<u8, ArgsError> {
     match show_information.to_lowercase().as_str() {
-        "none" => 0,
-        "default" => 1,
-        "title" => 1,
-        "verbose" => 2,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid information details option").red(),
-                style(show_information).red(),
-            );
-            println!(
-                "{}: none, title {}, verbose",
-                style("available information details options")
-                    .blue()
-                    .bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
-        }
+        "none" => Ok(0),
+        "default" => Ok(1),
+        "title" => Ok(1),
+        "verbose" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "information details option",
+            show_information,
+            &["none", "title", "verbose"],
+            Some("title"),
+        )),
     }
 }
 
-pub fn get_show_console(show_console: &str, runner_name: &str) -> u8 {
+pub fn get_show_console(show_console: &str, runner_name: &str) -> Result<u8, ArgsError> {
     match show_console.to_lowercase().as_str() {
-        "auto" => {
+        "auto" | "inherit" => {
             if runner_name.contains("windows") {
-                0
+                Ok(0)
             } else {
-                1
+                Ok(1)
             }
         }
-        "never" => 0,
-        "always" => 1,
-        "attach" => 2,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid console option").red(),
-                style(show_console).red(),
-            );
-            println!(
-                "{}: auto {}, always, never",
-                style("available console options").blue().bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
-        }
+        "never" => Ok(0),
+        "always" => Ok(1),
+        "attach" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "console option",
+            show_console,
+            &["auto", "inherit", "always", "never", "attach"],
+            Some("auto"),
+        )),
     }
 }
 
-pub fn get_current_dir(current_dir: &str) -> u8 {
+pub fn get_current_dir(current_dir: &str) -> Result<u8, ArgsError> {
     match current_dir.to_lowercase().as_str() {
-        "inherit" => 0,
-        "unpack" => 1,
-        "runner" => 2,
-        "command" => 3,
-        _ => {
-            println!(
-                "{}: {}",
-                style("not a valid current directory option").red(),
-                style(current_dir).red(),
-            );
-            println!(
-                "{}: inherit {}, directory, runner, command",
-                style("available current directory options").blue().bright(),
-                style("(default)").bold().dim()
-            );
-            std::process::exit(-1);
+        "inherit" => Ok(0),
+        "unpack" => Ok(1),
+        "runner" => Ok(2),
+        "command" => Ok(3),
+        _ => Err(ArgsError::invalid_value(
+            "current directory option",
+            current_dir,
+            &["inherit", "unpack", "runner", "command"],
+            Some("inherit"),
+        )),
+    }
+}
+
+pub fn get_process_group(process_group: &str) -> Result<u8, ArgsError> {
+    match process_group.to_lowercase().as_str() {
+        "inherit" => Ok(0),
+        "group" => Ok(1),
+        "session" => Ok(2),
+        _ => Err(ArgsError::invalid_value(
+            "process group option",
+            process_group,
+            &["inherit", "group", "session"],
+            Some("inherit"),
+        )),
+    }
+}
+
+pub fn get_passed_fds(passed_fds: &[u8]) -> Result<u64, ArgsError> {
+    let mut passed = 0;
+    for &fd in passed_fds {
+        if !(3..64).contains(&fd) {
+            return Err(ArgsError::invalid(
+                "not a valid file descriptor to pass (3 to 63)",
+                fd,
+            ));
         }
+        passed |= 1 << fd;
     }
+    Ok(passed)
 }
 
-pub fn get_source(source: &Path) -> PathBuf {
+pub fn get_source(source: &Path) -> Result<PathBuf, ArgsError> {
     let source = Path::new(&std::env::current_dir().unwrap()).join(source);
-    let source = std::fs::canonicalize(&source).unwrap_or_else(|_| {
-        println!(
-            "{}: {}",
-            style("input path does not exist").red(),
-            source.display()
-        );
-        std::process::exit(-1);
-    });
+    let source = std::fs::canonicalize(&source)
+        .map_err(|_| ArgsError::invalid("input path does not exist", source.display()))?;
     if !source.is_dir() && !source.is_file() {
-        println!(
-            "{}: {}",
-            style("input path is not a file or directory").red(),
-            source.display()
-        );
-        std::process::exit(-1);
-    }
-    source
-}
-
-pub fn get_output(output: Option<&Path>, command_path: &Path) -> PathBuf {
-    let output = output
-        .map(|path| path.as_os_str().to_owned())
-        .unwrap_or_else(|| {
-            let name = command_path
-                .file_name()
-                .unwrap_or_else(|| {
-                    println!(
-                        "{}",
-                        style("couldn't infer output path from the command path").red()
-                    );
-                    std::process::exit(-1);
-                })
-                .to_owned();
+        return Err(ArgsError::invalid(
+            "input path is not a file or directory",
+            source.display(),
+        ));
+    }
+    Ok(source)
+}
+
+pub fn get_sources(input: &Path, inputs: &[PathBuf]) -> Result<Vec<PathBuf>, ArgsError> {
+    let sources = std::iter::once(input)
+        .chain(inputs.iter().map(PathBuf::as_path))
+        .map(get_source)
+        .collect::<Result<Vec<_>, _>>()?;
+    if sources.len() > 1 {
+        if let Some(source) = sources.iter().find(|source| !source.is_dir()) {
+            return Err(ArgsError::invalid(
+                "input path is not a directory, multiple inputs can only be directories",
+                source.display(),
+            ));
+        }
+    }
+    Ok(sources)
+}
+
+/// Relative path of `command` in the payload composed of `files`.
+pub fn get_listed_command_path(
+    command: &Path, files: &[(PathBuf, PathBuf)],
+) -> Result<PathBuf, ArgsError> {
+    let command = command.strip_prefix(".").unwrap_or(command);
+    files
+        .iter()
+        .map(|(name, _)| name)
+        .find(|name| name.as_path() == command)
+        .cloned()
+        .ok_or_else(|| {
+            ArgsError::invalid(
+                "command path is not contained in the file list",
+                command.display(),
+            )
+        })
+}
+
+/// Render the output path `template`, replacing `{name}` with the file name of the command
+/// without extension, `{version}` with the version string, `{target}` with the runner target
+/// and `{ext}` with the executable extension of the target.
+pub fn get_output_template(
+    template: &str, command_path: &Path, version: Option<&str>, runner_name: &str,
+) -> Result<PathBuf, ArgsError> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| ArgsError::invalid("output template has an unclosed field", template))?;
+        match &rest[start + 1..end] {
+            "name" => output.push_str(
+                &command_path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+            ),
+            "version" => output.push_str(version.ok_or_else(|| {
+                ArgsError::message(
+                    "output template field {version} requires an explicit version string",
+                )
+            })?),
+            "target" => output.push_str(runner_name),
+            "ext" if runner_name.contains("windows") => output.push_str(".exe"),
+            "ext" => {}
+            field => {
+                return Err(ArgsError::invalid_value(
+                    "output template field",
+                    field,
+                    &["name", "version", "target", "ext"],
+                    None,
+                ));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(PathBuf::from(output))
+}
+
+pub fn get_output(output: Option<&Path>, command_path: &Path) -> Result<PathBuf, ArgsError> {
+    let output = match output {
+        Some(path) => path.as_os_str().to_owned(),
+        None => {
+            let name = command_path.file_name().ok_or_else(|| {
+                ArgsError::message("couldn't infer output path from the command path")
+            })?;
             let mut prefix = OsString::from("packed-");
             prefix.push(name);
             prefix
-        });
+        }
+    };
     let output = Path::new(&std::env::current_dir().unwrap()).join(output);
     if !output.parent().map(|path| path.is_dir()).unwrap_or(false) {
-        println!(
-            "{}: {}",
-            style("output path has no parent directory").red(),
-            output.parent().unwrap().display()
-        );
-        std::process::exit(-1);
+        return Err(ArgsError::invalid(
+            "output path has no parent directory",
+            output.parent().unwrap().display(),
+        ));
     }
     if output.is_dir() {
-        println!(
-            "{}: {}",
-            style("output path is a directory").red(),
-            output.display()
-        );
-        std::process::exit(-1);
-    }
-    std::fs::canonicalize(output.parent().unwrap())
-        .unwrap_or_else(|_| {
-            println!(
-                "{}: {}",
-                style("output path is invalid").red(),
-                output.display()
-            );
-            std::process::exit(-1);
-        })
-        .join(output.file_name().unwrap())
+        return Err(ArgsError::invalid(
+            "output path is a directory",
+            output.display(),
+        ));
+    }
+    Ok(std::fs::canonicalize(output.parent().unwrap())
+        .map_err(|_| ArgsError::invalid("output path is invalid", output.display()))?
+        .join(output.file_name().unwrap()))
 }
 
-pub fn get_unpack_directory(directory: Option<&str>, source: &Path) -> [u8; NAME_SIZE] {
+/// Output path of the `runner_name` target when packing for several runners without an output
+/// template, with the target appended to the file name of `output`.
+pub fn get_target_output(output: &Path, runner_name: &str) -> PathBuf {
+    let stem = match output.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("exe") => output.with_extension(""),
+        _ => output.to_owned(),
+    };
+    let mut output = stem.into_os_string();
+    output.push(format!("-{}", runner_name));
+    if runner_name.contains("windows") {
+        output.push(".exe");
+    }
+    PathBuf::from(output)
+}
+
+pub fn get_unpack_directory(
+    directory: Option<&str>, source: &Path,
+) -> Result<[u8; NAME_SIZE], ArgsError> {
     let directory = if let Some(directory) = directory {
-        directory.as_bytes()
+        // the fields are expanded by the runner when it starts
+        let mut rest = directory.replace("{{", "").replace("}}", "");
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| {
+                    ArgsError::invalid("unpack directory has an unclosed field", directory)
+                })?;
+            match &rest[start + 1..end] {
+                "name" | "version" | "target" => {}
+                field => {
+                    return Err(ArgsError::invalid_value(
+                        "unpack directory field",
+                        field,
+                        &["name", "version", "target"],
+                        None,
+                    ));
+                }
+            }
+            rest = rest[end + 1..].to_string();
+        }
+        directory.to_string()
     } else {
+        // braces in inferred names are escaped, so that they aren't expanded as fields
         source
             .file_name()
-            .unwrap_or_else(|| {
-                println!(
-                    "{}",
-                    style("couldn't infer unpack directory name from the input directory").red()
-                );
-                std::process::exit(-1);
-            })
+            .ok_or_else(|| {
+                ArgsError::message("couldn't infer unpack directory name from the input directory")
+            })?
             .to_str()
-            .unwrap_or_else(|| {
-                println!(
-                    "{}",
-                    style("couldn't infer unpack directory name from the input directory, not valid utf8").red()
-                );
-                std::process::exit(-1);
-            })
-            .as_bytes()
+            .ok_or_else(|| {
+                ArgsError::message(
+                    "couldn't infer unpack directory name from the input directory, not valid utf8",
+                )
+            })?
+            .replace('{', "{{")
+            .replace('}', "}}")
     };
     if directory.len() >= NAME_SIZE {
-        println!(
-            "{}",
-            style("unpack directory name is longer than 127 characters").red()
-        );
-        std::process::exit(-1);
+        return Err(ArgsError::message(
+            "unpack directory name is longer than 127 bytes",
+        ));
     }
-    let mut _directory = [0; NAME_SIZE];
-    _directory[0..directory.len()].copy_from_slice(directory);
-    _directory
+    encode_field(&directory)
+        .ok_or_else(|| ArgsError::message("unpack directory name contains a null character"))
 }
 
-pub fn get_command_path(command: &Path, source: &Path) -> PathBuf {
-    let source = if source.is_file() {
-        source.parent().unwrap_or_else(|| {
-            println!("{}", style("source path has no parent").red());
-            std::process::exit(-1);
-        })
-    } else {
-        source
+pub fn get_app_id(
+    app_id: Option<&str>, unpack_directory: &[u8; NAME_SIZE],
+) -> Result<[u8; 16], ArgsError> {
+    let Some(app_id) = app_id else {
+        // derived from the unpack directory name so that releases of the same application agree
+        // without configuration, with the version and variant bits of a custom UUID
+        let name = unpack_directory.split(|&c| c == 0).next().unwrap();
+        let mut id = [0; 16];
+        for (seed, half) in id.chunks_exact_mut(8).enumerate() {
+            let mut hasher = XxHash64::with_seed(seed as u64);
+            hasher.write(b"wrappe-app-id");
+            hasher.write(name);
+            half.copy_from_slice(&hasher.finish().to_be_bytes());
+        }
+        id[6] = (id[6] & 0x0f) | 0x80;
+        id[8] = (id[8] & 0x3f) | 0x80;
+        return Ok(id);
     };
-    let command = match std::fs::canonicalize(source.join(command)) {
-        Err(_) => std::fs::canonicalize(Path::new(&std::env::current_dir().unwrap()).join(command)),
-        command => command,
-    }
-    .unwrap_or_else(|e| {
-        println!("{}: {}", style("command path is invalid").red(), e);
-        std::process::exit(-1);
-    });
-    if !command.is_file() {
-        println!("{}", style("command path is not a file").red());
-        std::process::exit(-1);
-    }
-    let command = if source.is_dir() {
-        command.strip_prefix(source).unwrap_or_else(|_| {
-            println!(
-                "{}",
-                style("command path is not contained in the source directory").red()
-            );
-            std::process::exit(-1);
-        })
-    } else {
-        command.strip_prefix(source).unwrap_or_else(|_| {
-            println!(
-                "{}",
-                style("command path is not contained in the source directory").red()
-            );
-            std::process::exit(-1);
+    // either 32 hex digits or a hyphenated UUID
+    let hyphenated = app_id.len() == 36
+        && app_id
+            .char_indices()
+            .all(|(i, c)| matches!(i, 8 | 13 | 18 | 23) == (c == '-'));
+    let hex = app_id.replace('-', "");
+    if !(hyphenated || app_id.len() == 32)
+        || hex.len() != 32
+        || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(ArgsError::invalid("application ID is not a UUID", app_id));
+    }
+    let mut id = [0; 16];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(id)
+}
+
+pub fn get_command_path(command: &Path, sources: &[PathBuf]) -> Result<PathBuf, ArgsError> {
+    let roots = sources
+        .iter()
+        .map(|source| {
+            if source.is_file() {
+                source
+                    .parent()
+                    .ok_or_else(|| ArgsError::message("source path has no parent"))
+            } else {
+                Ok(source.as_path())
+            }
         })
-    };
-    command.to_owned()
+        .collect::<Result<Vec<_>, _>>()?;
+    // later inputs replace files of earlier inputs, so the command is looked up in reverse
+    let command = match roots
+        .iter()
+        .rev()
+        .find_map(|root| std::fs::canonicalize(root.join(command)).ok())
+    {
+        Some(command) => Ok(command),
+        None => std::fs::canonicalize(Path::new(&std::env::current_dir().unwrap()).join(command)),
+    }
+    .map_err(|e| ArgsError::invalid("command path is invalid", e))?;
+    if !command.is_file() {
+        return Err(ArgsError::message("command path is not a file"));
+    }
+    let command = roots
+        .iter()
+        .rev()
+        .find_map(|root| command.strip_prefix(root).ok())
+        .ok_or_else(|| {
+            ArgsError::message("command path is not contained in the source directory")
+        })?;
+    Ok(command.to_owned())
 }
 
-pub fn get_command(command_path: &Path) -> [u8; NAME_SIZE] {
+pub fn get_command(command_path: &Path) -> Result<[u8; NAME_SIZE], ArgsError> {
     let command = command_path
         .to_str()
-        .unwrap_or_else(|| {
-            println!("{}", style("command path is not valid utf8").red());
-            std::process::exit(-1);
-        })
-        .as_bytes();
+        .ok_or_else(|| ArgsError::message("command path is not valid utf8"))?;
     if command.len() >= NAME_SIZE {
-        println!(
-            "{}",
-            style("command path is longer than 127 characters").red()
-        );
-        std::process::exit(-1);
+        return Err(ArgsError::message("command path is longer than 127 bytes"));
     }
-    let mut _command = [0; NAME_SIZE];
-    _command[0..command.len()].copy_from_slice(command);
-    _command
+    Ok(encode_field(command).unwrap())
 }
 
-pub fn get_arguments(arguments: &[String]) -> [u8; ARGS_SIZE] {
-    let arguments = arguments.join("\u{1f}");
-    let arguments = arguments.as_bytes();
-    if arguments.len() >= ARGS_SIZE {
-        println!(
-            "{}",
-            style("arguments list is longer than 127 characters").red(),
-        );
-        std::process::exit(-1);
+pub fn get_arguments(arguments: &[String]) -> Result<Vec<u8>, ArgsError> {
+    encode_arguments(arguments).map_err(|e| ArgsError::invalid("couldn't bake arguments", e))
+}
+
+pub fn get_extra_sections(sections: &[String]) -> Result<Vec<u8>, ArgsError> {
+    let mut names = Vec::new();
+    let mut data = Vec::new();
+    for section in sections {
+        let Some((name, path)) = section.split_once('=') else {
+            return Err(ArgsError::invalid(
+                "extra section must be given as NAME=PATH",
+                section,
+            ));
+        };
+        if !is_valid_section_name(name) {
+            return Err(ArgsError::invalid(
+                "extra section name must consist of letters, digits, '-', '_' and '.' and not start with '.'",
+                name,
+            ));
+        }
+        if name == STATISTICS_SECTION {
+            return Err(ArgsError::invalid(
+                "extra section name is reserved for --statistics",
+                name,
+            ));
+        }
+        if names.contains(&name) {
+            return Err(ArgsError::invalid("duplicate extra section", name));
+        }
+        let contents = std::fs::read(path).map_err(|e| {
+            ArgsError::invalid("couldn't read extra section", format!("{}: {}", path, e))
+        })?;
+        write_extra_section(&mut data, name, &contents);
+        names.push(name);
+    }
+    Ok(data)
+}
+
+pub fn get_desktop_files(
+    desktop_file: Option<&Path>, desktop_icon: Option<&Path>,
+) -> Result<Vec<(PathBuf, PathBuf)>, ArgsError> {
+    let mut files = Vec::new();
+    if let Some(desktop_file) = desktop_file {
+        if !desktop_file.is_file() {
+            return Err(ArgsError::invalid(
+                "desktop entry file does not exist",
+                desktop_file.display(),
+            ));
+        }
+        files.push((
+            Path::new(WRAPPE_DIRECTORY).join(DESKTOP_ENTRY_FILE),
+            desktop_file.to_owned(),
+        ));
+    }
+    if let Some(desktop_icon) = desktop_icon {
+        if !desktop_icon.is_file() {
+            return Err(ArgsError::invalid(
+                "desktop icon file does not exist",
+                desktop_icon.display(),
+            ));
+        }
+        let mut name = OsString::from(DESKTOP_ICON_NAME);
+        if let Some(extension) = desktop_icon.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        files.push((
+            Path::new(WRAPPE_DIRECTORY).join(name),
+            desktop_icon.to_owned(),
+        ));
+    }
+    Ok(files)
+}
+
+/// Read the file list in `files_from`, or from stdin if it is `-`, as pairs of the relative
+/// destination path in the payload and the source path resolved relative to `base`.
+/// The list contains one `SOURCE -> DEST` mapping or `SOURCE` path per line, or a JSON array
+/// of objects with `source` and `dest` keys. Later entries replace earlier entries with the
+/// same destination path.
+pub fn get_files_from(files_from: &str, base: &Path) -> Result<Vec<(PathBuf, PathBuf)>, ArgsError> {
+    if !base.is_dir() {
+        return Err(ArgsError::invalid(
+            "input path must be a directory with a file list",
+            base.display(),
+        ));
+    }
+    let mut list = String::new();
+    let result = if files_from == "-" {
+        std::io::stdin().read_to_string(&mut list).map(|_| ())
+    } else {
+        std::fs::read_to_string(files_from).map(|contents| list = contents)
+    };
+    result.map_err(|e| ArgsError::invalid("couldn't read file list", e))?;
+
+    let entries = if list.trim_start().starts_with('[') {
+        let value = serde_json::from_str::<Value>(&list)
+            .map_err(|e| ArgsError::invalid("couldn't parse file list", e))?;
+        let Value::Array(values) = value else {
+            return Err(ArgsError::invalid(
+                "couldn't parse file list",
+                "expected an array",
+            ));
+        };
+        values
+            .iter()
+            .map(|value| {
+                let source = value.get("source").and_then(Value::as_str);
+                let dest = value.get("dest").and_then(Value::as_str).or(source);
+                match (source, dest) {
+                    (Some(source), Some(dest)) => Ok((source.to_string(), dest.to_string())),
+                    _ => Err(ArgsError::invalid(
+                        "couldn't parse file list",
+                        "expected objects with source and dest strings",
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        list.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once("->") {
+                Some((source, dest)) => (source.trim().to_string(), dest.trim().to_string()),
+                None => (line.to_string(), line.to_string()),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut files = BTreeMap::new();
+    for (source, dest) in entries {
+        let dest = PathBuf::from(dest.replace('\\', "/"));
+        if dest.as_os_str().is_empty()
+            || !dest
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(ArgsError::invalid(
+                "destination path in file list must be a relative path",
+                dest.display(),
+            ));
+        }
+        let source = base.join(source);
+        if !source.is_file() {
+            return Err(ArgsError::invalid(
+                "file in file list does not exist",
+                source.display(),
+            ));
+        }
+        files.insert(dest, source);
+    }
+    Ok(files.into_iter().collect())
+}
+
+pub fn get_content_filters(filters: &[String]) -> Result<ContentFilters, ArgsError> {
+    let mut content_filters = ContentFilters::default();
+    for filter in filters {
+        let Some((pattern, names)) = filter.rsplit_once('=') else {
+            return Err(ArgsError::invalid(
+                "filter must be given as GLOB=FILTER[,FILTER...]",
+                filter,
+            ));
+        };
+        let glob = Glob::new(pattern).map_err(|e| {
+            ArgsError::invalid("not a valid glob pattern", format!("{}: {}", pattern, e))
+        })?;
+        let filters = names
+            .split(',')
+            .map(|name| {
+                Filter::from_name(name)
+                    .ok_or_else(|| ArgsError::invalid_value("filter", name, &FILTER_NAMES, None))
+            })
+            .collect::<Result<_, _>>()?;
+        content_filters.push(glob, filters);
+    }
+    Ok(content_filters)
+}
+
+pub fn get_store_globs(store: &[String]) -> Result<GlobSet, ArgsError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in store {
+        let glob = Glob::new(pattern).map_err(|e| {
+            ArgsError::invalid("not a valid glob pattern", format!("{}: {}", pattern, e))
+        })?;
+        builder.add(glob);
     }
-    let mut _arguments = [0; ARGS_SIZE];
-    _arguments[0..arguments.len()].copy_from_slice(arguments);
-    _arguments
+    builder
+        .build()
+        .map_err(|e| ArgsError::invalid("couldn't build glob patterns", e))
 }
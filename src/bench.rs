@@ -0,0 +1,303 @@
+use std::{
+    env::{current_exe, temp_dir},
+    fs::{File, create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use console::style;
+use jwalk::WalkDir;
+use rand::{
+    distributions::{Alphanumeric, Distribution},
+    thread_rng,
+};
+
+use crate::{
+    args::{OrExit, emoji, get_algorithm, get_runner_name},
+    payload::Payload,
+};
+
+#[derive(Args)]
+#[command(
+    about = "Compare pack time, unpack time and size of an input directory across compression settings"
+)]
+pub struct BenchArgs {
+    /// Compression algorithms to compare
+    #[arg(
+        short = 'a',
+        long,
+        value_delimiter = ',',
+        default_value = "zstd,xz,brotli"
+    )]
+    algorithm:   Vec<String>,
+    /// Compression levels to compare [default: low, default and high level of each algorithm]
+    #[arg(short = 'c', long, value_delimiter = ',')]
+    compression: Vec<u32>,
+    /// Platform to pack for, unpacking is only measured for the native runner
+    #[arg(short = 'r', long, default_value = "native")]
+    runner:      String,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:   bool,
+    /// Path to the input directory
+    #[arg(name = "input")]
+    input:       PathBuf,
+    /// Path to the executable to start after unpacking [default: first file in the input]
+    #[arg(name = "command")]
+    command:     Option<PathBuf>,
+}
+
+/// Result of packing and unpacking the input with one compression setting.
+struct Measurement {
+    algorithm: String,
+    level:     u32,
+    size:      u64,
+    payload:   u64,
+    pack:      Duration,
+    unpack:    Option<Duration>,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Highest compression level of the algorithm `kind`.
+fn max_level(kind: u8) -> u32 {
+    match kind {
+        1 => 9,
+        2 => 11,
+        _ => 22,
+    }
+}
+
+/// Low, default and high compression level of the algorithm `kind`.
+fn default_levels(kind: u8) -> Vec<u32> {
+    match kind {
+        1 => vec![3, 6, 9],
+        2 => vec![3, 8, 11],
+        _ => vec![3, 8, 19],
+    }
+}
+
+/// Last lines of the output of a failed run.
+fn output_tail(output: &Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines = stdout
+        .lines()
+        .chain(stderr.lines())
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(3)..].join("\n")
+}
+
+fn format_duration(duration: Duration) -> String { format!("{:.2}s", duration.as_secs_f64()) }
+
+/// Pack the input directory in `args` with every combination of the selected algorithms and
+/// compression levels, unpack the output with the native runner and print a comparison table.
+pub fn bench(args: BenchArgs) {
+    let input =
+        std::fs::canonicalize(&args.input).unwrap_or_else(|e| fail("input path is invalid", e));
+    if !input.is_dir() {
+        fail("input path is not a directory", input.display());
+    }
+    let files = WalkDir::new(&input)
+        .skip_hidden(false)
+        .sort(true)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .collect::<Vec<_>>();
+    let command = match &args.command {
+        Some(command) => command.clone(),
+        None => files
+            .first()
+            .and_then(|entry| entry.path().strip_prefix(&input).ok().map(Path::to_owned))
+            .unwrap_or_else(|| fail("input directory contains no files", input.display())),
+    };
+    let input_size = files
+        .iter()
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum::<u64>();
+
+    let runner_name = get_runner_name(&args.runner).or_exit();
+    let native = runner_name == get_runner_name("native").or_exit();
+    let mut matrix = Vec::new();
+    for algorithm in args.algorithm.iter() {
+        let kind = get_algorithm(algorithm).or_exit();
+        let mut levels = if args.compression.is_empty() {
+            default_levels(kind)
+        } else {
+            args.compression
+                .iter()
+                .map(|level| (*level).min(max_level(kind)))
+                .collect()
+        };
+        levels.dedup();
+        matrix.extend(levels.into_iter().map(|level| (algorithm.clone(), level)));
+    }
+
+    let exe = current_exe().unwrap_or_else(|e| fail("couldn't get path of wrappe", e));
+    let directory = temp_dir().join(format!(
+        "wrappe-bench-{}",
+        Alphanumeric
+            .sample_iter(thread_rng())
+            .map(char::from)
+            .take(8)
+            .collect::<String>()
+    ));
+    create_dir_all(&directory).unwrap_or_else(|e| fail("couldn't create bench directory", e));
+    let output = directory.join("bench");
+
+    println!(
+        "{}benchmarking {} settings for {} ({:.2}MB in {} files)…",
+        emoji("📍 ", ""),
+        style(matrix.len()).magenta(),
+        style(input.display()).blue().bright(),
+        input_size as f64 / 1024.0 / 1024.0,
+        files.len()
+    );
+    if !native {
+        println!(
+            "{} {}",
+            style("note: unpacking is only measured for the native runner")
+                .yellow()
+                .dim(),
+            style(format!("(target: {})", runner_name)).yellow().dim(),
+        );
+    }
+
+    let total = matrix.len();
+    let mut measurements = Vec::new();
+    for (i, (algorithm, level)) in matrix.into_iter().enumerate() {
+        println!(
+            "{} {}packing with {} level {}…",
+            style(format!("[{}/{}]", i + 1, total)).bold().dim(),
+            emoji("🚚 ", ""),
+            style(&algorithm).magenta(),
+            style(level).magenta(),
+        );
+        let start = Instant::now();
+        let packed = Command::new(&exe)
+            .args(["--plain", "--no-validate", "-i", "none", "-s", "bench"])
+            .args([
+                "-r",
+                &args.runner,
+                "-a",
+                &algorithm,
+                "-c",
+                &level.to_string(),
+            ])
+            .arg(&input)
+            .arg(&command)
+            .arg(&output)
+            .output();
+        let pack = start.elapsed();
+        match packed {
+            Ok(packed) if packed.status.success() => {}
+            Ok(packed) => {
+                let _ = remove_dir_all(&directory);
+                fail("packing failed", output_tail(&packed));
+            }
+            Err(e) => {
+                let _ = remove_dir_all(&directory);
+                fail("couldn't start packing", e);
+            }
+        }
+        let size = output
+            .metadata()
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let payload = File::open(&output)
+            .and_then(|mut file| Payload::read(&mut file))
+            .map(|payload| {
+                let header = &payload.header;
+                header.payload_size + header.dictionary_size + header.sections_size
+            })
+            .unwrap_or_else(|e| {
+                let _ = remove_dir_all(&directory);
+                fail("couldn't read payload", e)
+            });
+
+        let unpack = if native {
+            let unpack_root = directory.join(format!("unpack-{}", i));
+            let start = Instant::now();
+            let unpacked = Command::new(&output)
+                .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+                .env("WRAPPE_UNPACK_ONLY", "1")
+                .output();
+            let unpack = start.elapsed();
+            let _ = remove_dir_all(&unpack_root);
+            match unpacked {
+                Ok(unpacked) if unpacked.status.success() => Some(unpack),
+                Ok(unpacked) => {
+                    let _ = remove_dir_all(&directory);
+                    fail("unpacking failed", output_tail(&unpacked));
+                }
+                Err(e) => {
+                    let _ = remove_dir_all(&directory);
+                    fail("couldn't start unpacking", e);
+                }
+            }
+        } else {
+            None
+        };
+        measurements.push(Measurement {
+            algorithm,
+            level,
+            size,
+            payload: payload.get(),
+            pack,
+            unpack,
+        });
+    }
+    let _ = remove_dir_all(&directory);
+
+    println!();
+    println!(
+        "{}",
+        style(format!(
+            "{:<10} {:>5} {:>12} {:>12} {:>8} {:>9} {:>9}",
+            "algorithm", "level", "size", "payload", "ratio", "pack", "unpack"
+        ))
+        .bold()
+    );
+    let smallest = measurements
+        .iter()
+        .map(|measurement| measurement.size)
+        .min()
+        .unwrap_or(0);
+    for measurement in measurements.iter() {
+        let line = format!(
+            "{:<10} {:>5} {:>10.2}MB {:>10.2}MB {:>7.2}% {:>9} {:>9}",
+            measurement.algorithm,
+            measurement.level,
+            measurement.size as f64 / 1024.0 / 1024.0,
+            measurement.payload as f64 / 1024.0 / 1024.0,
+            measurement.payload as f64 / input_size.max(1) as f64 * 100.0,
+            format_duration(measurement.pack),
+            measurement
+                .unpack
+                .map(format_duration)
+                .unwrap_or_else(|| "-".to_string())
+        );
+        if measurement.size == smallest {
+            println!("{}", style(line).green());
+        } else {
+            println!("{}", line);
+        }
+    }
+    println!();
+    println!(
+        "{}",
+        style(
+            "sizes include the runner, ratios are payload sizes relative to the input size, \
+             the smallest output is highlighted"
+        )
+        .dim()
+    );
+}
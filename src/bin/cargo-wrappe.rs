@@ -0,0 +1,28 @@
+//! Cargo subcommand for `cargo wrappe`, which runs `wrappe cargo` with the wrappe binary
+//! installed next to it.
+
+use std::{
+    env::{args_os, consts::EXE_SUFFIX, current_exe},
+    path::PathBuf,
+    process::{Command, exit},
+};
+
+fn main() {
+    let wrappe = current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name(format!("wrappe{}", EXE_SUFFIX)))
+        .filter(|wrappe| wrappe.is_file())
+        .unwrap_or_else(|| PathBuf::from("wrappe"));
+    // cargo passes the name of the subcommand as the first argument
+    let mut args = args_os().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "wrappe") {
+        args.next();
+    }
+    match Command::new(&wrappe).arg("cargo").args(args).status() {
+        Ok(status) => exit(status.code().unwrap_or(-1)),
+        Err(e) => {
+            eprintln!("couldn't run {}: {}", wrappe.display(), e);
+            exit(-1);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    env::{current_dir, current_exe, var_os},
+    ffi::OsString,
+    fs::{copy, create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use clap::Args;
+use console::style;
+use jwalk::WalkDir;
+use serde_json::Value;
+
+use crate::args::emoji;
+
+#[derive(Args)]
+#[command(about = "Build the binary of a Cargo package and pack it together with its assets")]
+pub struct CargoArgs {
+    /// Path to the Cargo.toml of the package [default: found from the current directory]
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+    /// Package to build in a workspace [default: the package in the current directory]
+    #[arg(short = 'p', long, value_name = "NAME")]
+    package:       Option<String>,
+    /// Binary target to build and pack [default: the only binary or the default-run binary]
+    #[arg(long, value_name = "NAME")]
+    bin:           Option<String>,
+    /// Target triple to build for, also selecting the runner
+    #[arg(long, value_name = "TRIPLE")]
+    target:        Option<String>,
+    /// Cargo profile to build with
+    #[arg(long, value_name = "NAME", default_value = "release")]
+    profile:       String,
+    /// Path of the output executable [default: the binary name in <target directory>/wrappe]
+    #[arg(short = 'o', long, value_name = "PATH")]
+    output:        Option<PathBuf>,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:     bool,
+    /// Options passed to wrappe when packing, after the options from the package metadata
+    #[arg(last = true, value_name = "OPTIONS")]
+    options:       Vec<String>,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Strings in the array `value`, failing with a message naming `key` if it isn't one.
+fn strings(value: Option<&Value>, key: &str) -> Vec<String> {
+    match value {
+        None => Vec::new(),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| {
+                value.as_str().map(str::to_string).unwrap_or_else(|| {
+                    fail(
+                        "package metadata must be an array of strings",
+                        format!("wrappe.{}", key),
+                    )
+                })
+            })
+            .collect(),
+        Some(_) => fail(
+            "package metadata must be an array of strings",
+            format!("wrappe.{}", key),
+        ),
+    }
+}
+
+/// Package of the workspace in `metadata` selected by `name`, or by `manifest` if it is one of
+/// the workspace members, or the only package of the workspace.
+fn select_package<'a>(metadata: &'a Value, name: Option<&str>, manifest: &Path) -> &'a Value {
+    let packages = match metadata.get("packages") {
+        Some(Value::Array(packages)) => packages,
+        _ => fail("couldn't read cargo metadata", "no packages"),
+    };
+    if let Some(name) = name {
+        return packages
+            .iter()
+            .find(|package| package.get("name").and_then(Value::as_str) == Some(name))
+            .unwrap_or_else(|| fail("package not found in the workspace", name));
+    }
+    let manifest = manifest.canonicalize().unwrap_or(manifest.to_path_buf());
+    packages
+        .iter()
+        .find(|package| {
+            package
+                .get("manifest_path")
+                .and_then(Value::as_str)
+                .and_then(|path| Path::new(path).canonicalize().ok())
+                .is_some_and(|path| path == manifest)
+        })
+        .or_else(|| (packages.len() == 1).then(|| &packages[0]))
+        .unwrap_or_else(|| {
+            fail(
+                "the workspace has multiple packages, select one with",
+                "--package",
+            )
+        })
+}
+
+/// Name of the binary target of `package` selected by `name`, the package metadata, the
+/// default-run binary or the only binary of the package.
+fn select_bin(package: &Value, name: Option<&str>, wrappe: Option<&Value>) -> String {
+    let bins = match package.get("targets") {
+        Some(Value::Array(targets)) => targets
+            .iter()
+            .filter(|target| match target.get("kind") {
+                Some(Value::Array(kinds)) => kinds.iter().any(|kind| kind.as_str() == Some("bin")),
+                _ => false,
+            })
+            .filter_map(|target| target.get("name").and_then(Value::as_str))
+            .collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+    let name = name
+        .or_else(|| {
+            wrappe
+                .and_then(|wrappe| wrappe.get("bin"))
+                .and_then(Value::as_str)
+        })
+        .or_else(|| package.get("default_run").and_then(Value::as_str));
+    match (name, bins.as_slice()) {
+        (Some(name), _) if bins.contains(&name) => name.to_string(),
+        (Some(name), _) => fail("binary target not found in the package", name),
+        (None, [bin]) => bin.to_string(),
+        (None, []) => fail("package has no binary targets", "add a [[bin]] target"),
+        (None, _) => fail(
+            "the package has multiple binaries, select one with",
+            "--bin or package.metadata.wrappe.bin",
+        ),
+    }
+}
+
+/// Copy the file or directory at `source` to `target`, keeping symlinks in directories.
+fn copy_asset(source: &Path, target: &Path) -> std::io::Result<()> {
+    if !source.is_dir() {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        return copy(source, target).map(|_| ());
+    }
+    for entry in WalkDir::new(source).skip_hidden(false).sort(true) {
+        let entry = entry?;
+        let relative = entry.path();
+        let relative = relative.strip_prefix(source).unwrap();
+        let destination = target.join(relative);
+        if entry.file_type().is_dir() {
+            create_dir_all(&destination)?;
+        } else if entry.file_type().is_symlink() {
+            let link = std::fs::read_link(entry.path())?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(link, &destination)?;
+            #[cfg(windows)]
+            if entry.path().is_dir() {
+                std::os::windows::fs::symlink_dir(link, &destination)?;
+            } else {
+                std::os::windows::fs::symlink_file(link, &destination)?;
+            }
+        } else {
+            copy(entry.path(), &destination)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `options` contain one of the options in `names`, with or without a value.
+fn has_option(options: &[String], names: &[&str]) -> bool {
+    options.iter().any(|option| {
+        names
+            .iter()
+            .any(|name| option == name || option.starts_with(&format!("{}=", name)))
+    })
+}
+
+/// Build the binary of the Cargo package in `args` and pack it together with the assets
+/// declared in its `package.metadata.wrappe` table, with the options from the table followed by
+/// the options in `args`. The binary and assets are assembled in `<target directory>/wrappe`.
+pub fn cargo(args: CargoArgs) {
+    let cargo = var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    let manifest = args
+        .manifest_path
+        .clone()
+        .or_else(|| {
+            let directory = current_dir().ok()?;
+            directory
+                .ancestors()
+                .map(|directory| directory.join("Cargo.toml"))
+                .find(|manifest| manifest.is_file())
+        })
+        .unwrap_or_else(|| fail("couldn't find Cargo.toml", "use --manifest-path"));
+
+    let output = Command::new(&cargo)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .output()
+        .unwrap_or_else(|e| fail("couldn't run cargo metadata", e));
+    if !output.status.success() {
+        fail(
+            "cargo metadata failed",
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+    let metadata = serde_json::from_slice::<Value>(&output.stdout)
+        .unwrap_or_else(|e| fail("couldn't read cargo metadata", e));
+    let package = select_package(&metadata, args.package.as_deref(), &manifest);
+    let package_name = package
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let package_dir = package
+        .get("manifest_path")
+        .and_then(Value::as_str)
+        .and_then(|path| Path::new(path).parent())
+        .unwrap_or_else(|| fail("couldn't read cargo metadata", "no manifest path"));
+    let wrappe = package
+        .get("metadata")
+        .and_then(|metadata| metadata.get("wrappe"));
+    let bin = select_bin(package, args.bin.as_deref(), wrappe);
+    let assets = strings(wrappe.and_then(|wrappe| wrappe.get("assets")), "assets");
+    let options = strings(wrappe.and_then(|wrappe| wrappe.get("options")), "options");
+    let target_dir = PathBuf::from(
+        metadata
+            .get("target_directory")
+            .and_then(Value::as_str)
+            .unwrap_or_else(|| fail("couldn't read cargo metadata", "no target directory")),
+    );
+
+    println!(
+        "{}{} {} {}",
+        emoji("🔨 ", ""),
+        style("building").dim(),
+        style(&bin).blue().bright(),
+        style(format!("({} profile)", args.profile)).dim()
+    );
+    let mut build = Command::new(&cargo);
+    build
+        .args(["build", "--package", package_name, "--bin", &bin])
+        .args(["--profile", &args.profile])
+        .arg("--manifest-path")
+        .arg(&manifest);
+    if let Some(target) = &args.target {
+        build.args(["--target", target]);
+    }
+    match build.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => fail("cargo build failed", status),
+        Err(e) => fail("couldn't run cargo build", e),
+    }
+
+    // cargo writes the dev and test profiles to the debug directory
+    let profile_dir = match args.profile.as_str() {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    };
+    let build_dir = match &args.target {
+        Some(target) => target_dir.join(target).join(profile_dir),
+        None => target_dir.join(profile_dir),
+    };
+    let windows = match &args.target {
+        Some(target) => target.contains("windows"),
+        None => cfg!(windows),
+    };
+    let executable = format!("{}{}", bin, if windows { ".exe" } else { "" });
+
+    let staging = target_dir.join("wrappe").join(&bin);
+    println!(
+        "{}{} {}",
+        emoji("📦 ", ""),
+        style("assembling").dim(),
+        style(staging.display()).blue().bright()
+    );
+    if staging.exists() {
+        remove_dir_all(&staging).unwrap_or_else(|e| fail("couldn't clean assembly directory", e));
+    }
+    create_dir_all(&staging).unwrap_or_else(|e| fail("couldn't create assembly directory", e));
+    copy_asset(&build_dir.join(&executable), &staging.join(&executable))
+        .unwrap_or_else(|e| fail("couldn't copy binary", e));
+    for asset in assets.iter() {
+        let source = package_dir.join(asset);
+        let target = Path::new(asset)
+            .components()
+            .filter(|component| matches!(component, std::path::Component::Normal(_)))
+            .collect::<PathBuf>();
+        copy_asset(&source, &staging.join(target))
+            .unwrap_or_else(|e| fail("couldn't copy asset", format!("{}: {}", asset, e)));
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        target_dir.join("wrappe").join(format!(
+            "{}-packed{}",
+            bin,
+            if windows { ".exe" } else { "" }
+        ))
+    });
+    let mut pack =
+        Command::new(current_exe().unwrap_or_else(|e| fail("couldn't get path of wrappe", e)));
+    if args.plain {
+        pack.arg("--plain");
+    }
+    // options after `--` are arguments baked into the executable
+    let explicit = [&options[..], &args.options[..]].concat();
+    let (explicit, arguments) = match explicit.iter().position(|option| option == "--") {
+        Some(separator) => explicit.split_at(separator),
+        None => (&explicit[..], &[][..]),
+    };
+    if let Some(target) = &args.target {
+        if !has_option(explicit, &[
+            "--runner",
+            "-r",
+            "--runner-path",
+            "--fetch-runner",
+        ]) {
+            pack.args(["--runner", target]);
+        }
+    }
+    if !has_option(explicit, &["--unpack-directory", "-d"]) {
+        pack.args(["--unpack-directory", package_name]);
+    }
+    let status = pack
+        .args(explicit)
+        .arg(&staging)
+        .arg(&executable)
+        .arg(&output)
+        .args(arguments)
+        .status()
+        .unwrap_or_else(|e| fail("couldn't run wrappe", e));
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(-1));
+    }
+}
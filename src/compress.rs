@@ -1,17 +1,24 @@
 use std::{
-    env::temp_dir,
-    fs::{File, read_link, remove_file, symlink_metadata},
+    collections::{BTreeMap, HashMap, HashSet},
+    env::{current_dir, temp_dir},
+    ffi::OsStr,
+    fmt::{Display, Formatter},
+    fs::{File, Metadata, read_link, remove_file, rename, symlink_metadata, write},
     hash::Hasher,
-    io::{BufReader, Cursor, Read, Result, Seek, Write, copy},
-    path::Path,
+    io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Result, Seek, Write, copy, sink},
+    path::{Path, PathBuf},
     sync::{
-        Arc, Mutex,
-        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::sync_channel,
     },
+    thread::scope,
     time::SystemTime,
 };
 
-use jwalk::WalkDir;
+use brotli::{BrotliCompress, Decompressor, enc::BrotliEncoderParams};
+use globset::GlobSet;
+use jwalk::WalkDirGeneric;
 use path_slash::PathExt;
 use rand::{
     distributions::{Alphanumeric, Distribution},
@@ -19,10 +26,19 @@ use rand::{
 };
 use rayon::prelude::*;
 use sysinfo::System;
+use tracing::{debug_span, info_span};
 use twox_hash::XxHash64;
-use zstd::{Encoder, dict::EncoderDictionary};
+use xz2::{stream::MtStreamBuilder, write::XzEncoder};
+use zstd::{
+    Decoder, Encoder,
+    dict::{DecoderDictionary, EncoderDictionary},
+};
 
-use crate::types::*;
+use crate::{
+    filter::ContentFilters,
+    shebang::{SHEBANG_SIZE, ShebangMode, Shebangs},
+    types::*,
+};
 
 pub const HASH_SEED: u64 = 1246736989840;
 
@@ -45,21 +61,866 @@ impl<R: Read, H: Hasher> Read for HashReader<R, H> {
     }
 }
 
+/// Writer that computes the BLAKE3 hash of everything written to it.
+pub struct PayloadWriter<W: Write + Seek> {
+    writer: W,
+    hasher: blake3::Hasher,
+}
+impl<W: Write + Seek> PayloadWriter<W> {
+    pub fn new(writer: W) -> Self {
+        PayloadWriter {
+            writer,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn finish(&self) -> [u8; 32] { self.hasher.finalize().into() }
+}
+impl<W: Write + Seek> Write for PayloadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let bytes = self.writer.write(buf)?;
+        self.hasher.update(&buf[0..bytes]);
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> Result<()> { self.writer.flush() }
+}
+impl<W: Write + Seek> Seek for PayloadWriter<W> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> Result<u64> { self.writer.seek(pos) }
+}
+
+#[derive(Debug)]
+pub enum CompressError {
+    Cancelled,
+    Io(Error),
+}
+impl Display for CompressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressError::Cancelled => write!(f, "cancelled"),
+            CompressError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for CompressError {}
+impl From<Error> for CompressError {
+    fn from(e: Error) -> Self { CompressError::Io(e) }
+}
+
+/// Event reported by [`compress`] while it compresses the payload.
+#[derive(Debug, Clone, Copy)]
+pub enum CompressEvent<'a> {
+    /// An entry was processed.
+    Progress,
+    /// Packing failed in a way that isn't tied to a single skipped entry, compression continues.
+    Error(&'a str),
+    /// The entry at `path` was skipped for `reason`, compression continues without it.
+    /// Entries skipped by design like junk files are `expected` and not reported as errors.
+    Skipped {
+        path:     &'a Path,
+        reason:   &'a str,
+        expected: bool,
+    },
+    /// The file at `path` in the payload with `size` bytes of contents hashed to `hash` was
+    /// written with `compressed` bytes, or in a solid block without a compressed size of its own.
+    Packed {
+        path:       &'a str,
+        size:       u64,
+        compressed: Option<u64>,
+        hash:       u64,
+        stored:     bool,
+    },
+    /// Compression started the step or file described by the message.
+    Step(&'a str),
+    /// Summary of how the entries were packed.
+    Info(&'a str),
+    /// Summary of entries that were packed differently than they are on disk.
+    Warning(&'a str),
+}
+
+/// Reader that fails once `cancel` is set.
+pub struct CancelReader<'a, R: Read> {
+    reader: R,
+    cancel: &'a AtomicBool,
+}
+impl<'a, R: Read> CancelReader<'a, R> {
+    pub fn new(reader: R, cancel: &'a AtomicBool) -> Self { CancelReader { reader, cancel } }
+}
+impl<R: Read> Read for CancelReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(Error::other("cancelled"));
+        }
+        self.reader.read(buf)
+    }
+}
+
+/// Compressed file data waiting to be written into the archive.
+enum CompressedData {
+    Memory(Vec<u8>),
+    Cache(PathBuf),
+    /// Uncompressed file contents read from the source file when they are written, which
+    /// also determines the file hash.
+    Stored(PathBuf),
+    /// Compressed file contents reused from the repack cache.
+    Cached(PathBuf),
+    /// Uncompressed file contents to be added to a solid block.
+    Solid(Vec<u8>),
+}
+impl CompressedData {
+    /// Number of bytes of file contents held in memory.
+    fn held(&self) -> u64 {
+        match self {
+            CompressedData::Memory(data) | CompressedData::Solid(data) => data.len() as u64,
+            _ => 0,
+        }
+    }
+}
+
+/// Budget of bytes of file contents held in memory while waiting to be written into the
+/// archive. Compressing threads reserve the size of a file before reading it into memory and
+/// wait while the budget is exhausted, the writer thread releases the bytes after writing.
+struct MemoryBudget {
+    limit:    u64,
+    /// Held bytes, the highest number of held bytes and the number of times a thread waited.
+    state:    Mutex<(u64, u64, u64)>,
+    released: Condvar,
+}
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            state: Mutex::new((0, 0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserve `size` bytes, waiting until they are available. Reserving never waits when no
+    /// bytes are held or when `ready` returns true, so that a single file larger than the
+    /// budget or the file the writer is waiting for can always be reserved.
+    fn acquire(&self, size: u64, ready: impl Fn() -> bool) -> Reservation<'_> {
+        let mut state = self.state.lock().unwrap();
+        let mut waited = false;
+        while state.0 > 0 && state.0 + size > self.limit && !ready() {
+            waited = true;
+            state = self.released.wait(state).unwrap();
+        }
+        state.0 += size;
+        state.1 = state.1.max(state.0);
+        state.2 += waited as u64;
+        Reservation { budget: self, size }
+    }
+
+    /// Replace the reservation of `reserved` bytes with `actual` bytes once the size of the
+    /// contents held in memory is known.
+    fn adjust(&self, reserved: u64, actual: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = (state.0 + actual).saturating_sub(reserved);
+        state.1 = state.1.max(state.0);
+        self.released.notify_all();
+    }
+
+    fn release(&self, size: u64) { self.adjust(size, 0); }
+
+    /// Wake waiting threads to check whether they are ready.
+    fn notify(&self) {
+        let _state = self.state.lock().unwrap();
+        self.released.notify_all();
+    }
+
+    /// Highest number of held bytes and the number of times a thread waited for the budget.
+    fn usage(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.1, state.2)
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], released when dropped before being handed over.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    size:   u64,
+}
+impl Reservation<'_> {
+    /// Hand the reservation over to `data`, whose held bytes are released by the writer thread.
+    fn hand_over(self, data: &CompressedData) {
+        self.budget.adjust(self.size, data.held());
+        std::mem::forget(self);
+    }
+}
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) { self.budget.release(self.size); }
+}
+
+/// Size below which files are grouped into solid blocks when solid compression is enabled.
+pub const SOLID_FILE_SIZE: u64 = 64 * 1024;
+
+/// Uncompressed contents of small files waiting to be compressed together.
+#[derive(Default)]
+struct SolidBlock {
+    data:  Vec<u8>,
+    files: Vec<(FileSectionHeader, String)>,
+}
+
+impl SolidBlock {
+    /// Append the `contents` of the file with `header` and `link` to the block.
+    fn push(&mut self, mut header: FileSectionHeader, link: String, contents: &[u8]) {
+        header.solid = 1;
+        header.block_offset = (self.data.len() as u64).into();
+        header.block_size = (contents.len() as u64).into();
+        self.data.extend_from_slice(contents);
+        self.files.push((header, link));
+    }
+
+    /// Compress the block and append it to `target`, then return the headers of its files
+    /// with the position relative to `zero`, size and hash of the compressed block.
+    /// The block is empty afterwards.
+    fn write<W: Write + Seek>(
+        &mut self, target: &mut W, zero: u64, algorithm: u8, compression: u32,
+        dictionary: Option<&EncoderDictionary>,
+    ) -> Result<Vec<(FileSectionHeader, String)>> {
+        let mut data = Vec::new();
+        let result = copy_encode(
+            &self.data[..],
+            &mut data,
+            algorithm,
+            compression as i32,
+            0,
+            dictionary,
+        );
+        self.data.clear();
+        let mut files = std::mem::take(&mut self.files);
+        result?;
+        let start = target.stream_position()?;
+        let mut hasher = HashReader::new(Cursor::new(&data), XxHash64::with_seed(HASH_SEED));
+        copy(&mut hasher, target.by_ref())?;
+        let compressed_hash = hasher.finish();
+        for (header, _) in files.iter_mut() {
+            header.position = (start - zero).into();
+            header.size = (data.len() as u64).into();
+            header.compressed_hash = compressed_hash.into();
+        }
+        Ok(files)
+    }
+}
+
+/// Clamp the time of `seconds` and `nanos` to `timestamp`.
+fn clamp_time(seconds: u64, nanos: u32, timestamp: u64) -> (u64, u32) {
+    if seconds >= timestamp {
+        (timestamp, 0)
+    } else {
+        (seconds, nanos)
+    }
+}
+
+/// Hash the contents of `file` and return the hash and the path of its entry in `cache`
+/// for the compression `settings`. The file is rewound afterwards.
+fn cache_entry(
+    cache: &Path, file: &mut File, size: u64, settings: &str, cancel: &AtomicBool,
+) -> Result<(u64, PathBuf)> {
+    let mut reader = HashReader::new(
+        CancelReader::new(&*file, cancel),
+        XxHash64::with_seed(HASH_SEED),
+    );
+    copy(&mut reader, &mut sink())?;
+    let file_hash = reader.finish();
+    file.rewind()?;
+    Ok((
+        file_hash,
+        cache.join(format!("{:016x}-{:x}-{}", file_hash, size, settings)),
+    ))
+}
+
+/// Path next to `path` with a random suffix for writing a file that is renamed to `path` once it
+/// is complete.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(
+        ".{}",
+        Alphanumeric
+            .sample_iter(thread_rng())
+            .map(char::from)
+            .take(8)
+            .collect::<String>()
+    ));
+    PathBuf::from(temporary)
+}
+
+/// Whether `error` was returned for renaming a file to another filesystem,
+/// `EXDEV` on Unix-like systems and `ERROR_NOT_SAME_DEVICE` on Windows.
+pub fn is_cross_device(error: &Error) -> bool {
+    #[cfg(windows)]
+    const CROSS_DEVICE: i32 = 17;
+    #[cfg(not(windows))]
+    const CROSS_DEVICE: i32 = 18;
+    error.raw_os_error() == Some(CROSS_DEVICE)
+}
+
+/// Rename `from` to `to`. If they are on different filesystems, `from` is copied to a temporary
+/// file next to `to` that is renamed to `to` instead, so that `to` is still replaced atomically,
+/// and `from` is removed afterwards.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match rename(from, to) {
+        Err(e) if is_cross_device(&e) => {
+            let temporary = temporary_path(to);
+            std::fs::copy(from, &temporary)
+                .and_then(|_| rename(&temporary, to))
+                .inspect_err(|_| {
+                    let _ = remove_file(&temporary);
+                })?;
+            let _ = remove_file(from);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Write the compressed `data` to the cache entry at `path`. Files compressed to disk are moved
+/// into the cache and written to the archive from there.
+fn store_cache_entry(data: &mut CompressedData, path: &Path) -> Result<()> {
+    let temporary = temporary_path(path);
+    match data {
+        CompressedData::Memory(data) => write(&temporary, data)?,
+        CompressedData::Cache(cache_path) => {
+            move_file(cache_path, path)?;
+            *data = CompressedData::Cached(path.to_owned());
+            return Ok(());
+        }
+        _ => return Ok(()),
+    }
+    rename(&temporary, path).inspect_err(|_| {
+        let _ = remove_file(&temporary);
+    })
+}
+
+/// Setuid and setgid bits of file modes, which are stripped unless they are allowed.
+pub const SETUID_BITS: u32 = 0o6000;
+
+/// Size of the sample used to detect incompressible files.
+pub const INCOMPRESSIBLE_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// Check whether a sample from the start of `file` doesn't compress to less than 97% of its size,
+/// in which case the file should be stored uncompressed, and rewind the file afterwards.
+pub fn is_incompressible(file: &mut File) -> Result<bool> {
+    let mut sample = Vec::with_capacity(INCOMPRESSIBLE_SAMPLE_SIZE as usize);
+    file.take(INCOMPRESSIBLE_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    file.rewind()?;
+    let compressed = zstd::bulk::compress(&sample, 1)?;
+    Ok(compressed.len() as u64 * 100 >= sample.len() as u64 * 97)
+}
+
+/// Check whether the file with `meta` can be run through its shebang, which requires an
+/// executable bit on Unix. Files on other platforms are always checked.
+fn is_script_candidate(meta: &Metadata) -> bool {
+    #[cfg(any(unix, target_os = "redox"))]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(any(unix, target_os = "redox")))]
+    {
+        let _ = meta;
+        true
+    }
+}
+
+/// Compress `source` into `destination` with the compression `algorithm`
+/// (0 = zstd, 1 = xz, 2 = brotli). The dictionary is only used for zstd.
 pub fn copy_encode<R: Read, W: Write>(
-    mut source: R, destination: W, level: i32, threads: u32, dict: Option<&EncoderDictionary>,
+    mut source: R, destination: W, algorithm: u8, level: i32, threads: u32,
+    dict: Option<&EncoderDictionary>,
 ) -> Result<()> {
-    let mut encoder = if let Some(dict) = dict {
-        Encoder::with_prepared_dictionary(destination, dict)?
-    } else {
-        Encoder::new(destination, level)?
-    };
-    encoder.multithread(threads)?;
-    copy(&mut source, &mut encoder)?;
-    encoder.finish()?;
+    match algorithm {
+        0 => encode_seekable(source, destination, level, threads, dict)?,
+        1 => {
+            let level = level.clamp(0, 9) as u32;
+            let mut encoder = if threads > 1 {
+                let stream = MtStreamBuilder::new()
+                    .threads(threads)
+                    .preset(level)
+                    .encoder()?;
+                XzEncoder::new_stream(destination, stream)
+            } else {
+                XzEncoder::new(destination, level)
+            };
+            copy(&mut source, &mut encoder)?;
+            encoder.finish()?;
+        }
+        2 => {
+            let mut destination = destination;
+            let params = BrotliEncoderParams {
+                quality: level.clamp(0, 11),
+                lgwin: 24,
+                ..Default::default()
+            };
+            BrotliCompress(&mut source, &mut destination, &params)?;
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported compression algorithm {}", algorithm),
+            ));
+        }
+    }
     Ok(())
 }
 
-/// Compress the payload in `source` and write it into `target`.
+/// Compress `source` with zstd into independent frames of `SEEKABLE_FRAME_SIZE` bytes of
+/// decompressed data, followed by a seek table in the zstd seekable format if there is more than
+/// one frame, so that ranges of the data can be decompressed without decompressing the frames
+/// before them. Up to `threads` frames are compressed in parallel.
+fn encode_seekable<R: Read, W: Write>(
+    mut source: R, mut destination: W, level: i32, threads: u32, dict: Option<&EncoderDictionary>,
+) -> Result<()> {
+    let encode = |chunk: &Vec<u8>| -> Result<Vec<u8>> {
+        let mut encoder = if let Some(dict) = dict {
+            Encoder::with_prepared_dictionary(Vec::new(), dict)?
+        } else {
+            Encoder::new(Vec::new(), level)?
+        };
+        encoder.include_contentsize(true)?;
+        encoder.set_pledged_src_size(Some(chunk.len() as u64))?;
+        encoder.write_all(chunk)?;
+        encoder.finish()
+    };
+    let mut frames = Vec::new();
+    let mut chunks = Vec::new();
+    let mut finished = false;
+    while !finished {
+        chunks.clear();
+        while chunks.len() < threads.max(1) as usize {
+            let mut chunk = Vec::new();
+            source
+                .by_ref()
+                .take(SEEKABLE_FRAME_SIZE)
+                .read_to_end(&mut chunk)?;
+            finished = (chunk.len() as u64) < SEEKABLE_FRAME_SIZE;
+            // empty input is compressed to a single empty frame
+            if !chunk.is_empty() || (frames.is_empty() && chunks.is_empty()) {
+                chunks.push(chunk);
+            }
+            if finished {
+                break;
+            }
+        }
+        let compressed = if chunks.len() > 1 {
+            chunks.par_iter().map(encode).collect::<Vec<_>>()
+        } else {
+            chunks.iter().map(encode).collect::<Vec<_>>()
+        };
+        for (chunk, compressed) in chunks.iter().zip(compressed) {
+            let compressed = compressed?;
+            destination.write_all(&compressed)?;
+            frames.push((compressed.len() as u32, chunk.len() as u32));
+        }
+    }
+    if frames.len() > 1 {
+        destination.write_all(&SEEKABLE_SKIPPABLE_MAGIC.to_le_bytes())?;
+        destination.write_all(&(frames.len() as u32 * 8 + 9).to_le_bytes())?;
+        for (compressed_size, size) in frames.iter() {
+            destination.write_all(&compressed_size.to_le_bytes())?;
+            destination.write_all(&size.to_le_bytes())?;
+        }
+        destination.write_all(&(frames.len() as u32).to_le_bytes())?;
+        // seek table descriptor without checksums
+        destination.write_all(&[0])?;
+        destination.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+    }
+    destination.flush()
+}
+
+/// Directory section for a directory named `name` with the metadata `meta` in the directory
+/// `parent`. Timestamps are clamped to `reproducible` if it is set.
+fn directory_section(
+    name: NameRef, parent: usize, meta: Option<&Metadata>, reproducible: Option<u64>,
+) -> DirectorySection {
+    let mut section = DirectorySection {
+        name,
+        parent: (parent as u32).into(),
+        time_accessed_seconds: 0.into(),
+        time_modified_seconds: 0.into(),
+        time_accessed_nanos: 0.into(),
+        time_modified_nanos: 0.into(),
+        mode: 0.into(),
+    };
+    if let Some(meta) = meta {
+        if let Ok(accessed) = meta.accessed() {
+            if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
+                section.time_accessed_seconds = accessed.as_secs().into();
+                section.time_accessed_nanos = accessed.subsec_nanos().into();
+            }
+        }
+        if let Ok(modified) = meta.modified() {
+            if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
+                section.time_modified_seconds = modified.as_secs().into();
+                section.time_modified_nanos = modified.subsec_nanos().into();
+            }
+        }
+        #[cfg(any(unix, target_os = "redox"))]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            section.mode = meta.permissions().mode().into();
+        }
+    }
+    if let Some(timestamp) = reproducible {
+        let (seconds, nanos) = clamp_time(
+            section.time_modified_seconds.get(),
+            section.time_modified_nanos.get(),
+            timestamp,
+        );
+        (section.time_accessed_seconds, section.time_accessed_nanos) =
+            (seconds.into(), nanos.into());
+        (section.time_modified_seconds, section.time_modified_nanos) =
+            (seconds.into(), nanos.into());
+    }
+    section
+}
+
+/// Symlink section for a symlink with the metadata `meta` in the directory `parent`, pointing
+/// to the directory `target`. Timestamps are clamped to `reproducible` if it is set.
+fn symlink_header(
+    parent: usize, target: usize, meta: Option<&Metadata>, reproducible: Option<u64>,
+) -> SymlinkSection {
+    let mut header = SymlinkSection {
+        // the names are added to the name table after sorting
+        name:                  NameRef::default(),
+        link:                  NameRef::default(),
+        parent:                (parent as u32).into(),
+        kind:                  0,
+        target:                (target as u32).into(),
+        time_accessed_nanos:   0.into(),
+        time_accessed_seconds: 0.into(),
+        time_modified_nanos:   0.into(),
+        time_modified_seconds: 0.into(),
+        mode:                  0.into(),
+        readonly:              0,
+    };
+
+    if let Some(meta) = meta {
+        if let Ok(accessed) = meta.accessed() {
+            if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
+                header.time_accessed_seconds = accessed.as_secs().into();
+                header.time_accessed_nanos = accessed.subsec_nanos().into();
+            }
+        }
+        if let Ok(modified) = meta.modified() {
+            if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
+                header.time_modified_seconds = modified.as_secs().into();
+                header.time_modified_nanos = modified.subsec_nanos().into();
+            }
+        }
+        header.readonly = meta.permissions().readonly() as u8;
+        #[cfg(any(unix, target_os = "redox"))]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            header.mode = meta.permissions().mode().into();
+        }
+    }
+    if let Some(timestamp) = reproducible {
+        let (seconds, nanos) = clamp_time(
+            header.time_modified_seconds.get(),
+            header.time_modified_nanos.get(),
+            timestamp,
+        );
+        (header.time_accessed_seconds, header.time_accessed_nanos) = (seconds.into(), nanos.into());
+        (header.time_modified_seconds, header.time_modified_nanos) = (seconds.into(), nanos.into());
+    }
+    header
+}
+
+/// Reason a directory entry wasn't descended into while walking the inputs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Skipped {
+    #[default]
+    No,
+    /// Symlink to a directory containing it, which would be walked endlessly.
+    Cycle,
+    /// Directory nested deeper than the depth limit.
+    Depth,
+    /// File or directory left behind by the file manager of the operating system.
+    Junk,
+}
+
+/// Names of files and directories created by file managers and archivers, which are skipped
+/// unless `--keep-junk` is set.
+pub const JUNK_NAMES: [&str; 4] = [".DS_Store", "Thumbs.db", "desktop.ini", "__MACOSX"];
+
+fn is_junk(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| {
+        JUNK_NAMES
+            .iter()
+            .any(|junk| junk.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Identity of a directory that is the same for all paths leading to it: its device and inode
+/// on Unix and its canonical path elsewhere.
+#[cfg(unix)]
+type DirectoryId = (u64, u64);
+#[cfg(not(unix))]
+type DirectoryId = PathBuf;
+
+fn directory_id(path: &Path) -> Option<DirectoryId> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        path.metadata().ok().map(|meta| (meta.dev(), meta.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        path.canonicalize().ok()
+    }
+}
+
+/// Absolute and canonical paths of the directories the entries of `sources` are relative to.
+pub fn input_roots<T: AsRef<Path>>(sources: &[T]) -> Vec<PathBuf> {
+    let current_dir = current_dir().unwrap_or_default();
+    let mut roots = Vec::new();
+    for source in sources {
+        let source = source.as_ref();
+        let source = if source.is_dir() {
+            source
+        } else {
+            source.parent().unwrap_or(source)
+        };
+        roots.push(current_dir.join(source));
+        if let Ok(canonical) = source.canonicalize() {
+            roots.push(canonical);
+        }
+    }
+    roots
+}
+
+/// Walk the contents of `source` in sorted order, including hidden files.
+/// If `dereference` is set, symlinks are followed. Symlinks to a directory containing them are
+/// marked as [`Skipped::Cycle`] in the state of their entry and not descended into, the walk
+/// state holds the identities of the directories above the current one. Directories nested
+/// `max_depth` levels below `source` are marked as [`Skipped::Depth`] and not descended into.
+/// Unless `keep_junk` is set, entries named in [`JUNK_NAMES`] are marked as [`Skipped::Junk`].
+pub fn walk_source(
+    source: &Path, dereference: bool, max_depth: usize, keep_junk: bool,
+) -> WalkDirGeneric<(Vec<DirectoryId>, Skipped)> {
+    WalkDirGeneric::<(Vec<DirectoryId>, Skipped)>::new(source)
+        .skip_hidden(false)
+        .sort(true)
+        .follow_links(dereference)
+        .process_read_dir(move |depth, path, ancestors, children| {
+            if depth.is_none() {
+                return;
+            }
+            if dereference {
+                if let Some(id) = directory_id(path) {
+                    ancestors.push(id);
+                }
+            }
+            for child in children.iter_mut().flatten() {
+                if !keep_junk && is_junk(&child.file_name) {
+                    child.read_children_path = None;
+                    child.client_state = Skipped::Junk;
+                    continue;
+                }
+                if child.read_children_path.is_none() {
+                    continue;
+                }
+                let cycle = dereference
+                    && child.path_is_symlink()
+                    && directory_id(&child.path()).is_some_and(|id| ancestors.contains(&id));
+                if cycle {
+                    child.read_children_path = None;
+                    child.client_state = Skipped::Cycle;
+                } else if child.depth >= max_depth {
+                    child.read_children_path = None;
+                    child.client_state = Skipped::Depth;
+                }
+            }
+        })
+}
+
+/// Separate files in `entries` that are hardlinks to an earlier entry, detected by their
+/// device and inode. Returns the remaining entries and the relative paths of the hardlinks
+/// paired with the relative paths of the entries they link to.
+#[allow(clippy::type_complexity)]
+fn split_hardlinks(
+    entries: Vec<(PathBuf, PathBuf)>, exclude: &[PathBuf],
+) -> (Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, PathBuf)>) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mut inodes = HashMap::<(u64, u64), PathBuf>::new();
+        let mut hardlinks = Vec::new();
+        let entries = entries
+            .into_iter()
+            .filter(|(entry, relative)| {
+                if exclude.contains(entry) {
+                    return true;
+                }
+                let Ok(meta) = entry.metadata() else {
+                    return true;
+                };
+                if meta.nlink() < 2 {
+                    return true;
+                }
+                match inodes.get(&(meta.dev(), meta.ino())) {
+                    Some(target) => {
+                        hardlinks.push((relative.to_owned(), target.to_owned()));
+                        false
+                    }
+                    None => {
+                        inodes.insert((meta.dev(), meta.ino()), relative.to_owned());
+                        true
+                    }
+                }
+            })
+            .collect();
+        (entries, hardlinks)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = exclude;
+        (entries, Vec::new())
+    }
+}
+
+/// Hash names, structure and file contents independently of the order files were written in,
+/// their timestamps and their compression. `links` are the relative paths of `files`.
+pub fn content_hash(
+    directories: &[DirectorySection], files: &[FileSectionHeader], symlinks: &[SymlinkSection],
+    hardlinks: &[HardlinkSection], links: &[String], names: &NameTable,
+) -> u64 {
+    let mut content_hasher = XxHash64::with_seed(HASH_SEED);
+    let write_name = |hasher: &mut XxHash64, name: NameRef| {
+        let name = names.bytes(name).unwrap_or_default();
+        hasher.write_usize(name.len());
+        hasher.write(name);
+    };
+    for section in directories.iter() {
+        write_name(&mut content_hasher, section.name);
+        content_hasher.write_u32(section.parent.get());
+        content_hasher.write_u32(section.mode.get());
+    }
+    let mut file_contents = files
+        .iter()
+        .map(|section| {
+            (
+                section.parent,
+                names.bytes(section.name).unwrap_or_default(),
+                section.mode,
+                section.uid,
+                section.gid,
+                section.file_hash,
+            )
+        })
+        .collect::<Vec<_>>();
+    file_contents.sort_unstable();
+    for (parent, name, mode, uid, gid, file_hash) in file_contents {
+        content_hasher.write_usize(name.len());
+        content_hasher.write(name);
+        content_hasher.write_u32(parent.get());
+        content_hasher.write_u32(mode.get());
+        content_hasher.write_u32(uid.get());
+        content_hasher.write_u32(gid.get());
+        content_hasher.write_u64(file_hash.get());
+    }
+    for section in symlinks.iter() {
+        write_name(&mut content_hasher, section.name);
+        content_hasher.write_u32(section.parent.get());
+        content_hasher.write_u8(section.kind);
+        match section.kind {
+            0 => content_hasher.write_u32(section.target.get()),
+            1 => content_hasher.write(links[section.target.get() as usize].as_bytes()),
+            _ => write_name(&mut content_hasher, section.link),
+        }
+    }
+    for section in hardlinks.iter() {
+        write_name(&mut content_hasher, section.name);
+        content_hasher.write_u32(section.parent.get());
+        content_hasher.write(links[section.target.get() as usize].as_bytes());
+    }
+    content_hasher.finish()
+}
+
+/// Decompress `source` compressed with the compression `algorithm` into `destination`
+/// in the same way the runner does. The dictionary is only used for zstd.
+pub fn copy_decode<R: BufRead, W: Write>(
+    source: R, destination: &mut W, algorithm: u8, dict: Option<&DecoderDictionary>,
+) -> Result<u64> {
+    match algorithm {
+        0 => {
+            let mut decoder = if let Some(dict) = dict {
+                Decoder::with_prepared_dictionary(source, dict)?
+            } else {
+                Decoder::with_buffer(source)?
+            };
+            copy(&mut decoder, destination)
+        }
+        1 => copy(&mut xz2::bufread::XzDecoder::new(source), destination),
+        2 => copy(&mut Decompressor::new(source, 4096), destination),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported compression algorithm {}", algorithm),
+        )),
+    }
+}
+
+/// Options of [`compress`] that decide which entries are packed and how they are written.
+pub struct CompressOptions<'a> {
+    /// Paths that are skipped.
+    pub exclude:          &'a [PathBuf],
+    /// Files added to the payload at their given relative path, replacing files with the same
+    /// path in the sources.
+    pub extra:            &'a [(PathBuf, PathBuf)],
+    /// Files with a relative path matching these globs are written uncompressed.
+    pub store:            &'a GlobSet,
+    /// Whether files detected as incompressible are written uncompressed.
+    pub auto_store:       bool,
+    /// Files with a relative path matching the filters are transformed before they are written.
+    pub content_filters:  &'a ContentFilters,
+    /// Shebangs of executable scripts pointing at interpreters on the packing machine are
+    /// reported and rewritten according to its mode, paths of the inputs in the files of virtual
+    /// environments are relocated if `venv` is set.
+    pub shebangs:         &'a Shebangs,
+    /// Algorithm file contents are compressed with, sections are always compressed with zstd.
+    pub algorithm:        u8,
+    /// Compression level of `algorithm`.
+    pub compression:      u32,
+    /// Whether a zstd dictionary is trained on the file contents.
+    pub build_dict:       bool,
+    /// Contents of a zstd dictionary to use instead of a trained one.
+    pub dictionary_file:  Option<&'a [u8]>,
+    /// If not zero, files smaller than [`SOLID_FILE_SIZE`] are compressed together in solid
+    /// blocks of about this size instead of separately.
+    pub solid_block_size: u64,
+    /// Limit in bytes of file contents held in memory while waiting to be written, a quarter of
+    /// the system memory if it is not set.
+    pub memory_limit:     Option<u64>,
+    /// Directory of the repack cache compressed files are reused from and added to.
+    pub cache:            Option<&'a Path>,
+    /// If set, files are written in a deterministic order with their timestamps clamped to its
+    /// value, and large files are compressed single-threaded.
+    pub reproducible:     Option<u64>,
+    /// Whether the owner and group of files are recorded.
+    pub ownership:        bool,
+    /// Whether the setuid and setgid bits of files are kept instead of stripped. They are
+    /// reported either way.
+    pub allow_setuid:     bool,
+    /// Whether symlinks are followed and their targets are packed in their place.
+    pub dereference:      bool,
+    /// Directories nested this many levels below a source are skipped.
+    pub max_depth:        usize,
+    /// Whether files and directories named in [`JUNK_NAMES`] are kept.
+    pub keep_junk:        bool,
+    /// Whether symlinks pointing outside of the sources are stored verbatim.
+    pub keep_external:    bool,
+}
+
+/// Compress the payload in `sources` with `options` and write it into `target`.
+/// The contents of all `sources` are merged, files and symlinks in later sources replace
+/// those with the same relative path in earlier sources.
+/// Compression stops with [`CompressError::Cancelled`] when `cancel` is set.
+/// Progress, packed files, skipped entries and summaries are reported to `event_callback` as
+/// they happen.
+/// Returns the number of compressed entries, the number of read and written bytes,
+/// and a hash of the payload contents that doesn't depend on timestamps or compression.
 /// The data is written subsequently in the following order:
 /// - compressed file contents
 /// - compression dictionary
@@ -67,70 +928,193 @@ pub fn copy_encode<R: Read, W: Write>(
 ///   - directory sections
 ///   - file section headers
 ///   - symlink sections
+///   - hardlink sections
+///   - name table
 /// - payload section header
-#[allow(clippy::too_many_arguments)]
+///
+/// Files that are hardlinks to another file in the payload are stored once.
 pub fn compress<
     T: AsRef<Path>,
     W: Write + Seek + Sync + Send,
-    X: AsRef<Path>,
-    P: Fn() + Sync + Send,
-    E: Fn(&str) + Sync + Send,
-    S: Fn(&str) + Sync + Send,
-    I: Fn(&str) + Sync + Send,
+    C: Fn(CompressEvent) + Sync + Send,
 >(
-    source: T, target: &mut W, exclude: X, compression: u32, build_dict: bool,
-    progress_callback: P, error_callback: E, step_callback: S, info_callback: I,
-) -> (u64, u64, u64) {
-    let source: &Path = source.as_ref();
-    let exclude: &Path = exclude.as_ref();
-
-    let num_cpus = num_cpus::get() as u64;
+    sources: &[T], target: &mut W, options: &CompressOptions, cancel: &AtomicBool,
+    event_callback: C,
+) -> std::result::Result<(u64, u64, u64, u64), CompressError> {
+    let CompressOptions {
+        exclude,
+        extra,
+        store,
+        auto_store,
+        content_filters,
+        shebangs,
+        algorithm,
+        compression,
+        build_dict,
+        dictionary_file,
+        solid_block_size,
+        memory_limit,
+        cache,
+        reproducible,
+        ownership,
+        allow_setuid,
+        dereference,
+        max_depth,
+        keep_junk,
+        keep_external,
+    } = *options;
+    let num_cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
     let system = System::new_with_specifics(
         sysinfo::RefreshKind::new().with_memory(sysinfo::MemoryRefreshKind::new().with_ram()),
     );
     let memory = system.total_memory();
     let in_memory_limit = memory / num_cpus * 1000;
+    let budget = MemoryBudget::new(memory_limit.unwrap_or(memory / 4));
+    // index of the next file written in reproducible mode, whose contents are never held back
+    let next_written = AtomicUsize::new(0);
 
-    let entries = WalkDir::new(source)
-        .skip_hidden(false)
-        .sort(true)
+    let walk_span = info_span!("walk", sources = sources.len()).entered();
+    // entries are paired with the directory their paths are relative to
+    let mut entries = Vec::new();
+    for source in sources.iter() {
+        let source: &Path = source.as_ref();
+        let root: &Path = if source.is_dir() {
+            source
+        } else {
+            source.parent().unwrap()
+        };
+        entries.extend(
+            walk_source(source, dereference, max_depth, keep_junk)
+                .into_iter()
+                .filter_map(|entry| match entry {
+                    Err(e) => {
+                        event_callback(CompressEvent::Error(&format!(
+                            "couldn't read entry: {}",
+                            e
+                        )));
+                        None
+                    }
+                    Ok(entry) => Some((root, entry)),
+                }),
+        );
+    }
+
+    // keep only the last file or symlink with the same relative path
+    let mut latest = HashMap::<PathBuf, usize>::new();
+    let mut merged_directories = HashSet::<PathBuf>::new();
+    for (index, (source, entry)) in entries.iter().enumerate() {
+        let Ok(relative) = entry.path().strip_prefix(source).map(Path::to_path_buf) else {
+            continue;
+        };
+        if entry.file_type().is_dir() {
+            merged_directories.insert(relative);
+        } else {
+            latest.insert(relative, index);
+        }
+    }
+    let mut junk_count = 0;
+    let entries = entries
         .into_iter()
-        .filter(|entry| {
-            if let Err(e) = entry {
-                error_callback(&format!("couldn't read entry: {}", e));
-                return false;
+        .enumerate()
+        .filter_map(|(index, (source, entry))| {
+            // symlinks forming a cycle, too deeply nested directories and junk files are marked
+            // while walking
+            match entry.client_state {
+                Skipped::Cycle => {
+                    event_callback(CompressEvent::Skipped {
+                        path:     &entry.path(),
+                        reason:   &format!(
+                            "symlink to parent directory {}, which would form a cycle",
+                            entry.path().read_link().unwrap_or_default().display()
+                        ),
+                        expected: false,
+                    });
+                    return None;
+                }
+                Skipped::Depth => {
+                    event_callback(CompressEvent::Skipped {
+                        path:     &entry.path(),
+                        reason:   &format!(
+                            "directory nested {} levels deep, raise the limit with --max-depth",
+                            entry.depth
+                        ),
+                        expected: false,
+                    });
+                    return None;
+                }
+                Skipped::Junk => {
+                    junk_count += 1;
+                    event_callback(CompressEvent::Skipped {
+                        path:     &entry.path(),
+                        reason:   "junk file",
+                        expected: true,
+                    });
+                    return None;
+                }
+                Skipped::No => {}
+            }
+            if entry.file_type().is_dir() {
+                return Some((source, entry));
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(source).ok()?;
+            if latest.get(relative) != Some(&index) {
+                event_callback(CompressEvent::Skipped {
+                    path:     &path,
+                    reason:   "file replaced by a later input",
+                    expected: true,
+                });
+                return None;
+            }
+            if merged_directories.contains(relative) {
+                event_callback(CompressEvent::Skipped {
+                    path:     &path,
+                    reason:   "file replacing a directory of another input",
+                    expected: false,
+                });
+                return None;
             }
-            true
+            Some((source, entry))
         })
         .collect::<Vec<_>>();
-
-    let source: &Path = if source.is_dir() {
-        source
-    } else {
-        source.parent().unwrap()
-    };
+    drop(walk_span);
+    if junk_count > 0 {
+        event_callback(CompressEvent::Info(&format!(
+            "skipped {} junk files like .DS_Store and Thumbs.db (keep them with --keep-junk)",
+            junk_count
+        )));
+    }
 
     // create compression dictionary
-    let dictionary_data = if build_dict {
-        step_callback("creating compression dictionary");
+    let dictionary_data = if let Some(dict) = dictionary_file {
+        event_callback(CompressEvent::Info(&format!(
+            "using {:.2}MB dictionary from file",
+            dict.len() as f64 / 1024.0 / 1024.0
+        )));
+        Some(dict.to_vec())
+    } else if build_dict {
+        let _span = info_span!("build_dictionary").entered();
+        event_callback(CompressEvent::Step("creating compression dictionary"));
         let mut sizes = Vec::new();
         let mut sample = Vec::new();
         let _ = entries
             .iter()
-            .filter_map(|entry| {
+            .filter_map(|(source, entry)| {
                 // zstd dictionary data is limited to 4GB
                 if sample.len() >= 4 * 1024 * 1024 * 1024 - 128 * 1024 {
                     return None;
                 }
-                let entry = entry.as_ref().ok()?;
+                if cancel.load(Ordering::Relaxed) {
+                    return None;
+                }
                 if !entry.file_type().is_file() {
                     return None;
                 }
                 let entry = entry.path();
-                if entry == exclude {
+                if exclude.contains(&entry) {
                     return None;
                 }
-                if entry.file_name()?.len() > NAME_SIZE {
+                if store.is_match(entry.strip_prefix(source).unwrap_or(&entry)) {
                     return None;
                 }
                 let file = File::open(&entry).ok()?;
@@ -142,327 +1126,843 @@ pub fn compress<
             })
             .count();
         if sizes.len() < 8 {
-            error_callback("couldn't build dictionary: not enough samples");
+            event_callback(CompressEvent::Error(
+                "couldn't build dictionary: not enough samples",
+            ));
             None
         } else {
             let dict = zstd::dict::from_continuous(&sample, &sizes, 128 * 1024).unwrap();
-            info_callback(&format!(
+            event_callback(CompressEvent::Info(&format!(
                 "built {:.2}MB dictionary from {:.2}MB of data",
                 dict.len() as f64 / 1024.0 / 1024.0,
                 sample.len() as f64 / 1024.0 / 1024.0
-            ));
+            )));
             Some(dict)
         }
     } else {
         None
     };
 
+    if cancel.load(Ordering::Relaxed) {
+        return Err(CompressError::Cancelled);
+    }
+
     let dictionary = dictionary_data
         .as_ref()
         .map(|dict| EncoderDictionary::copy(dict, compression as i32));
+    let dictionary_hash = dictionary_data.as_ref().map_or(0, |dict| {
+        let mut hasher = XxHash64::with_seed(HASH_SEED);
+        hasher.write(dict);
+        hasher.finish()
+    });
 
+    let directories_span = info_span!("enumerate_directories").entered();
     let mut directories = Vec::<DirectorySection>::new();
+    let mut names = NameTable::default();
     // start with the source directory as parent 0
     let mut parents = Vec::<String>::from(["".to_string()]);
 
     // enumerate directories
     let _ = entries
         .iter()
-        .filter_map(|entry| {
-            let entry = entry.as_ref().ok()?;
+        .filter_map(|(source, entry)| {
             if !entry.file_type().is_dir() {
                 return None;
             }
-            let entry = entry.path();
-            if entry == exclude {
-                error_callback(&format!("skipping excluded file: {}", entry.display()));
+            let full_path = entry.path();
+            if exclude.contains(&full_path) {
+                event_callback(CompressEvent::Skipped {
+                    path:     &full_path,
+                    reason:   "excluded file",
+                    expected: false,
+                });
                 return None;
             }
-            let entry = entry.strip_prefix(source).ok()?;
-
-            if entry.file_name()?.len() > NAME_SIZE {
-                error_callback(&format!(
-                    "skipping directory with name longer than {}: {}",
-                    NAME_SIZE,
-                    entry.display()
-                ));
+            let entry = full_path.strip_prefix(source).ok()?;
+
+            // directories of later sources are merged into those of earlier sources
+            let path = entry.to_slash()?.into_owned();
+            if parents.contains(&path) {
+                event_callback(CompressEvent::Progress);
                 return None;
             }
 
-            step_callback(&entry.display().to_string());
+            event_callback(CompressEvent::Step(&entry.display().to_string()));
 
             let name = entry.file_name()?.to_str()?;
 
-            parents.push(entry.to_slash()?.into_owned());
+            parents.push(path);
 
             let parent = entry.parent().unwrap().to_slash().unwrap();
             let parent = match parents.iter().position(|element| element == &parent) {
                 Some(index) => index,
                 None => {
-                    error_callback(&format!(
-                        "skipping directory with no included parent: {}",
-                        entry.display()
-                    ));
+                    event_callback(CompressEvent::Skipped {
+                        path:     entry,
+                        reason:   "directory with no included parent",
+                        expected: false,
+                    });
                     return None;
                 }
             };
 
-            let mut name_array = [0; NAME_SIZE];
-            name_array[0..name.len()].copy_from_slice(name.as_bytes());
-            directories.push(DirectorySection {
-                name:   name_array,
-                parent: parent as u32,
-            });
+            directories.push(directory_section(
+                names.push(name),
+                parent,
+                full_path.metadata().ok().as_ref(),
+                reproducible,
+            ));
 
-            progress_callback();
+            event_callback(CompressEvent::Progress);
             Some(())
         })
         .count();
 
-    let zero = target.stream_position().unwrap();
-    let archive = Arc::new(Mutex::new(target));
-
-    let files = Arc::new(Mutex::new(Vec::<FileSectionHeader>::new()));
-    let links = Arc::new(Mutex::new(Vec::<String>::new()));
-
-    let read = AtomicU64::new(0);
+    // create parent directories of extra files
+    for (name, _) in extra {
+        let mut parent_path = PathBuf::new();
+        for component in name.parent().into_iter().flat_map(|parent| parent.iter()) {
+            let parent = parent_path.to_slash().unwrap().into_owned();
+            parent_path.push(component);
+            let path = parent_path.to_slash().unwrap().into_owned();
+            if parents.contains(&path) {
+                continue;
+            }
+            let Some(component) = component.to_str() else {
+                event_callback(CompressEvent::Skipped {
+                    path:     &parent_path,
+                    reason:   "directory with a name that isn't valid UTF-8",
+                    expected: false,
+                });
+                break;
+            };
+            directories.push(directory_section(
+                names.push(component),
+                parents
+                    .iter()
+                    .position(|element| element == &parent)
+                    .unwrap(),
+                None,
+                reproducible,
+            ));
+            parents.push(path);
+        }
+    }
+    drop(directories_span);
 
-    // compress and append files
-    let _ = entries
-        .par_iter()
-        .filter_map(|entry| {
-            let entry = entry.as_ref().ok()?;
+    let file_entries = entries
+        .iter()
+        .filter_map(|(source, entry)| {
             if !entry.file_type().is_file() {
                 return None;
             }
             let entry = entry.path();
-            if entry == exclude {
-                error_callback(&format!("skipping excluded file: {}", entry.display()));
+            let relative = entry.strip_prefix(source).ok()?.to_owned();
+            if extra.iter().any(|(name, _)| name == &relative) {
+                event_callback(CompressEvent::Skipped {
+                    path:     &entry,
+                    reason:   "replaced file",
+                    expected: false,
+                });
                 return None;
             }
+            Some((entry, relative))
+        })
+        .chain(
+            extra
+                .iter()
+                .map(|(name, path)| (path.to_owned(), name.to_owned())),
+        )
+        .collect::<Vec<_>>();
+    let (file_entries, hardlink_entries) = split_hardlinks(file_entries, exclude);
 
-            if entry.file_name()?.len() > NAME_SIZE {
-                error_callback(&format!(
-                    "skipping file with name longer than: {}: {}",
-                    NAME_SIZE,
-                    entry.display()
-                ));
-                return None;
-            }
+    let zero = target.stream_position()?;
+    // the payload is only appended to, so its hash covers everything written from here on
+    let mut target = PayloadWriter::new(target);
+    let target = &mut target;
 
-            step_callback(&entry.strip_prefix(source).ok()?.display().to_string());
+    let read = AtomicU64::new(0);
+    let stored_count = AtomicU64::new(0);
+    let shebang_count = AtomicU64::new(0);
+    let shebang_example = Mutex::new(None::<String>);
+    let setuid_files = Mutex::new(Vec::<String>::new());
+    let cached_count = AtomicU64::new(0);
+    let mut block_count = 0;
 
-            let parent = entry.strip_prefix(source).ok()?.parent()?.to_slash()?;
-            let parent = match parents.iter().position(|element| element == &parent) {
-                Some(index) => index,
-                None => {
-                    error_callback(&format!(
-                        "skipping file with no included parent: {}",
-                        entry.display()
-                    ));
-                    return None;
+    // compress files in parallel and append them to the archive in a dedicated writer thread
+    let compress_span = info_span!(
+        "compress_files",
+        files = file_entries.len(),
+        peak_memory = tracing::field::Empty
+    );
+    let compress_guard = compress_span.enter();
+    let (files, links) = scope(|scope| {
+        let (sender, receiver) = sync_channel::<(
+            usize,
+            Option<(FileSectionHeader, String, CompressedData, u64)>,
+        )>(num_cpus as usize * 2);
+
+        let event_callback = &event_callback;
+        let target = &mut *target;
+        let compress_span = &compress_span;
+        let names = &mut names;
+        let block_dictionary = dictionary.as_ref();
+        let block_count = &mut block_count;
+        let budget = &budget;
+        let next_written = &next_written;
+        let writer = scope.spawn(move || {
+            let mut files = Vec::<FileSectionHeader>::new();
+            let mut links = Vec::<String>::new();
+            let mut block = SolidBlock::default();
+            let mut write_block = |block: &mut SolidBlock,
+                                   target: &mut PayloadWriter<&mut W>,
+                                   files: &mut Vec<FileSectionHeader>,
+                                   links: &mut Vec<String>,
+                                   names: &mut NameTable| {
+                let _span = debug_span!(parent: compress_span, "write_block").entered();
+                match block.write(target, zero, algorithm, compression, block_dictionary) {
+                    Ok(block_files) => {
+                        for (mut header, link) in block_files {
+                            header.name = names.push(link.rsplit('/').next().unwrap_or(&link));
+                            event_callback(CompressEvent::Packed {
+                                path:       &link,
+                                size:       header.block_size.get(),
+                                compressed: None,
+                                hash:       header.file_hash.get(),
+                                stored:     false,
+                            });
+                            files.push(header);
+                            links.push(link);
+                        }
+                        *block_count += 1;
+                    }
+                    Err(e) => event_callback(CompressEvent::Error(&format!(
+                        "couldn't write solid block to archive: {}",
+                        e
+                    ))),
                 }
             };
-
-            let name = entry.file_name()?.to_str()?;
-
-            let file = File::open(&entry);
-            if let Err(e) = file {
-                error_callback(&format!("couldn't open {}: {}", entry.display(), e));
-                return None;
-            }
-            let file = file.ok()?;
-
-            let mut in_memory = true;
-            let mut meta_len = 0;
-            let meta = file.metadata();
-            if let Ok(ref meta) = meta {
-                meta_len = meta.len();
-                if meta_len > in_memory_limit {
-                    in_memory = false;
+            let mut write_contents = |(mut header, link, data, size): (
+                FileSectionHeader,
+                String,
+                CompressedData,
+                u64,
+            )| {
+                let _span =
+                    debug_span!(parent: compress_span, "write_file", path = %link).entered();
+                if cancel.load(Ordering::Relaxed) {
+                    if let CompressedData::Cache(cache_path) = data {
+                        let _ = remove_file(cache_path);
+                    }
+                    return;
+                }
+                if let CompressedData::Solid(contents) = data {
+                    block.push(header, link, &contents);
+                    if block.data.len() as u64 >= solid_block_size {
+                        write_block(&mut block, target, &mut files, &mut links, names);
+                    }
+                    return;
+                }
+                let result = (|| -> Result<()> {
+                    let start = target.stream_position()?;
+                    header.compressed_hash = match &data {
+                        CompressedData::Memory(data) => {
+                            let mut hasher =
+                                HashReader::new(Cursor::new(data), XxHash64::with_seed(HASH_SEED));
+                            copy(&mut hasher, target.by_ref())?;
+                            hasher.finish().into()
+                        }
+                        CompressedData::Cache(path)
+                        | CompressedData::Stored(path)
+                        | CompressedData::Cached(path) => {
+                            let cache = File::open(path)?;
+                            let mut hasher = HashReader::new(
+                                BufReader::new(&cache),
+                                XxHash64::with_seed(HASH_SEED),
+                            );
+                            copy(&mut hasher, target.by_ref())?;
+                            hasher.finish().into()
+                        }
+                        CompressedData::Solid(_) => {
+                            unreachable!("solid files are written in blocks")
+                        }
+                    };
+                    if let CompressedData::Stored(_) = data {
+                        // stored contents are written unchanged
+                        header.file_hash = header.compressed_hash;
+                    }
+                    let end = target.stream_position()?;
+                    header.position = (start - zero).into();
+                    header.size = (end - start).into();
+                    Ok(())
+                })();
+                if let CompressedData::Cache(cache_path) = data {
+                    let _ = remove_file(cache_path);
+                }
+                if let Err(e) = result {
+                    event_callback(CompressEvent::Error(&format!(
+                        "couldn't write {} to archive: {}",
+                        link, e
+                    )));
+                    return;
+                }
+                header.name = names.push(link.rsplit('/').next().unwrap_or(&link));
+                event_callback(CompressEvent::Packed {
+                    path: &link,
+                    size,
+                    compressed: Some(header.size.get()),
+                    hash: header.file_hash.get(),
+                    stored: header.stored == 1,
+                });
+                files.push(header);
+                links.push(link);
+            };
+            let mut write_file = |file: (FileSectionHeader, String, CompressedData, u64)| {
+                let held = file.2.held();
+                write_contents(file);
+                budget.release(held);
+            };
+            // in reproducible mode, files are written in the order of their entries
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, file) in receiver {
+                if reproducible.is_none() {
+                    if let Some(file) = file {
+                        write_file(file);
+                    }
+                    continue;
+                }
+                pending.insert(index, file);
+                while let Some(file) = pending.remove(&next) {
+                    next += 1;
+                    if let Some(file) = file {
+                        write_file(file);
+                    }
+                    next_written.store(next, Ordering::Release);
+                    budget.notify();
                 }
             }
+            if !block.files.is_empty() && !cancel.load(Ordering::Relaxed) {
+                write_block(&mut block, target, &mut files, &mut links, names);
+            }
+            (files, links)
+        });
 
-            let mut start = 0;
-            let mut end = 0;
-            let mut compressed_hash = 0;
-            let mut reader = HashReader::new(file, XxHash64::with_seed(HASH_SEED));
-
-            if in_memory {
-                let mut data = Vec::new();
-                let mut reader = BufReader::new(&mut reader);
-                if let Err(e) = copy_encode(
-                    &mut reader,
-                    &mut data,
-                    compression as i32,
-                    0,
-                    dictionary.as_ref(),
-                ) {
-                    error_callback(&format!("couldn't compress {}: {}", entry.display(), e));
+        let compress_file = |(index, (entry, relative)): (usize, &(PathBuf, PathBuf))| {
+            let _span = debug_span!(
+                parent: compress_span,
+                "compress_file",
+                path = %relative.display()
+            )
+            .entered();
+            let file = (|| {
+                if cancel.load(Ordering::Relaxed) {
+                    return None;
+                }
+                if exclude.contains(entry) {
+                    event_callback(CompressEvent::Skipped {
+                        path:     entry,
+                        reason:   "excluded file",
+                        expected: false,
+                    });
                     return None;
                 }
 
-                let mut archive = archive.lock();
-                if let Ok(ref mut archive) = archive {
-                    start = archive.stream_position().unwrap();
-                    let mut hasher =
-                        HashReader::new(Cursor::new(&data), XxHash64::with_seed(HASH_SEED));
-                    if let Err(e) = copy(&mut hasher, archive.by_ref()) {
-                        error_callback(&format!(
-                            "couldn't write {} to archive: {}",
-                            entry.display(),
-                            e
-                        ));
+                event_callback(CompressEvent::Step(&relative.display().to_string()));
+
+                let parent = relative.parent()?.to_slash()?;
+                let parent = match parents.iter().position(|element| element == &parent) {
+                    Some(index) => index,
+                    None => {
+                        event_callback(CompressEvent::Skipped {
+                            path:     entry,
+                            reason:   "file with no included parent",
+                            expected: false,
+                        });
                         return None;
                     }
-                    compressed_hash = hasher.finish();
-                    end = archive.stream_position().unwrap();
+                };
+
+                let file = File::open(entry);
+                if let Err(e) = file {
+                    event_callback(CompressEvent::Skipped {
+                        path:     entry,
+                        reason:   &format!("file that couldn't be opened ({})", e),
+                        expected: false,
+                    });
+                    return None;
+                }
+                let mut file = file.ok()?;
+
+                let mut in_memory = true;
+                let mut meta_len = 0;
+                let meta = file.metadata();
+                if let Ok(ref meta) = meta {
+                    meta_len = meta.len();
+                    if meta_len > in_memory_limit || meta_len > budget.limit {
+                        in_memory = false;
+                    }
                 }
-            } else {
-                step_callback(&format!(
-                    "{} (compressing large file to disk)",
-                    entry.display(),
-                ));
-                let cache_path = temp_dir().join(
-                    Alphanumeric
-                        .sample_iter(thread_rng())
-                        .map(char::from)
-                        .take(16)
-                        .collect::<String>(),
+
+                // contents that are transformed are read completely
+                let read_all = |file: &mut File| {
+                    let mut contents = Vec::new();
+                    match CancelReader::new(file, cancel).read_to_end(&mut contents) {
+                        Ok(_) => Some(contents),
+                        Err(e) => {
+                            if !cancel.load(Ordering::Relaxed) {
+                                event_callback(CompressEvent::Skipped {
+                                    path:     entry,
+                                    reason:   &format!("file that couldn't be read ({})", e),
+                                    expected: false,
+                                });
+                            }
+                            None
+                        }
+                    }
+                };
+
+                // filtered files are read and transformed before compressing them from memory
+                let filters = content_filters.matching(relative);
+                let mut filtered = if filters.is_empty() {
+                    None
+                } else {
+                    let contents = ContentFilters::apply(&filters, read_all(&mut file)?);
+                    meta_len = contents.len() as u64;
+                    in_memory = true;
+                    Some(contents)
+                };
+
+                // shebangs of scripts pointing at interpreters on this machine are counted and
+                // rewritten, rewritten scripts are compressed from memory like filtered files
+                let mut placeholder = 0;
+                if meta.as_ref().is_ok_and(is_script_candidate) {
+                    let mut head = Vec::new();
+                    let head = match &filtered {
+                        Some(contents) => &contents[..contents.len().min(SHEBANG_SIZE + 2)],
+                        None => {
+                            let read = (&mut file)
+                                .take(SHEBANG_SIZE as u64 + 2)
+                                .read_to_end(&mut head)
+                                .and_then(|_| file.rewind());
+                            if let Err(e) = read {
+                                event_callback(CompressEvent::Skipped {
+                                    path:     entry,
+                                    reason:   &format!("file that couldn't be read ({})", e),
+                                    expected: false,
+                                });
+                                return None;
+                            }
+                            &head[..]
+                        }
+                    };
+                    if let Some(interpreter) = Shebangs::detect(head) {
+                        shebang_count.fetch_add(1, Ordering::AcqRel);
+                        shebang_example
+                            .lock()
+                            .unwrap()
+                            .get_or_insert_with(|| interpreter.to_string());
+                        if shebangs.mode != ShebangMode::Keep {
+                            let contents = match filtered.take() {
+                                Some(contents) => contents,
+                                None => read_all(&mut file)?,
+                            };
+                            let contents = match shebangs.rewrite(&contents) {
+                                Some(rewritten) => {
+                                    placeholder = rewritten.placeholder as u8;
+                                    rewritten.contents
+                                }
+                                None => contents,
+                            };
+                            meta_len = contents.len() as u64;
+                            in_memory = true;
+                            filtered = Some(contents);
+                        }
+                    }
+                }
+
+                // paths of the inputs in virtual environment files are replaced with the
+                // placeholder, which also covers a placeholder in their shebang
+                if shebangs.is_venv_file(relative) {
+                    let contents = match filtered.take() {
+                        Some(contents) => contents,
+                        None => read_all(&mut file)?,
+                    };
+                    let contents = match shebangs.relocate(&contents) {
+                        Some(relocated) => {
+                            placeholder = 2;
+                            relocated
+                        }
+                        None => contents,
+                    };
+                    meta_len = contents.len() as u64;
+                    in_memory = true;
+                    filtered = Some(contents);
+                }
+
+                let stored = store.is_match(relative)
+                    || (auto_store
+                        && filtered.is_none()
+                        && meta_len >= INCOMPRESSIBLE_SAMPLE_SIZE
+                        && is_incompressible(&mut file).unwrap_or(false)
+                        && {
+                            stored_count.fetch_add(1, Ordering::AcqRel);
+                            true
+                        });
+
+                let solid = solid_block_size > 0 && !stored && meta_len < SOLID_FILE_SIZE;
+
+                let threads = if in_memory || reproducible.is_some() {
+                    0
+                } else {
+                    u64::min(num_cpus / 2, meta_len / in_memory_limit + 1) as u32
+                };
+                let cache_entry = match cache {
+                    Some(cache) if !stored && !solid && filtered.is_none() => cache_entry(
+                        cache,
+                        &mut file,
+                        meta_len,
+                        &format!(
+                            "{}-{}-{}-{:016x}",
+                            algorithm, compression, threads, dictionary_hash
+                        ),
+                        cancel,
+                    )
+                    .ok(),
+                    _ => None,
+                };
+                let cache_hit = cache_entry.as_ref().filter(|(_, path)| path.is_file());
+
+                // contents read into memory wait for the memory budget, except for the file
+                // the writer is waiting for in reproducible mode
+                let reservation = (cache_hit.is_none()
+                    && (!stored || filtered.is_some())
+                    && (solid || in_memory))
+                    .then(|| {
+                        budget.acquire(meta_len, || {
+                            reproducible.is_some() && index <= next_written.load(Ordering::Acquire)
+                        })
+                    });
+
+                let filtered_contents = filtered.is_some();
+                let source: Box<dyn Read> = match filtered {
+                    Some(contents) => Box::new(Cursor::new(contents)),
+                    None => Box::new(file),
+                };
+                let mut reader = HashReader::new(
+                    CancelReader::new(source, cancel),
+                    XxHash64::with_seed(HASH_SEED),
                 );
 
-                if let Err(e) = (|| -> Result<()> {
+                let mut data = if let Some((_, path)) = cache_hit {
+                    cached_count.fetch_add(1, Ordering::AcqRel);
+                    CompressedData::Cached(path.to_owned())
+                } else if stored && filtered_contents {
+                    // filtered contents are written from memory instead of the source file
+                    let mut contents = Vec::new();
+                    if let Err(e) = reader.read_to_end(&mut contents) {
+                        if !cancel.load(Ordering::Relaxed) {
+                            event_callback(CompressEvent::Skipped {
+                                path:     entry,
+                                reason:   &format!("file that couldn't be read ({})", e),
+                                expected: false,
+                            });
+                        }
+                        return None;
+                    }
+                    CompressedData::Memory(contents)
+                } else if stored {
+                    // the contents are hashed while they are copied into the archive
+                    CompressedData::Stored(entry.to_owned())
+                } else if solid {
+                    let mut contents = Vec::new();
+                    if let Err(e) = reader.read_to_end(&mut contents) {
+                        if !cancel.load(Ordering::Relaxed) {
+                            event_callback(CompressEvent::Skipped {
+                                path:     entry,
+                                reason:   &format!("file that couldn't be read ({})", e),
+                                expected: false,
+                            });
+                        }
+                        return None;
+                    }
+                    CompressedData::Solid(contents)
+                } else if in_memory {
+                    let mut data = Vec::new();
                     let mut reader = BufReader::new(&mut reader);
-                    let mut cache = File::create(&cache_path)?;
-                    copy_encode(
+                    if let Err(e) = copy_encode(
                         &mut reader,
-                        &cache,
+                        &mut data,
+                        algorithm,
                         compression as i32,
-                        u64::min(num_cpus / 2, meta_len / in_memory_limit + 1) as u32,
+                        0,
                         dictionary.as_ref(),
-                    )?;
-                    cache.flush()?;
-                    cache.sync_all()?;
-                    Ok(())
-                })() {
-                    error_callback(&format!("couldn't compress {}: {}", entry.display(), e));
-                    return None;
-                }
-
-                if let Err(e) = (|| -> Result<()> {
-                    let cache = File::open(&cache_path)?;
-                    let mut reader = BufReader::new(&cache);
-                    let mut archive = archive.lock();
-                    let mut hasher = HashReader::new(&mut reader, XxHash64::with_seed(HASH_SEED));
-                    if let Ok(ref mut archive) = archive {
-                        start = archive.stream_position().unwrap();
-                        copy(&mut hasher, archive.by_ref())?;
-                        end = archive.stream_position().unwrap();
+                    ) {
+                        if !cancel.load(Ordering::Relaxed) {
+                            event_callback(CompressEvent::Skipped {
+                                path:     entry,
+                                reason:   &format!("file that couldn't be compressed ({})", e),
+                                expected: false,
+                            });
+                        }
+                        return None;
                     }
-                    compressed_hash = hasher.finish();
-                    Ok(())
-                })() {
-                    error_callback(&format!(
-                        "couldn't write {} to archive: {}",
+                    CompressedData::Memory(data)
+                } else {
+                    event_callback(CompressEvent::Step(&format!(
+                        "{} (compressing large file to disk)",
                         entry.display(),
-                        e
-                    ));
-                    return None;
+                    )));
+                    let cache_path = temp_dir().join(
+                        Alphanumeric
+                            .sample_iter(thread_rng())
+                            .map(char::from)
+                            .take(16)
+                            .collect::<String>(),
+                    );
+
+                    if let Err(e) = (|| -> Result<()> {
+                        let mut reader = BufReader::new(&mut reader);
+                        let mut cache = File::create(&cache_path)?;
+                        copy_encode(
+                            &mut reader,
+                            &cache,
+                            algorithm,
+                            compression as i32,
+                            threads,
+                            dictionary.as_ref(),
+                        )?;
+                        cache.flush()?;
+                        cache.sync_all()?;
+                        Ok(())
+                    })() {
+                        if !cancel.load(Ordering::Relaxed) {
+                            event_callback(CompressEvent::Skipped {
+                                path:     entry,
+                                reason:   &format!("file that couldn't be compressed ({})", e),
+                                expected: false,
+                            });
+                        }
+                        let _ = remove_file(cache_path);
+                        return None;
+                    }
+                    CompressedData::Cache(cache_path)
+                };
+                let file_hash = match cache_hit {
+                    Some((file_hash, _)) => *file_hash,
+                    None => reader.finish(),
+                };
+                if let (Some((_, path)), None) = (&cache_entry, cache_hit) {
+                    if let Err(e) = store_cache_entry(&mut data, path) {
+                        event_callback(CompressEvent::Error(&format!(
+                            "couldn't add {} to cache: {}",
+                            entry.display(),
+                            e
+                        )));
+                    }
                 }
 
-                let _ = remove_file(cache_path);
-            }
-            let file_hash = reader.finish();
-
-            read.fetch_add(meta_len, Ordering::AcqRel);
-
-            let mut name_array = [0; NAME_SIZE];
-            name_array[0..name.len()].copy_from_slice(name.as_bytes());
-            let mut header = FileSectionHeader {
-                name: name_array,
-                parent: parent as u32,
-                position: start - zero,
-                size: end - start,
-                file_hash,
-                compressed_hash,
-                time_accessed_nanos: 0,
-                time_accessed_seconds: 0,
-                time_modified_nanos: 0,
-                time_modified_seconds: 0,
-                mode: 0,
-                readonly: 0,
-            };
+                read.fetch_add(meta_len, Ordering::AcqRel);
+
+                let mut header = FileSectionHeader {
+                    // the name is added to the name table when the file is written
+                    name: NameRef::default(),
+                    parent: (parent as u32).into(),
+                    position: 0.into(),
+                    size: 0.into(),
+                    file_hash: file_hash.into(),
+                    compressed_hash: 0.into(),
+                    block_offset: 0.into(),
+                    block_size: 0.into(),
+                    time_accessed_nanos: 0.into(),
+                    time_accessed_seconds: 0.into(),
+                    time_modified_nanos: 0.into(),
+                    time_modified_seconds: 0.into(),
+                    mode: 0.into(),
+                    uid: 0.into(),
+                    gid: 0.into(),
+                    readonly: 0,
+                    stored: if stored { 1 } else { 0 },
+                    solid: 0,
+                    placeholder,
+                };
 
-            if let Ok(ref meta) = meta {
-                if let Ok(accessed) = meta.accessed() {
-                    if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
-                        header.time_accessed_seconds = accessed.as_secs();
-                        header.time_accessed_nanos = accessed.subsec_nanos();
+                if let Ok(ref meta) = meta {
+                    if let Ok(accessed) = meta.accessed() {
+                        if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
+                            header.time_accessed_seconds = accessed.as_secs().into();
+                            header.time_accessed_nanos = accessed.subsec_nanos().into();
+                        }
                     }
-                }
-                if let Ok(modified) = meta.modified() {
-                    if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                        header.time_modified_seconds = modified.as_secs();
-                        header.time_modified_nanos = modified.subsec_nanos();
+                    if let Ok(modified) = meta.modified() {
+                        if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
+                            header.time_modified_seconds = modified.as_secs().into();
+                            header.time_modified_nanos = modified.subsec_nanos().into();
+                        }
+                    }
+                    header.readonly = meta.permissions().readonly() as u8;
+                    #[cfg(any(unix, target_os = "redox"))]
+                    {
+                        use std::os::unix::fs::PermissionsExt;
+                        let mut mode = meta.permissions().mode();
+                        if mode & SETUID_BITS != 0 {
+                            setuid_files
+                                .lock()
+                                .unwrap()
+                                .push(relative.to_slash_lossy().into_owned());
+                            if !allow_setuid {
+                                mode &= !SETUID_BITS;
+                            }
+                        }
+                        header.mode = mode.into();
+                    }
+                    if ownership {
+                        #[cfg(any(unix, target_os = "redox"))]
+                        {
+                            use std::os::unix::fs::MetadataExt;
+                            header.uid = meta.uid().into();
+                            header.gid = meta.gid().into();
+                        }
                     }
                 }
-                header.readonly = meta.permissions().readonly() as u8;
-                #[cfg(any(unix, target_os = "redox"))]
-                {
-                    use std::os::unix::fs::PermissionsExt;
-                    header.mode = meta.permissions().mode();
+                if let Some(timestamp) = reproducible {
+                    let (seconds, nanos) = clamp_time(
+                        header.time_modified_seconds.get(),
+                        header.time_modified_nanos.get(),
+                        timestamp,
+                    );
+                    (header.time_accessed_seconds, header.time_accessed_nanos) =
+                        (seconds.into(), nanos.into());
+                    (header.time_modified_seconds, header.time_modified_nanos) =
+                        (seconds.into(), nanos.into());
                 }
-            }
 
-            let mut files = files.lock();
-            if let Ok(ref mut files) = files {
-                files.push(header);
-                let mut links = links.lock();
-                if let Ok(ref mut links) = links {
-                    links.push(entry.strip_prefix(source).ok()?.to_slash()?.into_owned());
+                let link = relative.to_slash()?.into_owned();
+                if let Some(reservation) = reservation {
+                    reservation.hand_over(&data);
                 }
-            }
+                event_callback(CompressEvent::Progress);
+                Some((header, link, data, meta_len))
+            })();
+            let _ = sender.send((index, file));
+        };
+        if reproducible.is_some() {
+            // start compressing files in the order of their entries to limit pending files
+            file_entries
+                .iter()
+                .enumerate()
+                .par_bridge()
+                .for_each(compress_file);
+        } else {
+            file_entries.par_iter().enumerate().for_each(compress_file);
+        }
 
-            progress_callback();
-            Some(())
-        })
-        .count();
+        drop(sender);
+        writer.join().unwrap()
+    });
+    let (peak_memory, waits) = budget.usage();
+    compress_span.record("peak_memory", peak_memory);
+    drop(compress_guard);
+
+    if cancel.load(Ordering::Relaxed) {
+        return Err(CompressError::Cancelled);
+    }
 
-    let symlinks = Arc::new(Mutex::new(Vec::<SymlinkSection>::new()));
+    if waits > 0 {
+        event_callback(CompressEvent::Info(&format!(
+            "limited files held in memory to {:.2}MB, compressing threads waited {} times (raise the limit with --memory-limit)",
+            budget.limit as f64 / 1024.0 / 1024.0,
+            waits
+        )));
+    }
+
+    let cached_count = cached_count.into_inner();
+    if cached_count > 0 {
+        event_callback(CompressEvent::Info(&format!(
+            "reused {} compressed files from cache",
+            cached_count
+        )));
+    }
+    if block_count > 0 {
+        event_callback(CompressEvent::Info(&format!(
+            "compressed small files together in {} solid blocks",
+            block_count
+        )));
+    }
+    let shebang_count = shebang_count.into_inner();
+    if let (true, Some(example)) = (shebang_count > 0, shebang_example.into_inner().unwrap()) {
+        if shebangs.mode == ShebangMode::Keep {
+            event_callback(CompressEvent::Info(&format!(
+                "{} scripts have shebangs pointing at interpreters on this machine like {} (rewrite them with --shebangs)",
+                shebang_count, example
+            )));
+        } else {
+            event_callback(CompressEvent::Info(&format!(
+                "rewrote the shebangs of {} scripts pointing at interpreters on this machine",
+                shebang_count
+            )));
+        }
+    }
+    let mut setuid_files = setuid_files.into_inner().unwrap();
+    if !setuid_files.is_empty() {
+        setuid_files.sort_unstable();
+        if allow_setuid {
+            event_callback(CompressEvent::Warning(&format!(
+                "kept the setuid and setgid bits of {} files: {}",
+                setuid_files.len(),
+                setuid_files.join(", ")
+            )));
+        } else {
+            event_callback(CompressEvent::Warning(&format!(
+                "stripped the setuid and setgid bits of {} files: {} (keep them with --allow-setuid)",
+                setuid_files.len(),
+                setuid_files.join(", ")
+            )));
+        }
+    }
+    let stored_count = stored_count.into_inner();
+    if stored_count > 0 {
+        event_callback(CompressEvent::Info(&format!(
+            "stored {} incompressible files without compression",
+            stored_count
+        )));
+    }
+
+    let symlinks = Arc::new(Mutex::new(
+        Vec::<(SymlinkSection, String, Option<String>)>::new(),
+    ));
 
     // enumerate symlinks
+    let symlinks_span = info_span!("enumerate_symlinks").entered();
     let _ = entries
         .par_iter()
-        .filter_map(|entry| {
-            let entry = entry.as_ref().ok()?;
+        .filter_map(|(source, entry)| {
             if !entry.file_type().is_symlink() {
                 return None;
             }
             let entry = entry.path();
-            if entry == exclude {
-                error_callback(&format!("skipping excluded file: {}", entry.display()));
-                return None;
-            }
-
-            if entry.file_name()?.len() > NAME_SIZE {
-                error_callback(&format!(
-                    "skipping file with name longer than: {}: {}",
-                    NAME_SIZE,
-                    entry.display()
-                ));
+            if exclude.contains(&entry) {
+                event_callback(CompressEvent::Skipped {
+                    path:     &entry,
+                    reason:   "excluded file",
+                    expected: false,
+                });
                 return None;
             }
 
-            step_callback(&entry.strip_prefix(source).ok()?.display().to_string());
+            event_callback(CompressEvent::Step(
+                &entry.strip_prefix(source).ok()?.display().to_string(),
+            ));
 
             let parent = entry.strip_prefix(source).ok()?.parent()?.to_slash()?;
             let parent = match parents.iter().position(|element| element == &parent) {
                 Some(index) => index,
                 None => {
-                    error_callback(&format!(
-                        "skipping file with no included parent: {}",
-                        entry.display()
-                    ));
+                    event_callback(CompressEvent::Skipped {
+                        path:     &entry,
+                        reason:   "file with no included parent",
+                        expected: false,
+                    });
                     return None;
                 }
             };
@@ -472,48 +1972,71 @@ pub fn compress<
 
             let link = read_link(&entry);
             if let Err(ref e) = link {
-                error_callback(&format!("couldn't read link {}: {}", entry.display(), e));
+                event_callback(CompressEvent::Skipped {
+                    path:     &entry,
+                    reason:   &format!("link that couldn't be read ({})", e),
+                    expected: false,
+                });
                 return None;
             }
             let link = link.ok()?;
-            let link = link.strip_prefix(".").unwrap_or(&link);
-            let link = entry.parent().unwrap().join(link);
-            let link = link.canonicalize();
-            if let Err(e) = link {
-                error_callback(&format!(
-                    "link could not be canonicalized, skipping {}: {}",
-                    entry.display(),
-                    e
-                ));
+            let resolved = entry
+                .parent()
+                .unwrap()
+                .join(link.strip_prefix(".").unwrap_or(&link))
+                .canonicalize();
+            // external symlinks are stored with their verbatim target
+            if keep_external && !resolved.as_ref().is_ok_and(|link| link.starts_with(source)) {
+                let Some(link) = link.to_str() else {
+                    event_callback(CompressEvent::Skipped {
+                        path:     &entry,
+                        reason:   "link with a target that isn't valid UTF-8",
+                        expected: false,
+                    });
+                    return None;
+                };
+                let is_dir = resolved.as_ref().is_ok_and(|link| link.is_dir());
+                let header = SymlinkSection {
+                    kind: if is_dir { 2 } else { 3 },
+                    ..symlink_header(parent, 0, meta.as_ref().ok(), reproducible)
+                };
+                if let Ok(ref mut symlinks) = symlinks.lock() {
+                    symlinks.push((header, name.to_string(), Some(link.to_string())));
+                }
+                event_callback(CompressEvent::Progress);
+                return Some(());
+            }
+            if let Err(e) = resolved {
+                event_callback(CompressEvent::Skipped {
+                    path:     &entry,
+                    reason:   &format!("link that couldn't be canonicalized ({})", e),
+                    expected: false,
+                });
                 return None;
             }
-            let link = link.ok()?;
+            let link = resolved.ok()?;
             let is_file = link.is_file();
             let link = link.strip_prefix(source);
-            if let Err(e) = link {
-                error_callback(&format!(
-                    "link points to outside the directory, skipping {}: {}",
-                    entry.display(),
-                    e
-                ));
+            if link.is_err() {
+                event_callback(CompressEvent::Skipped {
+                    path:     &entry,
+                    reason:   "link pointing outside of the input directory",
+                    expected: false,
+                });
                 return None;
             }
             let link = link.ok()?;
 
             let target = if is_file {
                 let link = link.to_slash()?;
-                match links
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .position(|element| element == &link)
-                {
+                match links.iter().position(|element| element == &link) {
                     Some(index) => index,
                     None => {
-                        error_callback(&format!(
-                            "skipping link with no included target: {}",
-                            entry.display()
-                        ));
+                        event_callback(CompressEvent::Skipped {
+                            path:     &entry,
+                            reason:   "link with no included target",
+                            expected: false,
+                        });
                         return None;
                     }
                 }
@@ -522,114 +2045,157 @@ pub fn compress<
                 match parents.iter().position(|element| element == &link) {
                     Some(index) => index,
                     None => {
-                        error_callback(&format!(
-                            "skipping link with no included target: {}",
-                            entry.display()
-                        ));
+                        event_callback(CompressEvent::Skipped {
+                            path:     &entry,
+                            reason:   "link with no included target",
+                            expected: false,
+                        });
                         return None;
                     }
                 }
             };
 
-            let mut name_array = [0; NAME_SIZE];
-            name_array[0..name.len()].copy_from_slice(name.as_bytes());
-            let mut header = SymlinkSection {
-                name:                  name_array,
-                parent:                parent as u32,
-                kind:                  is_file as u8,
-                target:                target as u32,
-                time_accessed_nanos:   0,
-                time_accessed_seconds: 0,
-                time_modified_nanos:   0,
-                time_modified_seconds: 0,
-                mode:                  0,
-                readonly:              0,
+            let header = SymlinkSection {
+                kind: is_file as u8,
+                ..symlink_header(parent, target, meta.as_ref().ok(), reproducible)
             };
 
-            if let Ok(ref meta) = meta {
-                if let Ok(accessed) = meta.accessed() {
-                    if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
-                        header.time_accessed_seconds = accessed.as_secs();
-                        header.time_accessed_nanos = accessed.subsec_nanos();
-                    }
-                }
-                if let Ok(modified) = meta.modified() {
-                    if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                        header.time_modified_seconds = modified.as_secs();
-                        header.time_modified_nanos = modified.subsec_nanos();
-                    }
-                }
-                header.readonly = meta.permissions().readonly() as u8;
-                #[cfg(any(unix, target_os = "redox"))]
-                {
-                    use std::os::unix::fs::PermissionsExt;
-                    header.mode = meta.permissions().mode();
-                }
-            }
-
             let mut symlinks = symlinks.lock();
             if let Ok(ref mut symlinks) = symlinks {
-                symlinks.push(header);
+                symlinks.push((header, name.to_string(), None));
             }
 
-            progress_callback();
+            event_callback(CompressEvent::Progress);
             Some(())
         })
         .count();
 
-    let mut target = archive.lock().unwrap();
-    let end = target.stream_position().unwrap();
+    drop(symlinks_span);
+
+    // sort symlinks to make their order independent of the enumeration order
+    let mut symlinks = std::mem::take(&mut *symlinks.lock().unwrap());
+    symlinks.sort_by(|(a, a_name, _), (b, b_name, _)| (a.parent, a_name).cmp(&(b.parent, b_name)));
+    let symlinks = symlinks
+        .into_iter()
+        .map(|(mut section, name, link)| {
+            section.name = names.push(&name);
+            if let Some(link) = link {
+                section.link = names.push(&link);
+            }
+            section
+        })
+        .collect::<Vec<_>>();
+
+    // resolve hardlinks to the written files
+    let mut hardlinks = hardlink_entries
+        .iter()
+        .filter_map(|(relative, linked)| {
+            event_callback(CompressEvent::Step(&relative.display().to_string()));
+            event_callback(CompressEvent::Progress);
+            let parent = relative.parent()?.to_slash()?;
+            let Some(parent) = parents.iter().position(|element| element == &parent) else {
+                event_callback(CompressEvent::Skipped {
+                    path:     relative,
+                    reason:   "hardlink with no included parent",
+                    expected: false,
+                });
+                return None;
+            };
+            let linked = linked.to_slash()?;
+            let Some(target) = links.iter().position(|element| element == &linked) else {
+                event_callback(CompressEvent::Skipped {
+                    path:     relative,
+                    reason:   "hardlink with no included target",
+                    expected: false,
+                });
+                return None;
+            };
+            let name = relative.file_name()?.to_str()?;
+            Some((parent as u32, name.to_string(), target as u32))
+        })
+        .collect::<Vec<_>>();
+    hardlinks.sort();
+    let hardlinks = hardlinks
+        .into_iter()
+        .map(|(parent, name, target)| HardlinkSection {
+            name:   names.push(&name),
+            parent: parent.into(),
+            target: target.into(),
+        })
+        .collect::<Vec<_>>();
+    if !hardlinks.is_empty() {
+        event_callback(CompressEvent::Info(&format!(
+            "stored {} hardlinks without duplicating their contents",
+            hardlinks.len()
+        )));
+    }
+
+    let end = target.stream_position()?;
+
+    let content_hash = content_hash(&directories, &files, &symlinks, &hardlinks, &links, &names);
 
     // write sections
+    let _span = info_span!("write_sections").entered();
     let mut hasher = XxHash64::with_seed(HASH_SEED);
     if let Some(dict) = &dictionary_data {
-        target.write_all(dict).unwrap();
+        target.write_all(dict)?;
     }
     let sections_buffer = Vec::new();
     let mut sections_buffer = Cursor::new(sections_buffer);
     for section in directories.iter() {
         hasher.write(section.as_bytes());
-        sections_buffer.write_all(section.as_bytes()).unwrap();
+        sections_buffer.write_all(section.as_bytes())?;
     }
-    for section in files.lock().unwrap().iter() {
+    for section in files.iter() {
         hasher.write(section.as_bytes());
-        sections_buffer.write_all(section.as_bytes()).unwrap();
+        sections_buffer.write_all(section.as_bytes())?;
     }
-    for section in symlinks.lock().unwrap().iter() {
+    for section in symlinks.iter() {
         hasher.write(section.as_bytes());
-        sections_buffer.write_all(section.as_bytes()).unwrap();
+        sections_buffer.write_all(section.as_bytes())?;
     }
+    for section in hardlinks.iter() {
+        hasher.write(section.as_bytes());
+        sections_buffer.write_all(section.as_bytes())?;
+    }
+    hasher.write(&names.0);
+    sections_buffer.write_all(&names.0)?;
     let sections_buffer = sections_buffer.into_inner();
     let mut sections_buffer = Cursor::new(&sections_buffer);
-    let sections_start = target.stream_position().unwrap();
+    let sections_start = target.stream_position()?;
     copy_encode(
         &mut sections_buffer,
         target.by_ref(),
+        0,
         compression as i32,
         0,
         None,
-    )
-    .unwrap();
-    let sections_size = target.stream_position().unwrap() - sections_start;
+    )?;
+    let sections_size = target.stream_position()? - sections_start;
+    let payload_hash = target.finish();
 
     // write payload header
     let payload_header = PayloadHeader {
-        kind: 0,
-        directory_sections: directories.len() as u64,
-        file_sections: files.lock().unwrap().len() as u64,
-        symlink_sections: symlinks.lock().unwrap().len() as u64,
-        dictionary_size: dictionary_data.map_or(0, |dict| dict.len() as u64),
-        section_hash: hasher.finish(),
-        payload_size: end - zero,
-        sections_size,
+        kind: algorithm,
+        directory_sections: (directories.len() as u64).into(),
+        file_sections: (files.len() as u64).into(),
+        symlink_sections: (symlinks.len() as u64).into(),
+        hardlink_sections: (hardlinks.len() as u64).into(),
+        dictionary_size: dictionary_data.map_or(0, |dict| dict.len() as u64).into(),
+        section_hash: hasher.finish().into(),
+        payload_hash,
+        payload_size: (end - zero).into(),
+        sections_size: sections_size.into(),
+        names_size: (names.0.len() as u64).into(),
     };
-    target.write_all(payload_header.as_bytes()).unwrap();
-    target.flush().unwrap();
-    let written = target.stream_position().unwrap();
+    target.write_all(payload_header.as_bytes())?;
+    target.flush()?;
+    let written = target.stream_position()?;
 
-    (
+    Ok((
         payload_header.len() as u64,
         read.load(Ordering::Acquire),
         written - zero,
-    )
+        content_hash,
+    ))
 }
@@ -0,0 +1,276 @@
+use std::{
+    collections::BTreeMap,
+    env::current_exe,
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::Args;
+use console::style;
+use jwalk::WalkDir;
+
+use crate::{
+    args::{OrExit, emoji, get_output},
+    payload::{Payload, join},
+};
+
+#[derive(Args)]
+#[command(about = "Watch an input directory and re-pack it on every change")]
+pub struct DevArgs {
+    /// Launch the packed executable after each pack, stopping the previous instance
+    #[arg(long, default_value = "false")]
+    run:       bool,
+    /// Argument passed to the packed executable when launching it
+    #[arg(long = "run-arg", value_name = "ARG", allow_hyphen_values = true)]
+    run_args:  Vec<String>,
+    /// Interval in milliseconds between checks of the input directory for changes
+    #[arg(long, value_name = "MILLISECONDS", default_value = "500")]
+    interval:  u64,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain: bool,
+    /// Path to the input directory
+    #[arg(name = "input")]
+    input:     PathBuf,
+    /// Path to the executable to start after unpacking
+    #[arg(name = "command")]
+    command:   PathBuf,
+    /// Path to or filename of the output executable
+    #[arg(name = "output")]
+    output:    Option<PathBuf>,
+    /// Options passed to wrappe when packing
+    #[arg(last = true, value_name = "OPTIONS")]
+    options:   Vec<String>,
+}
+
+/// Size and modification time of every file and symlink in a directory.
+type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Record the files in `input`, leaving out the `ignored` output files written when packing.
+fn snapshot(input: &Path, ignored: &[PathBuf]) -> Snapshot {
+    WalkDir::new(input)
+        .skip_hidden(false)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !ignored.contains(&entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), (metadata.len(), metadata.modified().ok())))
+        })
+        .collect()
+}
+
+/// Size and hash or target of every entry in the payload of a packed executable by path.
+type Entries = BTreeMap<String, (u64, String)>;
+
+/// Read the entries in the payload of `output`.
+fn entries(output: &Path) -> std::io::Result<Entries> {
+    let payload = File::open(output).and_then(|mut file| Payload::read(&mut file))?;
+    let directories = payload.directory_paths();
+    let files = payload
+        .files
+        .iter()
+        .map(|section| {
+            join(
+                &directories[section.parent.get() as usize],
+                &payload.names.get(section.name),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut entries = Entries::new();
+    for directory in directories.iter().skip(1) {
+        entries.insert(format!("{}/", directory), (0, String::new()));
+    }
+    for (section, path) in payload.files.iter().zip(files.iter()) {
+        // files in solid blocks share the compressed size of their block
+        let size = if section.solid == 1 {
+            section.block_size
+        } else {
+            section.size
+        };
+        let file_hash = section.file_hash.get();
+        entries.insert(path.clone(), (size.get(), format!("{:016x}", file_hash)));
+    }
+    for section in payload.symlinks.iter() {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        let target = match section.kind {
+            0 => directories
+                .get(section.target.get() as usize)
+                .map(|directory| format!("{}/", directory)),
+            1 => files.get(section.target.get() as usize).cloned(),
+            _ => Some(payload.names.get(section.link)),
+        };
+        entries.insert(path, (0, format!("-> {}", target.unwrap_or_default())));
+    }
+    for section in payload.hardlinks.iter() {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        let target = files.get(section.target.get() as usize).cloned();
+        entries.insert(path, (0, format!("=> {}", target.unwrap_or_default())));
+    }
+    Ok(entries)
+}
+
+/// Print the entries added to, removed from and modified in the payload between `previous`
+/// and `current`, with the difference of their compressed size.
+fn print_changes(previous: &Entries, current: &Entries) {
+    let mut changes = (0, 0, 0);
+    for (path, (size, detail)) in current.iter() {
+        match previous.get(path) {
+            None => {
+                changes.0 += 1;
+                println!("      {} {} (+{} bytes)", style("+").green(), path, size);
+            }
+            Some((previous_size, previous_detail))
+                if previous_size != size || previous_detail != detail =>
+            {
+                changes.2 += 1;
+                let delta = *size as i64 - *previous_size as i64;
+                println!("      {} {} ({:+} bytes)", style("~").yellow(), path, delta);
+            }
+            _ => {}
+        }
+    }
+    for (path, (size, _)) in previous.iter() {
+        if !current.contains_key(path) {
+            changes.1 += 1;
+            println!("      {} {} (-{} bytes)", style("-").red(), path, size);
+        }
+    }
+    println!(
+        "      {}",
+        style(format!(
+            "{} added, {} removed, {} modified in the payload, sizes are compressed sizes \
+             except for files in solid blocks",
+            changes.0, changes.1, changes.2
+        ))
+        .dim()
+    );
+}
+
+/// Stop the previously launched instance of the packed executable.
+fn stop(child: &mut Option<Child>) {
+    if let Some(mut child) = child.take() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Pack the input directory in `args`, then watch it for changes and pack it again after every
+/// change, reusing unchanged files from the repack cache. Changes are picked up once the input
+/// directory hasn't changed for one interval, so that a build writing many files is packed once.
+/// The entries that changed in the payload are printed after every pack.
+pub fn dev(args: DevArgs) {
+    let input =
+        std::fs::canonicalize(&args.input).unwrap_or_else(|e| fail("input path is invalid", e));
+    if !input.is_dir() {
+        fail("input path is not a directory", input.display());
+    }
+    let output = get_output(args.output.as_deref(), &args.command).or_exit();
+    let mut temporary_output = OsString::from(output.as_os_str());
+    temporary_output.push(".tmp");
+    let ignored = [output.clone(), PathBuf::from(temporary_output)];
+    let interval = Duration::from_millis(args.interval.max(10));
+
+    let exe = current_exe().unwrap_or_else(|e| fail("couldn't get path of wrappe", e));
+    let mut child = None;
+    let mut current = snapshot(&input, &ignored);
+    let mut packed_entries = None;
+    loop {
+        stop(&mut child);
+        let start = Instant::now();
+        let mut pack = Command::new(&exe);
+        if args.plain {
+            pack.arg("--plain");
+        }
+        if !args
+            .options
+            .iter()
+            .any(|option| option.starts_with("--cache"))
+        {
+            pack.arg("--cache");
+        }
+        let packed = pack
+            .args(&args.options)
+            .arg(&input)
+            .arg(&args.command)
+            .arg(&output)
+            .status()
+            .unwrap_or_else(|e| fail("couldn't start packing", e));
+        if packed.success() {
+            println!(
+                "{}{} {}",
+                emoji("🔁 ", ""),
+                style(format!("packed in {:.2}s", start.elapsed().as_secs_f64())).green(),
+                style("(watching for changes)").dim()
+            );
+            match entries(&output) {
+                Ok(entries) => {
+                    if let Some(previous) = &packed_entries {
+                        print_changes(previous, &entries);
+                    }
+                    packed_entries = Some(entries);
+                }
+                Err(e) => println!("{}: {}", style("couldn't read payload").red(), e),
+            }
+            if args.run {
+                child = Command::new(&output)
+                    .args(&args.run_args)
+                    .spawn()
+                    .map_err(|e| {
+                        println!(
+                            "{}: {}: {}",
+                            style("couldn't launch output").red(),
+                            output.display(),
+                            e
+                        )
+                    })
+                    .ok();
+            }
+        } else {
+            println!(
+                "{}{} {}",
+                emoji("🔁 ", ""),
+                style("packing failed").red(),
+                style("(watching for changes)").dim()
+            );
+        }
+
+        // wait for a change, then for the input to settle
+        loop {
+            sleep(interval);
+            let next = snapshot(&input, &ignored);
+            if next != current {
+                current = next;
+                break;
+            }
+        }
+        loop {
+            sleep(interval);
+            let next = snapshot(&input, &ignored);
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+        println!(
+            "{}{}",
+            emoji("📝 ", ""),
+            style("input changed, packing again…").magenta()
+        );
+    }
+}
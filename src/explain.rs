@@ -0,0 +1,408 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use clap::Args;
+use console::style;
+
+use crate::{
+    inspect::{CONSOLES, CURRENT_DIRS, UNPACK_TARGETS, VERIFICATION, VERSIONING, option_name},
+    payload::{Payload, executable_target, join, payload_path, section_name},
+    signing::hex,
+    types::*,
+};
+
+#[derive(Args)]
+#[command(about = "Describe what the runner of a packed executable does when it starts")]
+pub struct ExplainArgs {
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:  bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable: PathBuf,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Names of the once and process group options.
+const ONCE: [&str; 5] = ["none", "scan", "lock", "wait", "app"];
+const PROCESS_GROUPS: [&str; 3] = ["inherit", "group", "session"];
+
+/// Directory the runner creates the unpack directory in with `unpack_target` on `system`.
+fn unpack_root(unpack_target: u8, system: &str) -> &'static str {
+    match (unpack_target, system) {
+        (0, "windows") => "%TEMP%",
+        (0, _) => "$TMPDIR (or /tmp)",
+        (1, "windows") => "%LOCALAPPDATA%",
+        (1, "darwin") => "~/Library/Application Support",
+        (1, _) => "$XDG_DATA_HOME (or ~/.local/share)",
+        (2, _) => "<working directory>",
+        _ => "<unknown>",
+    }
+}
+
+/// Path template of the unpack directory of `payload` for the runner of `target`.
+fn unpack_template(payload: &Payload, target: &str) -> String {
+    let info = &payload.info;
+    let system = target.rsplit('-').next().unwrap_or_default();
+    let separator = if system == "windows" { "\\" } else { "/" };
+    let directory = expand_unpack_directory(
+        &section_name(&info.unpack_directory),
+        &section_name(&info.command),
+        &section_name(&info.uid),
+        // the runner names the system like the Rust standard library
+        &target.replace("darwin", "macos"),
+    );
+    let mut template = format!(
+        "{}{}{}",
+        unpack_root(info.unpack_target, system),
+        separator,
+        directory
+    );
+    match info.unpack_subdir {
+        1 => template.push_str(&format!("{}<hash of the executable path>", separator)),
+        2 => template.push_str(&format!(
+            "{}{}",
+            separator,
+            &format!("{:016x}", payload.header.section_hash.get())[..8]
+        )),
+        _ => {}
+    }
+    if info.versioning == 0 {
+        template.push_str(&format!("{}{}", separator, section_name(&info.uid)));
+    }
+    template
+}
+
+/// Steps of deciding whether the payload of `payload` is unpacked.
+fn extraction(payload: &Payload) -> Vec<String> {
+    let info = &payload.info;
+    let version = section_name(&info.uid);
+    let entries = payload.files.len() + payload.symlinks.len() + payload.hardlinks.len();
+    let mut steps = Vec::new();
+    match info.versioning {
+        0 | 1 => {
+            steps.push(format!(
+                "unpacks the payload if the unpack directory wasn't unpacked from version {}{}",
+                version,
+                if info.versioning == 1 {
+                    ", overwriting files of other versions"
+                } else {
+                    ""
+                }
+            ));
+            steps.push(match info.verification {
+                1 => format!(
+                    "otherwise checks that all {} files and links exist and unpacks the payload again if any is missing",
+                    entries
+                ),
+                2 => format!(
+                    "otherwise compares the checksums of all {} files and links and unpacks the payload again if any differs",
+                    entries
+                ),
+                _ => "otherwise starts the command without checking the unpacked files".to_string(),
+            });
+        }
+        _ => steps.push("always unpacks the payload, overwriting unpacked files".to_string()),
+    }
+    if info.public_key != [0; 32] {
+        steps.push(format!(
+            "checks the payload signature with public key {} before unpacking and refuses to unpack if it doesn't match",
+            hex(&info.public_key)
+        ));
+    }
+    if info.once != 0 {
+        steps.push(
+            "exits if another instance is unpacking to the same directory at the same time"
+                .to_string(),
+        );
+    }
+    if info.in_memory == 1 {
+        steps.push(
+            "reads the packed executable into memory and closes it before unpacking".to_string(),
+        );
+    } else if info.prefetch == 1 {
+        steps.push(format!(
+            "prefetches the payload into memory if it is larger than {} MB and enough memory is available",
+            info.prefetch_threshold.get()
+        ));
+    }
+    if info.low_priority == 1 {
+        steps.push("unpacks with lowered CPU and IO priority".to_string());
+    }
+    if info.backoff == 1 {
+        steps.push(format!(
+            "unpacks without prefetching and on a single thread after 2 failed extractions, and to the {} after 3 or if the unpack directory can't be created",
+            if info.unpack_target == 0 {
+                "local data directory"
+            } else {
+                "temporary directory"
+            }
+        ));
+    }
+    if info.ownership == 1 {
+        steps.push(
+            "restores the recorded owner and group of files when running as root".to_string(),
+        );
+    }
+    steps
+}
+
+/// Behavior of the console option `show_console` when the runner on `system` is started in
+/// different ways.
+fn console(show_console: u8, system: &str) -> Vec<(&'static str, &'static str)> {
+    if system != "windows" {
+        return vec![(
+            "any",
+            "the command inherits the terminal and standard streams of the runner",
+        )];
+    }
+    match show_console {
+        0 => vec![
+            (
+                "from Explorer",
+                "no console window is opened, the output of the command is discarded",
+            ),
+            (
+                "from a console",
+                "the runner returns immediately, the output of the command is discarded",
+            ),
+        ],
+        1 => vec![
+            (
+                "from Explorer",
+                "a console window is opened for the runner and the command",
+            ),
+            (
+                "from a console",
+                "the command uses the console and the runner waits for it to exit",
+            ),
+        ],
+        2 => vec![
+            (
+                "from Explorer",
+                "no console window is opened, the output of the command is discarded",
+            ),
+            (
+                "from a console",
+                "the runner attaches to the console and connects the command to it through a pseudo console",
+            ),
+        ],
+        _ => vec![("any", "unknown console option")],
+    }
+}
+
+/// Steps of starting the command of `payload` after unpacking.
+fn launch(payload: &Payload, system: &str) -> Vec<String> {
+    let info = &payload.info;
+    let mut steps = Vec::new();
+    let arguments = String::from_utf8_lossy(&payload.arguments);
+    steps.push(format!(
+        "runs {} in the unpack directory{}, followed by the arguments of the runner",
+        section_name(&info.command),
+        if arguments.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " with the arguments {}",
+                arguments
+                    .split(ARGUMENT_SEPARATOR)
+                    .map(|argument| format!("{:?}", argument))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        }
+    ));
+    steps.push(format!("working directory: {}", match info.current_dir {
+        0 => "the working directory of the runner",
+        1 => "the unpack directory",
+        2 => "the directory containing the packed executable",
+        3 => "the directory containing the command",
+        _ => "unknown",
+    }));
+    let directories = payload.directory_paths();
+    let hook = payload.files.iter().any(|section| {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        path.strip_prefix(WRAPPE_DIRECTORY)
+            .and_then(|path| path.strip_prefix('/'))
+            .is_some_and(|name| name.split('.').next() == Some("pre-launch"))
+    });
+    if hook {
+        steps.push(format!(
+            "runs the pre-launch hook {}/pre-launch before the command",
+            WRAPPE_DIRECTORY
+        ));
+    }
+    match info.once {
+        1 => steps.push(
+            "exits if the command is already running from the same unpack directory".to_string(),
+        ),
+        2 => steps.push(
+            "exits if another instance is running from the same unpack directory".to_string(),
+        ),
+        3 => steps.push(if info.once_timeout.get() == 0 {
+            "waits for other instances running from the same unpack directory to exit".to_string()
+        } else {
+            format!(
+                "waits up to {} seconds for other instances running from the same unpack directory to exit",
+                info.once_timeout.get()
+            )
+        }),
+        4 => steps.push(
+            "exits if another instance with the same application ID and version is running from any unpack directory"
+                .to_string(),
+        ),
+        _ => {}
+    }
+    if info.once != 0 {
+        steps.push(format!(
+            "exits with exit code {} {} a message when another instance prevents it from starting",
+            info.once_exit_code,
+            if info.once_quiet == 1 {
+                "without"
+            } else {
+                "with"
+            }
+        ));
+    }
+    if info.desktop_entry == 1 {
+        steps.push("installs a desktop entry for the application (Linux)".to_string());
+    }
+    let supervised = info.supervise == 1
+        || info.process_group > 0
+        || info.cleanup == 1
+        || info.once == 2
+        || info.once == 3
+        || info.once == 4;
+    steps.push(if system == "windows" {
+        "starts the command as a child process".to_string()
+    } else if supervised {
+        "starts the command as a child process and stays alive to forward signals and return its exit code".to_string()
+    } else {
+        "replaces the runner process with the command".to_string()
+    });
+    if info.process_group > 0 {
+        steps.push(format!(
+            "starts the command in a new process {}",
+            option_name(&PROCESS_GROUPS, info.process_group)
+        ));
+    }
+    if info.close_fds == 1 {
+        steps.push(format!(
+            "closes inherited file descriptors above stderr{}",
+            if info.passed_fds.get() == 0 {
+                String::new()
+            } else {
+                format!(
+                    " except {}",
+                    (3..64)
+                        .filter(|fd| info.passed_fds.get() & (1 << fd) != 0)
+                        .map(|fd| fd.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        ));
+    }
+    if info.cleanup == 1 {
+        steps.push("removes the unpack directory after the command exits".to_string());
+    }
+    steps
+}
+
+/// Print what the runner of the packed executable in `args` does when it starts, derived from
+/// its startup configuration in the same way the runner interprets it.
+pub fn explain(args: ExplainArgs) {
+    let payload = File::open(payload_path(&args.executable))
+        .and_then(|mut file| Payload::read(&mut file))
+        .unwrap_or_else(|e| fail("couldn't read payload", e));
+    let mut file =
+        File::open(&args.executable).unwrap_or_else(|e| fail("couldn't open packed executable", e));
+    let mut header = Vec::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.take(4096).read_to_end(&mut header))
+        .unwrap_or_else(|e| fail("couldn't read executable header", e));
+    let target = executable_target(&header);
+    let system = target.rsplit('-').next().unwrap_or_default();
+    let info = &payload.info;
+
+    let heading = |heading: &str| println!("{}", style(heading).bold());
+    let step = |step: &str| println!("  {}", step);
+
+    heading("configuration");
+    step(&format!("target: {}", target));
+    step(&format!(
+        "unpack target: {}, versioning: {}, verification: {}, current directory: {}, console: {}, once: {}",
+        option_name(&UNPACK_TARGETS, info.unpack_target),
+        option_name(&VERSIONING, info.versioning),
+        option_name(&VERIFICATION, info.verification),
+        option_name(&CURRENT_DIRS, info.current_dir),
+        option_name(&CONSOLES, info.show_console),
+        option_name(&ONCE, info.once),
+    ));
+    println!();
+
+    heading("unpack directory");
+    step(&unpack_template(&payload, &target));
+    step("WRAPPE_UNPACK_ROOT replaces the directory the unpack directory is created in");
+    if system == "linux" && info.unpack_target != 2 {
+        step(
+            "inside Flatpak and Snap sandboxes, a writable directory of the sandbox is used if the directory isn't writable",
+        );
+    }
+    println!();
+
+    heading("unpacking");
+    for line in extraction(&payload) {
+        step(&line);
+    }
+    println!();
+
+    heading("console");
+    for (scenario, behavior) in console(info.show_console, system) {
+        step(&format!("{}: {}", scenario, behavior));
+    }
+    if system == "windows" && info.show_console == 0 && info.show_information == 2 {
+        step("the runner attaches to the console of its parent process to print details");
+    }
+    println!();
+
+    heading("launch");
+    if !payload.manifest.is_empty() {
+        step(
+            "prints the embedded file manifest instead when started with --wrappe-manifest as the first argument",
+        );
+    }
+    step(
+        "prints a diagnostic report of the host environment instead when started with --wrappe-doctor as the first argument",
+    );
+    for line in launch(&payload, system) {
+        step(&line);
+    }
+    println!();
+
+    heading("environment");
+    step("WRAPPE_UNPACK_DIR: the unpack directory");
+    step("WRAPPE_LAUNCH_DIR: the working directory of the runner");
+    step(&format!(
+        "WRAPPE_APP_ID: {}",
+        format_app_id(&payload.info.app_id)
+    ));
+    if !payload.extra_sections.is_empty() {
+        step(&format!(
+            "WRAPPE_SECTIONS_DIR: {}/{} in the unpack directory",
+            WRAPPE_DIRECTORY, EXTRA_SECTIONS_DIRECTORY
+        ));
+    }
+    step("STARTPE_CLEANUP=1 or 0 overrides removing the unpack directory after the command exits");
+    step("STARTPE_FORCE_VERBOSE prints details about each step");
+}
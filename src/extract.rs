@@ -0,0 +1,373 @@
+use std::{
+    fs::{
+        File, copy as copy_file, create_dir_all, hard_link, remove_dir, remove_file,
+        set_permissions, symlink_metadata, write,
+    },
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write, copy},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use console::style;
+use filetime::{FileTime, set_file_times, set_symlink_file_times};
+use rayon::prelude::*;
+use twox_hash::XxHash64;
+use zstd::dict::DecoderDictionary;
+
+use crate::{
+    args::emoji,
+    compress::{HASH_SEED, HashReader, copy_decode},
+    payload::{Payload, SolidBlocks, join, payload_path, solid_contents},
+    types::*,
+};
+
+#[derive(Args)]
+#[command(about = "Extract the payload of a packed executable without running it")]
+pub struct ExtractArgs {
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:  bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable: PathBuf,
+    /// Path to the directory to extract the payload into
+    #[arg(name = "directory")]
+    directory:  PathBuf,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Check that `name` is a single path component that can't escape its parent directory.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// Path of the slash-separated `path` in the payload below `directory`.
+fn local_path(directory: &Path, path: &str) -> PathBuf {
+    let mut local = directory.to_owned();
+    local.extend(path.split('/').filter(|component| !component.is_empty()));
+    local
+}
+
+/// Path to `target` relative to `parent`, both slash-separated paths in the payload.
+fn relative_path(parent: &str, target: &str) -> PathBuf {
+    let mut relative = PathBuf::new();
+    relative.extend(
+        parent
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(|_| ".."),
+    );
+    relative.extend(target.split('/').filter(|component| !component.is_empty()));
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// Decompress the file in `section` of the payload in `executable` to `path`, or copy it from
+/// its decompressed solid block in `blocks`, and restore its permissions and timestamps.
+fn extract_file(
+    executable: &Path, files_start: u64, section: &FileSectionHeader, path: &Path, algorithm: u8,
+    dictionary: Option<&DecoderDictionary>, blocks: &SolidBlocks,
+) -> Result<()> {
+    if section.solid == 1 {
+        write(path, solid_contents(blocks, section)?)?;
+    } else {
+        let mut file = File::open(executable)?;
+        file.seek(SeekFrom::Start(files_start + section.position.get()))?;
+        let mut reader = HashReader::new(
+            file.take(section.size.get()),
+            XxHash64::with_seed(HASH_SEED),
+        );
+        {
+            let mut reader = BufReader::new(&mut reader);
+            let mut output = BufWriter::new(File::create(path)?);
+            if section.stored == 1 {
+                copy(&mut reader, &mut output)?;
+            } else {
+                copy_decode(&mut reader, &mut output, algorithm, dictionary)?;
+            }
+            output.flush()?;
+        }
+        let compressed_hash = reader.finish();
+        if compressed_hash != section.compressed_hash.get() {
+            let expected = section.compressed_hash.get();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "compressed file hash ({}) differs from expected hash ({})",
+                    compressed_hash, expected
+                ),
+            ));
+        }
+    }
+
+    // files packed on Windows don't have a mode
+    #[cfg(any(unix, target_os = "redox"))]
+    let mut permissions = if section.mode != 0 {
+        std::os::unix::fs::PermissionsExt::from_mode(section.mode.get())
+    } else {
+        path.metadata()?.permissions()
+    };
+    #[cfg(not(any(unix, target_os = "redox")))]
+    let mut permissions = path.metadata()?.permissions();
+    // clearing the readonly flag would make the file writable for everyone on Unix
+    if section.readonly != 0 {
+        permissions.set_readonly(true);
+    }
+    set_permissions(path, permissions)?;
+    set_file_times(
+        path,
+        FileTime::from_unix_time(
+            section.time_accessed_seconds.get() as i64,
+            section.time_accessed_nanos.get(),
+        ),
+        FileTime::from_unix_time(
+            section.time_modified_seconds.get() as i64,
+            section.time_modified_nanos.get(),
+        ),
+    )
+}
+
+/// Restore the permissions and timestamps of the extracted directory in `section` at `path`.
+/// Directories without recorded metadata are left as they are.
+fn restore_directory(section: &DirectorySection, path: &Path) -> Result<()> {
+    #[cfg(any(unix, target_os = "redox"))]
+    if section.mode != 0 {
+        set_permissions(
+            path,
+            std::os::unix::fs::PermissionsExt::from_mode(section.mode.get()),
+        )?;
+    }
+    if section.time_modified_seconds == 0 {
+        return Ok(());
+    }
+    set_file_times(
+        path,
+        FileTime::from_unix_time(
+            section.time_accessed_seconds.get() as i64,
+            section.time_accessed_nanos.get(),
+        ),
+        FileTime::from_unix_time(
+            section.time_modified_seconds.get() as i64,
+            section.time_modified_nanos.get(),
+        ),
+    )
+}
+
+/// Create a hardlink at `path` to the extracted file at `target`,
+/// or copy the file if the file system doesn't support hardlinks.
+fn extract_hardlink(path: &Path, target: &Path) -> Result<()> {
+    if symlink_metadata(path).is_ok() {
+        remove_file(path)?;
+    }
+    if hard_link(target, path).is_err() {
+        copy_file(target, path)?;
+    }
+    Ok(())
+}
+
+/// Create the symlink in `section` at `path` pointing to the relative `target`.
+fn extract_symlink(section: &SymlinkSection, path: &Path, target: &Path) -> Result<()> {
+    if let Ok(meta) = symlink_metadata(path) {
+        if meta.is_dir() {
+            remove_dir(path)?;
+        } else {
+            remove_file(path)?;
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::{symlink_dir, symlink_file};
+        if section.kind == 0 || section.kind == 2 {
+            symlink_dir(target, path)?;
+        } else {
+            symlink_file(target, path)?;
+        }
+    }
+    #[cfg(any(unix, target_os = "redox"))]
+    {
+        std::os::unix::fs::symlink(target, path)?;
+    }
+    #[cfg(not(any(windows, unix, target_os = "redox")))]
+    {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "symlinks are not supported on this platform",
+        ));
+    }
+    set_symlink_file_times(
+        path,
+        FileTime::from_unix_time(
+            section.time_accessed_seconds.get() as i64,
+            section.time_accessed_nanos.get(),
+        ),
+        FileTime::from_unix_time(
+            section.time_modified_seconds.get() as i64,
+            section.time_modified_nanos.get(),
+        ),
+    )
+}
+
+/// Extract the directories, files and symlinks in the payload of the packed executable
+/// in `args` into the target directory. Symlinks are created with relative targets.
+pub fn extract(args: ExtractArgs) {
+    println!(
+        "{} {}reading payload of {}…",
+        style("[1/2]").bold().dim(),
+        emoji("🔍 ", ""),
+        style(args.executable.display()).blue().bright()
+    );
+    let executable = payload_path(&args.executable);
+    let payload = File::open(&executable)
+        .and_then(|mut file| Payload::read(&mut file))
+        .unwrap_or_else(|e| fail("couldn't read payload", e));
+
+    let names = payload
+        .directories
+        .iter()
+        .map(|section| payload.names.get(section.name))
+        .chain(
+            payload
+                .files
+                .iter()
+                .map(|section| payload.names.get(section.name)),
+        )
+        .chain(
+            payload
+                .symlinks
+                .iter()
+                .map(|section| payload.names.get(section.name)),
+        )
+        .chain(
+            payload
+                .hardlinks
+                .iter()
+                .map(|section| payload.names.get(section.name)),
+        );
+    for name in names {
+        if !is_valid_name(&name) {
+            fail("payload contains an invalid name", name);
+        }
+    }
+
+    let directories = payload.directory_paths();
+    let files = payload
+        .files
+        .iter()
+        .map(|section| {
+            join(
+                &directories[section.parent.get() as usize],
+                &payload.names.get(section.name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{} {}extracting {} files to {}…",
+        style("[2/2]").bold().dim(),
+        emoji("📦 ", ""),
+        style(files.len()).magenta(),
+        style(args.directory.display()).blue().bright()
+    );
+
+    for directory in directories.iter() {
+        let path = local_path(&args.directory, directory);
+        create_dir_all(&path).unwrap_or_else(|e| {
+            fail(
+                "couldn't create directory",
+                format!("{}: {}", path.display(), e),
+            )
+        });
+    }
+
+    let algorithm = payload.header.kind;
+    let dictionary =
+        (!payload.dictionary.is_empty()).then(|| DecoderDictionary::copy(&payload.dictionary));
+    let blocks = payload.solid_blocks(&executable, dictionary.as_ref());
+    let mut errors = payload
+        .files
+        .par_iter()
+        .zip(files.par_iter())
+        .filter_map(|(section, path)| {
+            extract_file(
+                &executable,
+                payload.files_start,
+                section,
+                &local_path(&args.directory, path),
+                algorithm,
+                dictionary.as_ref(),
+                &blocks,
+            )
+            .err()
+            .map(|e| format!("couldn't extract {}: {}", path, e))
+        })
+        .collect::<Vec<_>>();
+
+    for section in payload.hardlinks.iter() {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        let target = &files[section.target.get() as usize];
+        if let Err(e) = extract_hardlink(
+            &local_path(&args.directory, &path),
+            &local_path(&args.directory, target),
+        ) {
+            errors.push(format!("couldn't extract {}: {}", path, e));
+        }
+    }
+
+    for section in payload.symlinks.iter() {
+        let parent = &directories[section.parent.get() as usize];
+        let path = join(parent, &payload.names.get(section.name));
+        let target = match section.kind {
+            0 => directories.get(section.target.get() as usize),
+            1 => files.get(section.target.get() as usize),
+            _ => None,
+        };
+        // external symlinks are extracted with their verbatim target
+        let target = match target {
+            Some(target) => relative_path(parent, target),
+            None if section.kind >= 2 => PathBuf::from(payload.names.get(section.link)),
+            None => {
+                errors.push(format!("couldn't extract {}: invalid target", path));
+                continue;
+            }
+        };
+        if let Err(e) = extract_symlink(section, &local_path(&args.directory, &path), &target) {
+            errors.push(format!("couldn't extract {}: {}", path, e));
+        }
+    }
+
+    // restored after their contents were created, subdirectories first
+    for (section, directory) in payload
+        .directories
+        .iter()
+        .zip(directories.iter().skip(1))
+        .rev()
+    {
+        if let Err(e) = restore_directory(section, &local_path(&args.directory, directory)) {
+            errors.push(format!("couldn't extract {}: {}", directory, e));
+        }
+    }
+
+    for error in errors.iter() {
+        println!("      {}{}", emoji("❗ ", ""), style(error).red());
+    }
+    if !errors.is_empty() {
+        fail("extraction failed", format!("{} errors", errors.len()));
+    }
+
+    println!(
+        "      {}{} {} {}",
+        emoji("✨ ", ""),
+        style("successfully extracted").green(),
+        style(payload.header.len()).magenta(),
+        style("files and directories").green(),
+    );
+}
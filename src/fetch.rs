@@ -0,0 +1,113 @@
+use std::{
+    fs::{create_dir_all, read, rename, write},
+    io::Read,
+    path::PathBuf,
+};
+
+use console::style;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    args::{ArgsError, check_runner, emoji, get_cache},
+    signing::hex,
+};
+
+/// URL prebuilt runners are fetched from by default, where the release workflow uploads them.
+pub const RUNNER_URL: &str =
+    "https://github.com/Systemcluster/wrappe/releases/download/v{version}/startpe-{target}{ext}";
+
+/// Upper limit of the size of a fetched runner.
+const RUNNER_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Fill in the {version}, {target} and {ext} fields of the runner URL `template`.
+fn runner_url(template: &str, target: &str) -> String {
+    template
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+        .replace("{target}", target)
+        .replace(
+            "{ext}",
+            if target.contains("windows") {
+                ".exe"
+            } else {
+                ""
+            },
+        )
+}
+
+/// Download `url` into memory, up to `limit` bytes.
+fn download(agent: &ureq::Agent, url: &str, limit: u64) -> Result<Vec<u8>, String> {
+    let response = agent.get(url).call().map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(limit)
+        .read_to_end(&mut data)
+        .map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+/// Path of the runner for `target` of this version of wrappe in the cache directory, downloading
+/// it from the URL `template` if it isn't cached yet. The runner is compared with the SHA-256
+/// digest `pinned` before it is cached or used from the cache. Without it, the checksum is
+/// downloaded from the same URL with `.sha256` appended, which detects corrupted downloads, but
+/// not runners replaced together with their checksum.
+pub fn fetch_runner(
+    target: &str, template: &str, pinned: Option<&str>,
+) -> Result<PathBuf, ArgsError> {
+    let url = runner_url(template, target);
+    let file_name = url.rsplit('/').next().unwrap_or(target).to_string();
+    let directory = get_cache(None)?
+        .join("runners")
+        .join(env!("CARGO_PKG_VERSION"));
+    let path = directory.join(&file_name);
+    let pinned = pinned.map(|digest| digest.trim().to_lowercase());
+    let cached = match &pinned {
+        Some(pinned) => read(&path).is_ok_and(|runner| &hex(&Sha256::digest(runner)) == pinned),
+        None => path.is_file(),
+    };
+    if cached {
+        return Ok(path);
+    }
+
+    println!(
+        "      {}{}",
+        emoji("🌐 ", ""),
+        style(format!("downloading runner {}…", url)).dim()
+    );
+    let agent = ureq::AgentBuilder::new().try_proxy_from_env(true).build();
+    let checksum = match pinned {
+        Some(pinned) => pinned,
+        None => {
+            let checksum = download(&agent, &format!("{}.sha256", url), 4096)
+                .map_err(|e| ArgsError::invalid("couldn't download runner checksum", e))?;
+            String::from_utf8_lossy(&checksum)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        }
+    };
+    let runner = download(&agent, &url, RUNNER_SIZE_LIMIT)
+        .map_err(|e| ArgsError::invalid("couldn't download runner", e))?;
+    let actual = hex(&Sha256::digest(&runner));
+    if actual != checksum {
+        return Err(ArgsError::invalid(
+            "runner checksum differs from expected checksum",
+            format!("{} (expected {})", actual, checksum),
+        ));
+    }
+    check_runner(&runner, &url)?;
+
+    // written to a temporary file first so that interrupted downloads aren't cached
+    let temporary = directory.join(format!("{}.tmp", file_name));
+    create_dir_all(&directory)
+        .and_then(|_| write(&temporary, &runner))
+        .and_then(|_| rename(&temporary, &path))
+        .map_err(|e| {
+            ArgsError::invalid(
+                "couldn't cache runner",
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+    Ok(path)
+}
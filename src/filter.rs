@@ -0,0 +1,90 @@
+use std::path::Path;
+
+use globset::{Glob, GlobMatcher};
+
+/// Transform applied to the contents of files when packing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// Convert CRLF and CR line endings to LF.
+    Lf,
+    /// Convert LF and CR line endings to CRLF.
+    Crlf,
+    /// Remove a UTF-8 byte order mark at the start of the file.
+    StripBom,
+}
+
+/// Names of the filters, in the order of [`Filter`].
+pub const FILTER_NAMES: [&str; 3] = ["lf", "crlf", "strip-bom"];
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "lf" => Some(Filter::Lf),
+            "crlf" => Some(Filter::Crlf),
+            "strip-bom" => Some(Filter::StripBom),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, contents: Vec<u8>) -> Vec<u8> {
+        match self {
+            Filter::Lf | Filter::Crlf => {
+                let mut filtered = Vec::with_capacity(contents.len());
+                let mut bytes = contents.iter().peekable();
+                while let Some(&byte) = bytes.next() {
+                    let newline = match byte {
+                        b'\r' => {
+                            bytes.next_if_eq(&&b'\n');
+                            true
+                        }
+                        b'\n' => true,
+                        _ => false,
+                    };
+                    if !newline {
+                        filtered.push(byte);
+                    } else if self == Filter::Crlf {
+                        filtered.extend_from_slice(b"\r\n");
+                    } else {
+                        filtered.push(b'\n');
+                    }
+                }
+                filtered
+            }
+            Filter::StripBom => match contents.strip_prefix(BOM) {
+                Some(stripped) => stripped.to_vec(),
+                None => contents,
+            },
+        }
+    }
+}
+
+/// Filters applied to files whose path relative to the input matches a glob pattern.
+/// Files matching multiple patterns are filtered with the filters of all of them, in order.
+#[derive(Default)]
+pub struct ContentFilters {
+    filters: Vec<(GlobMatcher, Vec<Filter>)>,
+}
+
+impl ContentFilters {
+    pub fn push(&mut self, glob: Glob, filters: Vec<Filter>) {
+        self.filters.push((glob.compile_matcher(), filters));
+    }
+
+    /// Filters of the file at `relative`, empty if it isn't filtered.
+    pub fn matching(&self, relative: &Path) -> Vec<Filter> {
+        self.filters
+            .iter()
+            .filter(|(glob, _)| glob.is_match(relative))
+            .flat_map(|(_, filters)| filters.iter().copied())
+            .collect()
+    }
+
+    /// Apply the filters in `filters` to `contents` in order.
+    pub fn apply(filters: &[Filter], contents: Vec<u8>) -> Vec<u8> {
+        filters
+            .iter()
+            .fold(contents, |contents, filter| filter.apply(contents))
+    }
+}
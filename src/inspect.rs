@@ -0,0 +1,328 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Result, copy, sink},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use console::style;
+use serde_json::{Value, json};
+use twox_hash::XxHash64;
+
+use crate::{
+    compress::{HASH_SEED, HashReader, content_hash},
+    payload::{Payload, algorithm_name, join, payload_path, section_name},
+    signing::hex,
+    statistics::{STATISTICS_SECTION, describe_statistics},
+    types::*,
+};
+
+#[derive(Args)]
+#[command(about = "Print the startup configuration and extra sections of a packed executable")]
+pub struct InspectArgs {
+    /// Write the contents of the extra section NAME to a file instead of printing the configuration
+    #[arg(long, value_name = "NAME")]
+    extract_section: Option<String>,
+    /// Path of the file to write the extracted section to [default: NAME in the current directory]
+    #[arg(short = 'o', long, requires = "extract_section")]
+    output:          Option<PathBuf>,
+    /// Print the configuration, payload statistics and hashes as JSON
+    #[arg(long, conflicts_with = "extract_section")]
+    pub json:        bool,
+    /// Print the embedded manifest of the packed files instead of the configuration
+    #[arg(long, conflicts_with_all = ["extract_section", "json"])]
+    manifest:        bool,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:       bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable:      PathBuf,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Name of the option value `value` out of `names`, in the order of their encoding.
+pub fn option_name(names: &[&'static str], value: u8) -> &'static str {
+    names.get(value as usize).copied().unwrap_or("unknown")
+}
+
+/// Names of the unpack target, unpack subdirectory, versioning, verification, current directory
+/// and console options, and of where the console option was taken from.
+pub const UNPACK_TARGETS: [&str; 3] = ["temp", "local", "cwd"];
+const UNPACK_SUBDIRS: [&str; 3] = ["none", "path", "content"];
+pub const VERSIONING: [&str; 3] = ["sidebyside", "replace", "none"];
+pub const VERIFICATION: [&str; 3] = ["none", "existence", "checksum"];
+pub const CURRENT_DIRS: [&str; 4] = ["inherit", "unpack", "runner", "command"];
+pub const CONSOLES: [&str; 3] = ["never", "always", "attach"];
+const CONSOLE_SOURCES: [&str; 3] = ["option", "command", "fallback"];
+
+/// Pack statistics recorded with `--statistics` in the extra sections `sections`.
+fn statistics(sections: &[(&str, &[u8])]) -> Option<Value> {
+    let (_, data) = sections
+        .iter()
+        .find(|(name, _)| *name == STATISTICS_SECTION)?;
+    serde_json::from_slice(data).ok()
+}
+
+/// Configuration, payload statistics and hashes of `payload` as reported by `inspect`.
+pub fn metadata(payload: &Payload) -> Value {
+    let info = &payload.info;
+    let header = &payload.header;
+    let directories = payload.directory_paths();
+    let links = payload
+        .files
+        .iter()
+        .map(|section| {
+            join(
+                &directories[section.parent.get() as usize],
+                &payload.names.get(section.name),
+            )
+        })
+        .collect::<Vec<_>>();
+    let content_hash = content_hash(
+        &payload.directories,
+        &payload.files,
+        &payload.symlinks,
+        &payload.hardlinks,
+        &links,
+        &payload.names,
+    );
+    let arguments = String::from_utf8_lossy(&payload.arguments);
+    let sections = read_extra_sections(&payload.extra_sections).unwrap_or_default();
+    json!({
+        "format": info.wrappe_format,
+        "version": section_name(&info.uid),
+        "app_id": format_app_id(&info.app_id),
+        "unpack_directory": section_name(&info.unpack_directory),
+        "unpack_target": option_name(&UNPACK_TARGETS, info.unpack_target),
+        "unpack_subdir": option_name(&UNPACK_SUBDIRS, info.unpack_subdir),
+        "versioning": option_name(&VERSIONING, info.versioning),
+        "verification": option_name(&VERIFICATION, info.verification),
+        "command": section_name(&info.command),
+        "arguments": if arguments.is_empty() {
+            Vec::new()
+        } else {
+            arguments.split(ARGUMENT_SEPARATOR).collect::<Vec<_>>()
+        },
+        "current_directory": option_name(&CURRENT_DIRS, info.current_dir),
+        "console": option_name(&CONSOLES, info.show_console),
+        "console_source": option_name(&CONSOLE_SOURCES, info.console_source),
+        "public_key": (info.public_key != [0; 32]).then(|| hex(&info.public_key)),
+        "manifest_size": payload.manifest.len(),
+        "statistics": statistics(&sections),
+        "payload": {
+            "compression": algorithm_name(header.kind),
+            "directories": header.directory_sections.get(),
+            "files": header.file_sections.get(),
+            "symlinks": header.symlink_sections.get(),
+            "hardlinks": header.hardlink_sections.get(),
+            "size": header.payload_size.get(),
+            "sections_size": header.sections_size.get(),
+            "dictionary_size": header.dictionary_size.get(),
+        },
+        "hashes": {
+            "sections": format!("{:016x}", { header.section_hash }),
+            "payload": hex(&header.payload_hash),
+            "contents": format!("{:016x}", content_hash),
+        },
+        "extra_sections": sections
+            .iter()
+            .map(|(name, data)| json!({ "name": name, "size": data.len() }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Write the metadata of the packed executable at `output` for the runner `target` to
+/// `<output>.wrappe.json`, adding the name, size and hash of the executable, and return its path.
+pub fn write_metadata(output: &Path, target: &str) -> Result<PathBuf> {
+    let mut file = File::open(payload_path(output))?;
+    let mut metadata = metadata(&Payload::read(&mut file)?);
+    let mut hasher = HashReader::new(File::open(output)?, XxHash64::with_seed(HASH_SEED));
+    let size = copy(&mut hasher, &mut sink())?;
+    let name = output
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    metadata["target"] = json!(target);
+    metadata["executable"] = json!({
+        "name": name,
+        "size": size,
+        "hash": format!("{:016x}", hasher.finish()),
+    });
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".wrappe.json");
+    let path = PathBuf::from(path);
+    std::fs::write(&path, format!("{:#}\n", metadata))?;
+    Ok(path)
+}
+
+/// Print a line of the configuration report.
+fn field(name: &str, value: impl std::fmt::Display) {
+    println!("{:<18} {}", style(name).bold(), value);
+}
+
+/// Print the startup configuration and extra sections of the packed executable in `args`,
+/// or write the contents of the selected extra section to a file.
+pub fn inspect(args: InspectArgs) {
+    let payload = File::open(payload_path(&args.executable))
+        .and_then(|mut file| Payload::read(&mut file))
+        .unwrap_or_else(|e| fail("couldn't read payload", e));
+    // validated when reading the payload
+    let sections = read_extra_sections(&payload.extra_sections).unwrap_or_default();
+
+    if let Some(name) = &args.extract_section {
+        let Some((_, data)) = sections.iter().find(|(section, _)| section == name) else {
+            fail("extra section not found", name);
+        };
+        let output = args.output.clone().unwrap_or_else(|| PathBuf::from(name));
+        std::fs::write(&output, data).unwrap_or_else(|e| {
+            fail(
+                "couldn't write extra section",
+                format!("{}: {}", output.display(), e),
+            )
+        });
+        println!(
+            "{} {} {} {}",
+            style("wrote").green(),
+            style(data.len()).magenta(),
+            style("bytes to").green(),
+            style(output.display()).blue().bright()
+        );
+        return;
+    }
+
+    if args.manifest {
+        if payload.manifest.is_empty() {
+            fail(
+                "no file manifest",
+                "the executable was packed with --no-manifest",
+            );
+        }
+        print!("{}", String::from_utf8_lossy(&payload.manifest));
+        return;
+    }
+
+    if args.json {
+        println!("{:#}", metadata(&payload));
+        return;
+    }
+
+    let info = &payload.info;
+    let header = &payload.header;
+    let arguments = String::from_utf8_lossy(&payload.arguments);
+    let (format, payload_size, dictionary_size, sections_size) = (
+        info.wrappe_format,
+        header.payload_size,
+        header.dictionary_size,
+        header.sections_size,
+    );
+
+    field("format", format);
+    field("version", section_name(&info.uid));
+    field("app id", format_app_id(&info.app_id));
+    field("unpack directory", section_name(&info.unpack_directory));
+    field(
+        "unpack target",
+        option_name(&UNPACK_TARGETS, info.unpack_target),
+    );
+    field(
+        "unpack subdirectory",
+        option_name(&UNPACK_SUBDIRS, info.unpack_subdir),
+    );
+    field("versioning", option_name(&VERSIONING, info.versioning));
+    field(
+        "verification",
+        option_name(&VERIFICATION, info.verification),
+    );
+    field("command", section_name(&info.command));
+    field(
+        "arguments",
+        if arguments.is_empty() {
+            "-".to_string()
+        } else {
+            arguments
+                .split(ARGUMENT_SEPARATOR)
+                .map(|argument| format!("{:?}", argument))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+    );
+    field(
+        "current directory",
+        option_name(&CURRENT_DIRS, info.current_dir),
+    );
+    field("console", match info.console_source {
+        1 => format!(
+            "{} (inherited from the subsystem of the command)",
+            option_name(&CONSOLES, info.show_console)
+        ),
+        2 => format!(
+            "{} (fallback, the subsystem of the command couldn't be inherited)",
+            option_name(&CONSOLES, info.show_console)
+        ),
+        _ => option_name(&CONSOLES, info.show_console).to_string(),
+    });
+    field(
+        "public key",
+        if info.public_key == [0; 32] {
+            "-".to_string()
+        } else {
+            hex(&info.public_key)
+        },
+    );
+    field(
+        "payload",
+        format!(
+            "{} entries compressed with {}, {} bytes with {} bytes of sections and a {} bytes dictionary",
+            header.len(),
+            algorithm_name(header.kind),
+            payload_size,
+            sections_size,
+            dictionary_size
+        ),
+    );
+    field(
+        "manifest",
+        if payload.manifest.is_empty() {
+            "-".to_string()
+        } else {
+            format!(
+                "{} bytes (print with --manifest or run the executable with --wrappe-manifest)",
+                payload.manifest.len()
+            )
+        },
+    );
+    field(
+        "statistics",
+        statistics(&sections)
+            .map(|statistics| describe_statistics(&statistics))
+            .unwrap_or_else(|| "-".to_string()),
+    );
+
+    println!();
+    if sections.is_empty() {
+        println!("{}", style("no extra sections").dim());
+        return;
+    }
+    println!(
+        "{}",
+        style(format!("{:<32} {:>12}", "extra section", "size")).bold()
+    );
+    for (name, data) in sections.iter() {
+        println!("{:<32} {:>12}", name, data.len());
+    }
+    println!();
+    println!(
+        "{}",
+        style(format!(
+            "extra sections are unpacked to {}/{} in the unpack directory",
+            WRAPPE_DIRECTORY, EXTRA_SECTIONS_DIRECTORY
+        ))
+        .dim()
+    );
+}
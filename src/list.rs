@@ -0,0 +1,213 @@
+use std::{
+    fs::File,
+    io::{Write, stdout},
+    path::PathBuf,
+};
+
+use clap::Args;
+use console::style;
+
+use crate::payload::{Payload, algorithm_name, join, payload_path, section_name};
+
+#[derive(Args)]
+#[command(
+    about = "Print the directories, files and symlinks in the payload of a packed executable"
+)]
+pub struct ListArgs {
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:  bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable: PathBuf,
+}
+
+/// Format `seconds` since the Unix epoch as date and time in UTC.
+fn format_time(seconds: u64) -> String {
+    // civil date from days since the epoch, see https://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719468;
+    let time = seconds % 86400;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Print the startup configuration and the directory, file and symlink sections
+/// of the packed executable in `args`.
+pub fn list(args: ListArgs) {
+    let payload = File::open(payload_path(&args.executable))
+        .and_then(|mut file| Payload::read(&mut file))
+        .unwrap_or_else(|e| {
+            println!("{}: {}", style("couldn't read payload").red(), e);
+            std::process::exit(-1);
+        });
+    let mut out = stdout().lock();
+    let info = &payload.info;
+    let header = &payload.header;
+
+    let directories = payload.directory_paths();
+    let files = payload
+        .files
+        .iter()
+        .map(|section| {
+            join(
+                &directories[section.parent.get() as usize],
+                &payload.names.get(section.name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let _ = writeln!(
+        out,
+        "{} {} {}",
+        style(section_name(&info.unpack_directory)).bold(),
+        style(format!("(version {})", section_name(&info.uid))).dim(),
+        style(format!("runs {}", section_name(&info.command))).dim(),
+    );
+    let (directory_sections, file_sections, symlink_sections, hardlink_sections) = (
+        header.directory_sections,
+        header.file_sections,
+        header.symlink_sections,
+        header.hardlink_sections,
+    );
+    let (payload_size, dictionary_size) = (header.payload_size.get(), header.dictionary_size.get());
+    let _ = writeln!(
+        out,
+        "{}",
+        style(format!(
+            "{} directories, {} files, {} symlinks, {} hardlinks, {} bytes compressed with {}{}",
+            directory_sections,
+            file_sections,
+            symlink_sections,
+            hardlink_sections,
+            payload_size,
+            algorithm_name(header.kind),
+            if dictionary_size > 0 {
+                format!(" and a {} bytes dictionary", dictionary_size)
+            } else {
+                String::new()
+            }
+        ))
+        .dim()
+    );
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "{}",
+        style(format!(
+            "{:<4} {:>12} {:<16} {:<19} {:<6} path",
+            "type", "size", "hash", "modified", "mode"
+        ))
+        .bold()
+    );
+
+    for (section, path) in payload.directories.iter().zip(directories.iter().skip(1)) {
+        let (modified, mode) = (section.time_modified_seconds.get(), section.mode.get());
+        // directories added by updates or for extra files have no metadata
+        let (modified, mode) = if modified == 0 && mode == 0 {
+            ("-".to_string(), "-".to_string())
+        } else {
+            (format_time(modified), format!("{:o}", mode & 0o7777))
+        };
+        let _ = writeln!(
+            out,
+            "{:<4} {:>12} {:<16} {:<19} {:<6} {}/",
+            "d", "-", "-", modified, mode, path
+        );
+    }
+    for (section, path) in payload.files.iter().zip(files.iter()) {
+        // files in solid blocks share the compressed size of their block
+        let size = if section.solid == 1 {
+            section.block_size
+        } else {
+            section.size
+        };
+        let (file_hash, modified, mode) = (
+            section.file_hash,
+            section.time_modified_seconds,
+            section.mode,
+        );
+        let _ = writeln!(
+            out,
+            "{:<4} {:>12} {:016x} {:<19} {:<6o} {}",
+            match (section.stored, section.solid) {
+                (1, _) => "f*",
+                (_, 1) => "f+",
+                _ => "f",
+            },
+            size,
+            file_hash,
+            format_time(modified.get()),
+            mode & 0o7777,
+            path
+        );
+    }
+    for section in payload.symlinks.iter() {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        let target = match section.kind {
+            0 => directories
+                .get(section.target.get() as usize)
+                .map(|directory| format!("{}/", directory)),
+            1 => files.get(section.target.get() as usize).cloned(),
+            _ => Some(payload.names.get(section.link)),
+        };
+        let modified = section.time_modified_seconds.get();
+        let _ = writeln!(
+            out,
+            "{:<4} {:>12} {:<16} {:<19} {:<6} {} -> {}",
+            "l",
+            "-",
+            "-",
+            format_time(modified),
+            "-",
+            path,
+            target.unwrap_or_else(|| "?".to_string())
+        );
+    }
+    for section in payload.hardlinks.iter() {
+        let path = join(
+            &directories[section.parent.get() as usize],
+            &payload.names.get(section.name),
+        );
+        let _ = writeln!(
+            out,
+            "{:<4} {:>12} {:<16} {:<19} {:<6} {} => {}",
+            "h",
+            "-",
+            "-",
+            "-",
+            "-",
+            path,
+            files[section.target.get() as usize]
+        );
+    }
+
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "{}",
+        style(
+            "sizes are compressed sizes, files marked with * are stored uncompressed, \
+             files marked with + are compressed in solid blocks and show their uncompressed size"
+        )
+        .dim()
+    );
+}
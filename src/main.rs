@@ -1,134 +1,998 @@
 use std::{
+    env::temp_dir,
     error::Error,
-    fs::File,
-    io::{BufWriter, Cursor, Write},
-    path::PathBuf,
-    time::{Duration, SystemTime},
+    ffi::OsString,
+    fmt::Display,
+    fs::{File, remove_dir_all, remove_file},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    mem::offset_of,
+    panic::{set_hook, take_hook},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use clap::Parser;
-use console::{Emoji, style};
+use console::style;
 use editpe::Image;
 use indicatif::{ProgressBar, ProgressStyle};
-use jwalk::WalkDir;
-use zstd::stream::copy_decode;
+use rand::{
+    distributions::{Alphanumeric, Distribution},
+    thread_rng,
+};
+use sysinfo::{Pid, ProcessesToUpdate, System};
+use tracing::info_span;
+use tracing_chrome::ChromeLayerBuilder;
+use tracing_subscriber::prelude::*;
 
 mod types;
 use types::*;
 
 mod compress;
-use compress::compress;
+use compress::{CompressError, CompressEvent, CompressOptions, compress, move_file, walk_source};
 
 mod args;
 use args::*;
 
+mod filter;
+
+
+mod payload;
+use payload::{Payload, find_info, payload_path};
+
+mod pe;
+use pe::{catch_panic, check_image, describe_image};
+
+mod validate;
+use validate::validate;
+
+mod update;
+use update::{UpdateArgs, update};
+
+mod list;
+use list::{ListArgs, list};
+
+mod extract;
+use extract::{ExtractArgs, extract};
+
+mod verify;
+use verify::{VerifyArgs, verify};
+
+mod bench;
+use bench::{BenchArgs, bench};
+
+mod inspect;
+use inspect::{InspectArgs, inspect, write_metadata};
+
+mod explain;
+use explain::{ExplainArgs, explain};
+
+mod dev;
+use dev::{DevArgs, dev};
+
+mod fetch;
+use fetch::{RUNNER_URL, fetch_runner};
+
+mod signing;
+use signing::{KeygenArgs, hex, keygen, sign_payload};
+
+mod manifest;
+use manifest::{ManifestArgs, manifest};
+
+mod sbom;
+use sbom::embed_manifest;
+
+mod cargo;
+use cargo::{CargoArgs, cargo};
+
+mod statistics;
+use statistics::{PackStatistics, STATISTICS_SECTION};
+
+mod shebang;
+use shebang::{ShebangMode, Shebangs};
+
+mod report;
+use report::PackReport;
+
 #[derive(Parser)]
-#[clap(about)]
+#[command(
+    about,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    subcommand_value_name = "SUBCOMMAND",
+    subcommand_help_heading = "Subcommands",
+    disable_help_subcommand = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    subcommand:   Option<Subcommands>,
+    #[command(flatten)]
+    pack:         Option<Args>,
+    /// Print available runners
+    #[arg(short = 'l', long, exclusive = true)]
+    list_runners: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommands {
+    Update(UpdateArgs),
+    List(ListArgs),
+    Extract(ExtractArgs),
+    Verify(VerifyArgs),
+    Inspect(InspectArgs),
+    Explain(ExplainArgs),
+    Bench(BenchArgs),
+    Dev(DevArgs),
+    Manifest(ManifestArgs),
+    Keygen(KeygenArgs),
+    Cargo(CargoArgs),
+}
+
+impl Subcommands {
+    /// Whether plain output was requested for the subcommand.
+    fn plain(&self) -> bool {
+        match self {
+            Subcommands::Update(UpdateArgs { plain, .. })
+            | Subcommands::List(ListArgs { plain, .. })
+            | Subcommands::Extract(ExtractArgs { plain, .. })
+            | Subcommands::Verify(VerifyArgs { plain, .. })
+            | Subcommands::Inspect(InspectArgs { plain, .. })
+            | Subcommands::Explain(ExplainArgs { plain, .. })
+            | Subcommands::Bench(BenchArgs { plain, .. })
+            | Subcommands::Dev(DevArgs { plain, .. })
+            | Subcommands::Manifest(ManifestArgs { plain, .. })
+            | Subcommands::Keygen(KeygenArgs { plain, .. })
+            | Subcommands::Cargo(CargoArgs { plain, .. }) => *plain,
+        }
+    }
+}
+
+#[derive(clap::Args)]
 pub struct Args {
-    /// Platform to pack for (see --list-runners for available options)
+    /// Platform to pack for, or all for all included runners (repeatable, see --list-runners for available options)
     #[arg(short = 'r', long, default_value = "native")]
-    runner:           String,
-    /// Zstd compression level (0-22)
+    runner:                 Vec<String>,
+    /// Path to a runner binary to use instead of the included runners
+    #[arg(long, value_name = "PATH", conflicts_with = "runner")]
+    runner_path:            Option<PathBuf>,
+    /// Download the prebuilt runner for the target triple instead of using the included runners
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["runner", "runner_path"])]
+    fetch_runner:           Option<String>,
+    /// URL to download runners from with {version}, {target} and {ext} fields [default: GitHub releases]
+    #[arg(long, value_name = "URL", requires = "fetch_runner")]
+    runner_url:             Option<String>,
+    /// Expected SHA-256 digest of the fetched runner [default: the .sha256 file next to the runner, which only detects corrupted downloads]
+    #[arg(long, value_name = "HEX", requires = "fetch_runner")]
+    runner_sha256:          Option<String>,
+    /// Compression algorithm (zstd, xz, brotli)
+    #[arg(short = 'a', long, default_value = "zstd")]
+    algorithm:              String,
+    /// Compression level (0-22 for zstd, 0-9 for xz, 0-11 for brotli)
     #[arg(short = 'c', long, default_value = "8")]
-    compression:      u32,
+    compression:            u32,
     /// Unpack directory target (temp, local, cwd)
     #[arg(short = 't', long, default_value = "temp")]
-    unpack_target:    String,
-    /// Unpack directory name [default: inferred from input directory]
+    unpack_target:          String,
+    /// Unpack directory name with optional {name}, {version} and {target} fields [default: inferred from input directory]
     #[arg(short = 'd', long)]
-    unpack_directory: Option<String>,
+    unpack_directory:       Option<String>,
+    /// Application ID as a UUID shared by all releases [default: derived from unpack directory name]
+    #[arg(long, value_name = "UUID")]
+    app_id:                 Option<String>,
+    /// Unpack to a subdirectory named after a hash of the executable path or payload (none, path, content)
+    #[arg(long, value_name = "HASH", default_value = "none")]
+    unpack_subdir:          String,
     /// Versioning strategy (sidebyside, replace, none)
     #[arg(short = 'v', long, default_value = "sidebyside")]
-    versioning:       String,
+    versioning:             String,
     /// Verification of existing unpacked data (existence, checksum, none)
     #[arg(short = 'e', long, default_value = "existence")]
-    verification:     String,
-    /// Version string override [default: randomly generated]
+    verification:           String,
+    /// Version string override or auto-hash to derive it from the contents [default: randomly generated]
     #[arg(short = 's', long)]
-    version_string:   Option<String>,
+    version_string:         Option<String>,
     /// Information output details (title, verbose, none)
     #[arg(short = 'i', long, default_value = "title")]
-    show_information: String,
-    /// Show or attach to a console window (auto, always, never, attach)
+    show_information:       String,
+    /// Show or attach to a console window (auto, inherit, always, never, attach)
     #[arg(short = 'n', long, default_value = "auto")]
-    console:          String,
+    console:                String,
     /// Working directory of the command (inherit, unpack, runner, command)
     #[arg(short = 'w', long, default_value = "inherit")]
-    current_dir:      String,
+    current_dir:            String,
     /// Cleanup the unpack directory after exit
     #[arg(short = 'u', long, default_value = "false")]
-    cleanup:          bool,
-    /// Only allow one instance of the application to run
-    #[arg(short = 'o', long, default_value = "false")]
-    once:             bool,
+    cleanup:                bool,
+    /// Allow only one running instance (scan, lock, wait, app) [default: scan]
+    #[arg(short = 'o', long, value_name = "STRATEGY", num_args = 0..=1, require_equals = true, default_missing_value = "scan")]
+    once:                   Option<String>,
+    /// Maximum time in seconds to wait for a running instance to exit with --once=wait [default: no limit]
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "0",
+        hide_default_value = true
+    )]
+    once_timeout:           u32,
+    /// Exit code of the runner when --once prevents it from starting the command
+    #[arg(long, value_name = "CODE", default_value = "1")]
+    once_exit_code:         u8,
+    /// Don't print a message when --once prevents the runner from starting the command
+    #[arg(long, default_value = "false")]
+    once_quiet:             bool,
+    /// Install a desktop entry for the application on startup (Linux)
+    #[arg(long, default_value = "false")]
+    desktop_entry:          bool,
+    /// Desktop entry file to install [default: generated, implies --desktop-entry]
+    #[arg(long)]
+    desktop_file:           Option<PathBuf>,
+    /// Icon to install with the desktop entry [implies --desktop-entry]
+    #[arg(long)]
+    desktop_icon:           Option<PathBuf>,
+    /// Lower the CPU and IO priority of the runner while unpacking
+    #[arg(long, default_value = "false")]
+    low_priority:           bool,
+    /// Keep the runner alive as a supervisor of the command instead of replacing it (Unix)
+    #[arg(long, default_value = "false")]
+    supervise:              bool,
+    /// Process group of the command (inherit, group, session)
+    #[arg(long, default_value = "inherit")]
+    process_group:          String,
+    /// Close inherited file descriptors above stderr when starting the command (Unix)
+    #[arg(long, default_value = "false")]
+    close_fds:              bool,
+    /// Keep the inherited file descriptor FD open for the command [implies --close-fds]
+    #[arg(long, value_name = "FD")]
+    pass_fd:                Vec<u8>,
+    /// Record the owner and group of files and restore them when unpacking as root (Unix)
+    #[arg(long, default_value = "false")]
+    preserve_ownership:     bool,
+    /// Keep the setuid and setgid bits of files instead of stripping them (Unix)
+    #[arg(long, default_value = "false")]
+    allow_setuid:           bool,
+    /// Prefetch the payload into memory when unpacking if it is larger than the threshold
+    #[arg(long, value_name = "MEGABYTES", default_value = "512")]
+    prefetch_threshold:     u32,
+    /// Don't prefetch the payload into memory when unpacking
+    #[arg(long, default_value = "false")]
+    no_prefetch:            bool,
+    /// Read the packed executable into memory when starting and close it right away
+    #[arg(long, default_value = "false")]
+    in_memory:              bool,
+    /// Switch unpack strategies after repeated extraction failures
+    #[arg(long, default_value = "false")]
+    backoff:                bool,
+    /// Additional input directory to merge into the payload, replacing files of earlier inputs
+    #[arg(long = "input", id = "inputs", value_name = "DIR")]
+    inputs:                 Vec<PathBuf>,
+    /// Follow symlinks in the inputs and pack their targets as regular files and directories
+    #[arg(long, default_value = "false")]
+    dereference:            bool,
+    /// Maximum directory depth below the inputs, deeper directories are skipped with a warning
+    #[arg(long, value_name = "DEPTH", default_value = "128")]
+    max_depth:              usize,
+    /// Pack .DS_Store, Thumbs.db, desktop.ini and __MACOSX instead of skipping them
+    #[arg(long, default_value = "false")]
+    keep_junk:              bool,
+    /// Store symlinks pointing outside of the inputs with their verbatim target
+    #[arg(long, default_value = "false")]
+    keep_external_symlinks: bool,
+    /// Store the file at PATH as an extra data section named NAME
+    #[arg(long, value_name = "NAME=PATH")]
+    extra_section:          Vec<String>,
+    /// Pack the files listed in the file or stdin instead of walking the input directory
+    #[arg(long, value_name = "PATH", conflicts_with = "inputs")]
+    files_from:             Option<String>,
+    /// Store files matching the glob pattern without compression
+    #[arg(long, value_name = "GLOB")]
+    store:                  Vec<String>,
+    /// Transform the contents of files matching the glob pattern with the filters (lf, crlf, strip-bom)
+    #[arg(long, value_name = "GLOB=FILTER[,FILTER...]")]
+    filter:                 Vec<String>,
+    /// Rewrite shebangs of scripts pointing at interpreters on this machine (keep, env, unpack)
+    #[arg(long, value_name = "MODE", default_value = "keep")]
+    shebangs:               String,
+    /// Pack the input as a relocatable Python virtual environment
+    #[arg(long, default_value = "false")]
+    venv:                   bool,
+    /// Compress files even when they are detected as incompressible
+    #[arg(long, default_value = "false")]
+    no_auto_store:          bool,
     /// Build compression dictionary
     #[arg(short = 'z', long, default_value = "false")]
-    build_dictionary: bool,
-    /// Print available runners
-    #[arg(short = 'l', long)]
-    #[allow(dead_code)]
-    list_runners:     bool,
+    build_dictionary:       bool,
+    /// Use the zstd dictionary in the file instead of building one
+    #[arg(long, value_name = "PATH", conflicts_with = "build_dictionary")]
+    dictionary_file:        Option<PathBuf>,
+    /// Compress files smaller than 64 KB together in solid blocks of the given size (0 to disable)
+    #[arg(long, value_name = "KILOBYTES", default_value = "0")]
+    solid_block_size:       u64,
+    /// Maximum memory for files waiting to be written while compressing [default: a quarter of the system memory]
+    #[arg(long, value_name = "MEGABYTES")]
+    memory_limit:           Option<u64>,
+    /// Reuse compressed files from a repack cache [default: user cache directory]
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true)]
+    cache:                  Option<Option<PathBuf>>,
+    /// Produce bit-identical output for identical input (honors SOURCE_DATE_EPOCH)
+    #[arg(long, default_value = "false")]
+    reproducible:           bool,
+    /// Don't embed a manifest of the packed files with their sizes and SHA-256 hashes
+    #[arg(long, default_value = "false")]
+    no_manifest:            bool,
+    /// Sign the payload with the Ed25519 private key in the file, checked by the runner before unpacking
+    #[arg(long, value_name = "PATH")]
+    sign_key:               Option<PathBuf>,
+    /// Launch the output executable after packing to check that it starts, optionally with the given argument
+    #[arg(long, value_name = "ARG", num_args = 0..=1, require_equals = true, allow_hyphen_values = true)]
+    test_run:               Option<Vec<String>>,
+    /// Run the shell command before packing, failing if it fails (repeatable)
+    #[arg(long, value_name = "COMMAND")]
+    pre_pack:               Vec<String>,
+    /// Run the shell command after packing, failing if it fails (repeatable)
+    #[arg(long, value_name = "COMMAND")]
+    post_pack:              Vec<String>,
+    /// Skip validating the output executable after packing
+    #[arg(long, default_value = "false")]
+    no_validate:            bool,
+    /// Write the payload to a .pack file next to the output executable instead of appending it to the runner
+    #[arg(long, default_value = "false")]
+    split:                  bool,
+    /// Output path with {name}, {version}, {target} and {ext} fields instead of the output argument
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "output")]
+    output_template:        Option<String>,
+    /// Write the configuration, payload statistics and hashes to <output>.wrappe.json
+    #[arg(long, default_value = "false")]
+    emit_metadata:          bool,
+    /// Record the payload size, entry count and compression settings in the executable for inspect
+    #[arg(long, default_value = "false")]
+    statistics:             bool,
+    /// Write the size, compressed size and hash of every packed file and why entries were skipped to the JSON file
+    #[arg(long, value_name = "PATH")]
+    report:                 Option<PathBuf>,
+    /// Write a trace of the packing steps in Chrome trace format to the file
+    #[arg(long, value_name = "PATH")]
+    trace_file:             Option<PathBuf>,
+    /// Plain output without colors, emoji and progress bars [default: when not a terminal]
+    #[arg(long)]
+    plain:                  bool,
     /// Path to the input directory
     #[arg(name = "input")]
-    input:            PathBuf,
+    input:                  PathBuf,
     /// Path to the executable to start after unpacking
     #[arg(name = "command")]
-    command:          PathBuf,
+    command:                PathBuf,
     /// Path to or filename of the output executable
     #[arg(name = "output")]
-    output:           Option<PathBuf>,
+    output:                 Option<PathBuf>,
     /// Command line arguments to pass to the executable
     #[arg(last = true)]
-    arguments:        Vec<String>,
-    /// Print version
-    #[arg(short = 'V', long)]
-    #[allow(dead_code)]
-    version:          bool,
+    arguments:              Vec<String>,
+}
+
+static CANCEL: AtomicBool = AtomicBool::new(false);
+static TEMPORARY_OUTPUT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Remember the output file being written, so that it is removed when packing fails.
+fn set_temporary_output(output: &Path) {
+    *TEMPORARY_OUTPUT.lock().unwrap() = Some(output.to_owned());
+}
+
+fn remove_temporary_output() {
+    if let Some(output) = TEMPORARY_OUTPUT.lock().unwrap().as_ref() {
+        let _ = remove_file(output);
+    }
+}
+
+/// Path of the pack file written next to `output` when splitting, and of its temporary file.
+fn pack_paths(output: &Path) -> (PathBuf, PathBuf) {
+    let pack = output.with_extension(PACK_EXTENSION);
+    let mut temporary_pack = OsString::from(pack.as_os_str());
+    temporary_pack.push(".tmp");
+    (pack, PathBuf::from(temporary_pack))
+}
+
+/// Set the subsystem of the Windows `runner` and copy the resources of the command at
+/// `command_file` into it, inheriting the subsystem of the command if `inherit_console` is set.
+/// Other runners are returned unchanged, and failures are printed as warnings.
+fn prepare_runner(
+    runner: Vec<u8>, runner_name: &str, command_file: &Path, inherit_console: bool,
+    show_console: &mut u8, console_source: &mut u8,
+) -> Vec<u8> {
+    if !runner_name.contains("windows") {
+        return runner;
+    }
+    let decompressed = runner;
+    let decompressed = catch_panic(|| {
+        let mut runner_image = Image::parse(&decompressed)?;
+        runner_image.set_subsystem(if *show_console == 1 { 3 } else { 2 });
+        Ok(runner_image.data().to_owned())
+    })
+    .unwrap_or_else(|error| {
+        println!(
+            "      {}{} {}",
+            emoji("❗ ", ""),
+            style("failed to set subsystem for runner:").yellow(),
+            style(error).yellow()
+        );
+        decompressed
+    });
+    let decompressed = (|| -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut runner_image = Image::parse(&decompressed)?;
+        let command_data = std::fs::read(command_file)?;
+        // malformed or unusual images are described in the warning to make them reportable
+        let describe = |error: &dyn Display| -> Box<dyn Error> {
+            format!("{} in {}", error, describe_image(&command_data)).into()
+        };
+        check_image(&command_data).map_err(|e| describe(&e))?;
+        catch_panic(|| {
+            let command_image = Image::parse(command_data.as_slice())?;
+            let command_resources = command_image
+                .resource_directory()
+                .cloned()
+                .unwrap_or_default();
+            if inherit_console {
+                *show_console = if command_image.subsystem() == 3 { 1 } else { 0 };
+                *console_source = 1;
+                runner_image.set_subsystem(command_image.subsystem());
+            }
+            runner_image.set_resource_directory(command_resources)?;
+            Ok(runner_image.data().to_owned())
+        })
+        .map_err(|e| describe(&e))
+    })()
+    .unwrap_or_else(|error| {
+        println!(
+            "      {}{} {}",
+            emoji("❗ ", ""),
+            style("failed to copy resources to runner:").yellow(),
+            style(error).yellow()
+        );
+        decompressed
+    });
+
+    let subsystem = if *show_console == 1 {
+        "console"
+    } else {
+        "windows"
+    };
+    if *console_source == 1 {
+        println!(
+            "      {}{}",
+            emoji("🖥️ ", ""),
+            style(format!(
+                "inherited the {} subsystem of the command (use --console always or never to override)",
+                subsystem
+            ))
+            .dim()
+        );
+    } else if *console_source == 2 {
+        println!(
+            "      {}{}",
+            emoji("🖥️ ", ""),
+            style(format!(
+                "couldn't inherit the subsystem of the command, using the {} subsystem",
+                subsystem
+            ))
+            .yellow()
+        );
+    }
+    decompressed
+}
+
+/// Make the finished `temporary` output of the `runner_name` runner executable, validate it and
+/// move it to `output`, moving its payload to a pack file next to it with `split`.
+fn finish_output(
+    temporary: &Path, output: &Path, runner_size: u64, runner_name: &str, split: bool,
+    validate_output: bool, emit_metadata: bool,
+) {
+    #[cfg(any(unix, target_os = "redox"))]
+    {
+        use ::std::{
+            fs::{metadata, set_permissions},
+            os::unix::prelude::*,
+        };
+        let mode = metadata(temporary)
+            .map(|metadata| metadata.permissions().mode())
+            .unwrap_or(0o755);
+        set_permissions(temporary, PermissionsExt::from_mode(mode | 0o111)).unwrap_or_else(|e| {
+            eprintln!(
+                "      {} failed to set permissions for {}: {}",
+                emoji("❗ ", ""),
+                output.display(),
+                e
+            )
+        });
+    }
+
+    if validate_output {
+        let _span = info_span!("validate").entered();
+        // outputs of the native runner are also checked by the runner itself without unpacking
+        let native = get_runner_name("native").is_ok_and(|native| native == runner_name);
+        match validate(temporary).and_then(|verified| {
+            if native {
+                verify_run(temporary)?;
+            }
+            Ok(verified)
+        }) {
+            Ok(verified) => println!(
+                "      {}{}",
+                emoji("🔎 ", ""),
+                style(format!(
+                    "validated output ({} files sampled{})",
+                    verified,
+                    if native {
+                        ", verified by the runner"
+                    } else {
+                        ""
+                    }
+                ))
+                .dim(),
+            ),
+            Err(e) => {
+                remove_temporary_output();
+                println!("{}: {}", style("output validation failed").red(), e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    if CANCEL.load(Ordering::Relaxed) {
+        remove_temporary_output();
+        println!("{}", style("cancelled, removed output file").red());
+        std::process::exit(-1);
+    }
+    if split {
+        let (pack, temporary_pack) = &pack_paths(output);
+        split_payload(temporary, temporary_pack, runner_size)
+            .and_then(|_| replace_output(temporary_pack, pack))
+            .unwrap_or_else(|e| {
+                remove_temporary_output();
+                let _ = remove_file(temporary_pack);
+                println!(
+                    "{}: {}: {}",
+                    style("couldn't write pack file").red(),
+                    pack.display(),
+                    e
+                );
+                std::process::exit(-1);
+            });
+        println!(
+            "      {}{}",
+            emoji("📦 ", ""),
+            style(format!("wrote payload to {}", pack.display())).dim(),
+        );
+    }
+    replace_output(temporary, output).unwrap_or_else(|e| {
+        remove_temporary_output();
+        println!(
+            "{}: {}: {}",
+            style("couldn't write output file").red(),
+            output.display(),
+            e
+        );
+        std::process::exit(-1);
+    });
+
+    if emit_metadata {
+        match write_metadata(output, runner_name) {
+            Ok(path) => println!(
+                "      {}{}",
+                emoji("🧾 ", ""),
+                style(format!("wrote metadata to {}", path.display())).dim(),
+            ),
+            Err(e) => {
+                println!("{}: {}", style("couldn't write metadata").red(), e);
+                std::process::exit(-1);
+            }
+        }
+    }
+}
+
+/// Write the output of another runner to `temporary` by appending the payload of the finished
+/// `packed` output to `runner`, with the console mode of the runner in its startup configuration.
+/// Returns the size of the runner.
+fn copy_payload(
+    packed: &Path, temporary: &Path, runner: &[u8], show_console: u8, console_source: u8,
+) -> std::io::Result<u64> {
+    let mut source = File::open(payload_path(packed))?;
+    let files_start = Payload::read(&mut source)?.files_start;
+    source.seek(SeekFrom::Start(files_start))?;
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(temporary)?;
+    let mut writer = BufWriter::new(&mut file);
+    writer.write_all(runner)?;
+    std::io::copy(&mut source, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    let (info_start, _) = find_info(&mut file)?;
+    for (offset, value) in [
+        (offset_of!(StarterInfo, show_console), show_console),
+        (offset_of!(StarterInfo, console_source), console_source),
+    ] {
+        file.seek(SeekFrom::Start(info_start + offset as u64))?;
+        file.write_all(&[value])?;
+    }
+    Ok(runner.len() as u64)
+}
+
+/// Move everything after the first `runner_size` bytes of the finished `output` to `pack`,
+/// leaving only the runner in `output`.
+fn split_payload(output: &Path, pack: &Path, runner_size: u64) -> std::io::Result<()> {
+    let mut file = File::options().read(true).write(true).open(output)?;
+    file.seek(SeekFrom::Start(runner_size))?;
+    let mut writer = BufWriter::new(File::create(pack)?);
+    std::io::copy(&mut file, &mut writer)?;
+    writer.flush()?;
+    file.set_len(runner_size)
+}
+
+/// Move the finished `temporary` output to `output`. A running executable can't be replaced on
+/// Windows, but it can be renamed, so an existing output that is in use is moved aside to a file
+/// with the `.old` extension first. The moved file is removed once it is no longer in use.
+fn replace_output(temporary: &Path, output: &Path) -> std::io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::fs::rename;
+        let mut old = output.as_os_str().to_owned();
+        old.push(".old");
+        let old = PathBuf::from(old);
+        let _ = remove_file(&old);
+        if let Err(e) = rename(temporary, output) {
+            if compress::is_cross_device(&e) {
+                return move_file(temporary, output);
+            }
+            if e.kind() != std::io::ErrorKind::PermissionDenied || !output.exists() {
+                return Err(e);
+            }
+            rename(output, &old)?;
+            rename(temporary, output)?;
+            let _ = remove_file(&old);
+        }
+        Ok(())
+    }
+    #[cfg(not(windows))]
+    move_file(temporary, output)
+}
+
+/// Run the packed `output` with `--wrappe-verify`, which makes the runner check the payload hash
+/// and signature and exit without unpacking.
+fn verify_run(output: &Path) -> std::io::Result<()> {
+    let result = Command::new(std::fs::canonicalize(output).unwrap_or(output.to_path_buf()))
+        .arg("--wrappe-verify")
+        .output()?;
+    if !result.status.success() {
+        return Err(std::io::Error::other(format!(
+            "runner dry run failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Run the packed `output` with `arguments` and an unpack root in the temporary directory, which
+/// is removed afterwards. If `timeout` is given and elapses first, the executable is killed
+/// together with the processes it started and `None` is returned.
+fn test_run(
+    output: &Path, arguments: &[String], timeout: Option<Duration>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let unpack_root = temp_dir().join(format!(
+        "wrappe-test-{}",
+        Alphanumeric
+            .sample_iter(thread_rng())
+            .map(char::from)
+            .take(8)
+            .collect::<String>()
+    ));
+    let status = (|| {
+        let mut child = Command::new(std::fs::canonicalize(output).unwrap_or(output.to_path_buf()))
+            .args(arguments)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .spawn()?;
+        let Some(timeout) = timeout else {
+            return child.wait().map(Some);
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                kill_process_tree(&mut child);
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    })();
+    let _ = remove_dir_all(&unpack_root);
+    status
+}
+
+/// Kill `child` together with the processes it started, which would otherwise keep running
+/// after the runner is killed.
+fn kill_process_tree(child: &mut Child) {
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::All, true);
+    let mut tree = vec![Pid::from_u32(child.id())];
+    let mut index = 0;
+    while index < tree.len() {
+        let parent = tree[index];
+        tree.extend(
+            system
+                .processes()
+                .iter()
+                .filter(|(_, process)| process.parent() == Some(parent))
+                .map(|(pid, _)| *pid),
+        );
+        index += 1;
+    }
+    let _ = child.kill();
+    for pid in &tree[1..] {
+        if let Some(process) = system.process(*pid) {
+            process.kill();
+        }
+    }
+    let _ = child.wait();
+}
+
+/// Run the pre-pack or post-pack hook `command` in the shell with `env` and exit if it fails.
+fn run_hook(stage: &str, command: &str, env: &[(&str, OsString)]) {
+    println!(
+        "      {}{} {}",
+        emoji("🪝 ", ""),
+        style(format!("running {} hook", stage)).dim(),
+        style(command).blue().bright()
+    );
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    };
+    match shell
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            println!(
+                "{}: {}",
+                style(format!("{} hook failed", stage)).red(),
+                status
+            );
+            std::process::exit(-1);
+        }
+        Err(e) => {
+            println!(
+                "{}: {}",
+                style(format!("couldn't run {} hook", stage)).red(),
+                e
+            );
+            std::process::exit(-1);
+        }
+    }
 }
 
 fn main() {
     color_backtrace::install();
+    let hook = take_hook();
+    set_hook(Box::new(move |panic| {
+        remove_temporary_output();
+        hook(panic);
+    }));
+
+    let cli = Cli::parse();
+    set_plain(match &cli.subcommand {
+        Some(subcommand) => subcommand.plain(),
+        None => cli.pack.as_ref().is_some_and(|args| args.plain),
+    });
 
-    if std::env::args().any(|arg| arg == "-l" || arg == "--list-runners") {
+    if cli.list_runners {
         list_runners();
         std::process::exit(0);
     }
 
-    println!(
-        "{}",
-        style(format!(
-            "{} {}",
-            env!("CARGO_PKG_NAME"),
-            env!("CARGO_PKG_VERSION"),
-        ))
-        .bold()
-        .bright(),
-    );
+    // keep the output of `inspect --json` parseable
+    if !matches!(&cli.subcommand, Some(Subcommands::Inspect(args)) if args.json) {
+        println!(
+            "{}",
+            style(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+            ))
+            .bold()
+            .bright(),
+        );
+    }
 
-    if std::env::args().any(|arg| arg == "-V" || arg == "--version") {
+    if let Some(subcommand) = cli.subcommand {
+        match subcommand {
+            Subcommands::Update(args) => update(args),
+            Subcommands::List(args) => list(args),
+            Subcommands::Extract(args) => extract(args),
+            Subcommands::Verify(args) => verify(args),
+            Subcommands::Inspect(args) => inspect(args),
+            Subcommands::Explain(args) => explain(args),
+            Subcommands::Bench(args) => bench(args),
+            Subcommands::Dev(args) => dev(args),
+            Subcommands::Manifest(args) => manifest(args).or_exit(),
+            Subcommands::Keygen(args) => keygen(args).or_exit(),
+            Subcommands::Cargo(args) => cargo(args),
+        }
         std::process::exit(0);
     }
+    let Some(args) = cli.pack else {
+        unreachable!("the input arguments are required without a subcommand");
+    };
+
+    // the trace is written when the guard is dropped at the end of main
+    let _trace = args.trace_file.as_deref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            println!(
+                "{}: {}: {}",
+                style("couldn't create trace file").red(),
+                path.display(),
+                e
+            );
+            std::process::exit(-1);
+        });
+        let (layer, guard) = ChromeLayerBuilder::new()
+            .writer(file)
+            .include_args(true)
+            .build();
+        tracing_subscriber::registry().with(layer).init();
+        guard
+    });
 
-    let args = Args::parse();
-
-    let runner = get_runner(&args.runner);
-    let runner_name = get_runner_name(&args.runner);
-    let unpack_target = get_unpack_target(&args.unpack_target);
-    let versioning = get_versioning(&args.versioning);
-    let version = get_version(args.version_string.as_deref());
-    let source = get_source(&args.input);
-    let command_path = get_command_path(&args.command, &source);
-    let command = get_command(&command_path);
-    let output = get_output(args.output.as_deref(), &command_path);
-    let unpack_directory = get_unpack_directory(args.unpack_directory.as_deref(), &source);
-    let verification = get_verification(&args.verification);
-    let show_information = get_show_information(&args.show_information);
-    let arguments = get_arguments(&args.arguments);
-    let current_dir = get_current_dir(&args.current_dir);
-
-    let mut show_console = get_show_console(&args.console, runner_name);
-    let once = if args.once { 1 } else { 0 };
+    let mut runners = match (&args.runner_path, &args.fetch_runner) {
+        (Some(path), _) => vec![get_runner_path(path).or_exit()],
+        (_, Some(target)) => {
+            let path = fetch_runner(
+                target,
+                args.runner_url.as_deref().unwrap_or(RUNNER_URL),
+                args.runner_sha256.as_deref(),
+            )
+            .or_exit();
+            vec![(get_runner_path(&path).or_exit().0, target.clone())]
+        }
+        _ => get_runners(&args.runner).or_exit(),
+    };
+    // the payload is compressed once into the output of the first runner and copied from there
+    // into the outputs of the other runners
+    let multiple = runners.len() > 1;
+    let (runner, runner_name) = runners.remove(0);
+    let runner_name = runner_name.as_str();
+    let algorithm = get_algorithm(&args.algorithm).or_exit();
+    let unpack_target = get_unpack_target(&args.unpack_target).or_exit();
+    let versioning = get_versioning(&args.versioning).or_exit();
+    let unpack_subdir = get_unpack_subdir(&args.unpack_subdir).or_exit();
+    let reproducible = get_reproducible(args.reproducible).or_exit();
+    let dictionary = get_dictionary_file(args.dictionary_file.as_deref()).or_exit();
+    let signing_key = get_signing_key(args.sign_key.as_deref()).or_exit();
+    let cache = args
+        .cache
+        .as_ref()
+        .map(|cache| get_cache(cache.as_deref()).or_exit());
+    // the version is derived from the payload contents with auto-hash and in reproducible mode
+    let version = if args.version_string.as_deref() == Some("auto-hash")
+        || (args.version_string.is_none() && reproducible.is_some())
+    {
+        None
+    } else {
+        Some(get_version(args.version_string.as_deref()).or_exit())
+    };
+    let sources = get_sources(&args.input, &args.inputs).or_exit();
+    let source = sources[0].clone();
+    // with a file list, the input directory is only used to resolve relative paths
+    let files_from = args
+        .files_from
+        .as_deref()
+        .map(|files_from| get_files_from(files_from, &source).or_exit());
+    let command_path = match &files_from {
+        Some(files) => get_listed_command_path(&args.command, files).or_exit(),
+        None => get_command_path(&args.command, &sources).or_exit(),
+    };
+    let command = get_command(&command_path).or_exit();
+    let target_output = |runner_name: &str| match &args.output_template {
+        Some(template) => {
+            // the version is only known in advance when it is set explicitly
+            let version = args
+                .version_string
+                .as_deref()
+                .filter(|version| *version != "auto-hash");
+            let output =
+                get_output_template(template, &command_path, version, runner_name).or_exit();
+            get_output(Some(&output), &command_path).or_exit()
+        }
+        None if multiple => get_target_output(
+            &get_output(args.output.as_deref(), &command_path).or_exit(),
+            runner_name,
+        ),
+        None => get_output(args.output.as_deref(), &command_path).or_exit(),
+    };
+    let output = target_output(runner_name);
+    let runners = runners
+        .into_iter()
+        .map(|(runner, runner_name)| {
+            let output = target_output(&runner_name);
+            (runner, runner_name, output)
+        })
+        .collect::<Vec<_>>();
+    if runners.iter().any(|(_, _, other)| {
+        other == &output || runners.iter().filter(|(_, _, path)| path == other).count() > 1
+    }) {
+        println!(
+            "{}: use the {{target}} field in the output template",
+            style("outputs of several runners have the same path").red(),
+        );
+        std::process::exit(-1);
+    }
+    let unpack_directory =
+        get_unpack_directory(args.unpack_directory.as_deref(), &source).or_exit();
+    let app_id = get_app_id(args.app_id.as_deref(), &unpack_directory).or_exit();
+    let verification = get_verification(&args.verification).or_exit();
+    let show_information = get_show_information(&args.show_information).or_exit();
+    let arguments = get_arguments(&args.arguments).or_exit();
+    let mut extra_sections = get_extra_sections(&args.extra_section).or_exit();
+    let current_dir = get_current_dir(&args.current_dir).or_exit();
+    let process_group = get_process_group(&args.process_group).or_exit();
+    let passed_fds = get_passed_fds(&args.pass_fd).or_exit();
+
+    let mut show_console = get_show_console(&args.console, runner_name).or_exit();
+    // the console mode is inherited from the subsystem of Windows commands with auto and inherit,
+    // and falls back to the default of the runner if the command isn't a Windows executable
+    let inherit_console = matches!(args.console.to_lowercase().as_str(), "auto" | "inherit");
+    let mut console_source = if inherit_console && runner_name.contains("windows") {
+        2
+    } else {
+        0
+    };
+    let once = get_once(args.once.as_deref()).or_exit();
     let cleanup = if args.cleanup { 1 } else { 0 };
+    let desktop_entry =
+        if args.desktop_entry || args.desktop_file.is_some() || args.desktop_icon.is_some() {
+            1
+        } else {
+            0
+        };
+    let mut extra = files_from.clone().unwrap_or_default();
+    for (name, path) in
+        get_desktop_files(args.desktop_file.as_deref(), args.desktop_icon.as_deref()).or_exit()
+    {
+        extra.retain(|(extra_name, _)| extra_name != &name);
+        extra.push((name, path));
+    }
+    let walked_sources = if files_from.is_some() {
+        &sources[..0]
+    } else {
+        &sources[..]
+    };
+    let store = get_store_globs(&args.store).or_exit();
+    let content_filters = get_content_filters(&args.filter).or_exit();
+    let mut shebang_mode = get_shebang_mode(&args.shebangs).or_exit();
+    if args.venv {
+        check_venv(&sources).or_exit();
+        // entry points of virtual environments point at the interpreter in the environment
+        if shebang_mode == ShebangMode::Keep {
+            shebang_mode = ShebangMode::Unpack;
+        }
+    }
+    let shebangs = Shebangs::new(shebang_mode, args.venv, &sources);
 
     if (versioning == 1 || versioning == 2) && once == 0 {
         println!(
@@ -150,18 +1014,102 @@ fn main() {
             style("versioning").yellow().dim(),
         );
     }
-    if once == 1 && !(runner_name.contains("windows") || runner_name.contains("linux")) {
+    if algorithm == 1 && args.compression > 9 {
         println!(
-            "{} {} {} {}",
+            "{} {} {}",
+            style("note: compression level").yellow().dim(),
+            style(args.compression).yellow().bold(),
+            style("will be limited to 9 with xz compression")
+                .yellow()
+                .dim(),
+        );
+    }
+    if algorithm == 2 && args.compression > 11 {
+        println!(
+            "{} {} {}",
+            style("note: compression level").yellow().dim(),
+            style(args.compression).yellow().bold(),
+            style("will be limited to 11 with brotli compression")
+                .yellow()
+                .dim(),
+        );
+    }
+    if algorithm != 0 && (args.build_dictionary || args.dictionary_file.is_some()) {
+        println!(
+            "{} {} {}",
             style("note: option").yellow().dim(),
-            style("once").yellow().bold(),
+            style(if args.build_dictionary {
+                "build-dictionary"
+            } else {
+                "dictionary-file"
+            })
+            .yellow()
+            .bold(),
+            style("is only supported with zstd compression")
+                .yellow()
+                .dim(),
+        );
+    }
+    let runner_names = std::iter::once(runner_name)
+        .chain(
+            runners
+                .iter()
+                .map(|(_, runner_name, _)| runner_name.as_str()),
+        )
+        .collect::<Vec<_>>();
+    for runner_name in runner_names.iter().filter(|runner_name| {
+        once == 1 && !(runner_name.contains("windows") || runner_name.contains("linux"))
+    }) {
+        println!(
+            "{} {} {} {}",
+            style("note: once strategy").yellow().dim(),
+            style("scan").yellow().bold(),
             style("is only supported for Windows and Linux runners")
                 .yellow()
                 .dim(),
             style(format!("(target: {})", runner_name)).yellow().dim(),
         );
     }
-    if show_console != 2 && !runner_name.contains("windows") {
+    if args.once_timeout > 0 && once != 3 {
+        println!(
+            "{} {} {} {}",
+            style("note: option").yellow().dim(),
+            style("once-timeout").yellow().bold(),
+            style("only applies to once strategy").yellow().dim(),
+            style("wait").yellow().bold(),
+        );
+    }
+    for runner_name in runner_names
+        .iter()
+        .filter(|runner_name| desktop_entry == 1 && !runner_name.contains("linux"))
+    {
+        println!(
+            "{} {} {} {}",
+            style("note: option").yellow().dim(),
+            style("desktop-entry").yellow().bold(),
+            style("is only supported for Linux runners").yellow().dim(),
+            style(format!("(target: {})", runner_name)).yellow().dim(),
+        );
+    }
+    let native_runner = get_runner_name("native").or_exit();
+    if args.test_run.is_some() && !runner_names.contains(&native_runner.as_str()) {
+        println!(
+            "{} {} {} {}",
+            style("note: option").yellow().dim(),
+            style("test-run").yellow().bold(),
+            style("is only supported for the native runner, skipping")
+                .yellow()
+                .dim(),
+            style(format!("(target: {})", runner_names.join(", ")))
+                .yellow()
+                .dim(),
+        );
+    }
+    if show_console != 2
+        && !runner_names
+            .iter()
+            .any(|runner_name| runner_name.contains("windows"))
+    {
         println!(
             "{}",
             style("note: setting console mode is only supported for Windows runners")
@@ -170,7 +1118,36 @@ fn main() {
         );
     }
 
-    if output == source {
+    // hooks receive the input, output, command, target and version through the environment,
+    // the version is only known before packing when it is set explicitly
+    let hook_env = |output: &Path, runner_name: &str, version: Option<&str>| {
+        let mut env = vec![
+            ("WRAPPE_INPUT", source.as_os_str().to_owned()),
+            ("WRAPPE_OUTPUT", output.as_os_str().to_owned()),
+            ("WRAPPE_COMMAND", command_path.as_os_str().to_owned()),
+            ("WRAPPE_TARGET", OsString::from(runner_name)),
+        ];
+        if let Some(version) = version {
+            env.push((
+                "WRAPPE_VERSION",
+                OsString::from(version.trim_end_matches('\0')),
+            ));
+        }
+        env
+    };
+    for hook in args.pre_pack.iter() {
+        run_hook(
+            "pre-pack",
+            hook,
+            &hook_env(&output, runner_name, version.as_deref()),
+        );
+    }
+
+    if sources.contains(&output)
+        || runners
+            .iter()
+            .any(|(_, _, output)| sources.contains(output))
+    {
         println!(
             "{}: {}",
             style("output file can't be the input file").red(),
@@ -178,14 +1155,43 @@ fn main() {
         );
         std::process::exit(-1);
     }
-    let file = File::create(&output).unwrap_or_else(|_| {
+    let mut temporary_output = OsString::from(output.as_os_str());
+    temporary_output.push(".tmp");
+    let temporary_output = PathBuf::from(temporary_output);
+    let mut excluded_outputs = Vec::new();
+    for output in std::iter::once(&output).chain(runners.iter().map(|(_, _, output)| output)) {
+        let mut temporary_output = OsString::from(output.as_os_str());
+        temporary_output.push(".tmp");
+        excluded_outputs.extend([output.clone(), PathBuf::from(temporary_output)]);
+        if args.split {
+            let (pack, temporary_pack) = pack_paths(output);
+            excluded_outputs.extend([pack, temporary_pack]);
+        }
+    }
+    let file = File::create(&temporary_output).unwrap_or_else(|_| {
         println!(
             "{}: {}",
             style("couldn't create output file").red(),
-            output.display()
+            temporary_output.display()
         );
         std::process::exit(-1);
     });
+    set_temporary_output(&temporary_output);
+
+    ctrlc::set_handler(|| {
+        if CANCEL.swap(true, Ordering::Relaxed) {
+            remove_temporary_output();
+            std::process::exit(-1);
+        }
+    })
+    .unwrap_or_else(|e| {
+        println!(
+            "      {}{} {}",
+            emoji("❗ ", ""),
+            style("failed to set interrupt handler:").yellow(),
+            style(e).yellow()
+        );
+    });
 
     let canonical_current_dir = std::fs::canonicalize(std::env::current_dir().unwrap()).unwrap();
     let relative_source = source
@@ -196,28 +1202,39 @@ fn main() {
     } else {
         relative_source
     };
+    let count_span = info_span!("count").entered();
     let count = if source.is_dir() {
         println!(
             "{} {}counting contents of {}…",
             style("[1/4]").bold().dim(),
-            Emoji("🔍 ", ""),
+            emoji("🔍 ", ""),
             style(relative_source.display()).blue().bright()
         );
-        WalkDir::new(&source).skip_hidden(false).into_iter().count() as u64 - 1
+        walked_sources
+            .iter()
+            .map(|source| {
+                walk_source(source, args.dereference, args.max_depth, args.keep_junk)
+                    .into_iter()
+                    .count() as u64
+                    - 1
+            })
+            .sum::<u64>()
+            + extra.len() as u64
     } else {
         println!(
             "{} {}checking {}…",
             style("[1/4]").bold().dim(),
-            Emoji("🔍 ", ""),
+            emoji("🔍 ", ""),
             style(relative_source.display()).blue().bright()
         );
-        1
+        1 + extra.len() as u64
     };
+    drop(count_span);
 
     println!(
         "{} {}writing runner {} for target {}…",
         style("[2/4]").bold().dim(),
-        Emoji("📃 ", ""),
+        emoji("📃 ", ""),
         style(
             &output
                 .strip_prefix(&canonical_current_dir)
@@ -228,109 +1245,168 @@ fn main() {
         .bright(),
         style(&runner_name).magenta(),
     );
-    let mut writer = BufWriter::new(file);
-    if runner_name.contains("windows") {
-        let mut decompressed = Vec::new();
-        copy_decode(Cursor::new(runner), &mut decompressed).unwrap();
-
-        let decompressed = (|| -> Result<Vec<u8>, Box<dyn Error>> {
-            let mut runner_image = Image::parse(&decompressed)?;
-            runner_image.set_subsystem(if show_console == 1 { 3 } else { 2 });
-            Ok(runner_image.data().to_owned())
-        })()
-        .unwrap_or_else(|error| {
-            println!(
-                "      {}{} {}",
-                Emoji("❗ ", ""),
-                style("failed to set subsystem for runner:").yellow(),
-                style(error).yellow()
-            );
-            decompressed
-        });
-        let decompressed = (|| -> Result<Vec<u8>, Box<dyn Error>> {
-            let mut runner_image = Image::parse(&decompressed)?;
-            let command_path = if source.is_file() {
-                source.clone()
-            } else {
-                source.join(get_command_path(&args.command, &source))
-            };
-            let command_data = std::fs::read(command_path)?;
-            let command_image = Image::parse(command_data)?;
-            let command_resources = command_image
-                .resource_directory()
-                .cloned()
-                .unwrap_or_default();
-            if args.console == "auto" {
-                show_console = if command_image.subsystem() == 3 { 1 } else { 0 };
-                runner_image.set_subsystem(command_image.subsystem());
-            }
-            runner_image.set_resource_directory(command_resources)?;
-            Ok(runner_image.data().to_owned())
-        })()
-        .unwrap_or_else(|error| {
-            println!(
-                "      {}{} {}",
-                Emoji("❗ ", ""),
-                style("failed to copy resources to runner:").yellow(),
-                style(error).yellow()
-            );
-            decompressed
-        });
-
-        writer.write_all(&decompressed).unwrap();
+    // the resources of Windows commands are copied into Windows runners
+    let command_file = if let Some(files) = &files_from {
+        files
+            .iter()
+            .find(|(name, _)| name == &command_path)
+            .map(|(_, path)| path.clone())
+            .unwrap()
+    } else if source.is_file() {
+        source.clone()
     } else {
-        copy_decode(Cursor::new(&runner), &mut writer).unwrap();
-    }
+        // the command is taken from the last input containing it
+        sources
+            .iter()
+            .rev()
+            .map(|source| source.join(&command_path))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| source.join(&command_path))
+    };
+    let runner_span = info_span!("write_runner", runner = %runner_name).entered();
+    let mut writer = BufWriter::new(file);
+    let runner = prepare_runner(
+        runner,
+        runner_name,
+        &command_file,
+        inherit_console,
+        &mut show_console,
+        &mut console_source,
+    );
+    writer.write_all(&runner).unwrap();
+    let runner_size = writer.stream_position().unwrap();
+    drop(runner_span);
 
     println!(
         "{} {}compressing {} files and directories…",
         style("[3/4]").bold().dim(),
-        Emoji("🚚 ", ""),
+        emoji("🚚 ", ""),
         style(count).magenta(),
     );
-    let bar_progress =
+    let bar_progress = if is_plain() {
+        ProgressBar::hidden()
+    } else {
         ProgressBar::new(0).with_style(
             ProgressStyle::default_bar()
                 .template("{spinner:.green} {elapsed_precise} [{wide_bar:.green}] {pos}/{len}\n{spinner:.green} {wide_msg}").unwrap(),
-        );
+        )
+    };
     bar_progress.set_length(count);
     bar_progress.set_position(0);
-    bar_progress.enable_steady_tick(Duration::from_millis(12));
+    if !is_plain() {
+        bar_progress.enable_steady_tick(Duration::from_millis(12));
+    }
+    // hidden progress bars don't print lines, so plain output is printed directly
+    let print_line = |line: String| {
+        if is_plain() {
+            println!("{}", line);
+        } else {
+            bar_progress.println(line);
+        }
+    };
+    let report = args.report.as_ref().map(|_| PackReport::new(&sources));
     let now = SystemTime::now();
-    let (compressed, read, written) = compress(
-        &source,
+    let compress_span = info_span!("compress").entered();
+    let result = compress(
+        walked_sources,
         &mut writer,
-        &output,
-        args.compression,
-        args.build_dictionary,
-        || {
-            bar_progress.inc(1);
-        },
-        |message| {
-            bar_progress.inc(1);
-            bar_progress.println(format!(
-                "      {}{}",
-                Emoji("❗ ", ""),
-                style(message).red()
-            ));
-        },
-        |message| {
-            bar_progress.set_message(format!("{}", style(message).blue().bright()));
+        &CompressOptions {
+            exclude: &excluded_outputs,
+            extra: &extra,
+            store: &store,
+            auto_store: !args.no_auto_store,
+            content_filters: &content_filters,
+            shebangs: &shebangs,
+            algorithm,
+            compression: args.compression,
+            build_dict: args.build_dictionary && algorithm == 0,
+            dictionary_file: dictionary.as_deref().filter(|_| algorithm == 0),
+            solid_block_size: args.solid_block_size * 1024,
+            memory_limit: args.memory_limit.map(|limit| limit * 1024 * 1024),
+            cache: cache.as_deref(),
+            reproducible,
+            ownership: args.preserve_ownership,
+            allow_setuid: args.allow_setuid,
+            dereference: args.dereference,
+            max_depth: args.max_depth,
+            keep_junk: args.keep_junk,
+            // the interpreter of virtual environments links to the base installation
+            keep_external: args.keep_external_symlinks || args.venv,
         },
-        |message| {
-            bar_progress.println(format!(
+        &CANCEL,
+        |event| match event {
+            CompressEvent::Progress => bar_progress.inc(1),
+            CompressEvent::Error(message) => {
+                bar_progress.inc(1);
+                print_line(format!(
+                    "      {}{}",
+                    emoji("❗ ", ""),
+                    style(message).red()
+                ));
+            }
+            CompressEvent::Skipped {
+                path,
+                reason,
+                expected,
+            } => {
+                bar_progress.inc(1);
+                if let Some(report) = &report {
+                    report.skipped(path, reason);
+                }
+                if !expected {
+                    print_line(format!(
+                        "      {}{}",
+                        emoji("❗ ", ""),
+                        style(format!("skipping {}: {}", reason, path.display())).red()
+                    ));
+                }
+            }
+            CompressEvent::Packed {
+                path,
+                size,
+                compressed,
+                hash,
+                stored,
+            } => {
+                if let Some(report) = &report {
+                    report.packed(path, size, compressed, hash, stored);
+                }
+            }
+            CompressEvent::Step(message) => {
+                bar_progress.set_message(format!("{}", style(message).blue().bright()));
+            }
+            CompressEvent::Info(message) => print_line(format!(
                 "      {}{}",
-                Emoji("💡 ", ""),
+                emoji("💡 ", ""),
                 style(message).dim()
-            ));
+            )),
+            CompressEvent::Warning(message) => print_line(format!(
+                "      {}{}",
+                emoji("❗ ", ""),
+                style(message).yellow()
+            )),
         },
     );
+    drop(compress_span);
     bar_progress.finish_and_clear();
+    let (compressed, read, written, payload_hash) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            drop(writer);
+            remove_temporary_output();
+            if let CompressError::Cancelled = error {
+                println!("{}", style("cancelled, removed output file").red());
+            } else {
+                println!("{}: {}", style("couldn't write payload").red(), error);
+            }
+            std::process::exit(-1);
+        }
+    };
     writer.flush().unwrap();
 
     println!(
         "      {}{}",
-        Emoji("💾 ", ""),
+        emoji("💾 ", ""),
         style(format!(
             "{:.2}MB read, {:.2}MB written, {:.2}% of original size",
             read as f64 / 1024.0 / 1024.0,
@@ -341,7 +1417,7 @@ fn main() {
     );
     println!(
         "      {}{}",
-        Emoji("📍 ", ""),
+        emoji("📍 ", ""),
         style(format!(
             "took {:.2}s",
             now.elapsed().unwrap_or_default().as_secs_f64()
@@ -350,7 +1426,7 @@ fn main() {
     );
     println!(
         "      {}{} {} {}{}",
-        Emoji("✨ ", ""),
+        emoji("✨ ", ""),
         style("successfully compressed").green(),
         style(compressed).magenta(),
         style("files and directories").green(),
@@ -366,48 +1442,259 @@ fn main() {
     println!(
         "{} {}writing startup configuration…",
         style("[4/4]").bold().dim(),
-        Emoji("📃 ", "")
+        emoji("📃 ", "")
     );
 
+    let version =
+        version.unwrap_or_else(|| get_version(Some(&format!("{:016x}", payload_hash))).or_exit());
+    if args.statistics {
+        let statistics = PackStatistics {
+            entries: compressed,
+            input_size: read,
+            payload_size: written,
+            algorithm,
+            level: args.compression,
+            solid_block_size: args.solid_block_size * 1024,
+            dictionary: match (&dictionary, args.build_dictionary) {
+                _ if algorithm != 0 => "none",
+                (Some(_), _) => "file",
+                (None, true) => "built",
+                (None, false) => "none",
+            },
+            store_patterns: args.store.len(),
+            auto_store: !args.no_auto_store,
+        };
+        write_extra_section(
+            &mut extra_sections,
+            STATISTICS_SECTION,
+            format!("{:#}\n", statistics.to_json()).as_bytes(),
+        );
+    }
     let info = StarterInfo {
         signature: WRAPPE_SIGNATURE,
         show_console,
+        console_source,
         current_dir,
         verification,
         show_information,
         cleanup,
         uid: version.as_bytes().try_into().unwrap(),
+        app_id,
         unpack_target,
         versioning,
         unpack_directory,
         once,
         command,
-        arguments,
         wrappe_format: WRAPPE_FORMAT,
+        desktop_entry,
+        once_timeout: args.once_timeout.into(),
+        once_exit_code: args.once_exit_code,
+        once_quiet: if args.once_quiet { 1 } else { 0 },
+        prefetch: if args.no_prefetch { 0 } else { 1 },
+        prefetch_threshold: args.prefetch_threshold.into(),
+        low_priority: if args.low_priority { 1 } else { 0 },
+        supervise: if args.supervise { 1 } else { 0 },
+        ownership: if args.preserve_ownership { 1 } else { 0 },
+        process_group,
+        close_fds: if args.close_fds || passed_fds != 0 {
+            1
+        } else {
+            0
+        },
+        in_memory: if args.in_memory { 1 } else { 0 },
+        unpack_subdir,
+        backoff: if args.backoff { 1 } else { 0 },
+        passed_fds: passed_fds.into(),
+        public_key: signing_key
+            .as_ref()
+            .map(|key| key.verifying_key().to_bytes())
+            .unwrap_or_default(),
+        payload_signature: [0; 64],
+        arguments_size: (arguments.len() as u32).into(),
+        extra_sections_size: (extra_sections.len() as u64).into(),
+        manifest_size: 0.into(),
     };
+    writer.write_all(&extra_sections).unwrap();
+    writer.write_all(&arguments).unwrap();
     writer.write_all(info.as_bytes()).unwrap();
 
     writer.flush().unwrap();
     drop(writer);
 
-    #[cfg(any(unix, target_os = "redox"))]
-    {
-        use ::std::{
-            fs::{metadata, set_permissions},
-            os::unix::prelude::*,
+    if !args.no_manifest {
+        let _span = info_span!("embed_manifest").entered();
+        let files = embed_manifest(&temporary_output).unwrap_or_else(|e| {
+            remove_temporary_output();
+            println!("{}: {}", style("couldn't embed file manifest").red(), e);
+            std::process::exit(-1);
+        });
+        println!(
+            "      {}{}",
+            emoji("📜 ", ""),
+            style(format!("embedded manifest of {} files", files)).dim(),
+        );
+    }
+
+    if let Some(key) = &signing_key {
+        sign_payload(&temporary_output, key).unwrap_or_else(|e| {
+            remove_temporary_output();
+            println!("{}: {}", style("couldn't sign payload").red(), e);
+            std::process::exit(-1);
+        });
+        println!(
+            "      {}{}",
+            emoji("🔏 ", ""),
+            style(format!(
+                "signed payload (public key {})",
+                hex(key.verifying_key().as_bytes())
+            ))
+            .dim(),
+        );
+    }
+
+    finish_output(
+        &temporary_output,
+        &output,
+        runner_size,
+        runner_name,
+        args.split,
+        !args.no_validate,
+        args.emit_metadata,
+    );
+
+    // the outputs of the other runners reuse the compressed payload of the first output
+    for (runner, runner_name, target) in runners.iter() {
+        let mut temporary_output = OsString::from(target.as_os_str());
+        temporary_output.push(".tmp");
+        let temporary_output = PathBuf::from(temporary_output);
+        set_temporary_output(&temporary_output);
+        let mut show_console = get_show_console(&args.console, runner_name).or_exit();
+        let mut console_source = if inherit_console && runner_name.contains("windows") {
+            2
+        } else {
+            0
         };
-        let mode = metadata(&output)
-            .map(|metadata| metadata.permissions().mode())
-            .unwrap_or(0o755);
-        set_permissions(&output, PermissionsExt::from_mode(mode | 0o111)).unwrap_or_else(|e| {
-            eprintln!(
-                "      {} failed to set permissions for {}: {}",
-                Emoji("❗ ", ""),
-                output.display(),
+        let runner = prepare_runner(
+            runner.clone(),
+            runner_name,
+            &command_file,
+            inherit_console,
+            &mut show_console,
+            &mut console_source,
+        );
+        let runner_size = copy_payload(
+            &output,
+            &temporary_output,
+            &runner,
+            show_console,
+            console_source,
+        )
+        .unwrap_or_else(|e| {
+            remove_temporary_output();
+            println!(
+                "{}: {}: {}",
+                style("couldn't write output file").red(),
+                temporary_output.display(),
                 e
-            )
+            );
+            std::process::exit(-1);
         });
+        if let Some(key) = &signing_key {
+            sign_payload(&temporary_output, key).unwrap_or_else(|e| {
+                remove_temporary_output();
+                println!("{}: {}", style("couldn't sign payload").red(), e);
+                std::process::exit(-1);
+            });
+        }
+        finish_output(
+            &temporary_output,
+            target,
+            runner_size,
+            runner_name,
+            args.split,
+            !args.no_validate,
+            args.emit_metadata,
+        );
+        println!(
+            "      {}{}",
+            emoji("🎯 ", ""),
+            style(format!(
+                "wrote output for {} to {}",
+                runner_name,
+                target
+                    .strip_prefix(&canonical_current_dir)
+                    .unwrap_or(target)
+                    .display()
+            ))
+            .dim(),
+        );
+    }
+
+    if let (Some(path), Some(report)) = (&args.report, &report) {
+        match report.write(path, read, written) {
+            Ok(()) => println!(
+                "      {}{}",
+                emoji("🧾 ", ""),
+                style(format!("wrote report to {}", path.display())).dim(),
+            ),
+            Err(e) => {
+                println!("{}: {}", style("couldn't write report").red(), e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    println!("      {}{}", emoji("✨ ", ""), style("done!").green());
+
+    // the output of the native runner is test-run if it was packed
+    let native_output = std::iter::once((runner_name, &output))
+        .chain(
+            runners
+                .iter()
+                .map(|(_, runner_name, output)| (runner_name.as_str(), output)),
+        )
+        .find(|(runner_name, _)| *runner_name == native_runner)
+        .map(|(_, output)| output);
+    if let (Some(test_arguments), Some(output)) = (&args.test_run, native_output) {
+        println!(
+            "      {}{}",
+            emoji("🚀 ", ""),
+            style("test-running output executable…").dim()
+        );
+        match test_run(output, test_arguments, None) {
+            Ok(Some(status)) if status.success() => {
+                println!(
+                    "      {}{}",
+                    emoji("✨ ", ""),
+                    style("test run succeeded").green()
+                );
+            }
+            Ok(status) => {
+                println!(
+                    "{}: {}",
+                    style("test run failed").red(),
+                    status.map(|status| status.to_string()).unwrap_or_default()
+                );
+                std::process::exit(-1);
+            }
+            Err(e) => {
+                println!("{}: {}", style("couldn't start test run").red(), e);
+                std::process::exit(-1);
+            }
+        }
     }
 
-    println!("      {}{}", Emoji("✨ ", ""), style("done!").green());
+    for (output, runner_name) in std::iter::once((&output, runner_name)).chain(
+        runners
+            .iter()
+            .map(|(_, runner_name, output)| (output, runner_name.as_str())),
+    ) {
+        for hook in args.post_pack.iter() {
+            run_hook(
+                "post-pack",
+                hook,
+                &hook_env(output, runner_name, Some(&version)),
+            );
+        }
+    }
 }
@@ -0,0 +1,147 @@
+use std::{
+    ffi::OsString,
+    fs::{File, read_to_string, write},
+    io::{Read, Seek, SeekFrom, copy},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use console::style;
+use ed25519_dalek::Signer;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    args::{ArgsError, emoji},
+    payload::{Payload, executable_target},
+    signing::{hex, read_signing_key},
+    types::decode_version,
+};
+
+#[derive(Args)]
+#[command(about = "Write an update manifest describing packed executables")]
+pub struct ManifestArgs {
+    /// Path to write the manifest to
+    #[arg(short = 'o', long, value_name = "PATH", default_value = "updates.json")]
+    output:    PathBuf,
+    /// URL the executables are published at, their file names are appended to it
+    #[arg(long, value_name = "URL")]
+    base_url:  Option<String>,
+    /// Sign the manifest with the private key and write the signature to <output>.sig
+    #[arg(long, value_name = "PATH")]
+    sign_key:  Option<PathBuf>,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain: bool,
+    /// Paths to the packed executables
+    #[arg(name = "artifacts", required = true)]
+    artifacts: Vec<PathBuf>,
+}
+
+/// Target of the packed executable at `path`, read from the metadata written next to it with
+/// `--emit-metadata`, or detected from its executable header as architecture and system.
+fn target(path: &Path, file: &mut File) -> std::io::Result<String> {
+    let mut metadata = OsString::from(path.as_os_str());
+    metadata.push(".wrappe.json");
+    if let Ok(metadata) = read_to_string(PathBuf::from(metadata)) {
+        if let Some(target) = serde_json::from_str::<Value>(&metadata)
+            .ok()
+            .and_then(|metadata| metadata.get("target")?.as_str().map(str::to_string))
+        {
+            return Ok(target);
+        }
+    }
+
+    let mut header = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(4096).read_to_end(&mut header)?;
+    Ok(executable_target(&header))
+}
+
+/// Entry of the packed executable at `path` in the manifest.
+fn artifact(path: &Path, base_url: Option<&str>) -> std::io::Result<Value> {
+    let mut file = File::open(path)?;
+    let payload = Payload::read(&mut file)?;
+    let version = decode_version(&payload.info.uid)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let target = target(path, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha256::new();
+    let size = copy(&mut file, &mut hasher)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut entry = json!({
+        "name": name,
+        "version": version,
+        "target": target,
+        "size": size,
+        "sha256": hex(&hasher.finalize()),
+    });
+    if let Some(base_url) = base_url {
+        entry["url"] = json!(format!("{}/{}", base_url.trim_end_matches('/'), name));
+    }
+    Ok(entry)
+}
+
+/// Write a manifest listing the version, target, URL, size and SHA-256 hash of packed
+/// executables for update servers and updaters, and sign it if a key is given.
+pub fn manifest(args: ManifestArgs) -> Result<(), ArgsError> {
+    let artifacts = args
+        .artifacts
+        .iter()
+        .map(|path| {
+            artifact(path, args.base_url.as_deref()).map_err(|e| {
+                ArgsError::invalid(
+                    "couldn't read packed executable",
+                    format!("{}: {}", path.display(), e),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let manifest = json!({
+        "format": 1,
+        "artifacts": artifacts,
+    });
+    let contents = format!("{:#}\n", manifest);
+    write(&args.output, &contents).map_err(|e| {
+        ArgsError::invalid(
+            "couldn't write manifest",
+            format!("{}: {}", args.output.display(), e),
+        )
+    })?;
+    println!(
+        "{}{} {}",
+        emoji("📜 ", ""),
+        style("wrote manifest to").green(),
+        style(args.output.display()).blue().bright()
+    );
+
+    if let Some(key) = &args.sign_key {
+        let key = read_signing_key(key)
+            .map_err(|e| ArgsError::invalid("couldn't read signing key", e))?;
+        let signature = key.sign(contents.as_bytes());
+        let mut path = OsString::from(args.output.as_os_str());
+        path.push(".sig");
+        let path = PathBuf::from(path);
+        write(&path, format!("{}\n", hex(&signature.to_bytes()))).map_err(|e| {
+            ArgsError::invalid(
+                "couldn't write signature",
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        println!(
+            "{}{} {} {}",
+            emoji("🔏 ", ""),
+            style("wrote signature to").green(),
+            style(path.display()).blue().bright(),
+            style(format!(
+                "(public key {})",
+                hex(key.verifying_key().as_bytes())
+            ))
+            .dim()
+        );
+    }
+    Ok(())
+}
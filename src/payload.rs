@@ -0,0 +1,453 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::Hasher,
+    io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    mem::{offset_of, size_of},
+    path::{Path, PathBuf},
+};
+
+use memchr::memmem;
+use rayon::prelude::*;
+use twox_hash::XxHash64;
+use zstd::{dict::DecoderDictionary, stream::copy_decode};
+
+use crate::{
+    compress::{self, HASH_SEED, HashReader},
+    types::*,
+};
+
+fn invalid(message: String) -> Error { Error::new(ErrorKind::InvalidData, message) }
+
+fn read_at(file: &mut File, position: u64, buffer: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(buffer)
+}
+
+/// Name stored in a startup configuration field, up to the first null byte.
+pub fn section_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(&name[..name.iter().position(|&c| c == b'\0').unwrap_or(name.len())])
+        .into_owned()
+}
+
+/// Whether the executable starting with `header` is an ELF executable for a big-endian
+/// architecture, PE and Mach-O executables are always little-endian on supported platforms.
+fn executable_big_endian(header: &[u8]) -> bool {
+    header.starts_with(b"\x7fELF") && header.get(5) == Some(&2)
+}
+
+/// Architecture and system of the executable starting with `header`, for instance
+/// `x86_64-linux`, detected from its PE, ELF or Mach-O header.
+pub fn executable_target(header: &[u8]) -> String {
+    let big_endian = executable_big_endian(header);
+    let u16_at = |offset: usize| {
+        header.get(offset..offset + 2).map_or(0, |bytes| {
+            if big_endian {
+                u16::from_be_bytes([bytes[0], bytes[1]])
+            } else {
+                u16::from_le_bytes([bytes[0], bytes[1]])
+            }
+        })
+    };
+    let u32_at = |offset: usize| {
+        header.get(offset..offset + 4).map_or(0, |bytes| {
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        })
+    };
+    let (architecture, system) = if header.starts_with(b"MZ") {
+        let architecture = match u16_at((u32_at(0x3c) as usize).saturating_add(4)) {
+            0x8664 => "x86_64",
+            0xaa64 => "aarch64",
+            0x014c => "i686",
+            _ => "unknown",
+        };
+        (architecture, "windows")
+    } else if header.starts_with(b"\x7fELF") {
+        let architecture = match u16_at(18) {
+            62 => "x86_64",
+            183 => "aarch64",
+            3 => "i686",
+            40 => "arm",
+            243 => "riscv64",
+            21 if big_endian => "powerpc64",
+            21 => "powerpc64le",
+            22 => "s390x",
+            _ => "unknown",
+        };
+        (architecture, "linux")
+    } else if u32_at(0) == 0xfeedfacf {
+        let architecture = match u32_at(4) {
+            0x01000007 => "x86_64",
+            0x0100000c => "aarch64",
+            _ => "unknown",
+        };
+        (architecture, "darwin")
+    } else {
+        ("unknown", "unknown")
+    };
+    format!("{}-{}", architecture, system)
+}
+
+/// Name of the compression algorithm of the payload `kind`.
+pub fn algorithm_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "zstd",
+        1 => "xz",
+        2 => "brotli",
+        _ => "unknown",
+    }
+}
+
+/// Startup configuration and payload sections of a packed executable.
+pub struct Payload {
+    pub info:           StarterInfo,
+    pub header:         PayloadHeader,
+    /// Position of the compressed file contents, everything before it belongs to the runner.
+    pub files_start:    u64,
+    pub dictionary:     Vec<u8>,
+    pub directories:    Vec<DirectorySection>,
+    pub files:          Vec<FileSectionHeader>,
+    pub symlinks:       Vec<SymlinkSection>,
+    pub hardlinks:      Vec<HardlinkSection>,
+    pub names:          NameTable,
+    /// File manifest stored between the payload header and the extra sections.
+    pub manifest:       Vec<u8>,
+    /// Extra sections stored between the payload header and the baked arguments.
+    pub extra_sections: Vec<u8>,
+    /// Baked arguments stored between the extra sections and the startup configuration.
+    pub arguments:      Vec<u8>,
+}
+/// Find the startup configuration at the end of `file` and return its position and format.
+/// If the startup configuration isn't found at the end, the last occurrence of its signature
+/// is used, in the same way the runner finds it. The format is read from its position in
+/// the current format, so that startup configurations of other formats can be recognized.
+pub fn find_info(file: &mut File) -> Result<(u64, u8)> {
+    let end = file.metadata()?.len();
+    let info_size = size_of::<StarterInfo>() as u64;
+    let format_offset = offset_of!(StarterInfo, wrappe_format) as u64;
+
+    let mut signature = [0; WRAPPE_SIGNATURE.len()];
+    if end >= info_size {
+        read_at(file, end - info_size, &mut signature)?;
+    }
+    let info_start = if end >= info_size && signature == WRAPPE_SIGNATURE {
+        end - info_size
+    } else {
+        let mut data = Vec::new();
+        file.rewind()?;
+        file.read_to_end(&mut data)?;
+        memmem::rfind(&data, &WRAPPE_SIGNATURE)
+            .ok_or_else(|| invalid("startup configuration signature not found".to_string()))?
+            as u64
+    };
+    if info_start + format_offset >= end {
+        return Err(invalid("startup configuration is truncated".to_string()));
+    }
+    let mut format = [0];
+    read_at(file, info_start + format_offset, &mut format)?;
+    Ok((info_start, format[0]))
+}
+
+/// Path of the file containing the payload of the packed executable at `path`, which is the pack
+/// file next to it if the executable is a split runner without a startup configuration.
+pub fn payload_path(path: &Path) -> PathBuf {
+    let pack = path.with_extension(PACK_EXTENSION);
+    let packed = File::open(path).and_then(|mut file| find_info(&mut file));
+    if packed.is_err() && pack.is_file() {
+        pack
+    } else {
+        path.to_owned()
+    }
+}
+
+impl Payload {
+    /// Read the startup configuration and payload sections from the end of `file`
+    /// and check their signature, format, bounds and section hash.
+    pub fn read(file: &mut File) -> Result<Self> {
+        let (info_start, format) = find_info(file)?;
+        if format != WRAPPE_FORMAT {
+            return Err(invalid(format!(
+                "startup configuration format ({}) differs from expected format ({})",
+                format, WRAPPE_FORMAT
+            )));
+        }
+
+        let info_size = size_of::<StarterInfo>() as u64;
+        let header_size = size_of::<PayloadHeader>() as u64;
+        let end = info_start + info_size;
+        if end > file.metadata()?.len() {
+            return Err(invalid("startup configuration is truncated".to_string()));
+        }
+        if info_start < header_size {
+            return Err(invalid(format!(
+                "file is too small ({} bytes)",
+                info_start + info_size
+            )));
+        }
+
+        let mut buffer = vec![0; info_size as usize];
+        read_at(file, info_start, &mut buffer)?;
+        let info = StarterInfo::read_from_bytes(&buffer)
+            .map_err(|_| invalid("couldn't read startup configuration".to_string()))?;
+
+        let arguments_start = info_start
+            .checked_sub(info.arguments_size.get() as u64)
+            .ok_or_else(|| invalid("baked arguments size exceeds file size".to_string()))?;
+        let mut arguments = vec![0; info.arguments_size.get() as usize];
+        read_at(file, arguments_start, &mut arguments)?;
+
+        let extra_sections_start = arguments_start
+            .checked_sub(info.extra_sections_size.get())
+            .ok_or_else(|| invalid("extra sections size exceeds file size".to_string()))?;
+        let mut extra_sections = vec![0; info.extra_sections_size.get() as usize];
+        read_at(file, extra_sections_start, &mut extra_sections)?;
+        if read_extra_sections(&extra_sections).is_none() {
+            return Err(invalid("extra sections are malformed".to_string()));
+        }
+
+        let manifest_start = extra_sections_start
+            .checked_sub(info.manifest_size.get())
+            .ok_or_else(|| invalid("manifest size exceeds file size".to_string()))?;
+        let mut manifest = vec![0; info.manifest_size.get() as usize];
+        read_at(file, manifest_start, &mut manifest)?;
+
+        let header_start = manifest_start
+            .checked_sub(header_size)
+            .ok_or_else(|| invalid("payload header exceeds file size".to_string()))?;
+        let mut buffer = vec![0; header_size as usize];
+        read_at(file, header_start, &mut buffer)?;
+        let header = PayloadHeader::read_from_bytes(&buffer)
+            .map_err(|_| invalid("couldn't read payload header".to_string()))?;
+
+        let sections_start = header_start
+            .checked_sub(header.sections_size.get())
+            .ok_or_else(|| invalid("sections size exceeds file size".to_string()))?;
+        let dictionary_start = sections_start
+            .checked_sub(header.dictionary_size.get())
+            .ok_or_else(|| invalid("dictionary size exceeds file size".to_string()))?;
+        let files_start = dictionary_start
+            .checked_sub(header.payload_size.get())
+            .ok_or_else(|| invalid("payload size exceeds file size".to_string()))?;
+
+        let mut dictionary = vec![0; header.dictionary_size.get() as usize];
+        read_at(file, dictionary_start, &mut dictionary)?;
+
+        let mut compressed = vec![0; header.sections_size.get() as usize];
+        read_at(file, sections_start, &mut compressed)?;
+        let mut sections = Vec::new();
+        copy_decode(&compressed[..], &mut sections)
+            .map_err(|e| invalid(format!("couldn't decompress payload sections: {}", e)))?;
+
+        let directories_size =
+            header.directory_sections.get() as usize * size_of::<DirectorySection>();
+        let files_size = header.file_sections.get() as usize * size_of::<FileSectionHeader>();
+        let symlinks_size = header.symlink_sections.get() as usize * size_of::<SymlinkSection>();
+        let hardlinks_size = header.hardlink_sections.get() as usize * size_of::<HardlinkSection>();
+        let hardlinks_start = directories_size + files_size + symlinks_size;
+        let names_start = hardlinks_start + hardlinks_size;
+        if sections.len() != names_start + header.names_size.get() as usize {
+            return Err(invalid(format!(
+                "sections size ({}) differs from expected sections size ({})",
+                sections.len(),
+                names_start + header.names_size.get() as usize
+            )));
+        }
+
+        let mut hasher = XxHash64::with_seed(HASH_SEED);
+        hasher.write(&sections);
+        let section_hash = hasher.finish();
+        if section_hash != header.section_hash.get() {
+            let expected = header.section_hash.get();
+            return Err(invalid(format!(
+                "section hash ({}) differs from expected section hash ({})",
+                section_hash, expected
+            )));
+        }
+
+        let directories = sections[..directories_size]
+            .chunks(size_of::<DirectorySection>())
+            .map(|section| DirectorySection::read_from_bytes(section).unwrap())
+            .collect::<Vec<_>>();
+        let files = sections[directories_size..directories_size + files_size]
+            .chunks(size_of::<FileSectionHeader>())
+            .map(|section| FileSectionHeader::read_from_bytes(section).unwrap())
+            .collect::<Vec<_>>();
+        let symlinks = sections[directories_size + files_size..hardlinks_start]
+            .chunks(size_of::<SymlinkSection>())
+            .map(|section| SymlinkSection::read_from_bytes(section).unwrap())
+            .collect::<Vec<_>>();
+        let hardlinks = sections[hardlinks_start..names_start]
+            .chunks(size_of::<HardlinkSection>())
+            .map(|section| HardlinkSection::read_from_bytes(section).unwrap())
+            .collect::<Vec<_>>();
+        let names = NameTable(sections[names_start..].to_vec());
+
+        if directories
+            .iter()
+            .map(|section| section.name)
+            .chain(files.iter().map(|section| section.name))
+            .chain(symlinks.iter().map(|section| section.name))
+            .chain(symlinks.iter().map(|section| section.link))
+            .chain(hardlinks.iter().map(|section| section.name))
+            .any(|name| names.bytes(name).is_none())
+        {
+            return Err(invalid(format!(
+                "section name exceeds name table size ({})",
+                names.0.len()
+            )));
+        }
+
+        if let Some(section) = directories
+            .iter()
+            .enumerate()
+            .find(|(i, section)| section.parent.get() as usize > *i)
+        {
+            return Err(invalid(format!(
+                "directory {} has an invalid parent",
+                names.get(section.1.name)
+            )));
+        }
+        if let Some(name) = files
+            .iter()
+            .map(|section| (section.parent, section.name))
+            .chain(
+                symlinks
+                    .iter()
+                    .map(|section| (section.parent, section.name)),
+            )
+            .chain(
+                hardlinks
+                    .iter()
+                    .map(|section| (section.parent, section.name)),
+            )
+            .find(|(parent, _)| parent.get() as usize > directories.len())
+            .map(|(_, name)| names.get(name))
+        {
+            return Err(invalid(format!("{} has an invalid parent", name)));
+        }
+        if let Some(section) = hardlinks
+            .iter()
+            .find(|section| section.target.get() as usize >= files.len())
+        {
+            return Err(invalid(format!(
+                "hardlink {} has an invalid target",
+                names.get(section.name)
+            )));
+        }
+
+        Ok(Payload {
+            info,
+            header,
+            files_start,
+            dictionary,
+            directories,
+            files,
+            symlinks,
+            hardlinks,
+            names,
+            manifest,
+            extra_sections,
+            arguments,
+        })
+    }
+
+    /// Relative paths of the directories separated by slashes,
+    /// starting with the root directory at index 0.
+    pub fn directory_paths(&self) -> Vec<String> {
+        let mut paths = Vec::<String>::from(["".to_string()]);
+        for section in self.directories.iter() {
+            paths.push(join(
+                &paths[section.parent.get() as usize],
+                &self.names.get(section.name),
+            ));
+        }
+        paths
+    }
+
+    /// Decompress the solid blocks of the files in the payload of `executable` and check
+    /// their compressed hashes. Returns the decompressed blocks by position.
+    pub fn solid_blocks(
+        &self, executable: &Path, dictionary: Option<&DecoderDictionary>,
+    ) -> SolidBlocks {
+        let mut blocks = self
+            .files
+            .iter()
+            .filter(|section| section.solid == 1)
+            .map(|section| {
+                (
+                    section.position.get(),
+                    section.size.get(),
+                    section.compressed_hash.get(),
+                )
+            })
+            .collect::<Vec<_>>();
+        blocks.sort_unstable();
+        blocks.dedup();
+        blocks
+            .par_iter()
+            .map(|&(position, size, expected)| {
+                let block = (|| -> Result<Vec<u8>> {
+                    let mut file = File::open(executable)?;
+                    file.seek(SeekFrom::Start(self.files_start + position))?;
+                    let mut reader =
+                        HashReader::new(file.take(size), XxHash64::with_seed(HASH_SEED));
+                    let mut block = Vec::new();
+                    compress::copy_decode(
+                        BufReader::new(&mut reader),
+                        &mut block,
+                        self.header.kind,
+                        dictionary,
+                    )?;
+                    let compressed_hash = reader.finish();
+                    if compressed_hash != expected {
+                        return Err(invalid(format!(
+                            "compressed block hash ({}) differs from expected hash ({})",
+                            compressed_hash, expected
+                        )));
+                    }
+                    Ok(block)
+                })()
+                .map_err(|e| format!("couldn't decompress solid block at {}: {}", position, e));
+                (position, block)
+            })
+            .collect()
+    }
+}
+
+/// Decompressed solid blocks by position, or the error that occurred decompressing them.
+pub type SolidBlocks = HashMap<u64, std::result::Result<Vec<u8>, String>>;
+
+/// Contents of the file in `section` in its decompressed solid block in `blocks`.
+pub fn solid_contents<'a>(
+    blocks: &'a SolidBlocks, section: &FileSectionHeader,
+) -> Result<&'a [u8]> {
+    let (position, offset, size) = (
+        section.position.get(),
+        section.block_offset.get(),
+        section.block_size.get(),
+    );
+    let block = blocks
+        .get(&position)
+        .ok_or_else(|| invalid(format!("solid block at {} not found", position)))?
+        .as_ref()
+        .map_err(|e| invalid(e.clone()))?;
+    block
+        .get(offset as usize..(offset + size) as usize)
+        .ok_or_else(|| {
+            invalid(format!(
+                "file data ({}..{}) exceeds solid block size ({})",
+                offset,
+                offset + size,
+                block.len()
+            ))
+        })
+}
+
+/// Join the slash-separated `parent` path and `name`.
+pub fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
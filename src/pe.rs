@@ -0,0 +1,185 @@
+use std::{
+    error::Error,
+    panic::{AssertUnwindSafe, catch_unwind, set_hook, take_hook},
+};
+
+use crate::payload::executable_target;
+
+/// Size of a section table entry.
+const SECTION_SIZE: usize = 40;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset.checked_add(2)?)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset.checked_add(4)?)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Entry of the section table of a PE image.
+struct Section {
+    name:            String,
+    virtual_address: u32,
+    virtual_size:    u32,
+    raw_offset:      u32,
+    raw_size:        u32,
+}
+
+/// Headers of a PE image relevant for editing its subsystem and resources.
+struct Layout {
+    pe32_plus:     bool,
+    subsystem:     u16,
+    sections:      Vec<Section>,
+    /// Virtual address and size of the resource directory.
+    resources:     (u32, u32),
+    /// Number of sections declared in the file header.
+    section_count: usize,
+}
+
+/// Read the headers of the PE image `data`, stopping at the first one that lies outside of it.
+fn read_layout(data: &[u8]) -> Result<Layout, String> {
+    if !data.starts_with(b"MZ") {
+        return Err("missing DOS header".to_string());
+    }
+    let header = u32_at(data, 0x3c).ok_or("truncated DOS header")? as usize;
+    if data.get(header..header.saturating_add(4)) != Some(b"PE\0\0") {
+        return Err(format!("missing PE signature at {:#x}", header));
+    }
+    let section_count = u16_at(data, header + 6).ok_or("truncated file header")? as usize;
+    let optional_size = u16_at(data, header + 20).ok_or("truncated file header")? as usize;
+    let optional = header + 24;
+    let pe32_plus = match u16_at(data, optional).ok_or("truncated optional header")? {
+        0x10b => false,
+        0x20b => true,
+        magic => return Err(format!("unknown optional header magic {:#x}", magic)),
+    };
+    if optional + optional_size > data.len() {
+        return Err(format!(
+            "optional header of {} bytes exceeds the file size",
+            optional_size
+        ));
+    }
+    let subsystem = u16_at(data, optional + 68).ok_or("truncated optional header")?;
+    let directories = optional + if pe32_plus { 112 } else { 96 };
+    let directory_count = u32_at(data, directories - 4).unwrap_or_default();
+    let resources = if directory_count > 2 && directories + 24 <= optional + optional_size {
+        (
+            u32_at(data, directories + 16).unwrap_or_default(),
+            u32_at(data, directories + 20).unwrap_or_default(),
+        )
+    } else {
+        (0, 0)
+    };
+
+    let table = optional + optional_size;
+    let mut sections = Vec::with_capacity(section_count.min(96));
+    for index in 0..section_count {
+        let entry = table + index * SECTION_SIZE;
+        let Some(bytes) = data.get(entry..entry + SECTION_SIZE) else {
+            break;
+        };
+        let name = &bytes[..8];
+        sections.push(Section {
+            name:            String::from_utf8_lossy(
+                &name[..name.iter().position(|&c| c == 0).unwrap_or(8)],
+            )
+            .into_owned(),
+            virtual_size:    u32_at(bytes, 8).unwrap_or_default(),
+            virtual_address: u32_at(bytes, 12).unwrap_or_default(),
+            raw_size:        u32_at(bytes, 16).unwrap_or_default(),
+            raw_offset:      u32_at(bytes, 20).unwrap_or_default(),
+        });
+    }
+    Ok(Layout {
+        pe32_plus,
+        subsystem,
+        sections,
+        resources,
+        section_count,
+    })
+}
+
+/// Check that the headers, section table, section data and resource directory of the PE image
+/// `data` lie within it, so that malformed images are reported instead of failing while editing.
+pub fn check_image(data: &[u8]) -> Result<(), String> {
+    let layout = read_layout(data)?;
+    if layout.sections.len() < layout.section_count {
+        return Err(format!(
+            "section table of {} sections exceeds the file size",
+            layout.section_count
+        ));
+    }
+    for section in &layout.sections {
+        let end = section.raw_offset as u64 + section.raw_size as u64;
+        if section.raw_size > 0 && end > data.len() as u64 {
+            return Err(format!(
+                "data of section {} ({:#x}..{:#x}) exceeds the file size ({:#x})",
+                section.name,
+                section.raw_offset,
+                end,
+                data.len()
+            ));
+        }
+    }
+    let (address, size) = layout.resources;
+    if size > 0
+        && !layout.sections.iter().any(|section| {
+            address >= section.virtual_address
+                && address as u64 + size as u64
+                    <= section.virtual_address as u64
+                        + section.virtual_size.max(section.raw_size) as u64
+        })
+    {
+        return Err(format!(
+            "resource directory ({:#x}, {} bytes) is not contained in a section",
+            address, size
+        ));
+    }
+    Ok(())
+}
+
+/// Target, format, subsystem and section table of the PE image `data` for diagnostics.
+pub fn describe_image(data: &[u8]) -> String {
+    let target = executable_target(data);
+    match read_layout(data) {
+        Ok(layout) => format!(
+            "{} {} image with subsystem {}, {} bytes, sections {}",
+            target,
+            if layout.pe32_plus { "PE32+" } else { "PE32" },
+            layout.subsystem,
+            data.len(),
+            layout
+                .sections
+                .iter()
+                .map(|section| format!(
+                    "{} ({:#x}+{:#x})",
+                    section.name, section.raw_offset, section.raw_size
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Err(error) => format!("{} image of {} bytes ({})", target, data.len(), error),
+    }
+}
+
+/// Run `edit` with panics converted into errors, so that images the PE parser can't handle don't
+/// abort packing. The panic hook is replaced while `edit` runs to not print a backtrace or remove
+/// the temporary output.
+pub fn catch_panic<T>(
+    edit: impl FnOnce() -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let hook = take_hook();
+    set_hook(Box::new(|_| {}));
+    let result = catch_unwind(AssertUnwindSafe(edit));
+    set_hook(hook);
+    result.unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown error".to_string());
+        Err(format!("PE parser failed: {}", message).into())
+    })
+}
@@ -0,0 +1,137 @@
+use std::{
+    io::Result,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use path_slash::PathExt;
+
+use serde_json::{Value, json};
+
+use crate::compress::input_roots;
+
+/// Format of the pack report, increased when its structure changes.
+const REPORT_FORMAT: u8 = 1;
+
+/// File written to the payload or entry skipped while packing.
+enum ReportEntry {
+    Packed {
+        path:       String,
+        size:       u64,
+        compressed: Option<u64>,
+        hash:       u64,
+        stored:     bool,
+    },
+    Skipped {
+        path:   String,
+        reason: String,
+    },
+}
+
+impl ReportEntry {
+    fn path(&self) -> &str {
+        match self {
+            ReportEntry::Packed { path, .. } | ReportEntry::Skipped { path, .. } => path,
+        }
+    }
+}
+
+/// Per-file report of a packing run written with `--report`, collected from the compression
+/// events of possibly several threads.
+pub struct PackReport {
+    /// Absolute and canonical paths of the input directories.
+    roots:   Vec<PathBuf>,
+    entries: Mutex<Vec<ReportEntry>>,
+}
+
+impl PackReport {
+    pub fn new<T: AsRef<Path>>(sources: &[T]) -> Self {
+        PackReport {
+            roots:   input_roots(sources),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record the file at `path` in the payload with `size` bytes of contents written with
+    /// `compressed` bytes, or in a solid block if it is `None`.
+    pub fn packed(&self, path: &str, size: u64, compressed: Option<u64>, hash: u64, stored: bool) {
+        self.entries.lock().unwrap().push(ReportEntry::Packed {
+            path: path.to_string(),
+            size,
+            compressed,
+            hash,
+            stored,
+        });
+    }
+
+    /// Record the entry at `path` on disk that was skipped for `reason`, with its path relative
+    /// to its input directory if it is inside one.
+    pub fn skipped(&self, path: &Path, reason: &str) {
+        let path = self
+            .roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path);
+        self.entries.lock().unwrap().push(ReportEntry::Skipped {
+            path:   path.to_slash_lossy().into_owned(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Report with the entries sorted by path, so that reports of different releases can be
+    /// compared line by line.
+    pub fn to_json(&self, input_size: u64, payload_size: u64) -> Value {
+        let mut entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+        let skipped = entries
+            .iter()
+            .filter(|entry| matches!(entry, ReportEntry::Skipped { .. }))
+            .count();
+        let files = entries.len() - skipped;
+        let entries = entries
+            .into_iter()
+            .map(|entry| match entry {
+                ReportEntry::Packed {
+                    path,
+                    size,
+                    compressed,
+                    hash,
+                    stored,
+                } => json!({
+                    "path": path,
+                    "size": size,
+                    "compressed_size": compressed,
+                    // rounded to keep reports readable
+                    "ratio": compressed
+                        .filter(|_| size > 0)
+                        .map(|compressed| (compressed as f64 / size as f64 * 10000.0).round() / 10000.0),
+                    "hash": format!("{:016x}", hash),
+                    "stored": stored,
+                    "solid": compressed.is_none(),
+                    "skipped": null,
+                }),
+                ReportEntry::Skipped { path, reason } => json!({
+                    "path": path,
+                    "skipped": reason,
+                }),
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "format": REPORT_FORMAT,
+            "packer": format!("wrappe {}", env!("CARGO_PKG_VERSION")),
+            "files": files,
+            "skipped": skipped,
+            "input_size": input_size,
+            "payload_size": payload_size,
+            "entries": entries,
+        })
+    }
+
+    /// Write the report to the JSON file at `path`.
+    pub fn write(&self, path: &Path, input_size: u64, payload_size: u64) -> Result<()> {
+        std::fs::write(
+            path,
+            format!("{:#}\n", self.to_json(input_size, payload_size)),
+        )
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Result, Seek, SeekFrom, Write, copy},
+    path::Path,
+};
+
+use rayon::prelude::*;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use zstd::dict::DecoderDictionary;
+
+use crate::{
+    compress::copy_decode,
+    payload::{Payload, SolidBlocks, find_info, join, section_name, solid_contents},
+    signing::hex,
+    types::*,
+};
+
+/// Format of the file manifest, increased when its structure changes.
+const MANIFEST_FORMAT: u8 = 1;
+
+/// Size and SHA-256 hash of the decompressed contents of the file in `section` of the payload
+/// in `executable`. Files in solid blocks are taken from the decompressed blocks in `blocks`.
+fn digest_file(
+    executable: &Path, payload: &Payload, section: &FileSectionHeader,
+    dictionary: Option<&DecoderDictionary>, blocks: &SolidBlocks,
+) -> Result<(u64, [u8; 32])> {
+    if section.solid == 1 {
+        let contents = solid_contents(blocks, section)?;
+        return Ok((contents.len() as u64, Sha256::digest(contents).into()));
+    }
+    let mut file = File::open(executable)?;
+    file.seek(SeekFrom::Start(
+        payload.files_start + section.position.get(),
+    ))?;
+    let mut reader = BufReader::new(file.take(section.size.get()));
+    let mut hasher = Sha256::new();
+    let size = if section.stored == 1 {
+        copy(&mut reader, &mut hasher)?
+    } else {
+        copy_decode(&mut reader, &mut hasher, payload.header.kind, dictionary)?
+    };
+    Ok((size, hasher.finalize().into()))
+}
+
+/// Manifest of the files, symlinks and hardlinks in the payload of `executable` with their
+/// paths, sizes, SHA-256 hashes of their decompressed contents and modification times.
+pub fn file_manifest(executable: &Path, payload: &Payload) -> Result<Value> {
+    let directories = payload.directory_paths();
+    let path = |parent: &U32, name: NameRef| {
+        join(
+            &directories[parent.get() as usize],
+            &payload.names.get(name),
+        )
+    };
+    let files = payload
+        .files
+        .iter()
+        .map(|section| path(&section.parent, section.name))
+        .collect::<Vec<_>>();
+
+    let dictionary =
+        (!payload.dictionary.is_empty()).then(|| DecoderDictionary::copy(&payload.dictionary));
+    let blocks = payload.solid_blocks(executable, dictionary.as_ref());
+    let digests = payload
+        .files
+        .par_iter()
+        .zip(files.par_iter())
+        .map(|(section, path)| {
+            digest_file(executable, payload, section, dictionary.as_ref(), &blocks).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("couldn't hash {}: {}", path, e))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let file_entries = payload
+        .files
+        .iter()
+        .zip(files.iter())
+        .zip(digests.iter())
+        .map(|((section, path), (size, digest))| {
+            json!({
+                "path": path,
+                "size": size,
+                "sha256": hex(digest),
+                "modified": section.time_modified_seconds.get(),
+                "mode": format!("{:o}", section.mode.get() & 0o7777),
+            })
+        })
+        .collect::<Vec<_>>();
+    let symlink_entries = payload
+        .symlinks
+        .iter()
+        .map(|section| {
+            let target = match section.kind {
+                0 => directories
+                    .get(section.target.get() as usize)
+                    .map(|directory| format!("{}/", directory)),
+                1 => files.get(section.target.get() as usize).cloned(),
+                _ => Some(payload.names.get(section.link)),
+            };
+            json!({
+                "path": path(&section.parent, section.name),
+                "target": target,
+                "external": section.kind > 1,
+            })
+        })
+        .collect::<Vec<_>>();
+    let hardlink_entries = payload
+        .hardlinks
+        .iter()
+        .map(|section| {
+            json!({
+                "path": path(&section.parent, section.name),
+                "target": files.get(section.target.get() as usize),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "format": MANIFEST_FORMAT,
+        "version": section_name(&payload.info.uid),
+        "command": section_name(&payload.info.command),
+        "files": file_entries,
+        "symlinks": symlink_entries,
+        "hardlinks": hardlink_entries,
+    }))
+}
+
+/// Generate the file manifest of the packed executable at `path` and store it between the
+/// payload header and the extra sections, replacing an existing manifest. Returns the number
+/// of files in the manifest.
+pub fn embed_manifest(path: &Path) -> Result<usize> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let payload = Payload::read(&mut file)?;
+    let manifest = format!("{:#}\n", file_manifest(path, &payload)?).into_bytes();
+
+    let (info_start, _) = find_info(&mut file)?;
+    let mut info = payload.info;
+    let manifest_start = info_start
+        - info.arguments_size.get() as u64
+        - info.extra_sections_size.get()
+        - info.manifest_size.get();
+    info.manifest_size = (manifest.len() as u64).into();
+
+    file.set_len(manifest_start)?;
+    file.seek(SeekFrom::Start(manifest_start))?;
+    file.write_all(&manifest)?;
+    file.write_all(&payload.extra_sections)?;
+    file.write_all(&payload.arguments)?;
+    file.write_all(info.as_bytes())?;
+    file.flush()?;
+    Ok(payload.files.len())
+}
@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use path_slash::PathExt;
+
+use crate::{compress::input_roots, types::UNPACK_DIR_PLACEHOLDER};
+
+/// Interpreter directories that exist on every Unix system, shebangs pointing into them are left
+/// as they are.
+const PORTABLE_DIRECTORIES: [&str; 4] = ["/bin/", "/sbin/", "/usr/bin/", "/usr/sbin/"];
+
+/// Files of Python virtual environments containing the path of the environment.
+const VENV_FILES: [&str; 6] = [
+    "pyvenv.cfg",
+    "bin/activate",
+    "bin/activate.csh",
+    "bin/activate.fish",
+    "Scripts/activate",
+    "Scripts/activate.bat",
+];
+
+/// Longest shebang line read by the kernel, longer lines aren't detected.
+pub const SHEBANG_SIZE: usize = 256;
+
+/// How shebangs of scripts pointing at interpreters on the packing machine are rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShebangMode {
+    /// Keep the shebangs and report them.
+    Keep,
+    /// Rewrite the shebangs to find the interpreter with `/usr/bin/env`.
+    Env,
+    /// Rewrite the shebangs of interpreters in the inputs to their path in the unpack directory
+    /// when unpacking, and the others to find the interpreter with `/usr/bin/env`.
+    Unpack,
+}
+
+/// Script with a rewritten shebang.
+pub struct Rewritten {
+    pub contents:    Vec<u8>,
+    /// Whether the shebang starts with [`UNPACK_DIR_PLACEHOLDER`] to be replaced when unpacking.
+    pub placeholder: bool,
+}
+
+/// Detection and rewriting of shebangs pointing at interpreters on the packing machine.
+pub struct Shebangs {
+    pub mode: ShebangMode,
+    /// Whether the inputs are a Python virtual environment whose paths are relocated.
+    pub venv: bool,
+    /// Absolute and canonical paths of the input directories.
+    roots:    Vec<PathBuf>,
+}
+
+impl Shebangs {
+    pub fn new<T: AsRef<Path>>(mode: ShebangMode, venv: bool, sources: &[T]) -> Self {
+        let mut roots = input_roots(sources);
+        // longer paths first so nested roots are replaced completely
+        roots.sort_by_key(|root| std::cmp::Reverse(root.as_os_str().len()));
+        Shebangs { mode, venv, roots }
+    }
+
+    /// Whether `relative` is a virtual environment file relocated with [`Shebangs::relocate`].
+    pub fn is_venv_file(&self, relative: &Path) -> bool {
+        self.venv
+            && relative
+                .to_slash()
+                .is_some_and(|relative| VENV_FILES.contains(&relative.as_ref()))
+    }
+
+    /// `contents` with every path of the input directories replaced with
+    /// [`UNPACK_DIR_PLACEHOLDER`], or `None` if it doesn't contain them.
+    pub fn relocate(&self, contents: &[u8]) -> Option<Vec<u8>> {
+        let mut relocated = contents.to_vec();
+        let mut changed = false;
+        for root in &self.roots {
+            let Some(root) = root.to_str() else {
+                continue;
+            };
+            let root = root.as_bytes();
+            let mut result = Vec::with_capacity(relocated.len());
+            let mut i = 0;
+            while i < relocated.len() {
+                let end = i + root.len();
+                // only complete path components are replaced
+                if relocated[i..].starts_with(root)
+                    && relocated.get(end).map_or(true, |&byte| {
+                        !(byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'.'))
+                    })
+                {
+                    result.extend_from_slice(UNPACK_DIR_PLACEHOLDER.as_bytes());
+                    i = end;
+                    changed = true;
+                } else {
+                    result.push(relocated[i]);
+                    i += 1;
+                }
+            }
+            relocated = result;
+        }
+        changed.then_some(relocated)
+    }
+
+    /// Interpreter of the shebang at the start of `head` if it is an absolute path outside of
+    /// the [`PORTABLE_DIRECTORIES`].
+    pub fn detect(head: &[u8]) -> Option<&str> {
+        let (interpreter, _, _) = parse(head)?;
+        (interpreter.starts_with('/')
+            && !PORTABLE_DIRECTORIES
+                .iter()
+                .any(|directory| interpreter.starts_with(directory)))
+        .then_some(interpreter)
+    }
+
+    /// `contents` with the shebang rewritten according to the mode,
+    /// or `None` if it isn't detected or the mode is [`ShebangMode::Keep`].
+    pub fn rewrite(&self, contents: &[u8]) -> Option<Rewritten> {
+        let interpreter = Self::detect(contents)?;
+        let (_, arguments, end) = parse(contents)?;
+        let relative = match self.mode {
+            ShebangMode::Keep => return None,
+            ShebangMode::Env => None,
+            ShebangMode::Unpack => self.roots.iter().find_map(|root| {
+                Path::new(interpreter)
+                    .strip_prefix(root)
+                    .ok()
+                    .and_then(|relative| relative.to_slash())
+            }),
+        };
+        let line = match &relative {
+            Some(relative) => format!("#!{}/{}{}", UNPACK_DIR_PLACEHOLDER, relative, arguments),
+            None => {
+                let name = interpreter.rsplit('/').next().unwrap_or(interpreter);
+                // python is often only installed as python3
+                let name = if name == "python" { "python3" } else { name };
+                if arguments.trim().is_empty() {
+                    format!("#!/usr/bin/env {}", name)
+                } else {
+                    // the kernel passes the arguments as one, env splits them with -S
+                    format!("#!/usr/bin/env -S {}{}", name, arguments)
+                }
+            }
+        };
+        Some(Rewritten {
+            contents:    [line.as_bytes(), &contents[end..]].concat(),
+            placeholder: relative.is_some(),
+        })
+    }
+}
+
+/// Interpreter and arguments of the shebang at the start of `contents`, and the end of the
+/// shebang line before its line break.
+fn parse(contents: &[u8]) -> Option<(&str, &str, usize)> {
+    let rest = contents.strip_prefix(b"#!")?;
+    let end = rest
+        .iter()
+        .take(SHEBANG_SIZE)
+        .position(|&byte| byte == b'\n')?;
+    let line = std::str::from_utf8(&rest[..end]).ok()?;
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let trimmed = line.trim_start();
+    let interpreter_end = trimmed
+        .find(|c: char| c.is_ascii_whitespace())
+        .unwrap_or(trimmed.len());
+    let interpreter = &trimmed[..interpreter_end];
+    if interpreter.is_empty() {
+        return None;
+    }
+    Some((
+        interpreter,
+        trimmed[interpreter_end..].trim_end(),
+        2 + line.len(),
+    ))
+}
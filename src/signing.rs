@@ -0,0 +1,151 @@
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions, read_to_string, remove_file, write},
+    io::{self, Read, Seek, SeekFrom, Write, copy},
+    mem::{offset_of, size_of},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use console::style;
+use ed25519_dalek::{SECRET_KEY_LENGTH, Signature, Signer, SigningKey, VerifyingKey};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    args::ArgsError,
+    payload::{Payload, find_info},
+    types::*,
+};
+
+#[derive(Args)]
+#[command(about = "Generate a key pair for signing payloads and update manifests")]
+pub struct KeygenArgs {
+    /// Overwrite existing key files
+    #[arg(short = 'f', long)]
+    force:     bool,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain: bool,
+    /// Path to write the private key to, the public key is written next to it with .pub appended
+    #[arg(name = "key")]
+    key:       PathBuf,
+}
+
+/// Encode `bytes` as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() }
+
+/// Path of the public key belonging to the private key at `key`.
+fn public_key_path(key: &Path) -> PathBuf {
+    let mut path = OsString::from(key.as_os_str());
+    path.push(".pub");
+    PathBuf::from(path)
+}
+
+/// Read an Ed25519 private key stored as hex at `path`.
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let contents = read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let contents = contents.trim();
+    if contents.len() != SECRET_KEY_LENGTH * 2 || !contents.is_ascii() {
+        return Err(format!(
+            "{}: expected {} hex digits",
+            path.display(),
+            SECRET_KEY_LENGTH * 2
+        ));
+    }
+    let mut secret = [0; SECRET_KEY_LENGTH];
+    for (index, byte) in secret.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&contents[index * 2..index * 2 + 2], 16)
+            .map_err(|_| format!("{}: invalid hex digits", path.display()))?;
+    }
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Position of the startup configuration and SHA-256 digest of the part of the packed executable
+/// `file` covered by its payload signature, which is everything from the compressed files to the
+/// end of the startup configuration with the signature zeroed.
+fn payload_digest(file: &mut File) -> io::Result<(u64, [u8; 32])> {
+    let files_start = Payload::read(file)?.files_start;
+    let (info_start, _) = find_info(file)?;
+    file.seek(SeekFrom::Start(files_start))?;
+    let mut hasher = Sha256::new();
+    copy(
+        &mut Read::by_ref(file).take(info_start - files_start),
+        &mut hasher,
+    )?;
+    let mut info = vec![0; size_of::<StarterInfo>()];
+    file.read_exact(&mut info)?;
+    let offset = offset_of!(StarterInfo, payload_signature);
+    info[offset..offset + Signature::BYTE_SIZE].fill(0);
+    hasher.update(&info);
+    Ok((info_start, hasher.finalize().into()))
+}
+
+/// Sign the payload of the packed executable at `path` with `key` and write the signature into
+/// its startup configuration, which has to contain the public key of `key` already.
+pub fn sign_payload(path: &Path, key: &SigningKey) -> io::Result<()> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let (info_start, digest) = payload_digest(&mut file)?;
+    let signature = key.sign(&digest);
+    file.seek(SeekFrom::Start(
+        info_start + offset_of!(StarterInfo, payload_signature) as u64,
+    ))?;
+    file.write_all(&signature.to_bytes())
+}
+
+/// Check the payload signature of the packed executable `file` against the public key in its
+/// startup configuration `info`, the same way the runner checks it before unpacking.
+pub fn verify_payload(file: &mut File, info: &StarterInfo) -> Result<(), String> {
+    let (_, digest) = payload_digest(file).map_err(|e| e.to_string())?;
+    let key = VerifyingKey::from_bytes(&info.public_key).map_err(|e| e.to_string())?;
+    key.verify_strict(&digest, &Signature::from_bytes(&info.payload_signature))
+        .map_err(|e| e.to_string())
+}
+
+/// Generate a new Ed25519 key pair and write the private and public key as hex.
+pub fn keygen(args: KeygenArgs) -> Result<(), ArgsError> {
+    let public = public_key_path(&args.key);
+    for path in [&args.key, &public] {
+        if path.exists() && !args.force {
+            return Err(ArgsError::invalid(
+                "key file already exists (use --force to overwrite)",
+                path.display(),
+            ));
+        }
+    }
+    let mut secret = [0; SECRET_KEY_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    let key = SigningKey::from_bytes(&secret);
+    // the private key is created readable only by the owner instead of restricting it after
+    // writing it, and replaced only when overwriting is requested
+    if args.force {
+        let _ = remove_file(&args.key);
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&args.key)
+        .and_then(|mut file| file.write_all(format!("{}\n", hex(&secret)).as_bytes()))
+        .map_err(|e| ArgsError::invalid("couldn't write private key", e))?;
+    write(
+        &public,
+        format!("{}\n", hex(key.verifying_key().as_bytes())),
+    )
+    .map_err(|e| ArgsError::invalid("couldn't write public key", e))?;
+    println!(
+        "{} {}",
+        style("wrote private key to").green(),
+        style(args.key.display()).blue().bright()
+    );
+    println!(
+        "{} {}",
+        style("wrote public key to").green(),
+        style(public.display()).blue().bright()
+    );
+    Ok(())
+}
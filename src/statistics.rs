@@ -0,0 +1,81 @@
+use serde_json::{Value, json};
+
+use crate::payload::algorithm_name;
+
+/// Name of the extra section the pack statistics are stored in with `--statistics`.
+pub const STATISTICS_SECTION: &str = "wrappe-statistics";
+
+/// Format of the pack statistics, increased when their structure changes.
+const STATISTICS_FORMAT: u8 = 1;
+
+/// Sizes and compression settings of a packed executable, recorded at pack time.
+pub struct PackStatistics {
+    pub entries:          u64,
+    pub input_size:       u64,
+    pub payload_size:     u64,
+    pub algorithm:        u8,
+    pub level:            u32,
+    pub solid_block_size: u64,
+    pub dictionary:       &'static str,
+    pub store_patterns:   usize,
+    pub auto_store:       bool,
+}
+
+impl PackStatistics {
+    /// Statistics as stored in the statistics section. They contain no timestamps or paths, so
+    /// that reproducible builds stay reproducible.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "format": STATISTICS_FORMAT,
+            "packer": format!("wrappe {}", env!("CARGO_PKG_VERSION")),
+            "entries": self.entries,
+            "input_size": self.input_size,
+            "payload_size": self.payload_size,
+            "compression": {
+                "algorithm": algorithm_name(self.algorithm),
+                "level": self.level,
+                "solid_block_size": self.solid_block_size,
+                "dictionary": self.dictionary,
+                "store_patterns": self.store_patterns,
+                "auto_store": self.auto_store,
+            },
+        })
+    }
+}
+
+/// One-line summary of the statistics in `statistics` for the configuration report.
+pub fn describe_statistics(statistics: &Value) -> String {
+    let number = |value: Option<&Value>| value.and_then(Value::as_u64).unwrap_or(0);
+    let compression = statistics.get("compression");
+    let setting = |key: &str| compression.and_then(|compression| compression.get(key));
+    let mut summary = format!(
+        "{} entries, {} bytes read, {} bytes written, {} level {}",
+        number(statistics.get("entries")),
+        number(statistics.get("input_size")),
+        number(statistics.get("payload_size")),
+        setting("algorithm")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown"),
+        number(setting("level"))
+    );
+    let solid_block_size = number(setting("solid_block_size"));
+    if solid_block_size > 0 {
+        summary.push_str(&format!(", solid blocks of {} bytes", solid_block_size));
+    }
+    match setting("dictionary").and_then(Value::as_str) {
+        Some("built") => summary.push_str(", built dictionary"),
+        Some("file") => summary.push_str(", dictionary from file"),
+        _ => {}
+    }
+    let store_patterns = number(setting("store_patterns"));
+    if store_patterns > 0 {
+        summary.push_str(&format!(", {} store patterns", store_patterns));
+    }
+    if setting("auto_store") == Some(&Value::Bool(false)) {
+        summary.push_str(", without auto-store");
+    }
+    if let Some(packer) = statistics.get("packer").and_then(Value::as_str) {
+        summary.push_str(&format!(" ({})", packer));
+    }
+    summary
+}
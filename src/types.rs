@@ -1,79 +1,515 @@
-pub use zerocopy::{Immutable, IntoBytes, KnownLayout};
+pub use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout,
+    byteorder::little_endian::{U32, U64},
+};
 
-pub const WRAPPE_FORMAT: u8 = 204;
+pub const WRAPPE_FORMAT: u8 = 233;
 pub const WRAPPE_SIGNATURE: [u8; 8] = [0x50, 0x45, 0x33, 0x44, 0x41, 0x54, 0x41, 0x00];
 pub const NAME_SIZE: usize = 128;
-pub const ARGS_SIZE: usize = 512;
+pub const WRAPPE_DIRECTORY: &str = ".wrappe";
+pub const DESKTOP_ENTRY_FILE: &str = "entry.desktop";
+pub const DESKTOP_ICON_NAME: &str = "icon";
+pub const EXTRA_SECTIONS_DIRECTORY: &str = "sections";
+pub const SEEKABLE_FRAME_SIZE: u64 = 2 * 1024 * 1024;
+pub const SEEKABLE_SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+pub const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+pub const RUNNER_MARKER: &[u8] = b"WRAPPE_RUNNER_FORMAT";
+/// Placeholder in file contents replaced with the unpack directory when unpacking.
+pub const UNPACK_DIR_PLACEHOLDER: &str = "{unpack_dir}";
+/// Extension of the payload file stored next to a split runner instead of at its end.
+pub const PACK_EXTENSION: &str = "pack";
 
+// multi-byte fields of the format are stored little-endian regardless of the byte order of the
+// packer and the runner, and converted with get() and new() or into() when accessed
 #[repr(C, packed)]
-#[derive(IntoBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct StarterInfo {
-    pub signature:        [u8; 8],
-    pub show_console:     u8,
-    pub current_dir:      u8,
-    pub verification:     u8,
-    pub show_information: u8,
-    pub uid:              [u8; 16],
-    pub unpack_target:    u8,
-    pub versioning:       u8,
-    pub once:             u8,
-    pub cleanup:          u8,
-    pub wrappe_format:    u8,
-    pub unpack_directory: [u8; NAME_SIZE],
-    pub command:          [u8; NAME_SIZE],
-    pub arguments:        [u8; ARGS_SIZE],
+    pub signature:           [u8; 8],
+    pub show_console:        u8,
+    pub console_source:      u8,
+    pub current_dir:         u8,
+    pub verification:        u8,
+    pub show_information:    u8,
+    pub uid:                 [u8; 16],
+    pub app_id:              [u8; 16],
+    pub unpack_target:       u8,
+    pub versioning:          u8,
+    pub once:                u8,
+    pub cleanup:             u8,
+    pub wrappe_format:       u8,
+    pub desktop_entry:       u8,
+    pub once_timeout:        U32,
+    pub once_exit_code:      u8,
+    pub once_quiet:          u8,
+    pub prefetch:            u8,
+    pub prefetch_threshold:  U32,
+    pub low_priority:        u8,
+    pub supervise:           u8,
+    pub ownership:           u8,
+    pub process_group:       u8,
+    pub close_fds:           u8,
+    pub in_memory:           u8,
+    pub unpack_subdir:       u8,
+    pub backoff:             u8,
+    pub arguments_size:      U32,
+    pub extra_sections_size: U64,
+    pub manifest_size:       U64,
+    pub passed_fds:          U64,
+    pub public_key:          [u8; 32],
+    pub payload_signature:   [u8; 64],
+    pub unpack_directory:    [u8; NAME_SIZE],
+    pub command:             [u8; NAME_SIZE],
 }
 
 #[repr(C, packed)]
-#[derive(IntoBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct PayloadHeader {
-    pub directory_sections: u64,
-    pub file_sections:      u64,
-    pub symlink_sections:   u64,
-    pub dictionary_size:    u64,
-    pub section_hash:       u64,
-    pub payload_size:       u64,
-    pub sections_size:      u64,
+    pub directory_sections: U64,
+    pub file_sections:      U64,
+    pub symlink_sections:   U64,
+    pub hardlink_sections:  U64,
+    pub dictionary_size:    U64,
+    pub section_hash:       U64,
+    pub payload_hash:       [u8; 32],
+    pub payload_size:       U64,
+    pub sections_size:      U64,
+    pub names_size:         U64,
     pub kind:               u8,
 }
 impl PayloadHeader {
     pub fn len(&self) -> u64 {
-        self.directory_sections + self.file_sections + self.symlink_sections
+        self.directory_sections.get()
+            + self.file_sections.get()
+            + self.symlink_sections.get()
+            + self.hardlink_sections.get()
     }
 }
+/// Position and size of a name in the name table following the hardlink sections.
 #[repr(C, packed)]
-#[derive(IntoBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Clone, Copy, Default)]
+pub struct NameRef {
+    pub offset: U32,
+    pub size:   U32,
+}
+#[repr(C, packed)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct DirectorySection {
-    pub name:   [u8; NAME_SIZE],
-    pub parent: u32,
+    pub name:                  NameRef,
+    pub parent:                U32,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub mode:                  U32,
 }
+/// Compressed contents of a file at `position`, or of the solid block containing the file if
+/// `solid` is set, in which case the file is `block_size` bytes at `block_offset` in the
+/// decompressed block. If `placeholder` is 1, the file starts with a shebang whose interpreter
+/// path starts with [`UNPACK_DIR_PLACEHOLDER`], if it is 2, all occurrences of the placeholder
+/// in the file are replaced.
 #[repr(C, packed)]
-#[derive(IntoBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct FileSectionHeader {
-    pub position:              u64,
-    pub size:                  u64,
-    pub name:                  [u8; NAME_SIZE],
-    pub file_hash:             u64,
-    pub compressed_hash:       u64,
-    pub time_accessed_seconds: u64,
-    pub time_modified_seconds: u64,
-    pub parent:                u32,
-    pub mode:                  u32,
-    pub time_accessed_nanos:   u32,
-    pub time_modified_nanos:   u32,
+    pub position:              U64,
+    pub size:                  U64,
+    pub name:                  NameRef,
+    pub file_hash:             U64,
+    pub compressed_hash:       U64,
+    pub block_offset:          U64,
+    pub block_size:            U64,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub parent:                U32,
+    pub mode:                  U32,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub uid:                   U32,
+    pub gid:                   U32,
     pub readonly:              u8,
+    pub stored:                u8,
+    pub solid:                 u8,
+    pub placeholder:           u8,
 }
+/// Symlink to the directory (kind 0) or file (kind 1) section at `target`, or an external
+/// symlink to a directory (kind 2) or file (kind 3) outside of the payload at the path `link`.
 #[repr(C, packed)]
-#[derive(IntoBytes, Immutable, KnownLayout)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
 pub struct SymlinkSection {
-    pub name:                  [u8; NAME_SIZE],
-    pub parent:                u32,
-    pub target:                u32,
-    pub time_accessed_seconds: u64,
-    pub time_modified_seconds: u64,
-    pub time_accessed_nanos:   u32,
-    pub time_modified_nanos:   u32,
-    pub mode:                  u32,
+    pub name:                  NameRef,
+    pub link:                  NameRef,
+    pub parent:                U32,
+    pub target:                U32,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub mode:                  U32,
     pub kind:                  u8,
     pub readonly:              u8,
 }
+/// Hardlink to the file section at `target`, sharing its contents and metadata.
+#[repr(C, packed)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+pub struct HardlinkSection {
+    pub name:   NameRef,
+    pub parent: U32,
+    pub target: U32,
+}
+
+/// Names of the directory, file, symlink and hardlink sections, stored after the hardlink sections.
+#[derive(Default)]
+pub struct NameTable(pub Vec<u8>);
+impl NameTable {
+    /// Append `name` to the table and return its position and size.
+    pub fn push(&mut self, name: &str) -> NameRef {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        NameRef {
+            offset: offset.into(),
+            size:   (name.len() as u32).into(),
+        }
+    }
+
+    /// Bytes of `name`, or `None` if it isn't within the table.
+    pub fn bytes(&self, name: NameRef) -> Option<&[u8]> {
+        let (offset, size) = (name.offset.get() as usize, name.size.get() as usize);
+        self.0.get(offset..offset.checked_add(size)?)
+    }
+
+    /// Name at `name`, with invalid UTF-8 replaced.
+    pub fn get(&self, name: NameRef) -> String {
+        String::from_utf8_lossy(self.bytes(name).unwrap_or_default()).into_owned()
+    }
+}
+
+/// Header of an extra data section, followed by its name and data.
+#[repr(C, packed)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+pub struct ExtraSectionHeader {
+    pub name_size: U32,
+    pub data_size: U64,
+}
+
+/// Format the application ID as a hyphenated UUID.
+pub fn format_app_id(app_id: &[u8; 16]) -> String {
+    let hex = app_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Unpack directory name from `template` with the `{name}`, `{version}` and `{target}` fields
+/// replaced with the file name of `command` without extension, `version` and `target`. Path
+/// separators in the replaced values are replaced with `_`, `{{` and `}}` stand for literal
+/// braces, and unknown or unclosed fields are kept as they are.
+pub fn expand_unpack_directory(
+    template: &str, command: &str, version: &str, target: &str,
+) -> String {
+    let value = |value: &str| value.replace(['/', '\\'], "_");
+    let mut directory = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        directory.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let escaped = rest.starts_with("{{") || rest.starts_with("}}");
+        if escaped || rest.starts_with('}') {
+            directory.push_str(&rest[..1]);
+            rest = &rest[if escaped { 2 } else { 1 }..];
+            continue;
+        }
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match &rest[1..end] {
+            "name" => directory.push_str(&value(
+                &std::path::Path::new(command)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+            )),
+            "version" => directory.push_str(&value(version)),
+            "target" => directory.push_str(&value(target)),
+            _ => directory.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    directory.push_str(rest);
+    directory
+}
+
+/// Check that `name` can be used as the file name of an extra section.
+pub fn is_valid_section_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Names and data of the extra sections stored one after another in `data`,
+/// or `None` if the data is truncated or contains an invalid name.
+pub fn read_extra_sections(mut data: &[u8]) -> Option<Vec<(&str, &[u8])>> {
+    let mut sections = Vec::new();
+    while !data.is_empty() {
+        let (header, rest) = ExtraSectionHeader::read_from_prefix(data).ok()?;
+        let name_size = header.name_size.get() as usize;
+        let data_size = usize::try_from(header.data_size.get()).ok()?;
+        if rest.len() < name_size.checked_add(data_size)? {
+            return None;
+        }
+        let (name, rest) = rest.split_at(name_size);
+        let (section, rest) = rest.split_at(data_size);
+        let name = std::str::from_utf8(name).ok()?;
+        if !is_valid_section_name(name) {
+            return None;
+        }
+        sections.push((name, section));
+        data = rest;
+    }
+    Some(sections)
+}
+
+/// Separator of the baked command line arguments stored before the startup configuration.
+pub const ARGUMENT_SEPARATOR: char = '\u{1f}';
+
+/// Encode `value` into a fixed-size field padded with null bytes.
+/// Returns `None` if `value` is longer than the field or contains null bytes,
+/// since the field is decoded up to the first null byte.
+pub fn encode_field<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() > N || value.contains('\0') {
+        return None;
+    }
+    let mut field = [0; N];
+    field[..value.len()].copy_from_slice(value.as_bytes());
+    Some(field)
+}
+
+/// Error of a version field of the startup configuration that can't be decoded.
+#[derive(Debug)]
+pub enum VersionError {
+    /// The version isn't valid UTF-8 from the byte at `position` on.
+    InvalidUtf8 { value: String, position: usize },
+    /// Data follows the null padding of the version, which is either longer than the field or
+    /// not written by the packer.
+    TrailingData { value: String },
+}
+impl std::fmt::Display for VersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionError::InvalidUtf8 { value, position } => write!(
+                f,
+                "version {:?} is not valid UTF-8 at byte {}",
+                value, position
+            ),
+            VersionError::TrailingData { value } => write!(
+                f,
+                "version {:?} is followed by data in its null padding",
+                value
+            ),
+        }
+    }
+}
+impl std::error::Error for VersionError {}
+
+/// Decode the version stored in the `uid` field of the startup configuration, the inverse of
+/// [`encode_field`].
+pub fn decode_version(field: &[u8; 16]) -> Result<&str, VersionError> {
+    let end = field
+        .iter()
+        .position(|&c| c == b'\0')
+        .unwrap_or(field.len());
+    let (version, padding) = field.split_at(end);
+    let lossy = || String::from_utf8_lossy(version).into_owned();
+    if padding.iter().any(|&c| c != b'\0') {
+        return Err(VersionError::TrailingData { value: lossy() });
+    }
+    std::str::from_utf8(version).map_err(|e| VersionError::InvalidUtf8 {
+        value:    lossy(),
+        position: e.valid_up_to(),
+    })
+}
+
+/// Append the extra section `name` with `data` to the extra sections in `buffer`.
+pub fn write_extra_section(buffer: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let header = ExtraSectionHeader {
+        name_size: (name.len() as u32).into(),
+        data_size: (data.len() as u64).into(),
+    };
+    buffer.extend_from_slice(header.as_bytes());
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.extend_from_slice(data);
+}
+
+/// Encode `arguments` separated by [`ARGUMENT_SEPARATOR`].
+pub fn encode_arguments(arguments: &[String]) -> Result<Vec<u8>, String> {
+    if let Some(argument) = arguments
+        .iter()
+        .find(|argument| argument.contains([ARGUMENT_SEPARATOR, '\0']))
+    {
+        return Err(format!(
+            "argument contains a null or unit separator character: {:?}",
+            argument
+        ));
+    }
+    // empty data decodes to no arguments
+    if arguments.len() == 1 && arguments[0].is_empty() {
+        return Err("a single empty argument can't be baked".to_string());
+    }
+    Ok(arguments.join(&ARGUMENT_SEPARATOR.to_string()).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator, so that failures are reproducible.
+    struct Random(u64);
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize { (self.next() % n as u64) as usize }
+
+        /// String of up to `max` characters of 1 to 4 bytes, including null and separator characters.
+        fn string(&mut self, max: usize) -> String {
+            const CHARS: [char; 10] = [
+                'a',
+                ' ',
+                '\0',
+                ARGUMENT_SEPARATOR,
+                'é',
+                'ß',
+                '中',
+                '€',
+                '🚀',
+                '𝄞',
+            ];
+            (0..self.below(max + 1))
+                .map(|_| CHARS[self.below(CHARS.len())])
+                .collect()
+        }
+    }
+
+    /// Decode a field in the same way as the runner.
+    fn decode(field: &[u8]) -> &str {
+        std::str::from_utf8(
+            &field[..field
+                .iter()
+                .position(|&c| c == b'\0')
+                .unwrap_or(field.len())],
+        )
+        .expect("encoded field is not valid utf-8")
+    }
+
+    #[test]
+    fn encode_field_roundtrips_or_rejects() {
+        let mut random = Random(0x5EED);
+        for _ in 0..10000 {
+            let value = random.string(NAME_SIZE / 2);
+            match encode_field::<NAME_SIZE>(&value) {
+                Some(field) => assert_eq!(decode(&field), value),
+                None => assert!(
+                    value.len() > NAME_SIZE || value.contains('\0'),
+                    "{:?}",
+                    value
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_field_multibyte_boundary() {
+        for padding in NAME_SIZE - 4..=NAME_SIZE {
+            for character in ['é', '中', '🚀'] {
+                let value = format!("{}{}", "a".repeat(padding), character);
+                match encode_field::<NAME_SIZE>(&value) {
+                    Some(field) => assert_eq!(decode(&field), value),
+                    None => assert!(value.len() > NAME_SIZE),
+                }
+            }
+        }
+        assert!(encode_field::<NAME_SIZE>(&"é".repeat(NAME_SIZE / 2)).is_some());
+        assert!(encode_field::<NAME_SIZE>(&format!("a{}", "é".repeat(NAME_SIZE / 2))).is_none());
+    }
+
+    #[test]
+    fn encode_arguments_roundtrips_or_rejects() {
+        let mut random = Random(0xA465);
+        for _ in 0..10000 {
+            let arguments = (0..random.below(12))
+                .map(|_| random.string(96))
+                .collect::<Vec<_>>();
+            match encode_arguments(&arguments) {
+                Ok(field) => {
+                    let decoded = decode(&field);
+                    let decoded = if decoded.is_empty() {
+                        Vec::new()
+                    } else {
+                        decoded.split(ARGUMENT_SEPARATOR).collect::<Vec<_>>()
+                    };
+                    assert_eq!(decoded, arguments);
+                }
+                Err(_) => assert!(
+                    arguments
+                        .iter()
+                        .any(|argument| argument.contains([ARGUMENT_SEPARATOR, '\0']))
+                        || arguments == [""],
+                    "{:?}",
+                    arguments
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn sections_are_little_endian() {
+        let name = NameRef {
+            offset: 0x04030201.into(),
+            size:   0x08070605.into(),
+        };
+        assert_eq!(name.as_bytes(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut buffer = Vec::new();
+        write_extra_section(&mut buffer, "a", &[0xff; 0x0102]);
+        assert_eq!(buffer[..13], [1, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0, b'a']);
+        let header = PayloadHeader::read_from_bytes(
+            &(0..std::mem::size_of::<PayloadHeader>() as u8).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert_eq!(header.directory_sections.get(), 0x0706050403020100);
+        assert_eq!(header.names_size.get(), 0x6766656463626160);
+        assert_eq!(header.payload_hash[0], 0x30);
+        assert_eq!(header.kind, 0x68);
+    }
+
+    #[test]
+    fn decode_version_reports_malformed_fields() {
+        let field = encode_field::<16>("版本 1.0").unwrap();
+        assert_eq!(decode_version(&field).unwrap(), "版本 1.0");
+        assert_eq!(decode_version(&[b'a'; 16]).unwrap(), "a".repeat(16));
+
+        let mut field = encode_field::<16>("1.0-\u{e9}").unwrap();
+        field[5] = 0xff;
+        assert!(matches!(
+            decode_version(&field),
+            Err(VersionError::InvalidUtf8 { position: 4, .. })
+        ));
+        let mut field = encode_field::<16>("1.0").unwrap();
+        field[8] = b'x';
+        assert!(matches!(
+            decode_version(&field),
+            Err(VersionError::TrailingData { .. })
+        ));
+    }
+}
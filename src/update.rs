@@ -0,0 +1,782 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    hash::Hasher,
+    io::{BufReader, BufWriter, Cursor, Read, Result, Seek, SeekFrom, Write, copy},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use clap::Args;
+use console::style;
+use twox_hash::XxHash64;
+use zstd::dict::EncoderDictionary;
+
+use crate::{
+    args::{OrExit, emoji, get_signing_key, get_version},
+    compress::{
+        HASH_SEED, HashReader, INCOMPRESSIBLE_SAMPLE_SIZE, PayloadWriter, SETUID_BITS,
+        content_hash, copy_encode, is_incompressible,
+    },
+    payload::{Payload, join, payload_path},
+    remove_temporary_output, replace_output,
+    sbom::embed_manifest,
+    set_temporary_output,
+    signing::{hex, sign_payload},
+    test_run,
+    types::*,
+    validate::validate,
+};
+
+#[derive(Args)]
+#[command(about = "Replace, add or remove files in the payload of a packed executable")]
+pub struct UpdateArgs {
+    /// Add or replace the file at PATH in the payload with FILE
+    #[arg(short = 'a', long, num_args = 2, value_names = ["PATH", "FILE"])]
+    add:                  Vec<String>,
+    /// Remove the file, symlink or directory at PATH from the payload
+    #[arg(short = 'r', long, value_name = "PATH")]
+    remove:               Vec<String>,
+    /// Compression level of added files
+    #[arg(short = 'c', long, default_value = "8")]
+    compression:          u32,
+    /// Version string override or auto-hash to derive it from the contents [default: randomly generated]
+    #[arg(short = 's', long)]
+    version_string:       Option<String>,
+    /// Keep the setuid and setgid bits of added files instead of stripping them (Unix)
+    #[arg(long, default_value = "false")]
+    allow_setuid:         bool,
+    /// Sign the updated payload with the Ed25519 private key in the file [default: remove the signature]
+    #[arg(long, value_name = "PATH")]
+    sign_key:             Option<PathBuf>,
+    /// Skip validating the output executable after updating
+    #[arg(long, default_value = "false")]
+    no_validate:          bool,
+    /// Keep the previous executable as <output>.bak
+    #[arg(long, default_value = "false")]
+    backup:               bool,
+    /// Launch the updated executable to check that it starts, optionally with the given argument, and restore the previous executable if it fails [implies --backup]
+    #[arg(long, value_name = "ARG", num_args = 0..=1, require_equals = true, allow_hyphen_values = true)]
+    health_check:         Option<Vec<String>>,
+    /// Kill the health-checked executable and restore the previous executable if it doesn't exit within the timeout in seconds
+    #[arg(long, value_name = "SECONDS", requires = "health_check")]
+    health_check_timeout: Option<u64>,
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:            bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable:           PathBuf,
+    /// Path to write the updated executable to [default: update the packed executable]
+    #[arg(name = "output")]
+    output:               Option<PathBuf>,
+}
+
+/// File in the updated payload with the path of its new contents if it was added or replaced.
+struct UpdatedFile {
+    path:     String,
+    section:  FileSectionHeader,
+    contents: Option<PathBuf>,
+}
+
+fn fail(message: &str, detail: impl std::fmt::Display) -> ! {
+    remove_temporary_output();
+    println!("{}: {}", style(message).red(), detail);
+    std::process::exit(-1);
+}
+
+/// Normalize a path in the payload to its components separated by slashes.
+fn normalize(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_below(path: &str, parent: &str) -> bool {
+    path == parent || (path.starts_with(parent) && path.as_bytes().get(parent.len()) == Some(&b'/'))
+}
+
+/// Compress the file at `path` into `target` and update `section` with its hashes and metadata.
+/// The owner and group are recorded if `ownership` is set, the setuid and setgid bits are
+/// stripped unless `allow_setuid` is set.
+/// Returns the number of written bytes.
+#[allow(clippy::too_many_arguments)]
+fn write_file<W: Write>(
+    path: &Path, section: &mut FileSectionHeader, target: &mut W, algorithm: u8, compression: u32,
+    dictionary: Option<&EncoderDictionary>, ownership: bool, allow_setuid: bool,
+) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let meta = file.metadata()?;
+    let stored =
+        meta.len() >= INCOMPRESSIBLE_SAMPLE_SIZE && is_incompressible(&mut file).unwrap_or(false);
+
+    let mut reader = HashReader::new(BufReader::new(file), XxHash64::with_seed(HASH_SEED));
+    let mut data = Vec::new();
+    if stored {
+        reader.read_to_end(&mut data)?;
+    } else {
+        copy_encode(
+            &mut reader,
+            &mut data,
+            algorithm,
+            compression as i32,
+            0,
+            dictionary,
+        )?;
+    }
+    section.file_hash = reader.finish().into();
+
+    let mut hasher = HashReader::new(Cursor::new(&data), XxHash64::with_seed(HASH_SEED));
+    copy(&mut hasher, target)?;
+    section.compressed_hash = hasher.finish().into();
+    section.size = (data.len() as u64).into();
+    section.stored = stored as u8;
+    section.solid = 0;
+    // replacing contents are packed as they are, without a shebang placeholder
+    section.placeholder = 0;
+    section.block_offset = 0.into();
+    section.block_size = 0.into();
+
+    if let Ok(accessed) = meta.accessed() {
+        if let Ok(accessed) = accessed.duration_since(SystemTime::UNIX_EPOCH) {
+            section.time_accessed_seconds = accessed.as_secs().into();
+            section.time_accessed_nanos = accessed.subsec_nanos().into();
+        }
+    }
+    if let Ok(modified) = meta.modified() {
+        if let Ok(modified) = modified.duration_since(SystemTime::UNIX_EPOCH) {
+            section.time_modified_seconds = modified.as_secs().into();
+            section.time_modified_nanos = modified.subsec_nanos().into();
+        }
+    }
+    section.readonly = meta.permissions().readonly() as u8;
+    #[cfg(any(unix, target_os = "redox"))]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        section.mode = meta.permissions().mode().into();
+    }
+    if !allow_setuid {
+        section.mode = (section.mode.get() & !SETUID_BITS).into();
+    }
+    if ownership {
+        #[cfg(any(unix, target_os = "redox"))]
+        {
+            use std::os::unix::fs::MetadataExt;
+            section.uid = meta.uid().into();
+            section.gid = meta.gid().into();
+        }
+    }
+
+    Ok(section.size.get())
+}
+
+/// Replace, add or remove files in the payload of an existing packed executable.
+/// Compressed contents of unchanged files are copied over without recompressing them,
+/// the sections, payload header and startup configuration are rewritten.
+pub fn update(args: UpdateArgs) {
+    let executable = args.executable;
+    let output = args.output.unwrap_or_else(|| executable.clone());
+    // only the pack file is rewritten for split runners
+    let payload_file = payload_path(&executable);
+    let split = payload_file != executable;
+    let destination = if split {
+        output.with_extension(PACK_EXTENSION)
+    } else {
+        output.clone()
+    };
+    let signing_key = get_signing_key(args.sign_key.as_deref()).or_exit();
+
+    println!(
+        "{} {}reading payload of {}…",
+        style("[1/3]").bold().dim(),
+        emoji("🔍 ", ""),
+        style(executable.display()).blue().bright()
+    );
+    let mut source =
+        File::open(&payload_file).unwrap_or_else(|e| fail("couldn't open packed executable", e));
+    let payload = Payload::read(&mut source).unwrap_or_else(|e| fail("couldn't read payload", e));
+    let mut paths = payload.directory_paths();
+    let Payload {
+        mut info,
+        header,
+        files_start,
+        dictionary,
+        mut directories,
+        files,
+        symlinks,
+        hardlinks,
+        names,
+        manifest,
+        extra_sections,
+        arguments,
+    } = payload;
+    let algorithm = header.kind;
+    let signed = info.public_key != [0; 32];
+
+    let mut kept = vec![true; paths.len()];
+    let mut files = files
+        .into_iter()
+        .map(|section| {
+            Some(UpdatedFile {
+                path: join(
+                    &paths[section.parent.get() as usize],
+                    &names.get(section.name),
+                ),
+                section,
+                contents: None,
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut symlinks = symlinks
+        .into_iter()
+        .map(|section| {
+            Some((
+                join(
+                    &paths[section.parent.get() as usize],
+                    &names.get(section.name),
+                ),
+                names.get(section.link),
+                section,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let mut hardlinks = hardlinks
+        .into_iter()
+        .map(|section| {
+            Some((
+                join(
+                    &paths[section.parent.get() as usize],
+                    &names.get(section.name),
+                ),
+                section,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{} {}updating payload…",
+        style("[2/3]").bold().dim(),
+        emoji("🚚 ", ""),
+    );
+
+    for remove in args.remove.iter() {
+        let remove = normalize(remove);
+        if remove.is_empty() {
+            fail("couldn't remove", "the root directory can't be removed");
+        }
+        let mut removed = 0;
+        for (i, path) in paths.iter().enumerate().skip(1) {
+            if kept[i] && is_below(path, &remove) {
+                kept[i] = false;
+                removed += 1;
+            }
+        }
+        for file in files.iter_mut() {
+            if file
+                .as_ref()
+                .is_some_and(|file| is_below(&file.path, &remove))
+            {
+                *file = None;
+                removed += 1;
+            }
+        }
+        for symlink in symlinks.iter_mut() {
+            if symlink
+                .as_ref()
+                .is_some_and(|(path, _, _)| is_below(path, &remove))
+            {
+                *symlink = None;
+                removed += 1;
+            }
+        }
+        for hardlink in hardlinks.iter_mut() {
+            if hardlink
+                .as_ref()
+                .is_some_and(|(path, _)| is_below(path, &remove))
+            {
+                *hardlink = None;
+                removed += 1;
+            }
+        }
+        if removed == 0 {
+            fail("couldn't find path in payload", &remove);
+        }
+        println!(
+            "      {}{}",
+            emoji("➖ ", ""),
+            style(format!("removed {}", remove)).dim()
+        );
+    }
+
+    for add in args.add.chunks(2) {
+        let (path, contents) = (normalize(&add[0]), PathBuf::from(&add[1]));
+        if !contents.is_file() {
+            fail("couldn't find file", contents.display());
+        }
+        #[cfg(any(unix, target_os = "redox"))]
+        if std::fs::metadata(&contents).is_ok_and(|meta| {
+            std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & SETUID_BITS != 0
+        }) {
+            let message = if args.allow_setuid {
+                format!("keeping the setuid and setgid bits of {}", path)
+            } else {
+                format!(
+                    "stripping the setuid and setgid bits of {} (keep them with --allow-setuid)",
+                    path
+                )
+            };
+            println!("      {}{}", emoji("❗ ", ""), style(message).yellow());
+        }
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if name.is_empty() {
+            fail("not a valid file path", &path);
+        }
+        if let Some(file) = files.iter_mut().flatten().find(|file| file.path == path) {
+            file.contents = Some(contents);
+            println!(
+                "      {}{}",
+                emoji("📝 ", ""),
+                style(format!("replaced {}", path)).dim()
+            );
+            continue;
+        }
+        if paths
+            .iter()
+            .enumerate()
+            .any(|(i, directory)| kept[i] && directory == &path)
+            || symlinks.iter().flatten().any(|(link, _, _)| link == &path)
+            || hardlinks.iter().flatten().any(|(link, _)| link == &path)
+        {
+            fail("path already exists in payload", &path);
+        }
+
+        // create missing parent directories
+        let mut parent = 0;
+        let mut parent_path = String::new();
+        for component in path.split('/').take(path.split('/').count() - 1) {
+            parent_path = join(&parent_path, component);
+            parent = match paths
+                .iter()
+                .enumerate()
+                .position(|(i, directory)| kept[i] && directory == &parent_path)
+            {
+                Some(index) => index,
+                None => {
+                    directories.push(DirectorySection {
+                        name:                  NameRef::default(),
+                        parent:                (parent as u32).into(),
+                        time_accessed_seconds: 0.into(),
+                        time_modified_seconds: 0.into(),
+                        time_accessed_nanos:   0.into(),
+                        time_modified_nanos:   0.into(),
+                        mode:                  0.into(),
+                    });
+                    paths.push(parent_path.clone());
+                    kept.push(true);
+                    paths.len() - 1
+                }
+            };
+        }
+
+        files.push(Some(UpdatedFile {
+            path:     path.clone(),
+            section:  FileSectionHeader {
+                position:              0.into(),
+                size:                  0.into(),
+                name:                  NameRef::default(),
+                file_hash:             0.into(),
+                compressed_hash:       0.into(),
+                block_offset:          0.into(),
+                block_size:            0.into(),
+                time_accessed_seconds: 0.into(),
+                time_modified_seconds: 0.into(),
+                parent:                (parent as u32).into(),
+                mode:                  0.into(),
+                time_accessed_nanos:   0.into(),
+                time_modified_nanos:   0.into(),
+                uid:                   0.into(),
+                gid:                   0.into(),
+                readonly:              0,
+                stored:                0,
+                solid:                 0,
+                placeholder:           0,
+            },
+            contents: Some(contents),
+        }));
+        println!(
+            "      {}{}",
+            emoji("➕ ", ""),
+            style(format!("added {}", path)).dim()
+        );
+    }
+
+    // renumber the remaining sections and rebuild the name table from their paths
+    let mut names = NameTable::default();
+    let name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+    let mut directory_indices = vec![None; paths.len()];
+    let mut next = 0;
+    for (i, index) in directory_indices.iter_mut().enumerate() {
+        if kept[i] {
+            *index = Some(next);
+            next += 1;
+        }
+    }
+    let directories = directories
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| kept[i + 1])
+        .map(|(i, mut section)| {
+            section.parent = directory_indices[section.parent.get() as usize]
+                .unwrap()
+                .into();
+            section.name = names.push(&name(&paths[i + 1]));
+            section
+        })
+        .collect::<Vec<_>>();
+    let mut file_indices = vec![None; files.len()];
+    let mut next = 0;
+    for (i, file) in files.iter().enumerate() {
+        if file.is_some() {
+            file_indices[i] = Some(next);
+            next += 1;
+        }
+    }
+    let mut links = Vec::new();
+    let mut contents = Vec::new();
+    let mut files = files
+        .into_iter()
+        .flatten()
+        .map(|mut file| {
+            file.section.parent = directory_indices[file.section.parent.get() as usize]
+                .unwrap()
+                .into();
+            file.section.name = names.push(&name(&file.path));
+            links.push(file.path);
+            contents.push(file.contents);
+            file.section
+        })
+        .collect::<Vec<_>>();
+    let symlinks = symlinks
+        .into_iter()
+        .flatten()
+        .filter_map(|(path, link, mut section)| {
+            let target = match section.kind {
+                0 => directory_indices[section.target.get() as usize],
+                1 => file_indices[section.target.get() as usize],
+                _ => {
+                    section.link = names.push(&link);
+                    Some(section.target.get())
+                }
+            };
+            let Some(target) = target else {
+                println!(
+                    "      {}{}",
+                    emoji("❗ ", ""),
+                    style(format!("removed {} pointing to a removed target", path)).red()
+                );
+                return None;
+            };
+            section.target = target.into();
+            section.parent = directory_indices[section.parent.get() as usize]
+                .unwrap()
+                .into();
+            section.name = names.push(&name(&path));
+            Some(section)
+        })
+        .collect::<Vec<_>>();
+    let hardlinks = hardlinks
+        .into_iter()
+        .flatten()
+        .filter_map(|(path, mut section)| {
+            let Some(target) = file_indices[section.target.get() as usize] else {
+                println!(
+                    "      {}{}",
+                    emoji("❗ ", ""),
+                    style(format!("removed {} linking to a removed file", path)).red()
+                );
+                return None;
+            };
+            section.target = target.into();
+            section.parent = directory_indices[section.parent.get() as usize]
+                .unwrap()
+                .into();
+            section.name = names.push(&name(&path));
+            Some(section)
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{} {}writing {}…",
+        style("[3/3]").bold().dim(),
+        emoji("📃 ", ""),
+        style(destination.display()).blue().bright()
+    );
+
+    let mut temporary_output = OsString::from(destination.as_os_str());
+    temporary_output.push(".tmp");
+    let temporary_output = PathBuf::from(temporary_output);
+    let target =
+        File::create(&temporary_output).unwrap_or_else(|e| fail("couldn't create output file", e));
+    set_temporary_output(&temporary_output);
+
+    let result = (|| -> Result<()> {
+        let mut writer = BufWriter::new(target);
+        source.rewind()?;
+        copy(&mut (&mut source).take(files_start), &mut writer)?;
+        let mut writer = PayloadWriter::new(writer);
+
+        let encoder_dictionary = (!dictionary.is_empty())
+            .then(|| EncoderDictionary::copy(&dictionary, args.compression as i32));
+        let mut position = 0;
+        // solid blocks are copied once for all files they contain
+        let mut blocks = HashMap::<u64, u64>::new();
+        for (section, contents) in files.iter_mut().zip(contents.iter()) {
+            let block = section.position.get();
+            if contents.is_none() && section.solid == 1 {
+                if let Some(&block_position) = blocks.get(&block) {
+                    section.position = block_position.into();
+                    continue;
+                }
+                blocks.insert(block, position);
+            }
+            let size = if let Some(contents) = contents {
+                write_file(
+                    contents,
+                    section,
+                    &mut writer,
+                    algorithm,
+                    args.compression,
+                    encoder_dictionary.as_ref(),
+                    info.ownership == 1,
+                    args.allow_setuid,
+                )
+                .map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("{}: {}", contents.display(), e))
+                })?
+            } else {
+                source.seek(SeekFrom::Start(files_start + section.position.get()))?;
+                copy(&mut (&mut source).take(section.size.get()), &mut writer)?
+            };
+            section.position = position.into();
+            position += size;
+        }
+        writer.write_all(&dictionary)?;
+
+        let mut sections = Vec::new();
+        for section in directories.iter() {
+            sections.extend_from_slice(section.as_bytes());
+        }
+        for section in files.iter() {
+            sections.extend_from_slice(section.as_bytes());
+        }
+        for section in symlinks.iter() {
+            sections.extend_from_slice(section.as_bytes());
+        }
+        for section in hardlinks.iter() {
+            sections.extend_from_slice(section.as_bytes());
+        }
+        sections.extend_from_slice(&names.0);
+        let mut hasher = XxHash64::with_seed(HASH_SEED);
+        hasher.write(&sections);
+        let mut compressed = Vec::new();
+        copy_encode(
+            &sections[..],
+            &mut compressed,
+            0,
+            args.compression as i32,
+            0,
+            None,
+        )?;
+        writer.write_all(&compressed)?;
+        let payload_hash = writer.finish();
+
+        let payload_header = PayloadHeader {
+            kind: algorithm,
+            directory_sections: (directories.len() as u64).into(),
+            file_sections: (files.len() as u64).into(),
+            symlink_sections: (symlinks.len() as u64).into(),
+            hardlink_sections: (hardlinks.len() as u64).into(),
+            dictionary_size: (dictionary.len() as u64).into(),
+            section_hash: hasher.finish().into(),
+            payload_hash,
+            payload_size: position.into(),
+            sections_size: (compressed.len() as u64).into(),
+            names_size: (names.0.len() as u64).into(),
+        };
+        writer.write_all(payload_header.as_bytes())?;
+
+        let version = match args.version_string.as_deref() {
+            Some("auto-hash") => get_version(Some(&format!(
+                "{:016x}",
+                content_hash(&directories, &files, &symlinks, &hardlinks, &links, &names)
+            )))
+            .or_exit(),
+            version => get_version(version).or_exit(),
+        };
+        info.uid = version.as_bytes().try_into().unwrap();
+        // the signature of the previous payload doesn't match the updated payload
+        info.public_key = signing_key
+            .as_ref()
+            .map(|key| key.verifying_key().to_bytes())
+            .unwrap_or_default();
+        info.payload_signature = [0; 64];
+        // the manifest of the previous payload is regenerated after writing the updated payload
+        info.manifest_size = 0.into();
+        writer.write_all(&extra_sections)?;
+        writer.write_all(&arguments)?;
+        writer.write_all(info.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        fail("couldn't write output file", e);
+    }
+    drop(source);
+
+    if !manifest.is_empty() {
+        let files = embed_manifest(&temporary_output)
+            .unwrap_or_else(|e| fail("couldn't embed file manifest", e));
+        println!(
+            "      {}{}",
+            emoji("📜 ", ""),
+            style(format!("embedded manifest of {} files", files)).dim(),
+        );
+    }
+
+    if let Some(key) = &signing_key {
+        sign_payload(&temporary_output, key).unwrap_or_else(|e| fail("couldn't sign payload", e));
+        println!(
+            "      {}{}",
+            emoji("🔏 ", ""),
+            style(format!(
+                "signed payload (public key {})",
+                hex(key.verifying_key().as_bytes())
+            ))
+            .dim(),
+        );
+    } else if signed {
+        println!(
+            "      {}{}",
+            emoji("❗ ", ""),
+            style("removed the payload signature, use --sign-key to sign the updated payload")
+                .yellow(),
+        );
+    }
+
+    #[cfg(any(unix, target_os = "redox"))]
+    if !split {
+        use std::{
+            fs::{metadata, set_permissions},
+            os::unix::prelude::*,
+        };
+        let mode = metadata(&executable)
+            .map(|metadata| metadata.permissions().mode())
+            .unwrap_or(0o755);
+        if let Err(e) = set_permissions(&temporary_output, PermissionsExt::from_mode(mode | 0o111))
+        {
+            println!(
+                "      {}{} {}",
+                emoji("❗ ", ""),
+                style("failed to set permissions:").yellow(),
+                style(e).yellow()
+            );
+        }
+    }
+
+    if !args.no_validate {
+        match validate(&temporary_output) {
+            Ok(verified) => println!(
+                "      {}{}",
+                emoji("🔎 ", ""),
+                style(format!("validated output ({} files sampled)", verified)).dim(),
+            ),
+            Err(e) => fail("output validation failed", e),
+        }
+    }
+
+    let mut backup_path = OsString::from(destination.as_os_str());
+    backup_path.push(".bak");
+    let backup_path = PathBuf::from(backup_path);
+    let backup = (args.backup || args.health_check.is_some()) && destination.is_file();
+    if backup {
+        std::fs::copy(&destination, &backup_path).unwrap_or_else(|e| {
+            fail(
+                "couldn't back up previous executable",
+                format!("{}: {}", backup_path.display(), e),
+            )
+        });
+    }
+
+    replace_output(&temporary_output, &destination).unwrap_or_else(|e| {
+        fail(
+            "couldn't write output file",
+            format!("{}: {}", destination.display(), e),
+        )
+    });
+    // the runner is copied along with the pack file when writing to another path
+    if split && output != executable {
+        std::fs::copy(&executable, &output).unwrap_or_else(|e| {
+            fail(
+                "couldn't write output file",
+                format!("{}: {}", output.display(), e),
+            )
+        });
+    }
+    if backup {
+        println!(
+            "      {}{}",
+            emoji("💾 ", ""),
+            style(format!(
+                "kept previous executable as {}",
+                backup_path.display()
+            ))
+            .dim(),
+        );
+    }
+
+    if let Some(arguments) = &args.health_check {
+        println!(
+            "      {}{}",
+            emoji("🚀 ", ""),
+            style("health-checking updated executable…").dim()
+        );
+        let timeout = args.health_check_timeout.map(Duration::from_secs);
+        let error = match test_run(&output, arguments, timeout) {
+            Ok(Some(status)) if status.success() => None,
+            Ok(Some(status)) => Some(format!("health check failed: {}", status)),
+            Ok(None) => Some(format!(
+                "health check timed out after {} seconds",
+                args.health_check_timeout.unwrap_or_default()
+            )),
+            Err(e) => Some(format!("couldn't start health check: {}", e)),
+        };
+        if let Some(error) = error {
+            println!("{}", style(error).red());
+            if !backup {
+                std::process::exit(-1);
+            }
+            match replace_output(&backup_path, &destination) {
+                Ok(()) => println!(
+                    "{}",
+                    style("rolled back to the previous executable").yellow()
+                ),
+                Err(e) => println!(
+                    "{}: {}: {}",
+                    style("couldn't roll back to the previous executable").red(),
+                    backup_path.display(),
+                    e
+                ),
+            }
+            std::process::exit(-1);
+        }
+        println!(
+            "      {}{}",
+            emoji("✨ ", ""),
+            style("health check succeeded").green()
+        );
+    }
+
+    println!("      {}{}", emoji("✨ ", ""), style("done!").green());
+}
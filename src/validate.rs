@@ -0,0 +1,64 @@
+use std::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, copy, sink},
+    path::Path,
+};
+
+use twox_hash::XxHash64;
+
+use crate::{
+    compress::{HASH_SEED, HashReader},
+    payload::Payload,
+};
+
+/// Maximum number of file sections to verify the compressed hash of.
+const SAMPLE_SIZE: usize = 16;
+
+fn invalid(message: String) -> Error { Error::new(ErrorKind::InvalidData, message) }
+
+/// Re-open the packed executable at `path` and check that the startup configuration
+/// and payload can be read back. Verifies the section hash and the compressed hash of
+/// a sample of files. Returns the number of verified files.
+pub fn validate(path: &Path) -> Result<usize> {
+    let mut file = File::open(path)?;
+    let payload = Payload::read(&mut file)?;
+
+    let files = &payload.files;
+    let step = files.len().div_ceil(SAMPLE_SIZE).max(1);
+    let mut samples = (0..files.len())
+        .step_by(step)
+        .chain(files.len().checked_sub(1))
+        .collect::<Vec<_>>();
+    samples.dedup();
+    let payload_size = payload.header.payload_size.get();
+    for &i in samples.iter() {
+        let section = &files[i];
+        let (position, size) = (section.position.get(), section.size.get());
+        if position + size > payload_size {
+            return Err(invalid(format!(
+                "file data ({}..{}) exceeds payload size ({})",
+                position,
+                position + size,
+                payload_size
+            )));
+        }
+        file.seek(SeekFrom::Start(payload.files_start + position))?;
+        let mut reader = HashReader::new(
+            BufReader::new((&mut file).take(size)),
+            XxHash64::with_seed(HASH_SEED),
+        );
+        copy(&mut reader, &mut sink())?;
+        let compressed_hash = reader.finish();
+        if compressed_hash != section.compressed_hash.get() {
+            let expected = section.compressed_hash.get();
+            return Err(invalid(format!(
+                "compressed file hash ({}) differs from expected hash ({}) for {}",
+                compressed_hash,
+                expected,
+                payload.names.get(section.name)
+            )));
+        }
+    }
+
+    Ok(samples.len())
+}
@@ -0,0 +1,280 @@
+use std::{
+    fs::File,
+    hash::Hasher,
+    io::{BufReader, Read, Result, Seek, SeekFrom, Write, copy},
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use console::style;
+use rayon::prelude::*;
+use twox_hash::XxHash64;
+use zstd::dict::DecoderDictionary;
+
+use crate::{
+    compress::{HASH_SEED, HashReader, copy_decode},
+    payload::{Payload, SolidBlocks, find_info, join, payload_path, solid_contents},
+    signing::{hex, verify_payload},
+    types::*,
+};
+
+#[derive(Args)]
+#[command(about = "Check the startup configuration, sections and all files of a packed executable")]
+pub struct VerifyArgs {
+    /// Plain output without colors and emoji
+    #[arg(long)]
+    pub plain:  bool,
+    /// Path to the packed executable
+    #[arg(name = "executable")]
+    executable: PathBuf,
+}
+
+/// Writer that only hashes the written data.
+struct HashWriter<H: Hasher>(H);
+impl<H: Hasher> Write for HashWriter<H> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> { Ok(()) }
+}
+
+/// Decompress the file in `section` of the payload in `executable` and compare the hashes
+/// of its compressed and decompressed contents to the recorded hashes. Files in solid blocks
+/// are taken from the decompressed blocks in `blocks`.
+fn verify_file(
+    executable: &Path, files_start: u64, section: &FileSectionHeader, algorithm: u8,
+    dictionary: Option<&DecoderDictionary>, blocks: &SolidBlocks,
+) -> std::result::Result<(), String> {
+    if section.solid == 1 {
+        let contents = solid_contents(blocks, section).map_err(|e| e.to_string())?;
+        let mut hasher = XxHash64::with_seed(HASH_SEED);
+        hasher.write(contents);
+        let (file_hash, expected) = (hasher.finish(), section.file_hash.get());
+        if file_hash != expected {
+            return Err(format!(
+                "file hash ({}) differs from expected hash ({})",
+                file_hash, expected
+            ));
+        }
+        return Ok(());
+    }
+    let (compressed_hash, file_hash) = (|| -> Result<(u64, u64)> {
+        let mut file = File::open(executable)?;
+        file.seek(SeekFrom::Start(files_start + section.position.get()))?;
+        let mut reader = HashReader::new(
+            file.take(section.size.get()),
+            XxHash64::with_seed(HASH_SEED),
+        );
+        let mut writer = HashWriter(XxHash64::with_seed(HASH_SEED));
+        {
+            let mut reader = BufReader::new(&mut reader);
+            if section.stored == 1 {
+                copy(&mut reader, &mut writer)?;
+            } else {
+                copy_decode(&mut reader, &mut writer, algorithm, dictionary)?;
+            }
+        }
+        Ok((reader.finish(), writer.0.finish()))
+    })()
+    .map_err(|e| format!("couldn't decompress: {}", e))?;
+    if compressed_hash != section.compressed_hash.get() {
+        let expected = section.compressed_hash.get();
+        return Err(format!(
+            "compressed file hash ({}) differs from expected hash ({})",
+            compressed_hash, expected
+        ));
+    }
+    if file_hash != section.file_hash.get() {
+        let expected = section.file_hash.get();
+        return Err(format!(
+            "file hash ({}) differs from expected hash ({})",
+            file_hash, expected
+        ));
+    }
+    Ok(())
+}
+
+/// Compare the BLAKE3 hash of the compressed files, dictionary and sections of `payload` in
+/// `file` to the recorded payload hash, in the same way the runner checks it before unpacking.
+fn verify_payload_hash(file: &mut File, payload: &Payload) -> std::result::Result<String, String> {
+    let header = &payload.header;
+    let size =
+        header.payload_size.get() + header.dictionary_size.get() + header.sections_size.get();
+    let mut hasher = blake3::Hasher::new();
+    file.seek(SeekFrom::Start(payload.files_start))
+        .and_then(|_| copy(&mut Read::by_ref(file).take(size), &mut hasher))
+        .map_err(|e| e.to_string())?;
+    let (payload_hash, expected) = (hasher.finalize(), header.payload_hash);
+    if payload_hash.as_bytes() != &expected {
+        return Err(format!(
+            "payload hash ({}) differs from expected hash ({})",
+            hex(payload_hash.as_bytes()),
+            hex(&expected)
+        ));
+    }
+    Ok(format!("{} bytes (payload hash {})", size, hex(&expected)))
+}
+
+/// Print a line of the verification report.
+fn report(check: &str, result: &std::result::Result<String, String>) {
+    match result {
+        Ok(detail) => println!("{:<10} {} {}", check, style("ok    ").green(), detail),
+        Err(detail) => println!("{:<10} {} {}", check, style("failed").red(), detail),
+    }
+}
+
+fn fail() -> ! {
+    println!();
+    println!("{}", style("verification failed").red());
+    std::process::exit(-1);
+}
+
+/// Verify the packed executable in `args` end-to-end and print a report of the checks.
+/// Exits with a non-zero exit code if any check fails.
+pub fn verify(args: VerifyArgs) {
+    let executable = payload_path(&args.executable);
+    let mut file = File::open(&executable).unwrap_or_else(|e| {
+        println!("{}: {}", style("couldn't open packed executable").red(), e);
+        std::process::exit(-1);
+    });
+
+    let info = find_info(&mut file);
+    report(
+        "signature",
+        &info
+            .as_ref()
+            .map(|(position, _)| format!("found at offset {}", position))
+            .map_err(|e| e.to_string()),
+    );
+    let Ok((_, format)) = info else { fail() };
+
+    let format = if format == WRAPPE_FORMAT {
+        Ok(format!("{}", format))
+    } else {
+        Err(format!(
+            "{} differs from expected format {}",
+            format, WRAPPE_FORMAT
+        ))
+    };
+    report("format", &format);
+    if format.is_err() {
+        fail();
+    }
+
+    let payload = Payload::read(&mut file);
+    report(
+        "sections",
+        &payload
+            .as_ref()
+            .map(|payload| {
+                let section_hash = payload.header.section_hash.get();
+                format!(
+                    "{} directories, {} files, {} symlinks, {} hardlinks (section hash {:016x})",
+                    payload.directories.len(),
+                    payload.files.len(),
+                    payload.symlinks.len(),
+                    payload.hardlinks.len(),
+                    section_hash
+                )
+            })
+            .map_err(|e| e.to_string()),
+    );
+    let Ok(payload) = payload else { fail() };
+
+    let payload_hash = verify_payload_hash(&mut file, &payload);
+    report("payload", &payload_hash);
+
+    let signature = if payload.info.public_key == [0; 32] {
+        Ok("not signed".to_string())
+    } else {
+        verify_payload(&mut file, &payload.info).map(|_| {
+            format!(
+                "payload signed with public key {}",
+                hex(&payload.info.public_key)
+            )
+        })
+    };
+    report("signed", &signature);
+
+    let directories = payload.directory_paths();
+    let algorithm = payload.header.kind;
+    let dictionary =
+        (!payload.dictionary.is_empty()).then(|| DecoderDictionary::copy(&payload.dictionary));
+    let payload_size = payload.header.payload_size.get();
+    let blocks = payload.solid_blocks(&executable, dictionary.as_ref());
+    let errors = payload
+        .files
+        .par_iter()
+        .filter_map(|section| {
+            let path = join(
+                &directories[section.parent.get() as usize],
+                &payload.names.get(section.name),
+            );
+            let (position, size) = (section.position.get(), section.size.get());
+            if position + size > payload_size {
+                return Some(format!(
+                    "{}: file data ({}..{}) exceeds payload size ({})",
+                    path,
+                    position,
+                    position + size,
+                    payload_size
+                ));
+            }
+            verify_file(
+                &executable,
+                payload.files_start,
+                section,
+                algorithm,
+                dictionary.as_ref(),
+                &blocks,
+            )
+            .err()
+            .map(|e| format!("{}: {}", path, e))
+        })
+        .collect::<Vec<_>>();
+    let files = payload.files.len();
+    report(
+        "files",
+        &if errors.is_empty() {
+            Ok(format!("{} of {} verified", files, files))
+        } else {
+            Err(format!("{} of {} verified", files - errors.len(), files))
+        },
+    );
+    for error in errors.iter() {
+        println!("{:<10} {}", "", style(error).red());
+    }
+
+    let invalid_symlinks = payload
+        .symlinks
+        .iter()
+        .filter(|section| match section.kind {
+            0 => section.target.get() as usize >= directories.len(),
+            1 => section.target.get() as usize >= payload.files.len(),
+            // external symlinks have no target in the payload
+            _ => false,
+        })
+        .count();
+    let symlinks = payload.symlinks.len();
+    report(
+        "symlinks",
+        &if invalid_symlinks == 0 {
+            Ok(format!("{} of {} verified", symlinks, symlinks))
+        } else {
+            Err(format!(
+                "{} of {} verified, {} with invalid targets",
+                symlinks - invalid_symlinks,
+                symlinks,
+                invalid_symlinks
+            ))
+        },
+    );
+
+    if payload_hash.is_err() || signature.is_err() || !errors.is_empty() || invalid_symlinks > 0 {
+        fail();
+    }
+    println!();
+    println!("{}", style("verification succeeded").green());
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs::{read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+};
+
+/// File in the unpack directory counting the extractions that didn't finish.
+pub const FAILURES_FILE: &str = "._wrappe_failures_";
+
+/// Failed extractions after which the payload is unpacked without prefetching and on one thread.
+const REDUCE_FAILURES: u32 = 2;
+/// Failed extractions after which the payload is unpacked to the alternative unpack target.
+const RELOCATE_FAILURES: u32 = 3;
+
+/// Strategy of the runner after previous extractions failed, so that unpacking can succeed on
+/// systems where the default strategy keeps failing.
+pub struct Backoff {
+    path:         PathBuf,
+    pub failures: u32,
+    /// Whether the payload is unpacked without prefetching and on one thread.
+    pub reduce:   bool,
+    /// Whether the payload is unpacked to the alternative unpack target.
+    pub relocate: bool,
+}
+
+impl Backoff {
+    /// Read the number of failed extractions from the counter file in `directory`.
+    pub fn read(directory: &Path) -> Self {
+        let path = directory.join(FAILURES_FILE);
+        let failures = read_to_string(&path)
+            .ok()
+            .and_then(|failures| failures.trim().parse().ok())
+            .unwrap_or(0);
+        Backoff {
+            path,
+            failures,
+            reduce: failures >= REDUCE_FAILURES,
+            relocate: failures >= RELOCATE_FAILURES,
+        }
+    }
+
+    /// Whether a strategy other than the default one is used.
+    pub fn active(&self) -> bool { self.reduce || self.relocate }
+
+    /// Count the extraction that is about to start as failed until it finishes, so that
+    /// extractions that are interrupted or crash the runner are counted as well.
+    pub fn start(&self) { let _ = write(&self.path, (self.failures + 1).to_string()); }
+
+    /// Reset the counter after a successful extraction. The counter is kept after a successful
+    /// extraction with another strategy, so that the strategy is used again on the next start.
+    pub fn finish(&self) {
+        if self.active() {
+            let _ = write(&self.path, self.failures.to_string());
+        } else {
+            let _ = remove_file(&self.path);
+        }
+    }
+
+    /// Description of the strategies used instead of the default one.
+    pub fn describe(&self, unpack_root: &Path) -> String {
+        let mut strategies = Vec::new();
+        if self.reduce {
+            strategies.push("without prefetching and on a single thread".to_string());
+        }
+        if self.relocate {
+            strategies.push(format!("in {}", unpack_root.display()));
+        }
+        format!(
+            "unpacking failed {} times before, unpacking {}",
+            self.failures,
+            strategies.join(" and ")
+        )
+    }
+}
+
+/// Unpack target used instead of `unpack_target` when the payload is relocated, which is the
+/// local data directory for the temporary directory and the temporary directory otherwise.
+pub fn alternative_root(unpack_target: u8) -> Option<PathBuf> {
+    match unpack_target {
+        0 => dirs::data_local_dir(),
+        _ => Some(std::env::temp_dir()),
+    }
+}
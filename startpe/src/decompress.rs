@@ -1,7 +1,11 @@
 use std::{
-    fs::{File, create_dir_all, read_link, remove_dir, remove_file},
+    collections::HashMap,
+    fs::{
+        File, copy as copy_file, create_dir_all, hard_link, read_link, remove_dir, remove_file,
+        write,
+    },
     hash::Hasher,
-    io::{BufReader, BufWriter, Read, Result, copy, sink},
+    io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write, copy, sink},
     mem::size_of,
     path::{Path, PathBuf},
     thread::sleep,
@@ -21,6 +25,11 @@ use crate::{types::*, versioning::*};
 
 pub const HASH_SEED: u64 = 1246736989840;
 pub const LOCK_FILE: &str = "._wrappe_lock_";
+pub const RUN_LOCK_FILE: &str = "._wrappe_run_lock_";
+
+/// Size of the input buffer of the brotli decoder.
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
 
 pub struct HashReader<R: Read, H: Hasher> {
     reader: R,
@@ -41,6 +50,207 @@ impl<R: Read, H: Hasher> Read for HashReader<R, H> {
     }
 }
 
+/// Name of the compression algorithm of the payload `kind`.
+pub fn algorithm_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "zstd",
+        1 => "xz",
+        2 => "brotli",
+        _ => "unknown",
+    }
+}
+
+/// Whether the runner supports decompressing payloads of `kind`.
+pub fn algorithm_supported(kind: u8) -> bool {
+    kind == 0 || (kind == 1 && cfg!(feature = "xz")) || (kind == 2 && cfg!(feature = "brotli"))
+}
+
+/// Decompress `reader` compressed with the compression algorithm of the payload `kind` into `writer`.
+pub fn copy_decode<R: BufRead, W: Write>(
+    kind: u8, reader: R, writer: &mut W, dictionary: Option<&DecoderDictionary>,
+) -> Result<u64> {
+    match kind {
+        0 => {
+            let mut decoder = if let Some(dictionary) = dictionary {
+                Decoder::with_prepared_dictionary(reader, dictionary)?
+            } else {
+                Decoder::with_buffer(reader)?
+            };
+            copy(&mut decoder, writer)
+        }
+        #[cfg(feature = "xz")]
+        1 => copy(&mut xz2::bufread::XzDecoder::new(reader), writer),
+        #[cfg(feature = "brotli")]
+        2 => copy(
+            &mut brotli_decompressor::Decompressor::new(reader, BROTLI_BUFFER_SIZE),
+            writer,
+        ),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported compression algorithm {}", algorithm_name(kind)),
+        )),
+    }
+}
+
+/// Compressed and decompressed sizes of the frames listed in the seek table at the end of the
+/// zstd compressed `data`, or `None` if it doesn't end with a seek table.
+pub fn seek_table(data: &[u8]) -> Option<Vec<(usize, usize)>> {
+    let read = |offset: usize| {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    if data.len() < 17 || read(data.len() - 4) != SEEKABLE_MAGIC {
+        return None;
+    }
+    // frames have an additional checksum if the first bit of the descriptor is set
+    let entry_size = if data[data.len() - 5] & 0x80 != 0 {
+        12
+    } else {
+        8
+    };
+    let count = read(data.len() - 9) as usize;
+    let table_size = count.checked_mul(entry_size)?.checked_add(9)?;
+    let table_start = data.len().checked_sub(table_size.checked_add(8)?)?;
+    if read(table_start) != SEEKABLE_SKIPPABLE_MAGIC || read(table_start + 4) as usize != table_size
+    {
+        return None;
+    }
+    let frames = (0..count)
+        .map(|index| {
+            let entry = table_start + 8 + index * entry_size;
+            (read(entry) as usize, read(entry + 4) as usize)
+        })
+        .collect::<Vec<_>>();
+    let compressed_size = frames.iter().try_fold(0usize, |size, (compressed, _)| {
+        size.checked_add(*compressed)
+    })?;
+    (compressed_size == table_start).then_some(frames)
+}
+
+/// Decompress the zstd compressed `data` split into `frames` into `writer`, decompressing as
+/// many frames in parallel as there are threads.
+fn copy_decode_frames<W: Write>(
+    data: &[u8], frames: &[(usize, usize)], writer: &mut W, dictionary: Option<&DecoderDictionary>,
+) -> Result<u64> {
+    let mut start = 0;
+    let mut written = 0;
+    for batch in frames.chunks(rayon::current_num_threads().max(1)) {
+        let ranges = batch
+            .iter()
+            .map(|&(compressed_size, size)| {
+                start += compressed_size;
+                (start - compressed_size, compressed_size, size)
+            })
+            .collect::<Vec<_>>();
+        let decompressed = ranges
+            .par_iter()
+            .map(|&(start, compressed_size, size)| {
+                let mut frame = Vec::with_capacity(size);
+                copy_decode(
+                    0,
+                    &data[start..start + compressed_size],
+                    &mut frame,
+                    dictionary,
+                )?;
+                Ok(frame)
+            })
+            .collect::<Vec<Result<_>>>();
+        for frame in decompressed {
+            let frame = frame?;
+            writer.write_all(&frame)?;
+            written += frame.len() as u64;
+        }
+    }
+    Ok(written)
+}
+
+/// Name of a section at `name` in the name table `names`.
+fn section_name(names: &[u8], name: NameRef) -> &str {
+    let (offset, size) = (name.offset.get() as usize, name.size.get() as usize);
+    let name = names.get(offset..offset + size).unwrap_or_else(|| {
+        panic!(
+            "section name ({}..{}) exceeds name table size ({})",
+            offset,
+            offset + size,
+            names.len()
+        )
+    });
+    decode_field(name).unwrap_or_else(|e| panic!("couldn't decode section name: {}", e))
+}
+
+/// Absolute path of `unpack_dir` replacing the placeholder in file contents.
+fn unpacked_dir(unpack_dir: &Path) -> Vec<u8> {
+    let unpack_dir = std::env::current_dir()
+        .map(|current_dir| current_dir.join(unpack_dir))
+        .unwrap_or_else(|_| unpack_dir.to_path_buf());
+    #[cfg(any(unix, target_os = "redox"))]
+    return std::os::unix::ffi::OsStrExt::as_bytes(unpack_dir.as_os_str()).to_vec();
+    #[cfg(not(any(unix, target_os = "redox")))]
+    return unpack_dir.to_string_lossy().as_bytes().to_vec();
+}
+
+/// `contents` with `from` replaced with `to` according to the `placeholder` mode of the file:
+/// at the start of the shebang for mode 1, and everywhere for mode 2.
+fn replace_placeholder(contents: Vec<u8>, placeholder: u8, from: &[u8], to: &[u8]) -> Vec<u8> {
+    match placeholder {
+        1 => match contents
+            .strip_prefix(b"#!")
+            .and_then(|rest| rest.strip_prefix(from))
+        {
+            Some(rest) => [b"#!", to, rest].concat(),
+            None => contents,
+        },
+        2 if !from.is_empty() => {
+            let mut replaced = Vec::with_capacity(contents.len());
+            let mut i = 0;
+            while i < contents.len() {
+                if contents[i..].starts_with(from) {
+                    replaced.extend_from_slice(to);
+                    i += from.len();
+                } else {
+                    replaced.push(contents[i]);
+                    i += 1;
+                }
+            }
+            replaced
+        }
+        _ => contents,
+    }
+}
+
+/// Check the hash of the whole payload at the end of `mmap`, which covers everything from the
+/// compressed file contents to the payload header, against the hash in its payload header.
+pub fn check_payload_hash(mmap: &[u8]) -> std::result::Result<(), String> {
+    let payload_header_start = mmap
+        .len()
+        .checked_sub(size_of::<PayloadHeader>())
+        .ok_or_else(|| "payload header exceeds file size".to_string())?;
+    let payload_header = Ref::into_ref(
+        Ref::<_, PayloadHeader>::from_bytes(&mmap[payload_header_start..])
+            .map_err(|_| "couldn't read payload header".to_string())?,
+    );
+    let files_start = (payload_header_start as u64)
+        .checked_sub(
+            payload_header.sections_size.get()
+                + payload_header.dictionary_size.get()
+                + payload_header.payload_size.get(),
+        )
+        .ok_or_else(|| "payload size exceeds file size".to_string())?
+        as usize;
+    let payload_hash = blake3::hash(&mmap[files_start..payload_header_start]);
+    if payload_hash.as_bytes() != &payload_header.payload_hash {
+        return Err(
+            "payload hash differs from expected hash, the executable is truncated or corrupted"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
 /// Decompress the payload and section data in `mmap` into `unpack_dir`.
 /// The data is expected to be in the following order at the end of `mmap`:
 /// - compressed file contents
@@ -49,10 +259,21 @@ impl<R: Read, H: Hasher> Read for HashReader<R, H> {
 ///   - directory sections
 ///   - file section headers
 ///   - symlink sections
+///   - hardlink sections
+///   - name table
 /// - payload section header
+///
+/// Payloads larger than `prefetch_threshold` bytes are prefetched into memory
+/// if enough memory is available.
+/// If `ownership` is set, the recorded owner and group of files are restored
+/// when running as root.
+/// The unpack directory placeholder in files with the `placeholder` flag is replaced.
+/// `before_extract` is called once it is decided that the payload is extracted.
+#[allow(clippy::too_many_arguments)]
 pub fn decompress(
     mmap: &[u8], unpack_dir: &Path, verification: u8, mut should_extract: bool, version: &str,
-    show_information: u8,
+    show_information: u8, prefetch_threshold: Option<u64>, ownership: bool,
+    before_extract: impl FnOnce(),
 ) -> bool {
     // read payload header sections
     let payload_header_start = mmap.len() - size_of::<PayloadHeader>();
@@ -61,24 +282,35 @@ pub fn decompress(
             .expect("couldn't read payload header"),
     );
 
-    let directory_sections = payload_header.directory_sections as usize;
-    let file_sections = payload_header.file_sections as usize;
-    let symlink_sections = payload_header.symlink_sections as usize;
-    let dictionary_size = payload_header.dictionary_size as usize;
-    let payload_size = payload_header.payload_size as usize;
-    let sections_size = payload_header.sections_size as usize;
+    let directory_sections = payload_header.directory_sections.get() as usize;
+    let file_sections = payload_header.file_sections.get() as usize;
+    let symlink_sections = payload_header.symlink_sections.get() as usize;
+    let hardlink_sections = payload_header.hardlink_sections.get() as usize;
+    let dictionary_size = payload_header.dictionary_size.get() as usize;
+    let payload_size = payload_header.payload_size.get() as usize;
+    let sections_size = payload_header.sections_size.get() as usize;
+    let names_size = payload_header.names_size.get() as usize;
     if show_information >= 2 {
         println!(
-            "payload: {} directories, {} files, {} symlinks ({} total)",
+            "payload: {} directories, {} files, {} symlinks, {} hardlinks ({} total)",
             directory_sections,
             file_sections,
             symlink_sections,
+            hardlink_sections,
             payload_header.len()
         );
+        println!("compression: {}", algorithm_name(payload_header.kind));
         println!("dictionary size: {}", dictionary_size);
         println!("payload size: {}", payload_size);
     }
 
+    if !algorithm_supported(payload_header.kind) {
+        panic!(
+            "payload compression algorithm ({}) is not supported by this runner",
+            algorithm_name(payload_header.kind)
+        );
+    }
+
     let mut sections = Vec::with_capacity(sections_size);
     let mut reader = BufReader::with_capacity(
         DCtx::in_size(),
@@ -93,11 +325,24 @@ pub fn decompress(
         directory_sections_start + directory_sections * size_of::<DirectorySection>();
     let symlink_sections_start =
         file_sections_start + file_sections * size_of::<FileSectionHeader>();
+    let hardlink_sections_start =
+        symlink_sections_start + symlink_sections * size_of::<SymlinkSection>();
+    let names_start = hardlink_sections_start + hardlink_sections * size_of::<HardlinkSection>();
+    if sections.len() != names_start + names_size {
+        panic!(
+            "sections size ({}) differs from expected sections size ({})",
+            sections.len(),
+            names_start + names_size
+        );
+    }
+    let names = &sections[names_start..];
 
     let dictionary_start = payload_header_start - sections_size - dictionary_size;
     let files_start = dictionary_start - payload_size;
 
     let mut section_hasher = XxHash64::with_seed(HASH_SEED);
+    let placeholder = UNPACK_DIR_PLACEHOLDER.as_bytes();
+    let unpacked_dir = unpacked_dir(unpack_dir);
 
     if show_information >= 2 {
         println!("reading sections...");
@@ -109,6 +354,7 @@ pub fn decompress(
     } else {
         None
     };
+    let mut directory_sections = Vec::new();
     let directories = sections[directory_sections_start..file_sections_start]
         .chunks(size_of::<DirectorySection>())
         .enumerate()
@@ -125,17 +371,10 @@ pub fn decompress(
                     .expect("couldn't read payload header"),
                 );
                 directories.push(
-                    directories[section.parent as usize].join(
-                        std::str::from_utf8(
-                            &section.name[0..(section
-                                .name
-                                .iter()
-                                .position(|&c| c == b'\0')
-                                .unwrap_or(section.name.len()))],
-                        )
-                        .unwrap(),
-                    ),
+                    directories[section.parent.get() as usize]
+                        .join(section_name(names, section.name)),
                 );
+                directory_sections.push(section);
                 directories
             },
         );
@@ -151,20 +390,10 @@ pub fn decompress(
                 )
                 .expect("couldn't read payload header"),
             );
-            (
-                section,
-                std::str::from_utf8(
-                    &section.name[0..(section
-                        .name
-                        .iter()
-                        .position(|&c| c == b'\0')
-                        .unwrap_or(section.name.len()))],
-                )
-                .unwrap(),
-            )
+            (section, section_name(names, section.name))
         })
         .collect::<Vec<_>>();
-    let symlinks = sections[symlink_sections_start..]
+    let symlinks = sections[symlink_sections_start..hardlink_sections_start]
         .chunks(size_of::<SymlinkSection>())
         .enumerate()
         .map(|(i, section)| {
@@ -176,23 +405,29 @@ pub fn decompress(
                 )
                 .expect("couldn't read payload header"),
             );
-            (
-                section,
-                std::str::from_utf8(
-                    &section.name[0..(section
-                        .name
-                        .iter()
-                        .position(|&c| c == b'\0')
-                        .unwrap_or(section.name.len()))],
+            (section, section_name(names, section.name))
+        })
+        .collect::<Vec<_>>();
+    let hardlinks = sections[hardlink_sections_start..names_start]
+        .chunks(size_of::<HardlinkSection>())
+        .enumerate()
+        .map(|(i, section)| {
+            let section_start = hardlink_sections_start + i * size_of::<HardlinkSection>();
+            section_hasher.write(section);
+            let section = Ref::into_ref(
+                Ref::<_, HardlinkSection>::from_bytes(
+                    &sections[section_start..section_start + size_of::<HardlinkSection>()],
                 )
-                .unwrap(),
-            )
+                .expect("couldn't read payload header"),
+            );
+            (section, section_name(names, section.name))
         })
         .collect::<Vec<_>>();
 
+    section_hasher.write(names);
     let section_hash = section_hasher.finish();
-    if section_hash != payload_header.section_hash {
-        let expected = payload_header.section_hash;
+    if section_hash != payload_header.section_hash.get() {
+        let expected = payload_header.section_hash.get();
         panic!(
             "section hash ({}) differs from expected section hash ({})",
             section_hash, expected
@@ -206,7 +441,7 @@ pub fn decompress(
         }
         should_extract = !files.par_iter().all(|(file, file_name)| {
             let path = unpack_dir
-                .join(&directories[file.parent as usize])
+                .join(&directories[file.parent.get() as usize])
                 .join(file_name);
             if !path.is_file() {
                 eprintln!("verification failed: not a file: {}", path.display());
@@ -230,8 +465,22 @@ pub fn decompress(
                 }
                 let target = target.unwrap();
                 let mut hasher = XxHash64::with_seed(HASH_SEED);
-                let mut reader = HashReader::new(&target, &mut hasher);
-                if copy(&mut reader, &mut sink()).is_err() {
+                let read = if file.placeholder != 0 {
+                    // files are hashed with the placeholder they were packed with
+                    let mut contents = Vec::new();
+                    (&target).read_to_end(&mut contents).map(|_| {
+                        hasher.write(&replace_placeholder(
+                            contents,
+                            file.placeholder,
+                            &unpacked_dir,
+                            placeholder,
+                        ))
+                    })
+                } else {
+                    let mut reader = HashReader::new(&target, &mut hasher);
+                    copy(&mut reader, &mut sink()).map(|_| ())
+                };
+                if read.is_err() {
                     eprintln!(
                         "verification failed: couldn't read file: {}",
                         path.display()
@@ -239,8 +488,8 @@ pub fn decompress(
                     return false;
                 };
                 let file_hash = hasher.finish();
-                if file_hash != file.file_hash {
-                    let expected = file.file_hash;
+                if file_hash != file.file_hash.get() {
+                    let expected = file.file_hash.get();
                     eprintln!(
                         "verification failed: file hash ({}) differs from expected file hash ({}): {}",
                         file_hash,
@@ -261,7 +510,7 @@ pub fn decompress(
         }
         should_extract = !symlinks.par_iter().all(|(symlink, symlink_name)| {
             let path = unpack_dir
-                .join(&directories[symlink.parent as usize])
+                .join(&directories[symlink.parent.get() as usize])
                 .join(symlink_name);
             let link = read_link(&path);
             if link.is_err() {
@@ -272,6 +521,19 @@ pub fn decompress(
                 return false;
             }
             let link = link.unwrap();
+            // external symlink
+            if symlink.kind >= 2 {
+                let target = section_name(names, symlink.link);
+                if link != Path::new(target) {
+                    eprintln!(
+                        "verification failed: symlink points to wrong target: {} (expected: {})",
+                        link.display(),
+                        target,
+                    );
+                    return false;
+                }
+                return true;
+            }
             if !link.starts_with(unpack_dir) {
                 eprintln!(
                     "verification failed: symlink points to target outside the target directory: {}",
@@ -281,7 +543,7 @@ pub fn decompress(
             }
             // directory symlink
             if symlink.kind == 0 {
-                let target = unpack_dir.join(&directories[symlink.target as usize]);
+                let target = unpack_dir.join(&directories[symlink.target.get() as usize]);
                 if link != target
                 {
                     eprintln!(
@@ -294,9 +556,9 @@ pub fn decompress(
             }
             // file symlink
             if symlink.kind == 1 {
-                let (file, file_name) = files[symlink.target as usize];
+                let (file, file_name) = files[symlink.target.get() as usize];
                 let target = unpack_dir
-                    .join(&directories[file.parent as usize])
+                    .join(&directories[file.parent.get() as usize])
                     .join(file_name);
                 if target != link
                 {
@@ -312,17 +574,61 @@ pub fn decompress(
         });
     }
 
+    // verify hardlinks
+    if verification > 0 && !should_extract && hardlink_sections > 0 {
+        if show_information >= 2 {
+            println!("verifying hardlinks...");
+        }
+        should_extract = !hardlinks.par_iter().all(|(hardlink, hardlink_name)| {
+            let path = unpack_dir
+                .join(&directories[hardlink.parent.get() as usize])
+                .join(hardlink_name);
+            if !path.is_file() {
+                eprintln!("verification failed: not a file: {}", path.display());
+                return false;
+            }
+            true
+        });
+    }
+
     if should_extract {
+        // check the whole payload before writing any file, so that truncated or corrupted
+        // executables don't fail halfway through unpacking
+        if show_information >= 2 {
+            println!("checking payload hash...");
+        }
+        check_payload_hash(mmap).unwrap_or_else(|e| panic!("{}", e));
+        before_extract();
         #[cfg(feature = "prefetch")]
         let mut prefetch_handle = None;
         #[cfg(feature = "prefetch")]
-        // prefetch memory mapped data if it is larger than 512 MB
-        if mmap.len() - files_start > 512 * 1024 * 1024 {
-            if show_information >= 2 {
-                println!("prefetching memory...");
+        // prefetch memory mapped data if it is larger than the threshold,
+        // unless it would take up more than half of the available memory
+        if let Some(threshold) = prefetch_threshold {
+            let payload_size = (mmap.len() - files_start) as u64;
+            if payload_size > threshold {
+                match crate::prefetch::available_memory() {
+                    Some(available) if available / 2 < payload_size => {
+                        if show_information >= 2 {
+                            println!(
+                                "skipping prefetch, {} MB of memory available",
+                                available / 1024 / 1024
+                            );
+                        }
+                    }
+                    _ => {
+                        if show_information >= 2 {
+                            println!("prefetching memory...");
+                        }
+                        prefetch_handle = crate::prefetch::prefetch_memory(mmap, files_start);
+                    }
+                }
             }
-            prefetch_handle = crate::prefetch::prefetch_memory(mmap, files_start);
         }
+        #[cfg(not(feature = "prefetch"))]
+        let _ = prefetch_threshold;
+        #[cfg(not(any(unix, target_os = "redox")))]
+        let _ = ownership;
 
         // create directories
         if show_information >= 2 {
@@ -333,48 +639,148 @@ pub fn decompress(
             create_dir_all(&path).unwrap_or_else(|e| {
                 panic!("couldn't create directory {}: {}", path.display(), e);
             });
+            // directories of a previous unpack may have been left without write permissions
+            #[cfg(any(unix, target_os = "redox"))]
+            {
+                use ::std::{fs::set_permissions, os::unix::prelude::*};
+                if let Ok(meta) = path.metadata() {
+                    if meta.permissions().mode() & 0o700 != 0o700 {
+                        let _ = set_permissions(
+                            &path,
+                            PermissionsExt::from_mode(meta.permissions().mode() | 0o700),
+                        );
+                    }
+                }
+            }
         });
 
         // unpack files
         if show_information >= 2 {
             println!("unpacking...");
         }
+        // solid blocks are decompressed once for all files they contain
+        let mut blocks = files
+            .iter()
+            .filter(|(file, _)| file.solid == 1)
+            .map(|(file, _)| {
+                (
+                    file.position.get(),
+                    file.size.get(),
+                    file.compressed_hash.get(),
+                )
+            })
+            .collect::<Vec<_>>();
+        blocks.sort_unstable();
+        blocks.dedup();
+        let blocks = blocks
+            .par_iter()
+            .map(|&(position, size, expected)| {
+                let content = &mmap
+                    [files_start + position as usize..files_start + (position + size) as usize];
+                let mut reader = HashReader::new(content, XxHash64::with_seed(HASH_SEED));
+                let mut block = Vec::new();
+                copy_decode(
+                    payload_header.kind,
+                    BufReader::with_capacity(DCtx::in_size(), &mut reader),
+                    &mut block,
+                    dictionary.as_ref(),
+                )
+                .unwrap_or_else(|e| panic!("failed to unpack solid block at {}: {}", position, e));
+                let compressed_hash = reader.finish();
+                if compressed_hash != expected {
+                    panic!(
+                        "compressed block hash ({}) differs from expected hash ({}) for block at {}",
+                        compressed_hash, expected, position
+                    );
+                }
+                (position, block)
+            })
+            .collect::<HashMap<_, _>>();
         files.par_iter().for_each(|(file, file_name)| {
             let path = unpack_dir
-                .join(&directories[file.parent as usize])
+                .join(&directories[file.parent.get() as usize])
                 .join(file_name);
-            let content = &mmap[files_start + file.position as usize
-                ..files_start + (file.position + file.size) as usize];
-            let mut reader = HashReader::new(content, XxHash64::with_seed(HASH_SEED));
-            {
-                let mut reader = BufReader::with_capacity(DCtx::in_size(), &mut reader);
-                let output = File::options()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&path)
+            if file.solid == 1 {
+                let (position, offset, size) = (
+                    file.position.get(),
+                    file.block_offset.get() as usize,
+                    file.block_size.get() as usize,
+                );
+                let content = blocks[&position]
+                    .get(offset..offset + size)
+                    .unwrap_or_else(|| panic!("file exceeds solid block: {}", path.display()));
+                let content = if file.placeholder != 0 {
+                    replace_placeholder(
+                        content.to_vec(),
+                        file.placeholder,
+                        placeholder,
+                        &unpacked_dir,
+                    )
+                } else {
+                    content.to_vec()
+                };
+                write(&path, content)
                     .unwrap_or_else(|e| panic!("failed to create file {}: {}", path.display(), e));
-                let mut output = BufWriter::with_capacity(DCtx::out_size(), output);
-                let decoder = if let Some(dict) = &dictionary {
-                    Decoder::with_prepared_dictionary(&mut reader, dict)
+            } else {
+                let content = &mmap[files_start + file.position.get() as usize
+                    ..files_start + (file.position.get() + file.size.get()) as usize];
+                // files split into several frames are decompressed in parallel
+                let frames = if file.stored == 0 && payload_header.kind == 0 {
+                    seek_table(content)
                 } else {
-                    Decoder::with_buffer(&mut reader)
+                    None
                 };
-                let mut decoder = decoder.unwrap_or_else(|e| {
-                    panic!("failed to create decoder for {}: {}", path.display(), e)
-                });
-                copy(&mut decoder, &mut output)
+                let mut reader = HashReader::new(content, XxHash64::with_seed(HASH_SEED));
+                // files with the placeholder are unpacked into memory to replace it
+                let mut script = Vec::new();
+                {
+                    let mut reader = BufReader::with_capacity(DCtx::in_size(), &mut reader);
+                    let mut output: Box<dyn Write> = if file.placeholder != 0 {
+                        Box::new(&mut script)
+                    } else {
+                        let output = File::options()
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(&path)
+                            .unwrap_or_else(|e| {
+                                panic!("failed to create file {}: {}", path.display(), e)
+                            });
+                        Box::new(BufWriter::with_capacity(DCtx::out_size(), output))
+                    };
+                    if file.stored == 1 {
+                        copy(&mut reader, &mut output)
+                    } else if let Some(frames) = &frames {
+                        copy(&mut reader, &mut sink()).and_then(|_| {
+                            copy_decode_frames(content, frames, &mut output, dictionary.as_ref())
+                        })
+                    } else {
+                        copy_decode(
+                            payload_header.kind,
+                            &mut reader,
+                            &mut output,
+                            dictionary.as_ref(),
+                        )
+                    }
                     .unwrap_or_else(|e| panic!("failed to unpack file {}: {}", path.display(), e));
-            }
-            let compressed_hash = reader.finish();
-            if file.compressed_hash != compressed_hash {
-                let expected = file.compressed_hash;
-                panic!(
-                    "compressed file hash ({}) differs from expected hash ({}) for {}",
-                    compressed_hash,
-                    expected,
-                    path.display()
-                );
+                }
+                if file.placeholder != 0 {
+                    write(
+                        &path,
+                        replace_placeholder(script, file.placeholder, placeholder, &unpacked_dir),
+                    )
+                    .unwrap_or_else(|e| panic!("failed to create file {}: {}", path.display(), e));
+                }
+                let compressed_hash = reader.finish();
+                if file.compressed_hash.get() != compressed_hash {
+                    let expected = file.compressed_hash.get();
+                    panic!(
+                        "compressed file hash ({}) differs from expected hash ({}) for {}",
+                        compressed_hash,
+                        expected,
+                        path.display()
+                    );
+                }
             }
             #[cfg(windows)]
             {
@@ -395,10 +801,21 @@ pub fn decompress(
                     fs::{Permissions, set_permissions},
                     os::unix::prelude::*,
                 };
-                let mode = file.mode;
-                let mut perm: Permissions = PermissionsExt::from_mode(mode);
-                let read = file.readonly != 0;
-                perm.set_readonly(read);
+                // changing the owner clears the setuid and setgid bits, so it's done first
+                if ownership && unsafe { libc::geteuid() } == 0 {
+                    ::std::os::unix::fs::chown(&path, Some(file.uid.get()), Some(file.gid.get()))
+                        .unwrap_or_else(|e| {
+                            eprintln!("failed to set owner for {}: {}", path.display(), e)
+                        });
+                }
+                // clearing the readonly flag makes files writable by everyone on Unix, so the
+                // mode is derived from it only for payloads packed without modes
+                let mode = match file.mode.get() {
+                    0 if file.readonly != 0 => 0o444,
+                    0 => 0o644,
+                    mode => mode,
+                };
+                let perm: Permissions = PermissionsExt::from_mode(mode);
                 set_permissions(&path, perm).unwrap_or_else(|e| {
                     eprintln!("failed to set permissions for {}: {}", path.display(), e)
                 });
@@ -406,17 +823,48 @@ pub fn decompress(
             set_file_times(
                 &path,
                 FileTime::from_unix_time(
-                    file.time_accessed_seconds as i64,
-                    file.time_accessed_nanos,
+                    file.time_accessed_seconds.get() as i64,
+                    file.time_accessed_nanos.get(),
                 ),
                 FileTime::from_unix_time(
-                    file.time_modified_seconds as i64,
-                    file.time_modified_nanos,
+                    file.time_modified_seconds.get() as i64,
+                    file.time_modified_nanos.get(),
                 ),
             )
             .unwrap_or_else(|e| println!("failed to set file times for {}: {}", path.display(), e));
         });
 
+        // create hardlinks, falling back to copies on file systems without hardlinks
+        if show_information >= 2 && hardlink_sections > 0 {
+            println!("creating hardlinks...");
+        }
+        hardlinks.par_iter().for_each(|(hardlink, hardlink_name)| {
+            let path = unpack_dir
+                .join(&directories[hardlink.parent.get() as usize])
+                .join(hardlink_name);
+            let (file, file_name) = files[hardlink.target.get() as usize];
+            let target = unpack_dir
+                .join(&directories[file.parent.get() as usize])
+                .join(file_name);
+            if path.symlink_metadata().is_ok() {
+                remove_file(&path).unwrap_or_else(|e| {
+                    panic!("failed to remove existing file {}: {}", path.display(), e)
+                });
+            }
+            if let Err(e) = hard_link(&target, &path) {
+                if show_information >= 2 {
+                    eprintln!(
+                        "failed to create hardlink {}, copying instead: {}",
+                        path.display(),
+                        e
+                    );
+                }
+                copy_file(&target, &path).unwrap_or_else(|e| {
+                    panic!("failed to create hardlink {}: {}", path.display(), e)
+                });
+            }
+        });
+
         // create symlinks
         #[cfg(not(any(windows, unix, target_os = "redox")))]
         {
@@ -429,8 +877,59 @@ pub fn decompress(
             }
             symlinks.par_iter().for_each(|(symlink, symlink_name)| {
                 let path = unpack_dir
-                    .join(&directories[symlink.parent as usize])
+                    .join(&directories[symlink.parent.get() as usize])
                     .join(symlink_name);
+                // external symlink
+                if symlink.kind >= 2 {
+                    if path.symlink_metadata().is_ok() {
+                        if cfg!(windows) && symlink.kind == 2 {
+                            remove_dir(&path)
+                        } else {
+                            remove_file(&path)
+                        }
+                        .unwrap_or_else(|e| {
+                            panic!(
+                                "failed to remove existing symlink {}: {}",
+                                path.display(),
+                                e
+                            )
+                        });
+                    }
+                    let target = section_name(names, symlink.link);
+                    #[cfg(windows)]
+                    {
+                        use ::std::os::windows::fs::{symlink_dir, symlink_file};
+                        if symlink.kind == 2 {
+                            symlink_dir(target, &path)
+                        } else {
+                            symlink_file(target, &path)
+                        }
+                        .unwrap_or_else(|e| {
+                            panic!("failed to create symlink {}: {}", path.display(), e)
+                        });
+                    }
+                    #[cfg(any(unix, target_os = "redox"))]
+                    {
+                        use ::std::os::unix::fs::symlink;
+                        symlink(target, &path).unwrap_or_else(|e| {
+                            panic!("failed to create symlink {}: {}", path.display(), e)
+                        });
+                    }
+                    set_symlink_file_times(
+                        &path,
+                        FileTime::from_unix_time(
+                            symlink.time_accessed_seconds.get() as i64,
+                            symlink.time_accessed_nanos.get(),
+                        ),
+                        FileTime::from_unix_time(
+                            symlink.time_modified_seconds.get() as i64,
+                            symlink.time_modified_nanos.get(),
+                        ),
+                    )
+                    .unwrap_or_else(|e| {
+                        eprintln!("failed to set file times for {}: {}", path.display(), e)
+                    });
+                }
                 // directory symlink
                 if symlink.kind == 0 {
                     if path.exists() {
@@ -445,7 +944,7 @@ pub fn decompress(
                     while path.exists() {
                         sleep(Duration::from_millis(20));
                     }
-                    let target = unpack_dir.join(&directories[symlink.target as usize]);
+                    let target = unpack_dir.join(&directories[symlink.target.get() as usize]);
                     #[cfg(windows)]
                     {
                         use ::std::os::windows::fs::symlink_dir;
@@ -475,9 +974,9 @@ pub fn decompress(
                     while path.exists() {
                         sleep(Duration::from_millis(20));
                     }
-                    let (file, file_name) = files[symlink.target as usize];
+                    let (file, file_name) = files[symlink.target.get() as usize];
                     let target = unpack_dir
-                        .join(&directories[file.parent as usize])
+                        .join(&directories[file.parent.get() as usize])
                         .join(file_name);
                     #[cfg(windows)]
                     {
@@ -496,12 +995,12 @@ pub fn decompress(
                     set_symlink_file_times(
                         &path,
                         FileTime::from_unix_time(
-                            symlink.time_accessed_seconds as i64,
-                            symlink.time_accessed_nanos,
+                            symlink.time_accessed_seconds.get() as i64,
+                            symlink.time_accessed_nanos.get(),
                         ),
                         FileTime::from_unix_time(
-                            symlink.time_modified_seconds as i64,
-                            symlink.time_modified_nanos,
+                            symlink.time_modified_seconds.get() as i64,
+                            symlink.time_modified_nanos.get(),
                         ),
                     )
                     .unwrap_or_else(|e| {
@@ -511,6 +1010,40 @@ pub fn decompress(
             });
         }
 
+        // set directory permissions and timestamps after their contents were created,
+        // subdirectories first
+        if show_information >= 2 {
+            println!("setting directory permissions...");
+        }
+        for (directory, section) in directories.iter().skip(1).zip(&directory_sections).rev() {
+            let path = unpack_dir.join(directory);
+            // directories without recorded metadata are left as they are
+            #[cfg(any(unix, target_os = "redox"))]
+            if section.mode.get() != 0 {
+                use ::std::{fs::set_permissions, os::unix::prelude::*};
+                set_permissions(&path, PermissionsExt::from_mode(section.mode.get()))
+                    .unwrap_or_else(|e| {
+                        eprintln!("failed to set permissions for {}: {}", path.display(), e)
+                    });
+            }
+            if section.time_modified_seconds.get() != 0 {
+                set_file_times(
+                    &path,
+                    FileTime::from_unix_time(
+                        section.time_accessed_seconds.get() as i64,
+                        section.time_accessed_nanos.get(),
+                    ),
+                    FileTime::from_unix_time(
+                        section.time_modified_seconds.get() as i64,
+                        section.time_modified_nanos.get(),
+                    ),
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to set file times for {}: {}", path.display(), e)
+                });
+            }
+        }
+
         set_version(unpack_dir, version);
 
         #[cfg(feature = "prefetch")]
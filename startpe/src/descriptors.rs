@@ -0,0 +1,91 @@
+use std::process::Command;
+
+/// Prevent the command from inheriting file descriptors above the standard streams, except for
+/// the descriptors set in the `passed` bitmask. The descriptors are marked close-on-exec in the
+/// forked process, so that the descriptors of the runner itself are unaffected.
+#[cfg(any(unix, target_os = "redox"))]
+pub fn close_inherited_descriptors(command: &mut Command, passed: u64) {
+    use std::os::unix::process::CommandExt;
+
+    /// Mark the descriptors from `first` to `last` as close-on-exec.
+    fn set_cloexec(first: libc::c_int, last: libc::c_int) {
+        // not defined by libc for Android, the value is the same as on Linux
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                last as libc::c_uint,
+                CLOSE_RANGE_CLOEXEC,
+            )
+        } == 0
+        {
+            return;
+        }
+        for fd in first..=last {
+            // fails for descriptors that aren't open
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+    }
+
+    let max = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        max if max > 0 => max.min(libc::c_int::MAX as libc::c_long) as libc::c_int,
+        _ => 1024,
+    };
+    unsafe {
+        command.pre_exec(move || {
+            let mut first = 3;
+            for fd in 3..=64 {
+                if fd == 64 || passed & (1 << fd) != 0 {
+                    let last = if fd == 64 { max - 1 } else { fd - 1 };
+                    if first <= last {
+                        set_cloexec(first, last);
+                    }
+                    first = fd + 1;
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Prevent the command from inheriting handles of the runner other than the standard handles.
+/// Handles can't be passed by number on Windows, so `passed` is ignored.
+#[cfg(windows)]
+pub fn close_inherited_descriptors(_command: &mut Command, _passed: u64) {
+    use windows_sys::Win32::{
+        Foundation::{GetHandleInformation, HANDLE, HANDLE_FLAG_INHERIT, SetHandleInformation},
+        System::Console::{GetStdHandle, STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE},
+    };
+
+    let standard = [STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, STD_ERROR_HANDLE]
+        .map(|id| unsafe { GetStdHandle(id) });
+    // handle values are multiples of four, so inheritable handles are found by probing them
+    for value in (4..0x10000usize).step_by(4) {
+        let handle = value as HANDLE;
+        if standard.contains(&handle) {
+            continue;
+        }
+        let mut flags = 0;
+        if unsafe { GetHandleInformation(handle, &mut flags) } != 0
+            && flags & HANDLE_FLAG_INHERIT != 0
+        {
+            unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT, 0) };
+        }
+    }
+}
+
+/// Clear the inheritance flag of the handle of `file`, so that processes started by the runner
+/// can't hold it open after the runner closed it.
+#[cfg(windows)]
+pub fn set_non_inheritable(file: &std::fs::File) {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::{HANDLE_FLAG_INHERIT, SetHandleInformation};
+
+    unsafe { SetHandleInformation(file.as_raw_handle(), HANDLE_FLAG_INHERIT, 0) };
+}
+
+#[cfg(not(any(unix, target_os = "redox", windows)))]
+pub fn close_inherited_descriptors(_command: &mut Command, _passed: u64) {}
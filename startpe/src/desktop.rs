@@ -0,0 +1,116 @@
+use std::path::Path;
+
+/// Install or refresh the desktop entry and icon for the packed executable `exe`.
+/// Returns whether the installed desktop entry was changed.
+#[cfg(target_os = "linux")]
+pub fn install_desktop_entry(
+    unpack_dir: &Path, name: &str, exe: &Path,
+) -> Result<bool, std::io::Error> {
+    use crate::types::{DESKTOP_ENTRY_FILE, DESKTOP_ICON_NAME, WRAPPE_DIRECTORY};
+    use std::{
+        fs::{copy, create_dir_all, read, read_dir, read_to_string, write},
+        io::{Error, ErrorKind},
+    };
+
+    let data_dir = dirs::data_dir()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "couldn't get data directory"))?;
+    let wrappe_dir = unpack_dir.join(WRAPPE_DIRECTORY);
+
+    // the directory only exists if a desktop entry file or icon was packed
+    let icon = read_dir(&wrappe_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.file_stem() == Some(DESKTOP_ICON_NAME.as_ref()));
+    let icon = if let Some(icon) = icon {
+        let extension = icon.extension().unwrap_or_default().to_string_lossy();
+        let target = data_dir
+            .join("icons")
+            .join(format!("{}.{}", name, extension));
+        if read(&icon)? != read(&target).unwrap_or_default() {
+            create_dir_all(target.parent().unwrap())?;
+            copy(&icon, &target)?;
+        }
+        Some(target)
+    } else {
+        None
+    };
+
+    let exe = exe.to_string_lossy();
+    let exec = format!(
+        "\"{}\"",
+        exe.replace('\\', "\\\\\\\\")
+            .replace('"', "\\\\\"")
+            .replace('`', "\\\\`")
+            .replace('$', "\\\\$")
+            .replace('%', "%%")
+    );
+    let entry = if let Ok(template) = read_to_string(wrappe_dir.join(DESKTOP_ENTRY_FILE)) {
+        template
+            .lines()
+            .map(|line| {
+                if let Some(value) = line.strip_prefix("Exec=") {
+                    format!("Exec={}{}", exec, exec_arguments(value))
+                } else if line.starts_with("TryExec=") {
+                    format!("TryExec={}", exe)
+                } else if let (true, Some(icon)) = (line.starts_with("Icon="), &icon) {
+                    format!("Icon={}", icon.display())
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+            + "\n"
+    } else {
+        let mut entry = format!(
+            "[Desktop Entry]\nType=Application\nName={}\nExec={}\nTerminal=false\n",
+            name, exec
+        );
+        if let Some(icon) = &icon {
+            entry.push_str(&format!("Icon={}\n", icon.display()));
+        }
+        entry
+    };
+
+    let target = data_dir
+        .join("applications")
+        .join(format!("{}.desktop", name));
+    if read_to_string(&target).ok().as_deref() == Some(&entry) {
+        return Ok(false);
+    }
+    create_dir_all(target.parent().unwrap())?;
+    write(&target, entry)?;
+    Ok(true)
+}
+
+/// Return the arguments following the program in the value of an `Exec` key.
+#[cfg(target_os = "linux")]
+fn exec_arguments(value: &str) -> &str {
+    let value = value.trim_start();
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut escaped = false;
+        for (i, c) in quoted.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                return &quoted[i + 1..];
+            }
+        }
+        ""
+    } else {
+        value
+            .find(char::is_whitespace)
+            .map(|i| &value[i..])
+            .unwrap_or("")
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline(always)]
+pub fn install_desktop_entry(_: &Path, _: &str, _: &Path) -> Result<bool, std::io::Error> {
+    Ok(false)
+}
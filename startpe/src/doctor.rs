@@ -0,0 +1,451 @@
+use std::{
+    env::{current_dir, temp_dir, var_os},
+    fmt::Display,
+    fs::{create_dir_all, read_dir, remove_dir_all},
+    io::{IsTerminal, stdin, stdout},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+use fslock_guard::LockFileGuard;
+use zerocopy::Ref;
+
+use crate::{
+    decompress::{LOCK_FILE, RUN_LOCK_FILE},
+    sandbox::*,
+    types::*,
+    versioning::*,
+};
+
+/// Outcome of a check of the doctor report.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Counts of the problems printed in the doctor report.
+#[derive(Default)]
+struct Report {
+    errors:   usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn check(&mut self, status: Status, name: &str, detail: impl Display) {
+        let label = match status {
+            Status::Ok => "ok",
+            Status::Warning => {
+                self.warnings += 1;
+                "warning"
+            }
+            Status::Error => {
+                self.errors += 1;
+                "error"
+            }
+        };
+        println!("  {:<8}{}: {}", label, name, detail);
+    }
+}
+
+/// Packed executable whose host environment is checked with `--wrappe-doctor`.
+pub struct Doctor<'a> {
+    pub info:             &'a StarterInfo,
+    pub exe:              &'a Path,
+    /// File containing the payload, which is the pack file next to split runners.
+    pub payload_path:     &'a Path,
+    /// Data of the payload file up to the end of the payload header.
+    pub payload:          &'a [u8],
+    pub console_attached: bool,
+    pub login_shell:      bool,
+}
+
+/// Human-readable size of `bytes` in binary units.
+fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Space available to the current user on the file system containing `path`.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // the field types differ between platforms
+    #[allow(clippy::useless_conversion)]
+    Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(windows)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    let path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    let mut available = 0;
+    let result = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (result != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn free_space(_: &Path) -> Option<u64> { None }
+
+/// Check that an executable file can be started from `directory`, which fails on file systems
+/// mounted with `noexec`. Returns the error if it can't.
+#[cfg(unix)]
+fn probe_executable(directory: &Path) -> Option<String> {
+    use std::{
+        fs::{set_permissions, write},
+        os::unix::fs::PermissionsExt,
+        process::Command,
+    };
+    let script = directory.join("probe.sh");
+    let result = write(&script, "#!/bin/sh\nexit 0\n")
+        .and_then(|_| set_permissions(&script, PermissionsExt::from_mode(0o755)))
+        .and_then(|_| Command::new(&script).status());
+    match result {
+        Ok(status) if status.success() => None,
+        Ok(status) => Some(format!("probe exited with {}", status)),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+fn probe_executable(_: &Path) -> Option<String> { None }
+
+/// Check that symlinks can be created in `directory`, which requires developer mode or
+/// administrator rights on Windows. Returns the error if they can't.
+fn probe_symlink(directory: &Path) -> Option<String> {
+    let link = directory.join("probe-link");
+    #[cfg(any(unix, target_os = "redox"))]
+    let result = std::os::unix::fs::symlink("probe-target", link);
+    #[cfg(windows)]
+    let result = std::os::windows::fs::symlink_file("probe-target", link);
+    #[cfg(not(any(unix, target_os = "redox", windows)))]
+    let result: std::io::Result<()> = Ok(());
+    result.err().map(|e| e.to_string())
+}
+
+/// Check the unpack target `root`, creating it if it doesn't exist, and describe its writability,
+/// executability and free space. Problems of the selected target are errors.
+fn check_target(report: &mut Report, name: &str, root: &Path, selected: bool, payload_size: u64) {
+    let name = if selected {
+        format!("{} (selected)", name)
+    } else {
+        name.to_string()
+    };
+    let problem = if selected {
+        Status::Error
+    } else {
+        Status::Warning
+    };
+    if !is_writable(root) {
+        report.check(
+            problem,
+            &name,
+            format!("{} is not writable", root.display()),
+        );
+        return;
+    }
+    let probe = root.join(format!("._wrappe_doctor_{}", std::process::id()));
+    let executable = create_dir_all(&probe)
+        .err()
+        .map(|e| e.to_string())
+        .or_else(|| probe_executable(&probe));
+    let _ = remove_dir_all(&probe);
+    if let Some(error) = executable {
+        report.check(
+            problem,
+            &name,
+            format!(
+                "files in {} can't be executed, it might be mounted with noexec ({})",
+                root.display(),
+                error
+            ),
+        );
+        return;
+    }
+    match free_space(root) {
+        Some(free) if free < payload_size => report.check(
+            problem,
+            &name,
+            format!(
+                "{} has {} free, less than the compressed payload",
+                root.display(),
+                format_size(free)
+            ),
+        ),
+        Some(free) => report.check(
+            Status::Ok,
+            &name,
+            format!("{} is writable, {} free", root.display(), format_size(free)),
+        ),
+        None => report.check(Status::Ok, &name, format!("{} is writable", root.display())),
+    }
+}
+
+/// State of the lock file at `path`, or `None` if there is none.
+fn lock_state(path: &Path) -> Option<(bool, String)> {
+    if !path.is_file() {
+        return None;
+    }
+    Some(match LockFileGuard::try_lock(path) {
+        Ok(Some(_)) => (false, "stale".to_string()),
+        Ok(None) => (true, "held".to_string()),
+        Err(e) => (true, format!("unreadable ({})", e)),
+    })
+}
+
+/// Print a diagnostic report of the environment the packed executable runs in and return
+/// whether no errors were found.
+pub fn run(doctor: &Doctor) -> bool {
+    let info = doctor.info;
+    let mut report = Report::default();
+    let version =
+        decode_field(&info.uid).unwrap_or_else(|e| panic!("couldn't decode version: {}", e));
+    let unpack_dir_name = expand_unpack_directory(
+        decode_field(&info.unpack_directory)
+            .unwrap_or_else(|e| panic!("couldn't decode unpack directory name: {}", e)),
+        decode_field(&info.command).unwrap_or_else(|e| panic!("couldn't decode command: {}", e)),
+        version,
+        &runner_target(),
+    );
+    let header_start = doctor.payload.len() - size_of::<PayloadHeader>();
+    let header = Ref::into_ref(
+        Ref::<_, PayloadHeader>::from_bytes(&doctor.payload[header_start..])
+            .expect("couldn't read payload header"),
+    );
+    let payload_size =
+        header.payload_size.get() + header.dictionary_size.get() + header.sections_size.get();
+
+    println!(
+        "{} {} doctor ({}, format {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        runner_target(),
+        WRAPPE_FORMAT
+    );
+    println!("executable: {}", doctor.exe.display());
+    if doctor.payload_path != doctor.exe {
+        println!("pack file: {}", doctor.payload_path.display());
+    }
+    println!(
+        "application: {} version {} ({})",
+        unpack_dir_name,
+        version,
+        format_app_id(&info.app_id)
+    );
+    println!(
+        "payload: {} entries, {} compressed",
+        header.len(),
+        format_size(payload_size)
+    );
+
+    println!();
+    println!("unpack targets:");
+    let sandbox = detect_sandbox();
+    let override_root = var_os("WRAPPE_UNPACK_ROOT").map(PathBuf::from);
+    let targets = [
+        ("temp", Some(temp_dir())),
+        ("local", dirs::data_local_dir()),
+        ("cwd", current_dir().ok()),
+    ];
+    for (target, (name, root)) in targets.iter().enumerate() {
+        let selected = override_root.is_none() && target == info.unpack_target as usize;
+        match root {
+            Some(root) => check_target(&mut report, name, root, selected, payload_size),
+            None => report.check(
+                if selected {
+                    Status::Error
+                } else {
+                    Status::Warning
+                },
+                name,
+                "directory is not available",
+            ),
+        }
+    }
+    if let Some(root) = &override_root {
+        check_target(&mut report, "WRAPPE_UNPACK_ROOT", root, true, payload_size);
+    }
+    let mut unpack_root = override_root
+        .clone()
+        .or_else(|| targets.get(info.unpack_target as usize)?.1.clone());
+    if let (None, Some(sandbox)) = (&override_root, sandbox) {
+        println!("  sandbox: {}", sandbox);
+        // the runner falls back to the sandbox directories in the same way
+        if info.unpack_target != 2 && !unpack_root.as_deref().is_some_and(is_writable) {
+            unpack_root = sandbox_directories(sandbox)
+                .into_iter()
+                .find(|directory| is_writable(directory));
+            match &unpack_root {
+                Some(root) => check_target(&mut report, sandbox, root, true, payload_size),
+                None => report.check(
+                    Status::Error,
+                    sandbox,
+                    "no writable directory to unpack to in the sandbox",
+                ),
+            }
+        }
+    }
+    let Some(unpack_root) = unpack_root else {
+        println!();
+        println!("{} errors, {} warnings", report.errors, report.warnings);
+        return false;
+    };
+
+    println!();
+    println!("unpack directory:");
+    let mut unpack_root = unpack_root.join(&unpack_dir_name);
+    if let Some(subdir) = get_instance_subdir(info.unpack_subdir, doctor.exe, doctor.payload) {
+        unpack_root = unpack_root.join(subdir);
+    }
+    let unpack_dir = if info.versioning == 0 {
+        unpack_root.join(version)
+    } else {
+        unpack_root.clone()
+    };
+    println!("  {}", unpack_dir.display());
+    let probe = unpack_root
+        .parent()
+        .unwrap_or(&unpack_root)
+        .join(format!("._wrappe_doctor_{}", std::process::id()));
+    let symlink = create_dir_all(&probe)
+        .err()
+        .map(|e| e.to_string())
+        .or_else(|| probe_symlink(&probe));
+    let _ = remove_dir_all(&probe);
+    match (symlink, header.symlink_sections.get()) {
+        (None, _) => report.check(Status::Ok, "symlinks", "can be created"),
+        (Some(error), 0) => report.check(
+            Status::Ok,
+            "symlinks",
+            format!(
+                "can't be created, but the payload contains none ({})",
+                error
+            ),
+        ),
+        (Some(error), symlinks) => report.check(
+            Status::Error,
+            "symlinks",
+            format!(
+                "can't be created, but the payload contains {} ({})",
+                symlinks, error
+            ),
+        ),
+    }
+
+    // unpacked versions are directories with a version file in the unpack root, or the unpack
+    // root itself when versions replace each other
+    let mut versions = read_dir(&unpack_root)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    versions.push(unpack_root.clone());
+    versions.retain(|directory| directory.join(VERSION_FILE).is_file());
+    versions.sort();
+    if versions.is_empty() {
+        report.check(Status::Ok, "versions", "not unpacked yet");
+    }
+    for directory in versions {
+        let unpacked = get_version(&directory);
+        let name = if directory == unpack_dir && unpacked == version {
+            format!("version {} (current)", unpacked)
+        } else {
+            format!("version {}", unpacked)
+        };
+        let mut details = vec![directory.display().to_string()];
+        let mut status = Status::Ok;
+        if let Some((held, state)) = lock_state(&directory.join(LOCK_FILE)) {
+            if held {
+                status = Status::Warning;
+            }
+            details.push(format!("{} unpack lock", state));
+        }
+        if let Some((held, state)) = lock_state(&directory.join(RUN_LOCK_FILE)) {
+            details.push(if held {
+                "running".to_string()
+            } else {
+                format!("{} run lock", state)
+            });
+        }
+        report.check(status, &name, details.join(", "));
+    }
+
+    println!();
+    println!("console:");
+    let terminal = |is_terminal: bool| {
+        if is_terminal {
+            "terminal"
+        } else {
+            "not a terminal"
+        }
+    };
+    report.check(Status::Ok, "stdin", terminal(stdin().is_terminal()));
+    report.check(Status::Ok, "stdout", terminal(stdout().is_terminal()));
+    if cfg!(windows) {
+        report.check(
+            Status::Ok,
+            "console",
+            format!(
+                "{} ({})",
+                if doctor.console_attached {
+                    "attached to the parent console"
+                } else {
+                    "not attached"
+                },
+                ["never shown", "always shown", "attached to the parent"]
+                    .get(info.show_console as usize)
+                    .unwrap_or(&"unknown")
+            ),
+        );
+    }
+    if doctor.login_shell {
+        report.check(
+            Status::Warning,
+            "login shell",
+            "started as a login shell, runner output is hidden",
+        );
+    }
+    if var_os("SSH_CONNECTION").is_some() && !stdout().is_terminal() {
+        report.check(
+            Status::Warning,
+            "ssh",
+            "started over SSH without a terminal, runner output is hidden",
+        );
+    }
+
+    println!();
+    println!("{} errors, {} warnings", report.errors, report.warnings);
+    report.errors == 0
+}
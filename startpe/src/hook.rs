@@ -0,0 +1,55 @@
+use std::{
+    env::temp_dir,
+    ffi::OsStr,
+    fs::{read_to_string, remove_file, write},
+    io::Result,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+use crate::types::WRAPPE_DIRECTORY;
+
+/// Name of the pre-launch hook in the wrappe directory of the payload.
+pub const PRE_LAUNCH_HOOK: &str = "pre-launch";
+
+/// Path of the pre-launch hook in `unpack_dir` if the payload contains one.
+pub fn find_pre_launch_hook(unpack_dir: &Path) -> Option<PathBuf> {
+    let directory = unpack_dir.join(WRAPPE_DIRECTORY);
+    let extensions: &[&str] = if cfg!(windows) {
+        &["exe", "cmd", "bat", ""]
+    } else {
+        &[""]
+    };
+    extensions
+        .iter()
+        .map(|extension| directory.join(PRE_LAUNCH_HOOK).with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+/// Run the pre-launch `hook` with the launch context of `command` in environment variables.
+/// The arguments are written to a file with one argument per line, which the hook can rewrite
+/// to change the arguments passed to the command.
+/// Returns the exit status of the hook and the arguments read back from the file.
+pub fn run_pre_launch_hook(
+    hook: &Path, command: &Path, arguments: Vec<String>, context: &[(&str, &OsStr)],
+    current_dir: &Path,
+) -> Result<(ExitStatus, Vec<String>)> {
+    let arguments_file = temp_dir().join(format!("wrappe-arguments-{}", std::process::id()));
+    write(
+        &arguments_file,
+        arguments
+            .iter()
+            .map(|argument| format!("{}\n", argument))
+            .collect::<String>(),
+    )?;
+    let status = Command::new(hook)
+        .envs(context.iter().copied())
+        .env("WRAPPE_COMMAND", command)
+        .env("WRAPPE_ARGUMENTS_FILE", &arguments_file)
+        .current_dir(current_dir)
+        .status();
+    let arguments = read_to_string(&arguments_file);
+    let _ = remove_file(&arguments_file);
+    let arguments = arguments?.lines().map(str::to_string).collect();
+    Ok((status?, arguments))
+}
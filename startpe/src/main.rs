@@ -1,11 +1,14 @@
 use std::{
-    env::{current_exe, var_os},
+    env::{args_os, current_exe, var_os},
     fs::{File, create_dir_all, read_link, remove_dir, remove_dir_all},
-    io::Write,
+    io::{IsTerminal, Write, stdout},
     mem::size_of,
+    ops::Deref,
     panic::set_hook,
+    path::{Path, PathBuf},
     process::Command,
-    time::SystemTime,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
 };
 
 #[cfg(windows)]
@@ -21,7 +24,7 @@ use windows_sys::Win32::System::Console::{ATTACH_PARENT_PROCESS, AttachConsole};
 
 use fslock_guard::LockFileGuard;
 use memchr::memmem;
-use memmap2::MmapOptions;
+use memmap2::{Mmap, MmapOptions};
 use zerocopy::Ref;
 
 mod types;
@@ -36,13 +39,112 @@ use permissions::*;
 mod versioning;
 use versioning::*;
 
+mod desktop;
+
+mod sections;
+use sections::*;
+
+mod hook;
+use hook::*;
+
+mod supervise;
+use supervise::*;
+
+mod descriptors;
+use descriptors::*;
+
+mod priority;
+use priority::*;
+
+mod sandbox;
+use sandbox::*;
+
+mod backoff;
+use backoff::*;
+
+mod doctor;
+use doctor::Doctor;
+
 #[cfg(feature = "prefetch")]
 mod prefetch;
 
 #[cfg(feature = "once")]
 mod once;
 
+#[cfg(feature = "signing")]
+mod signing;
+
+#[cfg(windows)]
+mod pseudoconsole;
+
+/// Exit because another instance is running or unpacking, optionally without a message.
+fn once_exit(message: &str, quiet: bool, exit_code: u8) -> ! {
+    if !quiet {
+        println!("{}", message);
+    }
+    std::process::exit(exit_code as i32)
+}
+
+/// Release `lockfile` and remove its file at `path`, so that it doesn't remain in the unpack
+/// directory. Runners waiting for the lock notice that the file was removed and lock a new one.
+/// The lock file is opened close-on-exec and non-inheritable, so the command never holds it.
+fn release_lock(lockfile: Option<LockFileGuard>, path: &Path) {
+    if let Some(lockfile) = lockfile {
+        let _ = lockfile.delete_lock_file(path);
+    }
+}
+
+/// Marker followed by the payload format of the runner, checked by the packer when packing with
+/// a runner binary from a path.
+#[used]
+static RUNNER_FORMAT: [u8; RUNNER_MARKER.len() + 1] = {
+    let mut marker = [WRAPPE_FORMAT; RUNNER_MARKER.len() + 1];
+    let mut index = 0;
+    while index < RUNNER_MARKER.len() {
+        marker[index] = RUNNER_MARKER[index];
+        index += 1;
+    }
+    marker
+};
+
+/// Open and memory map the file at `path` containing the payload.
+fn map_file(path: &Path) -> std::io::Result<(File, Mmap)> {
+    #[cfg(windows)]
+    let file = File::options()
+        .read(true)
+        .custom_flags(0x10000000) // FILE_FLAG_RANDOM_ACCESS
+        .open(path)?;
+    // the executable stays replaceable while the command is running
+    #[cfg(windows)]
+    set_non_inheritable(&file);
+    #[cfg(not(windows))]
+    let file = File::options().read(true).open(path)?;
+    let mmap = unsafe { MmapOptions::new().map(&file)? };
+    Ok((file, mmap))
+}
+
+/// Position of the starter info at the end of `data`, or of the last occurrence of its
+/// `signature` if it isn't found at the end.
+fn find_starter_info(data: &[u8], signature: &[u8]) -> Option<usize> {
+    let end = data.len();
+    if end >= size_of::<StarterInfo>()
+        && data[end - size_of::<StarterInfo>()..].starts_with(signature)
+    {
+        return Some(end - size_of::<StarterInfo>());
+    }
+    memmem::rfind(data, signature)
+}
+
+/// Whether the runner was started as a login shell with a leading dash in its name.
+fn started_as_login_shell() -> bool {
+    args_os()
+        .next()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"-"))
+}
+
 fn main() {
+    // keep the marker from being removed by the linker
+    std::hint::black_box(&RUNNER_FORMAT);
     set_hook(Box::<_>::new(move |panic| {
         if let Some(message) = panic.payload().downcast_ref::<&str>() {
             eprintln!("error: {}", message);
@@ -78,40 +180,28 @@ fn main() {
     while let Ok(link) = read_link(&exe) {
         exe = link;
     }
-    #[cfg(windows)]
-    let file = File::options()
-        .read(true)
-        .custom_flags(0x10000000) // FILE_FLAG_RANDOM_ACCESS
-        .open(&exe)
-        .expect("couldn't open current executable");
-    #[cfg(not(windows))]
-    let file = File::options()
-        .read(true)
-        .open(&exe)
-        .expect("couldn't open current executable");
-
-    let mmap = unsafe {
-        MmapOptions::new()
-            .map(&file)
-            .expect("couldn't memory map current executable")
-    };
-    let end = mmap.len();
-    if end < size_of::<StarterInfo>() {
-        panic!("file is too small ({} < {})", end, size_of::<StarterInfo>())
-    }
-
     let mut signature = Vec::with_capacity(8);
     signature.extend_from_slice(&WRAPPE_SIGNATURE_1[..4]);
     signature.extend_from_slice(&WRAPPE_SIGNATURE_2[..4]);
 
-    let mut info_start = end - size_of::<StarterInfo>();
-    if mmap[info_start..info_start + 8] != signature[..] {
-        if let Some(pos) = memmem::rfind(&mmap[..], &signature) {
-            info_start = pos;
-        } else {
-            panic!("couldn't find starter info")
-        }
+    let (mut file, mut mmap) =
+        map_file(&exe).unwrap_or_else(|e| panic!("couldn't map current executable: {}", e));
+    let mut info_start = find_starter_info(&mmap, &signature);
+    // split runners find the payload in the pack file next to them
+    let pack_path = exe.with_extension(PACK_EXTENSION);
+    let split = info_start.is_none() && pack_path.is_file();
+    if split {
+        (file, mmap) = map_file(&pack_path)
+            .unwrap_or_else(|e| panic!("couldn't map {}: {}", pack_path.display(), e));
+        info_start = find_starter_info(&mmap, &signature);
     }
+    let Some(info_start) = info_start else {
+        panic!(
+            "couldn't find starter info in the executable or in {}",
+            pack_path.display()
+        )
+    };
+    let end = mmap.len();
 
     if info_start + size_of::<StarterInfo>() > end {
         panic!(
@@ -133,10 +223,48 @@ fn main() {
             WRAPPE_FORMAT, info.wrappe_format
         );
     }
+    // the file manifest, extra sections and baked arguments are stored between the payload and
+    // the starter info
+    let arguments_size = info.arguments_size.get() as usize;
+    let extra_sections_size = info.extra_sections_size.get() as usize;
+    let manifest_size = info.manifest_size.get() as usize;
+    if arguments_size + extra_sections_size + manifest_size > info_start {
+        panic!(
+            "baked arguments, extra sections and manifest size ({}) exceeds file size ({})",
+            arguments_size + extra_sections_size + manifest_size,
+            info_start
+        );
+    }
+    let arguments_start = info_start - arguments_size;
+    let extra_sections_start = arguments_start - extra_sections_size;
+    let payload_end = extra_sections_start - manifest_size;
+
+    // the executable is copied into memory and closed right away when requested,
+    // so that it can be replaced while the command is running
+    let in_memory = info.in_memory == 1;
+    let (mmap, file): (Box<dyn Deref<Target = [u8]> + Send + Sync>, Option<File>) = if in_memory {
+        let data = mmap.to_vec();
+        drop(mmap);
+        drop(file);
+        (Box::new(data), None)
+    } else {
+        (Box::new(mmap), Some(file))
+    };
+    let info = Ref::into_ref(
+        Ref::<_, StarterInfo>::from_bytes(&mmap[info_start..info_start + size_of::<StarterInfo>()])
+            .expect("couldn't read starter info"),
+    );
 
     let mut show_information = info.show_information;
     let show_console = info.show_console;
 
+    // output of the runner would be mixed into the output of the command when started as a
+    // login shell or as a forced command of an SSH session without a terminal
+    let login_shell = started_as_login_shell();
+    if login_shell || (var_os("SSH_CONNECTION").is_some() && !stdout().is_terminal()) {
+        show_information = 0;
+    }
+
     if show_information < 2 && var_os("STARTPE_FORCE_VERBOSE").is_some() {
         show_information = 2;
     }
@@ -150,6 +278,84 @@ fn main() {
         console_attached = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) != 0 };
     }
 
+    // the embedded file manifest is printed instead of unpacking and starting the command
+    if args_os()
+        .nth(1)
+        .is_some_and(|argument| argument == "--wrappe-manifest")
+    {
+        #[cfg(windows)]
+        if !console_attached {
+            unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+        }
+        if manifest_size == 0 {
+            panic!("the executable contains no file manifest");
+        }
+        let mut out = stdout().lock();
+        out.write_all(&mmap[payload_end..extra_sections_start])
+            .and_then(|_| out.flush())
+            .unwrap_or_else(|e| panic!("couldn't write file manifest: {}", e));
+        std::process::exit(0);
+    }
+
+    // the host environment is checked instead of unpacking and starting the command
+    if args_os()
+        .nth(1)
+        .is_some_and(|argument| argument == "--wrappe-doctor")
+    {
+        #[cfg(windows)]
+        if !console_attached {
+            unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+        }
+        let healthy = doctor::run(&Doctor {
+            info,
+            exe: &exe,
+            payload_path: if split { &pack_path } else { &exe },
+            payload: &mmap[..payload_end],
+            console_attached,
+            login_shell,
+        });
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+
+    // the payload hash and signature are checked instead of unpacking and starting the command
+    if args_os()
+        .nth(1)
+        .is_some_and(|argument| argument == "--wrappe-verify")
+    {
+        #[cfg(windows)]
+        if !console_attached {
+            unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+        }
+        let signed = info.public_key != [0; 32];
+        let result = check_payload_hash(&mmap[..payload_end]).and_then(|_| {
+            if !signed {
+                return Ok(());
+            }
+            #[cfg(feature = "signing")]
+            return signing::verify_payload(&mmap, payload_end, info_start)
+                .map_err(|e| format!("payload signature verification failed: {}", e));
+            #[cfg(not(feature = "signing"))]
+            Err(
+                "payload is signed, but this runner was built without signature verification"
+                    .to_string(),
+            )
+        });
+        match result {
+            Ok(()) => {
+                println!("payload hash: ok");
+                println!(
+                    "payload signature: {}",
+                    if signed { "verified" } else { "not signed" }
+                );
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("verification failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     if show_information >= 1 {
         println!(
             "{} {}{}",
@@ -164,42 +370,98 @@ fn main() {
     if info.unpack_directory.is_empty() {
         panic!("empty unpack directory name")
     }
-    let unpack_dir_name = std::str::from_utf8(
-        &info.unpack_directory[0..(info
-            .unpack_directory
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(info.unpack_directory.len()))],
-    )
-    .unwrap();
+    let version =
+        decode_field(&info.uid).unwrap_or_else(|e| panic!("couldn't decode version: {}", e));
+    let command_name =
+        decode_field(&info.command).unwrap_or_else(|e| panic!("couldn't decode command: {}", e));
+    let unpack_dir_name = expand_unpack_directory(
+        decode_field(&info.unpack_directory)
+            .unwrap_or_else(|e| panic!("couldn't decode unpack directory name: {}", e)),
+        command_name,
+        version,
+        &runner_target(),
+    );
     if show_information >= 1 {
         println!("{}", unpack_dir_name);
     }
 
-    let version = std::str::from_utf8(
-        &info.uid[0..(info
-            .uid
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(info.uid.len()))],
-    )
-    .unwrap();
+    let app_id = format_app_id(&info.app_id);
     if show_information >= 2 {
         println!();
         println!("version: {}", version);
+        println!("app id: {}", app_id);
         println!(
             "show console: {} (attached: {})",
             show_console, console_attached
         );
     }
 
-    let mut unpack_root = match info.unpack_target {
-        0 => std::env::temp_dir(),
-        1 => dirs::data_local_dir().unwrap(),
-        2 => std::env::current_dir().unwrap(),
-        _ => panic!("invalid unpack target"),
+    let mut unpack_root = if let Some(root) = var_os("WRAPPE_UNPACK_ROOT") {
+        PathBuf::from(root)
+    } else {
+        match info.unpack_target {
+            0 => std::env::temp_dir(),
+            1 => dirs::data_local_dir().unwrap(),
+            2 => std::env::current_dir().unwrap(),
+            _ => panic!("invalid unpack target"),
+        }
     };
-    unpack_root = unpack_root.join(unpack_dir_name);
+    // home and temporary directories can be read-only in application sandboxes
+    if let (None, Some(sandbox)) = (var_os("WRAPPE_UNPACK_ROOT"), detect_sandbox()) {
+        if show_information >= 2 {
+            println!("sandbox: {}", sandbox);
+        }
+        if info.unpack_target != 2 && !is_writable(&unpack_root) {
+            let directories = sandbox_directories(sandbox);
+            let writable = directories
+                .iter()
+                .find(|directory| is_writable(directory))
+                .unwrap_or_else(|| {
+                    panic!(
+                        "no writable directory to unpack to in the {} sandbox, tried {} and {:?} (set WRAPPE_UNPACK_ROOT to a writable directory)",
+                        sandbox,
+                        unpack_root.display(),
+                        directories
+                    )
+                });
+            if show_information >= 2 {
+                println!(
+                    "{} is not writable, unpacking to {}",
+                    unpack_root.display(),
+                    writable.display()
+                );
+            }
+            unpack_root = writable.clone();
+        }
+    }
+    // instances launched from different locations or with different payloads unpack to their
+    // own subdirectory when requested, so that they don't overwrite each other
+    let subdir = get_instance_subdir(info.unpack_subdir, &exe, &mmap[..payload_end]);
+    if let (true, Some(subdir)) = (show_information >= 2, &subdir) {
+        println!("instance subdirectory: {}", subdir);
+    }
+    let locate = |root: &Path| {
+        let root = root.join(&unpack_dir_name);
+        match &subdir {
+            Some(subdir) => root.join(subdir),
+            None => root,
+        }
+    };
+    unpack_root = locate(&unpack_root);
+    // failed extractions are counted in the unpack directory of the default strategy, and the
+    // payload is unpacked to the alternative target after repeated failures or if the directory
+    // can't be created
+    let mut backoff = (info.backoff == 1).then(|| Backoff::read(&unpack_root));
+    if let Some(backoff) = &mut backoff {
+        if var_os("WRAPPE_UNPACK_ROOT").is_none()
+            && (backoff.relocate || !is_writable(&unpack_root))
+        {
+            if let Some(root) = alternative_root(info.unpack_target) {
+                backoff.relocate = true;
+                unpack_root = locate(&root);
+            }
+        }
+    }
     let mut unpack_dir = unpack_root.clone();
     if info.versioning == 0 {
         unpack_dir = unpack_dir.join(version);
@@ -208,14 +470,6 @@ fn main() {
         println!("target directory: {}", unpack_dir.display());
     }
 
-    let command_name = std::str::from_utf8(
-        &info.command[0..(info
-            .command
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(info.command.len()))],
-    )
-    .unwrap();
     let run_path = &unpack_dir.join(command_name);
     if show_information >= 2 {
         println!("runpath: {}", run_path.display());
@@ -224,16 +478,81 @@ fn main() {
     create_dir_all(&unpack_dir)
         .unwrap_or_else(|e| panic!("couldn't create directory {}: {}", unpack_dir.display(), e));
 
-    let lockfile = if info.once == 1 {
-        let lockfile = LockFileGuard::try_lock(unpack_dir.join(LOCK_FILE))
+    // held until the command exits with the app strategy, wherever the application is unpacked
+    #[cfg(feature = "once")]
+    let app_instance = if info.once == 4 {
+        let identity = once::app_identity(&app_id, version);
+        if show_information >= 2 {
+            println!("application identity: {}", identity);
+        }
+        let app_instance = once::claim_identity(&identity)
+            .unwrap_or_else(|e| panic!("couldn't claim application identity: {}", e));
+        if app_instance.is_none() {
+            once_exit(
+                "another instance is already running, exiting...",
+                info.once_quiet == 1,
+                info.once_exit_code,
+            );
+        }
+        app_instance
+    } else {
+        None
+    };
+    #[cfg(not(feature = "once"))]
+    let app_instance = None::<()>;
+
+    // held until the command exits with the lock and wait strategies
+    let run_lock_path = unpack_dir.join(RUN_LOCK_FILE);
+    let run_lockfile = if info.once == 2 || info.once == 3 {
+        let mut run_lockfile = LockFileGuard::try_lock(&run_lock_path)
+            .unwrap_or_else(|e| panic!("couldn't lock file: {}", e));
+        if run_lockfile.is_none() && info.once == 3 {
+            if info.once_quiet == 0 {
+                println!("another instance is already running, waiting...");
+            }
+            let once_timeout = info.once_timeout.get();
+            if once_timeout == 0 {
+                run_lockfile = Some(
+                    LockFileGuard::lock(&run_lock_path)
+                        .unwrap_or_else(|e| panic!("couldn't lock file: {}", e)),
+                );
+            } else {
+                let start = Instant::now();
+                while run_lockfile.is_none()
+                    && start.elapsed() < Duration::from_secs(once_timeout as u64)
+                {
+                    sleep(Duration::from_millis(100));
+                    run_lockfile = LockFileGuard::try_lock(&run_lock_path)
+                        .unwrap_or_else(|e| panic!("couldn't lock file: {}", e));
+                }
+            }
+        }
+        if run_lockfile.is_none() {
+            once_exit(
+                "another instance is already running, exiting...",
+                info.once_quiet == 1,
+                info.once_exit_code,
+            );
+        }
+        run_lockfile
+    } else {
+        None
+    };
+
+    let lock_path = unpack_dir.join(LOCK_FILE);
+    let lockfile = if info.once != 0 {
+        let lockfile = LockFileGuard::try_lock(&lock_path)
             .unwrap_or_else(|e| panic!("couldn't lock file: {}", e));
         if lockfile.is_none() {
-            println!("another instance is already unpacking, exiting...");
-            return;
+            once_exit(
+                "another instance is already unpacking, exiting...",
+                info.once_quiet == 1,
+                info.once_exit_code,
+            );
         }
         lockfile.unwrap()
     } else {
-        LockFileGuard::lock(unpack_dir.join(LOCK_FILE)).unwrap_or_else(|e| {
+        LockFileGuard::lock(&lock_path).unwrap_or_else(|e| {
             panic!("couldn't lock file: {}", e);
         })
     };
@@ -245,8 +564,11 @@ fn main() {
         }
         let running = once::check_instance(run_path).unwrap();
         if running {
-            println!("another instance is already running, exiting...");
-            return;
+            once_exit(
+                "another instance is already running, exiting...",
+                info.once_quiet == 1,
+                info.once_exit_code,
+            );
         }
     }
 
@@ -256,6 +578,9 @@ fn main() {
     } else {
         cleanup = info.cleanup == 1
     }
+    let supervise = info.supervise == 1;
+    let process_group = info.process_group;
+    let (close_fds, passed_fds) = (info.close_fds == 1, info.passed_fds.get());
 
     let should_extract = match info.versioning {
         0 => get_version(&unpack_dir) != version,
@@ -272,18 +597,91 @@ fn main() {
         println!("should verify: {}", verification);
         println!("should extract: {}", should_extract);
         println!("should cleanup: {}", cleanup);
+        println!("should supervise: {}", supervise);
     }
 
+    // signed payloads are checked before they are extracted, also when extraction is decided
+    // by the verification of the unpacked files
+    let check_signature = || {
+        if info.public_key == [0; 32] {
+            return;
+        }
+        #[cfg(feature = "signing")]
+        {
+            signing::verify_payload(&mmap, payload_end, info_start)
+                .unwrap_or_else(|e| panic!("payload signature verification failed: {}", e));
+            if show_information >= 2 {
+                println!("payload signature: verified");
+            }
+        }
+        #[cfg(not(feature = "signing"))]
+        panic!("payload is signed, but this runner was built without signature verification");
+    };
+
+    let mut extracted = false;
     if should_extract || verification > 0 {
         let now = SystemTime::now();
-        let extracted = decompress(
-            &mmap[..info_start],
-            &unpack_dir,
-            verification,
-            should_extract,
-            version,
-            show_information,
-        );
+        if let Some(backoff) = &backoff {
+            if backoff.active() {
+                eprintln!("{}", backoff.describe(&unpack_root));
+            }
+            backoff.start();
+        }
+        let reduce = backoff.as_ref().is_some_and(|backoff| backoff.reduce);
+        let prefetch_threshold = (info.prefetch == 1 && !in_memory && !reduce)
+            .then_some(info.prefetch_threshold.get() as u64 * 1024 * 1024);
+        let lower_priority = move || {
+            if let Err(e) = lower_thread_priority() {
+                if show_information >= 2 {
+                    eprintln!("failed to lower thread priority: {}", e);
+                }
+            }
+        };
+        let mut pool = rayon::ThreadPoolBuilder::new();
+        if reduce {
+            pool = pool.num_threads(1);
+        }
+        if info.low_priority == 1 {
+            pool = pool.start_handler(move |_| lower_priority());
+        }
+        let _ = pool.build_global();
+        extracted = if info.low_priority == 1 {
+            // unpack with lowered priority on separate threads to keep the priority of the main thread
+            std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        lower_priority();
+                        decompress(
+                            &mmap[..payload_end],
+                            &unpack_dir,
+                            verification,
+                            should_extract,
+                            version,
+                            show_information,
+                            prefetch_threshold,
+                            info.ownership == 1,
+                            check_signature,
+                        )
+                    })
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+        } else {
+            decompress(
+                &mmap[..payload_end],
+                &unpack_dir,
+                verification,
+                should_extract,
+                version,
+                show_information,
+                prefetch_threshold,
+                info.ownership == 1,
+                check_signature,
+            )
+        };
+        if let Some(backoff) = &backoff {
+            backoff.finish();
+        }
         if extracted {
             if show_information >= 2 {
                 println!(
@@ -295,21 +693,29 @@ fn main() {
         }
     }
 
-    drop(lockfile);
+    if extra_sections_size > 0 && (extracted || !extra_sections_dir(&unpack_dir).is_dir()) {
+        write_extra_sections(&mmap[extra_sections_start..arguments_start], &unpack_dir)
+            .unwrap_or_else(|e| panic!("couldn't write extra sections: {}", e));
+    }
+
+    // used by wrappe bench to measure unpacking without starting the command
+    if var_os("WRAPPE_UNPACK_ONLY").is_some() {
+        release_lock(Some(lockfile), &lock_path);
+        std::process::exit(0);
+    }
+
+    if info.desktop_entry == 1 {
+        match desktop::install_desktop_entry(&unpack_dir, &unpack_dir_name, &exe) {
+            Ok(true) if show_information >= 2 => println!("installed desktop entry"),
+            Ok(_) => {}
+            Err(e) => eprintln!("failed to install desktop entry: {}", e),
+        }
+    }
+
+    release_lock(Some(lockfile), &lock_path);
 
-    let baked_arguments = std::str::from_utf8(
-        &info.arguments[0..(info
-            .arguments
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(info.arguments.len()))],
-    )
-    .expect("couldn't parse baked arguments");
-    let baked_arguments = baked_arguments
-        .split('\u{1f}')
-        .map(|arg| arg.trim().to_string())
-        .filter(|arg| !arg.is_empty())
-        .collect::<Vec<_>>();
+    let baked_arguments = decode_arguments(&mmap[arguments_start..info_start])
+        .unwrap_or_else(|e| panic!("couldn't decode baked arguments: {}", e));
     if show_information >= 2 && !baked_arguments.is_empty() {
         println!("baked arguments: {:?}", baked_arguments);
     }
@@ -331,9 +737,43 @@ fn main() {
         println!("current dir: {}", current_dir.display());
     }
 
+    // closed before running the hook and the command, which don't inherit the handle
     drop(mmap);
     drop(file);
 
+    let sections_dir = (extra_sections_size > 0).then(|| extra_sections_dir(&unpack_dir));
+    let mut environment = vec![
+        ("WRAPPE_UNPACK_DIR", unpack_dir.as_os_str()),
+        ("WRAPPE_LAUNCH_DIR", launch_dir.as_os_str()),
+        ("WRAPPE_APP_ID", app_id.as_ref()),
+    ];
+    if let Some(sections_dir) = &sections_dir {
+        environment.push(("WRAPPE_SECTIONS_DIR", sections_dir.as_os_str()));
+    }
+
+    let mut arguments = baked_arguments;
+    arguments.extend(forwarded_arguments);
+    if let Some(hook) = find_pre_launch_hook(&unpack_dir) {
+        if show_information >= 2 {
+            println!("running pre-launch hook...");
+        }
+        let (status, hook_arguments) =
+            run_pre_launch_hook(&hook, run_path, arguments, &environment, current_dir)
+                .unwrap_or_else(|e| panic!("failed to run {}: {}", hook.display(), e));
+        if !status.success() {
+            release_lock(run_lockfile, &run_lock_path);
+            if cleanup {
+                let _ = remove_dir_all(&unpack_dir);
+                let _ = remove_dir(&unpack_root);
+            }
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        if show_information >= 2 {
+            println!("arguments after pre-launch hook: {:?}", hook_arguments);
+        }
+        arguments = hook_arguments;
+    }
+
     if show_information >= 2 {
         println!("running...");
     }
@@ -343,10 +783,21 @@ fn main() {
     }
 
     let mut command = Command::new(run_path);
-    command.args(baked_arguments);
-    command.args(forwarded_arguments);
-    command.env("WRAPPE_UNPACK_DIR", unpack_dir.as_os_str());
-    command.env("WRAPPE_LAUNCH_DIR", launch_dir.as_os_str());
+    // the command is started as a login shell as well
+    #[cfg(any(unix, target_os = "redox"))]
+    if login_shell {
+        if let Some(name) = run_path.file_name() {
+            let mut login_name = std::ffi::OsString::from("-");
+            login_name.push(name);
+            command.arg0(login_name);
+        }
+    }
+    command.args(arguments);
+    command.envs(environment);
+    set_process_group(&mut command, process_group);
+    if close_fds {
+        close_inherited_descriptors(&mut command, passed_fds);
+    }
     command.current_dir(current_dir);
 
     #[cfg(not(any(unix, target_os = "redox")))]
@@ -357,16 +808,32 @@ fn main() {
             command.stdin(Stdio::null());
         }
     }
-    if cleanup {
-        let mut child = command
-            .spawn()
-            .unwrap_or_else(|e| panic!("failed to run {}: {}", run_path.display(), e));
-        let status = child
-            .wait()
+    // connect the command to the attached console through a pseudo console
+    #[cfg(windows)]
+    if show_console == 2 && console_attached {
+        if let Some(exit_code) = pseudoconsole::run_pseudo_console(&command)
+            .unwrap_or_else(|e| panic!("failed to run {}: {}", run_path.display(), e))
+        {
+            release_lock(run_lockfile, &run_lock_path);
+            if cleanup {
+                let _ = remove_dir_all(unpack_dir);
+                let _ = remove_dir(unpack_root);
+            }
+            std::process::exit(exit_code as i32)
+        }
+    }
+
+    // the runner stays alive to forward signals to a command in its own process group
+    if supervise || process_group > 0 || cleanup || run_lockfile.is_some() || app_instance.is_some()
+    {
+        let code = run_supervised(&mut command, process_group > 0)
             .unwrap_or_else(|e| panic!("failed to run {}: {}", run_path.display(), e));
-        let _ = remove_dir_all(unpack_dir);
-        let _ = remove_dir(unpack_root);
-        std::process::exit(status.code().unwrap_or(1))
+        release_lock(run_lockfile, &run_lock_path);
+        if cleanup {
+            let _ = remove_dir_all(unpack_dir);
+            let _ = remove_dir(unpack_root);
+        }
+        std::process::exit(code)
     } else {
         #[cfg(any(unix, target_os = "redox"))]
         {
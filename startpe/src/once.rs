@@ -1,7 +1,183 @@
-use std::path::Path;
+use std::{hash::Hasher, io::Error, path::Path};
 
+use twox_hash::XxHash64;
+
+use crate::decompress::HASH_SEED;
+
+/// Identity of the application shared by all places it is unpacked to, derived from the
+/// application ID and the version of the payload.
+pub fn app_identity(app_id: &str, version: &str) -> String {
+    let mut hasher = XxHash64::with_seed(HASH_SEED);
+    hasher.write(app_id.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(version.as_bytes());
+    format!("wrappe-{:016x}", hasher.finish())
+}
+
+/// Running instance of the application, holding its identity until the runner exits.
+#[cfg(target_os = "linux")]
+pub struct AppInstance(#[allow(dead_code)] std::os::unix::net::UnixListener);
+
+/// Claim `identity` with an abstract socket, which is visible to all users in the network
+/// namespace and released by the system when the runner exits.
+/// Returns `None` if another instance holds it.
+#[cfg(target_os = "linux")]
+pub fn claim_identity(identity: &str) -> Result<Option<AppInstance>, Error> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixListener},
+    };
+    let address = SocketAddr::from_abstract_name(identity.as_bytes())?;
+    match UnixListener::bind_addr(&address) {
+        Ok(listener) => Ok(Some(AppInstance(listener))),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Running instance of the application, holding its identity until the runner exits.
 #[cfg(windows)]
-pub fn check_instance(run_path: &Path) -> Result<bool, std::io::Error> {
+pub struct AppInstance(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl Drop for AppInstance {
+    fn drop(&mut self) { unsafe { windows_sys::Win32::Foundation::CloseHandle(self.0) }; }
+}
+
+/// Claim `identity` with a named mutex in the global namespace, which is visible to all sessions
+/// and released by the system when the runner exits.
+/// Returns `None` if another instance holds it.
+#[cfg(windows)]
+pub fn claim_identity(identity: &str) -> Result<Option<AppInstance>, Error> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt};
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, ERROR_ALREADY_EXISTS, GetLastError},
+        System::Threading::CreateMutexW,
+    };
+    let name = OsStr::new(&format!("Global\\{}", identity))
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    let handle = unsafe { CreateMutexW(std::ptr::null(), 0, name.as_ptr()) };
+    if handle.is_null() {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+        unsafe { CloseHandle(handle) };
+        return Ok(None);
+    }
+    Ok(Some(AppInstance(handle)))
+}
+
+/// Running instance of the application, holding its identity until the runner exits.
+#[cfg(not(any(windows, target_os = "linux")))]
+pub struct AppInstance(#[allow(dead_code)] fslock_guard::LockFileGuard);
+
+/// Claim `identity` with a lock file in the temporary directory, which is shared by the
+/// instances of the same user.
+/// Returns `None` if another instance holds it.
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn claim_identity(identity: &str) -> Result<Option<AppInstance>, Error> {
+    let path = std::env::temp_dir().join(format!("{}.lock", identity));
+    Ok(fslock_guard::LockFileGuard::try_lock(path)?.map(AppInstance))
+}
+
+/// Compare paths by the identity of the files they point to, since they can differ in case,
+/// use short names or be prefixed with `\\?\` when they contain spaces or non-ASCII characters.
+#[cfg(windows)]
+fn same_path(a: &Path, b: &Path) -> bool {
+    use std::{fs::File, os::windows::io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        BY_HANDLE_FILE_INFORMATION, GetFileInformationByHandle,
+    };
+
+    fn file_id(path: &Path) -> Option<(u32, u32, u32)> {
+        let file = File::open(path).ok()?;
+        let mut info = unsafe { std::mem::zeroed::<BY_HANDLE_FILE_INFORMATION>() };
+        if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut info) } == 0 {
+            return None;
+        }
+        Some((
+            info.dwVolumeSerialNumber,
+            info.nFileIndexHigh,
+            info.nFileIndexLow,
+        ))
+    }
+
+    if a == b {
+        return true;
+    }
+    if let (Some(a), Some(b)) = (file_id(a), file_id(b)) {
+        return a == b;
+    }
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a
+            .as_os_str()
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&b.as_os_str().to_string_lossy()),
+    }
+}
+
+/// Claim a named mutex in the session namespace derived from `run_path`, and return whether
+/// another runner already holds it. The handle is inheritable and never closed, so that the
+/// command keeps holding it after the runner exits.
+#[cfg(windows)]
+fn claim_run_path(run_path: &Path) -> Result<bool, Error> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt};
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, ERROR_ALREADY_EXISTS, GetLastError},
+        Security::SECURITY_ATTRIBUTES,
+        System::Threading::CreateMutexW,
+    };
+    // paths are compared like in the process scan, the unpack directory exists at this point
+    let path = match (
+        run_path.parent().map(Path::canonicalize),
+        run_path.file_name(),
+    ) {
+        (Some(Ok(parent)), Some(name)) => parent.join(name),
+        _ => run_path.to_owned(),
+    };
+    let mut hasher = XxHash64::with_seed(HASH_SEED);
+    hasher.write(path.to_string_lossy().to_lowercase().as_bytes());
+    let name = OsStr::new(&format!("Local\\wrappe-{:016x}", hasher.finish()))
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    let attributes = SECURITY_ATTRIBUTES {
+        nLength:              std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: std::ptr::null_mut(),
+        bInheritHandle:       1,
+    };
+    let handle = unsafe { CreateMutexW(&attributes, 0, name.as_ptr()) };
+    if handle.is_null() {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+        unsafe { CloseHandle(handle) };
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Check whether the command at `run_path` is already running with a named mutex held by the
+/// runners and commands of other instances. The processes are only scanned to activate the
+/// window of a running instance, or when the mutex can't be created.
+#[cfg(windows)]
+pub fn check_instance(run_path: &Path) -> Result<bool, Error> {
+    match claim_run_path(run_path) {
+        Ok(false) => Ok(false),
+        Ok(true) => {
+            let _ = scan_processes(run_path);
+            Ok(true)
+        }
+        Err(_) => scan_processes(run_path),
+    }
+}
+
+/// Check whether a process was started from `run_path` and bring its window into the foreground.
+#[cfg(windows)]
+fn scan_processes(run_path: &Path) -> Result<bool, std::io::Error> {
     use core::ffi::c_void;
     use std::{ffi::OsString, os::windows::ffi::OsStringExt};
     use windows_sys::Win32::{
@@ -70,7 +246,7 @@ pub fn check_instance(run_path: &Path) -> Result<bool, std::io::Error> {
                     return Err(std::io::Error::last_os_error());
                 }
                 let path = OsString::from_wide(&path[..len as usize]);
-                if path == run_path.as_os_str() {
+                if same_path(Path::new(&path), run_path) {
                     let result =
                         unsafe { EnumWindows(Some(enum_windows_proc), entry.th32ProcessID as _) };
                     if result == 0 {
@@ -93,30 +269,29 @@ pub fn check_instance(run_path: &Path) -> Result<bool, std::io::Error> {
 
 #[cfg(target_os = "linux")]
 pub fn check_instance(run_path: &Path) -> Result<bool, std::io::Error> {
-    let processes = procfs::process::all_processes();
+    // process executable paths are resolved, the command doesn't exist yet before unpacking
+    let run_path = run_path
+        .canonicalize()
+        .unwrap_or_else(|_| run_path.to_owned());
+    // processes of other users are hidden or not accessible with restricted /proc access,
+    // which doesn't affect instances started by the same user
+    let processes = std::fs::read_dir("/proc");
     if let Err(_e) = processes {
         #[cfg(debug_assertions)]
         eprintln!("error: {}", _e);
         return Ok(false);
     }
-    for proc in processes.unwrap() {
-        match proc {
-            Ok(p) => match p.exe() {
-                Ok(exe) => {
-                    if exe == run_path {
-                        return Ok(true);
-                    }
-                }
-                Err(_e) => {
-                    #[cfg(debug_assertions)]
-                    eprintln!("error: {}", _e);
-                    continue;
-                }
-            },
-            Err(_e) => {
-                #[cfg(debug_assertions)]
-                eprintln!("error: {}", _e);
-                continue;
+    for entry in processes.unwrap() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let name = entry.file_name();
+        if !name.as_encoded_bytes().iter().all(u8::is_ascii_digit) {
+            continue;
+        }
+        if let Ok(exe) = std::fs::read_link(entry.path().join("exe")) {
+            if exe == run_path {
+                return Ok(true);
             }
         }
     }
@@ -68,6 +68,72 @@ pub fn prefetch_memory(mmap: &[u8], offset: usize) -> Option<JoinHandle<Result<(
     }))
 }
 
-#[cfg(not(windows))]
+#[cfg(unix)]
+pub fn prefetch_memory(mmap: &[u8], offset: usize) -> Option<JoinHandle<Result<()>>> {
+    // madvise requires a page-aligned address
+    let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    };
+    let start = mmap.as_ptr() as usize + offset;
+    let virtual_address = start - start % page_size;
+    let number_of_bytes = mmap.len() - offset + (start - virtual_address);
+    Some(std::thread::spawn(move || {
+        // files are read front to back, so more aggressive readahead and
+        // early reclaim of already read pages is preferred
+        let result = unsafe {
+            libc::madvise(
+                virtual_address as *mut _,
+                number_of_bytes,
+                libc::MADV_SEQUENTIAL,
+            )
+        };
+        if result != 0 {
+            Err(std::io::Error::last_os_error())?;
+        }
+        let result = unsafe {
+            libc::madvise(
+                virtual_address as *mut _,
+                number_of_bytes,
+                libc::MADV_WILLNEED,
+            )
+        };
+        if result != 0 {
+            Err(std::io::Error::last_os_error())?;
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(not(any(windows, unix)))]
 #[inline(always)]
 pub fn prefetch_memory(_: &[u8], _: usize) -> Option<JoinHandle<Result<()>>> { None }
+
+/// Amount of memory in bytes that is available without swapping, if it can be determined.
+#[cfg(windows)]
+pub fn available_memory() -> Option<u64> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return None;
+    }
+    Some(status.ullAvailPhys)
+}
+
+/// Amount of memory in bytes that is available without swapping, if it can be determined.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|value| value * 1024)
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+#[inline(always)]
+pub fn available_memory() -> Option<u64> { None }
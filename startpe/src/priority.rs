@@ -0,0 +1,53 @@
+use std::io::Result;
+
+/// Lower the CPU and IO priority of the calling thread. Only the unpacking threads are
+/// lowered, so the command is started with the unchanged priority of the main thread.
+#[cfg(windows)]
+pub fn lower_thread_priority() -> Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_MODE_BACKGROUND_BEGIN,
+    };
+
+    if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lower the CPU and IO priority of the calling thread. Only the unpacking threads are
+/// lowered, so the command is started with the unchanged priority of the main thread.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lower_thread_priority() -> Result<()> {
+    // the nice value and IO priority are per-thread on Linux, 0 refers to the calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, 10) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_BE: libc::c_long = 2;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    // lowest priority of the best-effort class, the idle class could starve the extraction
+    let priority = IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | 7;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lower the CPU and IO priority of the calling thread. Only the unpacking threads are
+/// lowered, so the command is started with the unchanged priority of the main thread.
+#[cfg(target_vendor = "apple")]
+pub fn lower_thread_priority() -> Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, libc::PRIO_DARWIN_BG) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "linux",
+    target_os = "android",
+    target_vendor = "apple"
+)))]
+#[inline(always)]
+pub fn lower_thread_priority() -> Result<()> { Ok(()) }
@@ -0,0 +1,341 @@
+use core::{ffi::c_void, ptr::null_mut};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Error, Read, Result, Write, stdin, stdout},
+    mem::{size_of, zeroed},
+    os::windows::{ffi::OsStrExt, io::FromRawHandle},
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use windows_sys::{
+    Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        System::{
+            Console::{
+                CONSOLE_SCREEN_BUFFER_INFO, COORD, DISABLE_NEWLINE_AUTO_RETURN, ENABLE_ECHO_INPUT,
+                ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT, ENABLE_VIRTUAL_TERMINAL_INPUT,
+                ENABLE_VIRTUAL_TERMINAL_PROCESSING, GetConsoleMode, GetConsoleScreenBufferInfo,
+                GetStdHandle, HPCON, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, SetConsoleMode,
+            },
+            LibraryLoader::{GetProcAddress, LOAD_LIBRARY_SEARCH_SYSTEM32, LoadLibraryExA},
+            Pipes::CreatePipe,
+            Threading::{
+                CreateProcessW, DeleteProcThreadAttributeList, EXTENDED_STARTUPINFO_PRESENT,
+                GetExitCodeProcess, INFINITE, InitializeProcThreadAttributeList,
+                LPPROC_THREAD_ATTRIBUTE_LIST, PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+                PROCESS_INFORMATION, STARTF_USESTDHANDLES, STARTUPINFOEXW,
+                UpdateProcThreadAttribute, WaitForSingleObject,
+            },
+        },
+    },
+    core::{HRESULT, PCSTR},
+};
+
+type CreatePseudoConsole =
+    unsafe extern "system" fn(COORD, HANDLE, HANDLE, u32, *mut HPCON) -> HRESULT;
+type ClosePseudoConsole = unsafe extern "system" fn(HPCON);
+type ResizePseudoConsole = unsafe extern "system" fn(HPCON, COORD) -> HRESULT;
+
+fn get_function(library: PCSTR, function: PCSTR) -> Option<*const c_void> {
+    let module = unsafe { LoadLibraryExA(library, null_mut(), LOAD_LIBRARY_SEARCH_SYSTEM32) };
+    if module.is_null() {
+        return None;
+    }
+    unsafe { GetProcAddress(module, function) }.map(|address| address as *const c_void)
+}
+
+/// Size of the visible window of the console attached to `handle`.
+pub fn console_size(handle: HANDLE) -> Option<COORD> {
+    let mut info = unsafe { zeroed::<CONSOLE_SCREEN_BUFFER_INFO>() };
+    if unsafe { GetConsoleScreenBufferInfo(handle, &mut info) } == 0 {
+        return None;
+    }
+    Some(COORD {
+        X: info.srWindow.Right - info.srWindow.Left + 1,
+        Y: info.srWindow.Bottom - info.srWindow.Top + 1,
+    })
+}
+
+/// Error for the failed `HRESULT` of `function`, which wraps a Windows error code if its
+/// facility is `FACILITY_WIN32`.
+fn hresult_error(function: &str, result: HRESULT) -> Error {
+    let result = result as u32;
+    if result >> 16 == 0x8007 {
+        Error::from_raw_os_error((result & 0xffff) as i32)
+    } else {
+        Error::other(format!("{} failed with HRESULT {:#010x}", function, result))
+    }
+}
+
+/// Append `argument` to `command_line`, quoted according to the rules of `CommandLineToArgvW`.
+fn push_argument(command_line: &mut Vec<u16>, argument: &OsStr) {
+    if !command_line.is_empty() {
+        command_line.push(b' ' as u16);
+    }
+    let argument = argument.encode_wide().collect::<Vec<_>>();
+    let quote = argument.is_empty()
+        || argument
+            .iter()
+            .any(|&c| c == b' ' as u16 || c == b'\t' as u16 || c == b'"' as u16);
+    if !quote {
+        command_line.extend(argument);
+        return;
+    }
+    command_line.push(b'"' as u16);
+    let mut backslashes = 0;
+    for c in argument {
+        if c == b'\\' as u16 {
+            backslashes += 1;
+        } else {
+            if c == b'"' as u16 {
+                command_line.extend(std::iter::repeat(b'\\' as u16).take(backslashes + 1));
+            }
+            backslashes = 0;
+        }
+        command_line.push(c);
+    }
+    command_line.extend(std::iter::repeat(b'\\' as u16).take(backslashes));
+    command_line.push(b'"' as u16);
+}
+
+/// Run `command` connected to a pseudo console that is forwarded to the attached console
+/// and wait for it to exit. The runner has to keep running to forward the input and output
+/// of the pseudo console, so it doesn't exit before the command.
+/// Returns `None` if pseudo consoles are not supported (before Windows 10 1809).
+pub fn run_pseudo_console(command: &Command) -> Result<Option<u32>> {
+    // dynamically load the pseudo console functions since they are only available on Windows 10 1809 and later
+    #[allow(clippy::manual_c_str_literals)]
+    let (create_fn, close_fn, resize_fn) = match (
+        get_function(
+            b"kernel32.dll\0".as_ptr() as _,
+            b"CreatePseudoConsole\0".as_ptr() as _,
+        ),
+        get_function(
+            b"kernel32.dll\0".as_ptr() as _,
+            b"ClosePseudoConsole\0".as_ptr() as _,
+        ),
+        get_function(
+            b"kernel32.dll\0".as_ptr() as _,
+            b"ResizePseudoConsole\0".as_ptr() as _,
+        ),
+    ) {
+        (Some(create_fn), Some(close_fn), Some(resize_fn)) => unsafe {
+            (
+                std::mem::transmute::<*const c_void, CreatePseudoConsole>(create_fn),
+                std::mem::transmute::<*const c_void, ClosePseudoConsole>(close_fn),
+                std::mem::transmute::<*const c_void, ResizePseudoConsole>(resize_fn),
+            )
+        },
+        _ => return Ok(None),
+    };
+
+    let input = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+    let output = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+    let size = console_size(output).unwrap_or(COORD { X: 80, Y: 25 });
+
+    let mut input_read = null_mut();
+    let mut input_write = null_mut();
+    let mut output_read = null_mut();
+    let mut output_write = null_mut();
+    if unsafe { CreatePipe(&mut input_read, &mut input_write, core::ptr::null(), 0) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { CreatePipe(&mut output_read, &mut output_write, core::ptr::null(), 0) } == 0 {
+        unsafe {
+            CloseHandle(input_read);
+            CloseHandle(input_write);
+        }
+        return Err(Error::last_os_error());
+    }
+    let input_write = unsafe { File::from_raw_handle(input_write) };
+    let mut output_read = unsafe { File::from_raw_handle(output_read) };
+
+    let mut console: HPCON = 0;
+    let result = unsafe { create_fn(size, input_read, output_write, 0, &mut console) };
+    // the pseudo console keeps its own duplicates of these handles
+    unsafe {
+        CloseHandle(input_read);
+        CloseHandle(output_write);
+    }
+    if result < 0 {
+        return Err(hresult_error("CreatePseudoConsole", result));
+    }
+
+    let mut attributes_size = 0;
+    unsafe { InitializeProcThreadAttributeList(null_mut(), 1, 0, &mut attributes_size) };
+    let mut attributes_buffer = vec![0u8; attributes_size];
+    let attributes = attributes_buffer.as_mut_ptr() as LPPROC_THREAD_ATTRIBUTE_LIST;
+    if unsafe { InitializeProcThreadAttributeList(attributes, 1, 0, &mut attributes_size) } == 0 {
+        unsafe { close_fn(console) };
+        return Err(Error::last_os_error());
+    }
+    if unsafe {
+        UpdateProcThreadAttribute(
+            attributes,
+            0,
+            PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+            console as *const c_void,
+            size_of::<HPCON>(),
+            null_mut(),
+            core::ptr::null(),
+        )
+    } == 0
+    {
+        let error = Error::last_os_error();
+        unsafe {
+            DeleteProcThreadAttributeList(attributes);
+            close_fn(console);
+        }
+        return Err(error);
+    }
+
+    let mut startup_info = unsafe { zeroed::<STARTUPINFOEXW>() };
+    startup_info.StartupInfo.cb = size_of::<STARTUPINFOEXW>() as u32;
+    // prevent the process from inheriting the redirected standard handles of the runner
+    startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+    startup_info.lpAttributeList = attributes;
+
+    let mut command_line = Vec::new();
+    push_argument(&mut command_line, command.get_program());
+    for argument in command.get_args() {
+        push_argument(&mut command_line, argument);
+    }
+    command_line.push(0);
+    let current_dir = command.get_current_dir().map(|current_dir| {
+        current_dir
+            .as_os_str()
+            .encode_wide()
+            .chain(Some(0))
+            .collect::<Vec<_>>()
+    });
+    // the pseudo console translates output to sequences understood by xterm compatible terminals
+    if std::env::var_os("TERM").is_none() {
+        std::env::set_var("TERM", "xterm-256color");
+    }
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => std::env::set_var(key, value),
+            None => std::env::remove_var(key),
+        }
+    }
+
+    let mut process_info = unsafe { zeroed::<PROCESS_INFORMATION>() };
+    let created = unsafe {
+        CreateProcessW(
+            core::ptr::null(),
+            command_line.as_mut_ptr(),
+            core::ptr::null(),
+            core::ptr::null(),
+            0,
+            EXTENDED_STARTUPINFO_PRESENT,
+            core::ptr::null(),
+            current_dir
+                .as_ref()
+                .map_or(core::ptr::null(), |current_dir| current_dir.as_ptr()),
+            &startup_info.StartupInfo,
+            &mut process_info,
+        )
+    };
+    if created == 0 {
+        let error = Error::last_os_error();
+        unsafe {
+            DeleteProcThreadAttributeList(attributes);
+            close_fn(console);
+        }
+        return Err(error);
+    }
+    unsafe { CloseHandle(process_info.hThread) };
+
+    let mut input_mode = 0;
+    let mut output_mode = 0;
+    let has_input_mode = unsafe { GetConsoleMode(input, &mut input_mode) } != 0;
+    let has_output_mode = unsafe { GetConsoleMode(output, &mut output_mode) } != 0;
+    if has_input_mode {
+        unsafe {
+            SetConsoleMode(
+                input,
+                (input_mode & !(ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT | ENABLE_PROCESSED_INPUT))
+                    | ENABLE_VIRTUAL_TERMINAL_INPUT,
+            )
+        };
+    }
+    if has_output_mode {
+        unsafe {
+            SetConsoleMode(
+                output,
+                output_mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING | DISABLE_NEWLINE_AUTO_RETURN,
+            )
+        };
+    }
+
+    let output_thread = std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        let mut stdout = stdout().lock();
+        while let Ok(read) = output_read.read(&mut buffer) {
+            if read == 0 || stdout.write_all(&buffer[..read]).is_err() {
+                break;
+            }
+            let _ = stdout.flush();
+        }
+    });
+    // the input thread blocks on reading and is not joined
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        let mut input_write = input_write;
+        while let Ok(read) = stdin().read(&mut buffer) {
+            if read == 0 || input_write.write_all(&buffer[..read]).is_err() {
+                break;
+            }
+        }
+    });
+
+    // forward size changes of the attached console to the pseudo console
+    let exited = Arc::new(AtomicBool::new(false));
+    let resize_thread = {
+        let exited = exited.clone();
+        let output = output as usize;
+        std::thread::spawn(move || {
+            let mut current = size;
+            while !exited.load(Ordering::Relaxed) {
+                sleep(Duration::from_millis(100));
+                if let Some(size) = console_size(output as HANDLE) {
+                    if size.X != current.X || size.Y != current.Y {
+                        unsafe { resize_fn(console, size) };
+                        current = size;
+                    }
+                }
+            }
+        })
+    };
+
+    let mut exit_code = 1;
+    unsafe {
+        WaitForSingleObject(process_info.hProcess, INFINITE);
+        GetExitCodeProcess(process_info.hProcess, &mut exit_code);
+        CloseHandle(process_info.hProcess);
+    }
+    exited.store(true, Ordering::Relaxed);
+    let _ = resize_thread.join();
+    unsafe {
+        // closing the pseudo console flushes the remaining output and closes the output pipe
+        close_fn(console);
+        DeleteProcThreadAttributeList(attributes);
+    }
+    let _ = output_thread.join();
+
+    if has_input_mode {
+        unsafe { SetConsoleMode(input, input_mode) };
+    }
+    if has_output_mode {
+        unsafe { SetConsoleMode(output, output_mode) };
+    }
+
+    Ok(Some(exit_code))
+}
@@ -0,0 +1,51 @@
+use std::{
+    env::var_os,
+    fs::{File, create_dir_all, remove_file},
+    path::{Path, PathBuf},
+};
+
+/// Name of the application sandbox the runner is running in, detected from the environment
+/// variables and files set up by Flatpak and Snap.
+pub fn detect_sandbox() -> Option<&'static str> {
+    if var_os("FLATPAK_ID").is_some() || Path::new("/.flatpak-info").exists() {
+        Some("flatpak")
+    } else if var_os("SNAP").is_some() && var_os("SNAP_NAME").is_some() {
+        Some("snap")
+    } else {
+        None
+    }
+}
+
+/// Directories that are writable inside `sandbox`, in order of preference.
+pub fn sandbox_directories(sandbox: &str) -> Vec<PathBuf> {
+    let var = |name: &str| var_os(name).map(PathBuf::from);
+    let mut directories = match sandbox {
+        "flatpak" => vec![
+            var("XDG_CACHE_HOME"),
+            var("XDG_DATA_HOME"),
+            var("XDG_RUNTIME_DIR")
+                .zip(var_os("FLATPAK_ID"))
+                .map(|(runtime, id)| runtime.join("app").join(id)),
+        ],
+        "snap" => vec![
+            var("SNAP_USER_COMMON"),
+            var("SNAP_USER_DATA"),
+            var("XDG_RUNTIME_DIR"),
+        ],
+        _ => vec![],
+    };
+    directories.push(dirs::cache_dir());
+    let mut unique = Vec::new();
+    for directory in directories.into_iter().flatten() {
+        if !unique.contains(&directory) {
+            unique.push(directory);
+        }
+    }
+    unique
+}
+
+/// Whether files can be created in `directory`, creating it if it doesn't exist.
+pub fn is_writable(directory: &Path) -> bool {
+    let probe = directory.join(format!("._wrappe_probe_{}", std::process::id()));
+    create_dir_all(directory).is_ok() && File::create(&probe).is_ok() && remove_file(&probe).is_ok()
+}
@@ -0,0 +1,27 @@
+use std::{
+    fs::{create_dir_all, write},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::types::{EXTRA_SECTIONS_DIRECTORY, WRAPPE_DIRECTORY, read_extra_sections};
+
+/// Directory in `unpack_dir` the extra sections are written to.
+pub fn extra_sections_dir(unpack_dir: &Path) -> PathBuf {
+    unpack_dir
+        .join(WRAPPE_DIRECTORY)
+        .join(EXTRA_SECTIONS_DIRECTORY)
+}
+
+/// Write the extra sections in `data` to files named after the sections
+/// in the extra sections directory of `unpack_dir`.
+pub fn write_extra_sections(data: &[u8], unpack_dir: &Path) -> Result<()> {
+    let sections = read_extra_sections(data)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "extra sections are malformed"))?;
+    let directory = extra_sections_dir(unpack_dir);
+    create_dir_all(&directory)?;
+    for (name, section) in sections {
+        write(directory.join(name), section)?;
+    }
+    Ok(())
+}
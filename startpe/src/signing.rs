@@ -0,0 +1,53 @@
+use std::mem::{offset_of, size_of};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use zerocopy::Ref;
+
+use crate::types::*;
+
+/// Check the Ed25519 signature of the packed executable `mmap` against the public key in its
+/// startup configuration at `info_start`. The signature covers everything from the compressed
+/// files of the payload ending at `payload_end` to the end of the startup configuration, with
+/// the signature itself zeroed.
+pub fn verify_payload(mmap: &[u8], payload_end: usize, info_start: usize) -> Result<(), String> {
+    let header_start = payload_end
+        .checked_sub(size_of::<PayloadHeader>())
+        .ok_or_else(|| "payload header exceeds file size".to_string())?;
+    let header = Ref::into_ref(
+        Ref::<_, PayloadHeader>::from_bytes(
+            mmap.get(header_start..payload_end)
+                .ok_or_else(|| "payload header exceeds file size".to_string())?,
+        )
+        .map_err(|_| "couldn't read payload header".to_string())?,
+    );
+    let files_start = (header_start as u64)
+        .checked_sub(
+            header.sections_size.get() + header.dictionary_size.get() + header.payload_size.get(),
+        )
+        .ok_or_else(|| "payload size exceeds file size".to_string())?
+        as usize;
+
+    let info_end = info_start
+        .checked_add(size_of::<StarterInfo>())
+        .filter(|info_end| *info_end <= mmap.len())
+        .ok_or_else(|| "starter info exceeds file size".to_string())?;
+    let signature_start = info_start + offset_of!(StarterInfo, payload_signature);
+    let signature_end = signature_start + Signature::BYTE_SIZE;
+    let mut hasher = Sha256::new();
+    hasher.update(
+        mmap.get(files_start..signature_start)
+            .ok_or_else(|| "payload overlaps starter info".to_string())?,
+    );
+    hasher.update([0; Signature::BYTE_SIZE]);
+    hasher.update(&mmap[signature_end..info_end]);
+    let digest = hasher.finalize();
+
+    let info = Ref::into_ref(
+        Ref::<_, StarterInfo>::from_bytes(&mmap[info_start..info_end])
+            .map_err(|_| "couldn't read starter info".to_string())?,
+    );
+    let key = VerifyingKey::from_bytes(&info.public_key).map_err(|e| e.to_string())?;
+    key.verify_strict(&digest, &Signature::from_bytes(&info.payload_signature))
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,119 @@
+use std::{io::Result, process::Command};
+
+/// Signals forwarded to the command when sent to the runner by another process.
+#[cfg(any(unix, target_os = "redox"))]
+const FORWARDED_SIGNALS: [libc::c_int; 9] = [
+    libc::SIGINT,
+    libc::SIGTERM,
+    libc::SIGHUP,
+    libc::SIGQUIT,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+    libc::SIGCONT,
+    libc::SIGTSTP,
+];
+
+/// Start the command in a new process group if `process_group` is 1, or in a new session
+/// if it is 2. On Windows, the command is started in a new process group in both cases.
+pub fn set_process_group(command: &mut Command, process_group: u8) {
+    #[cfg(any(unix, target_os = "redox"))]
+    {
+        use std::os::unix::process::CommandExt;
+        match process_group {
+            1 => {
+                command.process_group(0);
+            }
+            2 => unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            },
+            _ => {}
+        }
+    }
+    #[cfg(windows)]
+    if process_group > 0 {
+        use std::os::windows::process::CommandExt;
+        use windows_sys::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP;
+        command.creation_flags(CREATE_NEW_PROCESS_GROUP);
+    }
+    #[cfg(not(any(unix, target_os = "redox", windows)))]
+    let _ = (command, process_group);
+}
+
+/// Spawn `command` and wait for it to exit, keeping the runner alive to perform cleanup
+/// afterwards. Signals sent to the runner by other processes are forwarded to the command,
+/// signals sent by the terminal already reach it through the process group. If the command
+/// was started in its own process group with `group`, all signals are forwarded to its group.
+/// The runner stops together with the command on SIGTSTP, so that job control in shells
+/// keeps working. Other children inherited by the runner are reaped while waiting.
+/// Returns the exit code of the command, or 128 plus the signal number if it was terminated
+/// by a signal.
+#[cfg(any(unix, target_os = "redox"))]
+pub fn run_supervised(command: &mut Command, group: bool) -> Result<i32> {
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+    static CHILD: AtomicI32 = AtomicI32::new(0);
+    static GROUP: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn forward(
+        signal: libc::c_int, info: *mut libc::siginfo_t, _context: *mut libc::c_void,
+    ) {
+        let child = CHILD.load(Ordering::Relaxed);
+        let group = GROUP.load(Ordering::Relaxed);
+        let sent_by_process = !info.is_null() && unsafe { (*info).si_pid() } != 0;
+        if child > 0 && (group || sent_by_process) {
+            // the command is the leader of its own process group
+            unsafe { libc::kill(if group { -child } else { child }, signal) };
+        }
+        if signal == libc::SIGTSTP {
+            unsafe { libc::raise(libc::SIGSTOP) };
+        }
+    }
+
+    let child = command.spawn()?.id() as libc::pid_t;
+    CHILD.store(child, Ordering::Relaxed);
+    GROUP.store(group, Ordering::Relaxed);
+    for signal in FORWARDED_SIGNALS {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid == -1 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if pid != child {
+            continue;
+        }
+        if libc::WIFEXITED(status) {
+            return Ok(libc::WEXITSTATUS(status));
+        }
+        if libc::WIFSIGNALED(status) {
+            return Ok(128 + libc::WTERMSIG(status));
+        }
+    }
+}
+
+/// Spawn `command` and wait for it to exit, keeping the runner alive to perform cleanup
+/// afterwards. Returns the exit code of the command.
+#[cfg(not(any(unix, target_os = "redox")))]
+pub fn run_supervised(command: &mut Command, _group: bool) -> Result<i32> {
+    let status = command.spawn()?.wait()?;
+    Ok(status.code().unwrap_or(1))
+}
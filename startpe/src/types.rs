@@ -1,80 +1,398 @@
-pub use zerocopy::{FromBytes, Immutable, KnownLayout};
+pub use zerocopy::{
+    FromBytes, Immutable, KnownLayout,
+    byteorder::little_endian::{U32, U64},
+};
 
-pub const WRAPPE_FORMAT: u8 = 204;
+pub const WRAPPE_FORMAT: u8 = 233;
 pub const WRAPPE_SIGNATURE_1: [u8; 6] = [0x50, 0x45, 0x33, 0x44, 0x00, 0x00];
 pub const WRAPPE_SIGNATURE_2: [u8; 4] = [0x41, 0x54, 0x41, 0x00];
 pub const NAME_SIZE: usize = 128;
-pub const ARGS_SIZE: usize = 512;
+pub const WRAPPE_DIRECTORY: &str = ".wrappe";
+pub const DESKTOP_ENTRY_FILE: &str = "entry.desktop";
+pub const DESKTOP_ICON_NAME: &str = "icon";
+pub const EXTRA_SECTIONS_DIRECTORY: &str = "sections";
+pub const SEEKABLE_SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+pub const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+pub const RUNNER_MARKER: &[u8] = b"WRAPPE_RUNNER_FORMAT";
+/// Placeholder in file contents replaced with the unpack directory when unpacking.
+pub const UNPACK_DIR_PLACEHOLDER: &str = "{unpack_dir}";
+/// Extension of the payload file stored next to a split runner instead of at its end.
+pub const PACK_EXTENSION: &str = "pack";
 
+// multi-byte fields of the format are stored little-endian regardless of the byte order of the
+// packer and the runner, and converted with get() and new() or into() when accessed
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout)]
 pub struct StarterInfo {
-    pub signature:        [u8; 8],
-    pub show_console:     u8,
-    pub current_dir:      u8,
-    pub verification:     u8,
-    pub show_information: u8,
-    pub uid:              [u8; 16],
-    pub unpack_target:    u8,
-    pub versioning:       u8,
-    pub once:             u8,
-    pub cleanup:          u8,
-    pub wrappe_format:    u8,
-    pub unpack_directory: [u8; NAME_SIZE],
-    pub command:          [u8; NAME_SIZE],
-    pub arguments:        [u8; ARGS_SIZE],
+    pub signature:           [u8; 8],
+    pub show_console:        u8,
+    pub console_source:      u8,
+    pub current_dir:         u8,
+    pub verification:        u8,
+    pub show_information:    u8,
+    pub uid:                 [u8; 16],
+    pub app_id:              [u8; 16],
+    pub unpack_target:       u8,
+    pub versioning:          u8,
+    pub once:                u8,
+    pub cleanup:             u8,
+    pub wrappe_format:       u8,
+    pub desktop_entry:       u8,
+    pub once_timeout:        U32,
+    pub once_exit_code:      u8,
+    pub once_quiet:          u8,
+    pub prefetch:            u8,
+    pub prefetch_threshold:  U32,
+    pub low_priority:        u8,
+    pub supervise:           u8,
+    pub ownership:           u8,
+    pub process_group:       u8,
+    pub close_fds:           u8,
+    pub in_memory:           u8,
+    pub unpack_subdir:       u8,
+    pub backoff:             u8,
+    pub arguments_size:      U32,
+    pub extra_sections_size: U64,
+    pub manifest_size:       U64,
+    pub passed_fds:          U64,
+    pub public_key:          [u8; 32],
+    pub payload_signature:   [u8; 64],
+    pub unpack_directory:    [u8; NAME_SIZE],
+    pub command:             [u8; NAME_SIZE],
 }
 
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout)]
 pub struct PayloadHeader {
-    pub directory_sections: u64,
-    pub file_sections:      u64,
-    pub symlink_sections:   u64,
-    pub dictionary_size:    u64,
-    pub section_hash:       u64,
-    pub payload_size:       u64,
-    pub sections_size:      u64,
+    pub directory_sections: U64,
+    pub file_sections:      U64,
+    pub symlink_sections:   U64,
+    pub hardlink_sections:  U64,
+    pub dictionary_size:    U64,
+    pub section_hash:       U64,
+    pub payload_hash:       [u8; 32],
+    pub payload_size:       U64,
+    pub sections_size:      U64,
+    pub names_size:         U64,
     pub kind:               u8,
 }
 impl PayloadHeader {
     pub fn len(&self) -> u64 {
-        self.directory_sections + self.file_sections + self.symlink_sections
+        self.directory_sections.get()
+            + self.file_sections.get()
+            + self.symlink_sections.get()
+            + self.hardlink_sections.get()
     }
 }
+/// Position and size of a name in the name table following the hardlink sections.
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout, Clone, Copy, Default)]
+pub struct NameRef {
+    pub offset: U32,
+    pub size:   U32,
+}
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout)]
 pub struct DirectorySection {
-    pub name:   [u8; NAME_SIZE],
-    pub parent: u32,
+    pub name:                  NameRef,
+    pub parent:                U32,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub mode:                  U32,
 }
+/// Compressed contents of a file at `position`, or of the solid block containing the file if
+/// `solid` is set, in which case the file is `block_size` bytes at `block_offset` in the
+/// decompressed block. If `placeholder` is 1, the file starts with a shebang whose interpreter
+/// path starts with [`UNPACK_DIR_PLACEHOLDER`], if it is 2, all occurrences of the placeholder
+/// in the file are replaced.
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout)]
 pub struct FileSectionHeader {
-    pub position:              u64,
-    pub size:                  u64,
-    pub name:                  [u8; NAME_SIZE],
-    pub file_hash:             u64,
-    pub compressed_hash:       u64,
-    pub time_accessed_seconds: u64,
-    pub time_modified_seconds: u64,
-    pub parent:                u32,
-    pub mode:                  u32,
-    pub time_accessed_nanos:   u32,
-    pub time_modified_nanos:   u32,
+    pub position:              U64,
+    pub size:                  U64,
+    pub name:                  NameRef,
+    pub file_hash:             U64,
+    pub compressed_hash:       U64,
+    pub block_offset:          U64,
+    pub block_size:            U64,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub parent:                U32,
+    pub mode:                  U32,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub uid:                   U32,
+    pub gid:                   U32,
     pub readonly:              u8,
+    pub stored:                u8,
+    pub solid:                 u8,
+    pub placeholder:           u8,
 }
+/// Symlink to the directory (kind 0) or file (kind 1) section at `target`, or an external
+/// symlink to a directory (kind 2) or file (kind 3) outside of the payload at the path `link`.
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout)]
 pub struct SymlinkSection {
-    pub name:                  [u8; NAME_SIZE],
-    pub parent:                u32,
-    pub target:                u32,
-    pub time_accessed_seconds: u64,
-    pub time_modified_seconds: u64,
-    pub time_accessed_nanos:   u32,
-    pub time_modified_nanos:   u32,
-    pub mode:                  u32,
+    pub name:                  NameRef,
+    pub link:                  NameRef,
+    pub parent:                U32,
+    pub target:                U32,
+    pub time_accessed_seconds: U64,
+    pub time_modified_seconds: U64,
+    pub time_accessed_nanos:   U32,
+    pub time_modified_nanos:   U32,
+    pub mode:                  U32,
     pub kind:                  u8,
     pub readonly:              u8,
 }
+/// Hardlink to the file section at `target`, sharing its contents and metadata.
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout)]
+pub struct HardlinkSection {
+    pub name:   NameRef,
+    pub parent: U32,
+    pub target: U32,
+}
+
+/// Header of an extra data section, followed by its name and data.
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout)]
+pub struct ExtraSectionHeader {
+    pub name_size: U32,
+    pub data_size: U64,
+}
+
+/// Format the application ID as a hyphenated UUID.
+pub fn format_app_id(app_id: &[u8; 16]) -> String {
+    let hex = app_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Unpack directory name from `template` with the `{name}`, `{version}` and `{target}` fields
+/// replaced with the file name of `command` without extension, `version` and `target`. Path
+/// separators in the replaced values are replaced with `_`, `{{` and `}}` stand for literal
+/// braces, and unknown or unclosed fields are kept as they are.
+pub fn expand_unpack_directory(
+    template: &str, command: &str, version: &str, target: &str,
+) -> String {
+    let value = |value: &str| value.replace(['/', '\\'], "_");
+    let mut directory = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        directory.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let escaped = rest.starts_with("{{") || rest.starts_with("}}");
+        if escaped || rest.starts_with('}') {
+            directory.push_str(&rest[..1]);
+            rest = &rest[if escaped { 2 } else { 1 }..];
+            continue;
+        }
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match &rest[1..end] {
+            "name" => directory.push_str(&value(
+                &std::path::Path::new(command)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+            )),
+            "version" => directory.push_str(&value(version)),
+            "target" => directory.push_str(&value(target)),
+            _ => directory.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    directory.push_str(rest);
+    directory
+}
+
+/// Check that `name` can be used as the file name of an extra section.
+pub fn is_valid_section_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Names and data of the extra sections stored one after another in `data`,
+/// or `None` if the data is truncated or contains an invalid name.
+pub fn read_extra_sections(mut data: &[u8]) -> Option<Vec<(&str, &[u8])>> {
+    let mut sections = Vec::new();
+    while !data.is_empty() {
+        let (header, rest) = ExtraSectionHeader::read_from_prefix(data).ok()?;
+        let name_size = header.name_size.get() as usize;
+        let data_size = usize::try_from(header.data_size.get()).ok()?;
+        if rest.len() < name_size.checked_add(data_size)? {
+            return None;
+        }
+        let (name, rest) = rest.split_at(name_size);
+        let (section, rest) = rest.split_at(data_size);
+        let name = std::str::from_utf8(name).ok()?;
+        if !is_valid_section_name(name) {
+            return None;
+        }
+        sections.push((name, section));
+        data = rest;
+    }
+    Some(sections)
+}
+
+/// Separator of the baked command line arguments stored before the startup configuration.
+pub const ARGUMENT_SEPARATOR: char = '\u{1f}';
+
+/// Error of a field that isn't valid UTF-8, with a lossy representation of the field
+/// and the bytes around the invalid sequence in hex.
+#[derive(Debug)]
+pub struct DecodeError {
+    value:    String,
+    position: usize,
+    context:  String,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid UTF-8 at byte {} of {:?} ({})",
+            self.position, self.value, self.context
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decode a fixed-size field padded with null bytes up to the first null byte.
+pub fn decode_field(field: &[u8]) -> Result<&str, DecodeError> {
+    let field = &field[..field
+        .iter()
+        .position(|&c| c == b'\0')
+        .unwrap_or(field.len())];
+    std::str::from_utf8(field).map_err(|e| {
+        let position = e.valid_up_to();
+        let invalid_end = position + e.error_len().unwrap_or(field.len() - position);
+        // up to four valid bytes on each side, invalid bytes in brackets
+        let context = field[position.saturating_sub(4)..(invalid_end + 4).min(field.len())]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if (position..invalid_end).contains(&(i + position.saturating_sub(4))) {
+                    format!("[{:02x}]", byte)
+                } else {
+                    format!("{:02x}", byte)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        DecodeError {
+            value: String::from_utf8_lossy(field).into_owned(),
+            position,
+            context,
+        }
+    })
+}
+
+/// Decode the arguments separated by [`ARGUMENT_SEPARATOR`] in `data`.
+pub fn decode_arguments(data: &[u8]) -> Result<Vec<String>, DecodeError> {
+    let arguments = decode_field(data)?;
+    if arguments.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(arguments
+        .split(ARGUMENT_SEPARATOR)
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_field_never_panics() {
+        // deterministic xorshift generator, so that failures are reproducible
+        let mut state = 0x5EED_u64;
+        let mut field = [0u8; NAME_SIZE];
+        for _ in 0..10000 {
+            for byte in field.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                // favor null bytes and multi-byte sequences
+                *byte = [
+                    0, b'a', 0x1f, 0xC3, 0xA9, 0xE4, 0xB8, 0xAD, 0xF0, 0x9F, 0x9A, 0x80,
+                ][(state % 12) as usize];
+            }
+            let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+            match decode_field(&field) {
+                Ok(value) => assert_eq!(value.as_bytes(), &field[..end]),
+                Err(_) => assert!(std::str::from_utf8(&field[..end]).is_err()),
+            }
+            let _ = decode_arguments(&field);
+        }
+    }
+
+    #[test]
+    fn decode_arguments_keeps_empty_and_spaced_arguments() {
+        let arguments = " spaced \u{1f}\u{1f}中文";
+        assert_eq!(decode_arguments(arguments.as_bytes()).unwrap(), [
+            " spaced ", "", "中文"
+        ]);
+        assert!(decode_arguments(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_field_error_shows_invalid_bytes() {
+        let mut field = [0u8; NAME_SIZE];
+        // truncated multi-byte sequence after a valid one
+        let value = b"app-\xC3\xA9-\xE4\xB8";
+        field[..value.len()].copy_from_slice(value);
+        let error = decode_field(&field).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "invalid UTF-8 at byte 7 of \"app-é-\u{fffd}\" (2d c3 a9 2d [e4] [b8])"
+        );
+    }
+
+    #[test]
+    fn extra_sections_are_little_endian() {
+        let mut data = vec![4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"icon");
+        data.extend_from_slice(&[0xaa, 0xbb]);
+        assert_eq!(read_extra_sections(&data).unwrap(), [(
+            "icon",
+            &[0xaa, 0xbb][..]
+        )]);
+        let name = NameRef::read_from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(
+            (name.offset.get(), name.size.get()),
+            (0x04030201, 0x08070605)
+        );
+    }
+
+    #[test]
+    fn unpack_directory_fields_are_expanded() {
+        let expand =
+            |template| expand_unpack_directory(template, "bin/app.sh", "1.0/b", "x86_64-linux");
+        assert_eq!(expand("acme/{name}-{version}"), "acme/app-1.0_b");
+        assert_eq!(expand("{target}"), "x86_64-linux");
+        assert_eq!(expand("app{x"), "app{x");
+        assert_eq!(expand("app}x{"), "app}x{");
+        assert_eq!(expand("{user}-{name}"), "{user}-app");
+        assert_eq!(expand("my{{version}}"), "my{version}");
+        assert_eq!(expand("{{{name}}}"), "{app}");
+    }
+}
@@ -1,9 +1,16 @@
 use std::{
     fs::{read_to_string, write},
+    hash::Hasher,
+    mem::size_of,
     path::Path,
 };
 
-const VERSION_FILE: &str = "._wrappe_uid_";
+use twox_hash::XxHash64;
+use zerocopy::Ref;
+
+use crate::{decompress::HASH_SEED, types::*};
+
+pub const VERSION_FILE: &str = "._wrappe_uid_";
 
 pub fn get_version(target: &Path) -> String {
     read_to_string(target.join(VERSION_FILE)).unwrap_or_else(|_| "0".to_string())
@@ -12,3 +19,32 @@ pub fn get_version(target: &Path) -> String {
 pub fn set_version(target: &Path, version: &str) {
     write(target.join(VERSION_FILE), version).unwrap()
 }
+
+/// Architecture and system of the runner, for instance `x86_64-linux`.
+pub fn runner_target() -> String { format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS) }
+
+/// Name of the subdirectory of the unpack directory that isolates instances of the packed
+/// executable, derived from the path of the executable `exe` with `mode` 1 or the section hash of
+/// the `payload` with `mode` 2.
+pub fn get_instance_subdir(mode: u8, exe: &Path, payload: &[u8]) -> Option<String> {
+    let hash = match mode {
+        0 => return None,
+        1 => {
+            let exe = exe.canonicalize().unwrap_or_else(|_| exe.to_path_buf());
+            let mut hasher = XxHash64::with_seed(HASH_SEED);
+            hasher.write(exe.to_string_lossy().as_bytes());
+            hasher.finish()
+        }
+        2 => {
+            let header_start = payload.len() - size_of::<PayloadHeader>();
+            Ref::into_ref(
+                Ref::<_, PayloadHeader>::from_bytes(&payload[header_start..])
+                    .expect("couldn't read payload header"),
+            )
+            .section_hash
+            .get()
+        }
+        _ => panic!("invalid unpack subdirectory"),
+    };
+    Some(format!("{:016x}", hash)[..8].to_string())
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the integration tests, which pack inputs with the built packer and run the
+//! packed executables.
+// every test crate compiles this module but uses only some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    env::temp_dir,
+    fs::{create_dir_all, set_permissions, write},
+    os::unix::fs::{PermissionsExt, symlink},
+    path::{Path, PathBuf},
+    process::Output,
+    time::SystemTime,
+};
+
+pub const NAMES: [&str; 3] = ["with spaces", "中文 路径", "emoji 🚀 📦"];
+
+pub fn test_dir() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    temp_dir().join(format!("wrappe-test-{}-{}", std::process::id(), nanos))
+}
+
+pub fn check(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+pub fn create_input(input: &Path) {
+    create_dir_all(input.join("sub dir 子")).unwrap();
+    write(input.join("sub dir 子").join("data 📄.txt"), "contents").unwrap();
+    symlink(
+        Path::new("sub dir 子").join("data 📄.txt"),
+        input.join("link 🔗.txt"),
+    )
+    .unwrap();
+    symlink("sub dir 子", input.join("linked dir 🔗")).unwrap();
+    let command = input.join("run 程序.sh");
+    write(
+        &command,
+        "#!/bin/sh\nprintf '%s\\n' \"$WRAPPE_UNPACK_DIR\"\ncat \"$WRAPPE_UNPACK_DIR/link 🔗.txt\" \
+         \"$WRAPPE_UNPACK_DIR/linked dir 🔗/data 📄.txt\"\necho\nprintf '%s\\n' \"$@\"\n",
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+}
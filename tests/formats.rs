@@ -0,0 +1,252 @@
+#![cfg(unix)]
+
+//! Golden packed executables in `formats/<format>/`, packed from `formats/input` with a stand-in
+//! runner so that they only contain the payload. The executables of the current format are
+//! checked with the runner and the inspect, extract and verify subcommands, executables of older
+//! formats have to be rejected. Run with `WRAPPE_BLESS=1` to pack the executables of the current
+//! format after changing it.
+
+use std::{
+    env::{temp_dir, var_os},
+    fs::{create_dir_all, read, read_link, read_to_string, remove_dir_all, set_permissions, write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    time::SystemTime,
+};
+
+const RUNNER_MARKER: &[u8] = b"WRAPPE_RUNNER_FORMAT";
+
+/// Names of the golden executables with the options and baked arguments they are packed with.
+/// `{formats}` is replaced with the path of the corpus.
+const VARIANTS: [(&str, &[&str], &[&str]); 9] = [
+    ("zstd", &[], &[]),
+    ("xz", &["--algorithm", "xz"], &[]),
+    ("brotli", &["--algorithm", "brotli"], &[]),
+    ("solid", &["--solid-block-size", "64"], &[]),
+    (
+        "dictionary",
+        &["--dictionary-file", "{formats}/input/data/hello.txt"],
+        &[],
+    ),
+    ("stored", &["--store", "*.txt"], &[]),
+    (
+        "sections",
+        &["--extra-section", "license={formats}/license.txt"],
+        &["argument"],
+    ),
+    ("signed", &["--sign-key", "{formats}/signing.key"], &[]),
+    ("no-manifest", &["--no-manifest"], &[]),
+];
+
+fn test_dir() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    temp_dir().join(format!("wrappe-test-{}-{}", std::process::id(), nanos))
+}
+
+fn check(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn formats_dir() -> PathBuf { Path::new(env!("CARGO_MANIFEST_DIR")).join("formats") }
+
+/// Pack the corpus input into `output` with `options`, baking `arguments`.
+fn pack(options: &[&str], arguments: &[&str], output: &Path) {
+    let formats = formats_dir();
+    let options = options
+        .iter()
+        .map(|option| option.replace("{formats}", &formats.display().to_string()));
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .env("SOURCE_DATE_EPOCH", "0")
+            .args(["--reproducible", "--show-information", "none"])
+            .args(options)
+            .arg(formats.join("input"))
+            .arg("run.sh")
+            .arg(output)
+            .arg("--")
+            .args(arguments)
+            .output()
+            .unwrap(),
+    );
+}
+
+fn inspect_json(executable: &Path) -> String {
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["inspect", "--plain", "--json"])
+            .arg(executable)
+            .output()
+            .unwrap(),
+    )
+}
+
+/// Current format and the included native runner, found by packing the corpus input with the
+/// native runner and with a stand-in runner and comparing their sizes.
+fn native_runner(root: &Path) -> (u8, Vec<u8>) {
+    let native = root.join("native");
+    pack(&[], &[], &native);
+    let metadata = inspect_json(&native);
+    let format = metadata
+        .split("\"format\": ")
+        .nth(1)
+        .and_then(|format| format.split(',').next())
+        .and_then(|format| format.parse::<u8>().ok())
+        .expect("couldn't read format");
+
+    let stand_in = root.join("stand-in");
+    write(&stand_in, stand_in_runner(format)).unwrap();
+    let packed = root.join("packed");
+    pack(&["--runner-path", stand_in.to_str().unwrap()], &[], &packed);
+    let (native, packed) = (read(&native).unwrap(), read(&packed).unwrap());
+    let runner_size = native.len() - (packed.len() - stand_in_runner(format).len());
+    (format, native[..runner_size].to_vec())
+}
+
+/// Runner that only contains the runner marker, accepted by the packer for `format`.
+fn stand_in_runner(format: u8) -> Vec<u8> { [RUNNER_MARKER, &[format]].concat() }
+
+/// Pack the golden executables of `format` into `directory`, with the inspect output of each
+/// executable written next to it.
+fn bless(root: &Path, format: u8, directory: &Path) {
+    create_dir_all(directory).unwrap();
+    let stand_in = root.join("stand-in");
+    write(&stand_in, stand_in_runner(format)).unwrap();
+    for (name, options, arguments) in VARIANTS {
+        let executable = directory.join(name);
+        let options = [&["--runner-path", stand_in.to_str().unwrap()], options].concat();
+        pack(&options, arguments, &executable);
+        write(
+            directory.join(format!("{}.json", name)),
+            inspect_json(&executable),
+        )
+        .unwrap();
+    }
+}
+
+/// Write `runner` followed by the payload of the golden executable `golden` to `output`.
+fn with_runner(runner: &[u8], golden: &[u8], format: u8, output: &Path) {
+    let payload = &golden[stand_in_runner(format).len()..];
+    write(output, [runner, payload].concat()).unwrap();
+    set_permissions(output, PermissionsExt::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn golden_executables_are_readable() {
+    let root = test_dir();
+    create_dir_all(&root).unwrap();
+    let (format, runner) = native_runner(&root);
+    let formats = formats_dir();
+    let current = formats.join(format.to_string());
+    if var_os("WRAPPE_BLESS").is_some() {
+        bless(&root, format, &current);
+    }
+    assert!(
+        current.is_dir(),
+        "no golden executables for format {}, run the tests with WRAPPE_BLESS=1 to create them",
+        format
+    );
+
+    let input = formats.join("input");
+    for (name, _, arguments) in VARIANTS {
+        let golden = current.join(name);
+
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["verify", "--plain"])
+                .arg(&golden)
+                .output()
+                .unwrap(),
+        );
+        assert_eq!(
+            inspect_json(&golden),
+            read_to_string(current.join(format!("{}.json", name))).unwrap(),
+            "{}",
+            name
+        );
+
+        let extracted = root.join(format!("extracted-{}", name));
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["extract", "--plain"])
+                .arg(&golden)
+                .arg(&extracted)
+                .output()
+                .unwrap(),
+        );
+        for file in ["run.sh", "data/hello.txt"] {
+            assert_eq!(
+                read(extracted.join(file)).unwrap(),
+                read(input.join(file)).unwrap(),
+                "{}: {}",
+                name,
+                file
+            );
+        }
+        assert_eq!(
+            read_link(extracted.join("link.txt")).unwrap(),
+            Path::new("data/hello.txt")
+        );
+
+        let executable = root.join(name);
+        with_runner(&runner, &read(&golden).unwrap(), format, &executable);
+        let output = check(
+            Command::new(&executable)
+                .args(["from", "runner"])
+                .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+                .output()
+                .unwrap(),
+        );
+        let mut expected = read_to_string(input.join("data/hello.txt")).unwrap();
+        if name == "sections" {
+            expected.push_str(&read_to_string(formats.join("license.txt")).unwrap());
+        }
+        for argument in arguments.iter().chain(["from", "runner"].iter()) {
+            expected.push_str(&format!("{}\n", argument));
+        }
+        assert_eq!(output, expected, "{}", name);
+
+        let manifest = Command::new(&executable)
+            .arg("--wrappe-manifest")
+            .output()
+            .unwrap();
+        assert_eq!(manifest.status.success(), name != "no-manifest", "{}", name);
+    }
+
+    // executables of older formats are rejected instead of being misread
+    for entry in formats.read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        let older = path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse::<u8>().ok())
+            .is_some_and(|older| older != format);
+        if !older {
+            continue;
+        }
+        let golden = path.join("zstd");
+        let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["verify", "--plain"])
+            .arg(&golden)
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", golden.display());
+        let executable = root.join("older");
+        with_runner(&runner, &read(&golden).unwrap(), format, &executable);
+        let output = Command::new(&executable)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", golden.display());
+    }
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,112 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{create_dir_all, read_to_string, remove_dir_all, set_permissions, write},
+    os::unix::fs::PermissionsExt,
+    process::Command,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn pre_launch_hook_rewrites_arguments_and_exit_code() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_dir_all(input.join(".wrappe")).unwrap();
+    let command = input.join("run.sh");
+    write(&command, "#!/bin/sh\nprintf '%s\\n' \"$@\"\n").unwrap();
+    let hook = input.join(".wrappe").join("pre-launch");
+    write(
+        &hook,
+        "#!/bin/sh\ngrep -qx fail \"$WRAPPE_ARGUMENTS_FILE\" && exit 7\nprintf '%s\\n' \
+         \"$WRAPPE_COMMAND\" 参数 >> \"$WRAPPE_ARGUMENTS_FILE\"\n",
+    )
+    .unwrap();
+    for path in [&command, &hook] {
+        set_permissions(path, PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .args(["--", "baked"])
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .arg("forwarded")
+            .output()
+            .unwrap(),
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines[..2], ["baked", "forwarded"]);
+    assert!(lines[2].ends_with("run.sh"), "{}", output);
+    assert_eq!(lines[3..], ["参数"]);
+
+    let output = Command::new(&packed)
+        .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+        .arg("fail")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    assert!(output.stdout.is_empty());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn pack_hooks_receive_paths_and_version() {
+    let root = test_dir();
+    let input = root.join("input 入力");
+    let packed = root.join("packed 📦");
+    let log = root.join("hooks.log");
+    create_input(&input);
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--version-string", "v1"])
+            .arg("--pre-pack")
+            .arg(format!(
+                "printf 'pre %s %s %s\\n' \"$WRAPPE_INPUT\" \"$WRAPPE_OUTPUT\" \"$WRAPPE_VERSION\" >> '{}'",
+                log.display()
+            ))
+            .arg("--post-pack")
+            .arg(format!(
+                "test -f \"$WRAPPE_OUTPUT\" && printf 'post %s\\n' \"$WRAPPE_VERSION\" >> '{}'",
+                log.display()
+            ))
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(
+        read_to_string(&log).unwrap(),
+        format!("pre {} {} v1\npost v1\n", input.display(), packed.display())
+    );
+
+    // a failing pre-pack hook fails packing before the output is written
+    let failed = root.join("failed");
+    let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["--show-information", "none", "--pre-pack", "exit 3"])
+        .arg(&input)
+        .arg(input.join("run 程序.sh"))
+        .arg(&failed)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!failed.exists());
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,513 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{
+        create_dir_all, hard_link, metadata, read, read_to_string, remove_dir_all, set_permissions,
+        write,
+    },
+    os::unix::fs::{PermissionsExt, symlink},
+    path::PathBuf,
+    process::Command,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn hardlinks_are_recreated() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_dir_all(input.join("链接")).unwrap();
+    write(input.join("data 📄.txt"), "contents").unwrap();
+    hard_link(
+        input.join("data 📄.txt"),
+        input.join("链接").join("link 🔗.txt"),
+    )
+    .unwrap();
+    let command = input.join("run.sh");
+    write(
+        &command,
+        "#!/bin/sh\ncd \"$WRAPPE_UNPACK_DIR\"\n[ \"data 📄.txt\" -ef \"链接/link 🔗.txt\" ] && cat \
+         \"链接/link 🔗.txt\"\n",
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["list", "--plain"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("1 hardlinks"), "{}", output);
+
+    // the second run verifies the unpacked hardlink
+    for _ in 0..2 {
+        let output = check(
+            Command::new(&packed)
+                .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+                .output()
+                .unwrap(),
+        );
+        assert_eq!(output, "contents");
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn files_in_solid_blocks_are_unpacked() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_input(&input);
+    create_dir_all(input.join("本地化")).unwrap();
+    for i in 0..200 {
+        write(
+            input.join("本地化").join(format!("{}.json", i)),
+            format!("{{\"key\": {}}}", i),
+        )
+        .unwrap();
+    }
+
+    // small blocks to split the files into several blocks
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--solid-block-size", "1", "--show-information", "none"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["verify", "--plain"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let unpack_dir = PathBuf::from(output.lines().next().unwrap());
+    for i in 0..200 {
+        assert_eq!(
+            std::fs::read_to_string(unpack_dir.join("本地化").join(format!("{}.json", i))).unwrap(),
+            format!("{{\"key\": {}}}", i)
+        );
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn symlink_cycles_and_deep_directories_are_skipped() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    let unpack_root = root.join("unpack");
+    create_input(&input);
+    symlink("..", input.join("sub dir 子").join("cycle 🔁")).unwrap();
+    create_dir_all(input.join("a").join("b").join("c")).unwrap();
+    write(input.join("a").join("b").join("c").join("deep.txt"), "deep").unwrap();
+
+    let output = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--plain", "--show-information", "none", "--dereference"])
+            .args(["--max-depth", "2"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("would form a cycle"));
+    assert!(output.contains("skipping directory nested 2 levels deep"));
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let unpacked = PathBuf::from(output.lines().next().unwrap());
+    assert!(unpacked.join("sub dir 子").join("data 📄.txt").is_file());
+    assert!(!unpacked.join("sub dir 子").join("cycle 🔁").exists());
+    assert!(unpacked.join("a").is_dir());
+    assert!(!unpacked.join("a").join("b").exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn junk_files_are_skipped_unless_kept() {
+    let root = test_dir();
+    let input = root.join("input");
+    create_input(&input);
+    write(input.join(".DS_Store"), "junk").unwrap();
+    write(input.join("sub dir 子").join("Thumbs.db"), "junk").unwrap();
+    create_dir_all(input.join("__MACOSX")).unwrap();
+    write(input.join("__MACOSX").join("._data"), "junk").unwrap();
+
+    for keep_junk in [false, true] {
+        let packed = root.join(format!("packed-{}", keep_junk));
+        let output = check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--plain", "--show-information", "none"])
+                .args(keep_junk.then_some("--keep-junk"))
+                .arg(&input)
+                .arg(input.join("run 程序.sh"))
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        assert_eq!(output.contains("skipped 3 junk files"), !keep_junk);
+
+        let output = check(
+            Command::new(&packed)
+                .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+                .output()
+                .unwrap(),
+        );
+        let unpacked = PathBuf::from(output.lines().next().unwrap());
+        assert!(unpacked.join("sub dir 子").join("data 📄.txt").is_file());
+        assert_eq!(unpacked.join(".DS_Store").exists(), keep_junk);
+        assert_eq!(
+            unpacked.join("sub dir 子").join("Thumbs.db").exists(),
+            keep_junk
+        );
+        assert_eq!(unpacked.join("__MACOSX").exists(), keep_junk);
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn setuid_bits_are_stripped_unless_allowed() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_dir_all(&input).unwrap();
+    let command = input.join("特权");
+    write(&command, "#!/bin/sh\nstat -L -c %a \"$0\"\n").unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o6755)).unwrap();
+
+    for (allow, mode) in [(false, "755"), (true, "6755")] {
+        let output = check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--show-information", "none"])
+                .args(allow.then_some("--allow-setuid"))
+                .arg(&input)
+                .arg(&command)
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        assert!(
+            output.contains("setuid and setgid bits of 1 files: 特权"),
+            "{}",
+            output
+        );
+        let output = check(
+            Command::new(&packed)
+                .env("WRAPPE_UNPACK_ROOT", root.join(mode))
+                .output()
+                .unwrap(),
+        );
+        assert_eq!(output.trim(), mode);
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn signed_payloads_are_verified_before_unpacking() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    let key = root.join("release.key");
+    let other_key = root.join("other.key");
+    create_input(&input);
+
+    for key in [&key, &other_key] {
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .arg("keygen")
+                .arg(key)
+                .output()
+                .unwrap(),
+        );
+    }
+    assert_eq!(metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+    // existing keys are only replaced with --force
+    let existing = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .arg("keygen")
+        .arg(&key)
+        .output()
+        .unwrap();
+    assert_eq!(existing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&existing.stdout).contains("key file already exists"));
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--sign-key"])
+            .arg(&key)
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .arg("verify")
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+
+    let public_key = |key: &PathBuf| {
+        let mut path = key.clone().into_os_string();
+        path.push(".pub");
+        let hex = read_to_string(path).unwrap();
+        (0..64)
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let contents = read(&packed).unwrap();
+    let key = public_key(&key);
+    let embedded = contents
+        .windows(32)
+        .position(|window| window == key)
+        .unwrap();
+    let signature = embedded + 32;
+
+    // a different public key and a modified signature are both rejected by the signature check
+    let mut wrong_key = contents.clone();
+    wrong_key[embedded..embedded + 32].copy_from_slice(&public_key(&other_key));
+    let mut tampered = contents.clone();
+    tampered[signature] ^= 1;
+    for (name, contents) in [("wrong key", wrong_key), ("tampered", tampered)] {
+        let modified = root.join(name);
+        write(&modified, contents).unwrap();
+        set_permissions(&modified, PermissionsExt::from_mode(0o755)).unwrap();
+
+        let verified = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .arg("verify")
+            .arg(&modified)
+            .output()
+            .unwrap();
+        assert!(!verified.status.success(), "{}", name);
+
+        let output = Command::new(&modified)
+            .env("WRAPPE_UNPACK_ROOT", root.join(format!("unpack {}", name)))
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{}", name);
+        assert!(
+            stderr.contains("payload signature verification failed"),
+            "{}: {}",
+            name,
+            stderr
+        );
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn reproducible_outputs_are_bit_identical() {
+    let root = test_dir();
+    let input = root.join("input");
+    let pack = |packed: &PathBuf| {
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--show-information", "none", "--reproducible"])
+                .arg(&input)
+                .arg(input.join("run 程序.sh"))
+                .arg(packed)
+                .output()
+                .unwrap(),
+        );
+        read(packed).unwrap()
+    };
+
+    // the input is created again between both packs, so that the files have other timestamps
+    // and the larger file is compressed into several frames
+    let mut outputs = Vec::new();
+    for name in ["first", "second"] {
+        create_input(&input);
+        let large = (0..400_000)
+            .map(|i| format!("{} {}\n", i, i * 7))
+            .collect::<String>();
+        write(input.join("large.txt"), large).unwrap();
+        outputs.push(pack(&root.join(name)));
+        remove_dir_all(&input).unwrap();
+    }
+    assert!(outputs[0] == outputs[1]);
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn files_from_list_is_packed_by_destination() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    let list = root.join("files.json");
+    create_input(&input);
+    write(input.join("other.txt"), "other").unwrap();
+    write(input.join("unlisted.txt"), "unlisted").unwrap();
+    // the first mapping to the link path is replaced by the later one
+    write(
+        &list,
+        r#"[
+            {"source": "run 程序.sh", "dest": "bin/run 程序.sh"},
+            {"source": "other.txt", "dest": "link 🔗.txt"},
+            {"source": "sub dir 子/data 📄.txt", "dest": "linked dir 🔗/data 📄.txt"},
+            {"source": "sub dir 子/data 📄.txt", "dest": "link 🔗.txt"}
+        ]"#,
+    )
+    .unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--files-from"])
+            .arg(&list)
+            .arg(&input)
+            .arg("bin/run 程序.sh")
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("contentscontents"), "{}", output);
+    let unpacked = PathBuf::from(output.lines().next().unwrap());
+    assert!(unpacked.join("bin").join("run 程序.sh").is_file());
+    assert!(!unpacked.join("run 程序.sh").exists());
+    assert!(!unpacked.join("sub dir 子").exists());
+    assert!(!unpacked.join("other.txt").exists());
+    assert!(!unpacked.join("unlisted.txt").exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn payloads_of_all_algorithms_are_unpacked() {
+    let root = test_dir();
+    let input = root.join("input");
+    create_input(&input);
+
+    for algorithm in ["zstd", "xz", "brotli"] {
+        let packed = root.join(format!("packed-{}", algorithm));
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--show-information", "none", "--algorithm", algorithm])
+                .arg(&input)
+                .arg(input.join("run 程序.sh"))
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        let output = check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["inspect", "--plain"])
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        assert!(
+            output.contains(&format!("compressed with {}", algorithm)),
+            "{}",
+            output
+        );
+
+        let output = check(
+            Command::new(&packed)
+                .env(
+                    "WRAPPE_UNPACK_ROOT",
+                    root.join(format!("unpack-{}", algorithm)),
+                )
+                .output()
+                .unwrap(),
+        );
+        assert!(output.contains("contentscontents"), "{}", output);
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn large_files_are_compressed_into_seekable_frames() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_input(&input);
+    // about 5 MB, so that the file is split into three frames
+    let large = (0..400_000)
+        .map(|i| format!("{} {}\n", i, i * 7))
+        .collect::<String>();
+    write(input.join("large.txt"), &large).unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    // the seek table ends with the number of frames, a descriptor without checksums and the
+    // magic number of the seekable format
+    let contents = read(&packed).unwrap();
+    let footer = [&3u32.to_le_bytes()[..], &[0], &0x8F92EAB1u32.to_le_bytes()].concat();
+    assert!(
+        contents
+            .windows(footer.len())
+            .any(|window| window == footer)
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap(),
+    );
+    let unpacked = PathBuf::from(output.lines().next().unwrap());
+    assert!(read_to_string(unpacked.join("large.txt")).unwrap() == large);
+
+    remove_dir_all(&root).unwrap();
+}
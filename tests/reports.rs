@@ -0,0 +1,88 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{read_to_string, remove_dir_all, write},
+    process::Command,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn pack_statistics_are_shown_by_inspect() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_input(&input);
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--statistics", "-c", "3"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let inspected = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["inspect", "--plain", "--json"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let inspected = serde_json::from_str::<serde_json::Value>(&inspected).unwrap();
+    let statistics = &inspected["statistics"];
+    assert_eq!(statistics["format"], 1);
+    assert_eq!(statistics["compression"]["level"], 3);
+    assert_eq!(statistics["compression"]["dictionary"], "none");
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn report_lists_packed_and_skipped_entries() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    let report = root.join("报告.json");
+    create_input(&input);
+    write(input.join("Thumbs.db"), "junk").unwrap();
+
+    for solid_block_size in ["0", "64"] {
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--show-information", "none", "--report"])
+                .arg(&report)
+                .args(["--solid-block-size", solid_block_size])
+                .arg(&input)
+                .arg(input.join("run 程序.sh"))
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        let report =
+            serde_json::from_str::<serde_json::Value>(&read_to_string(&report).unwrap()).unwrap();
+        assert_eq!(report["files"], 2, "{}", report);
+        assert_eq!(report["skipped"], 1, "{}", report);
+        let entries = report["entries"].as_array().unwrap();
+        let entry = |path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry["path"] == path)
+                .unwrap_or_else(|| panic!("{} missing from {}", path, report))
+        };
+        let data = entry("sub dir 子/data 📄.txt");
+        assert_eq!(data["size"], 8, "{}", report);
+        assert_eq!(
+            data["compressed_size"].is_null(),
+            solid_block_size != "0",
+            "{}",
+            report
+        );
+        assert_eq!(entry("Thumbs.db")["skipped"], "junk file", "{}", report);
+    }
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,571 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{
+        create_dir_all, read, read_dir, read_to_string, remove_dir_all, remove_file,
+        set_permissions, write,
+    },
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+    time::SystemTime,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn once_app_detects_instances_in_other_unpack_directories() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    let (started, release) = (root.join("started"), root.join("release"));
+    create_dir_all(&input).unwrap();
+    let command = input.join("等待.sh");
+    // instances stop waiting after 30 seconds so that a failing check doesn't hang the test
+    write(
+        &command,
+        "#!/bin/sh\ntouch \"$1\"\ni=0\nwhile [ ! -e \"$2\" ] && [ $i -lt 600 ]; do\n  sleep 0.05\n  i=$((i + 1))\ndone\n",
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--once=app"])
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let run = |unpack_root: &str| {
+        let mut command = Command::new(&packed);
+        command
+            .args([&started, &release])
+            .env("WRAPPE_UNPACK_ROOT", root.join(unpack_root));
+        command
+    };
+
+    let mut first = run("first").spawn().unwrap();
+    let start = SystemTime::now();
+    while !started.exists() {
+        assert!(
+            start.elapsed().unwrap().as_secs() < 30,
+            "first instance didn't start"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let second = run("second").output().unwrap();
+    assert_eq!(second.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&second.stdout).contains("another instance is already running")
+    );
+
+    write(&release, "").unwrap();
+    assert!(first.wait().unwrap().success());
+    check(run("second").output().unwrap());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn app_id_is_stable_across_releases() {
+    let root = test_dir();
+    let input = root.join("输入");
+    create_dir_all(&input).unwrap();
+    let command = input.join("id.sh");
+    write(&command, "#!/bin/sh\necho \"$WRAPPE_APP_ID\"\n").unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+
+    let pack = |packed: &str, args: &[&str]| {
+        let packed = root.join(packed);
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["--show-information", "none"])
+                .args(args)
+                .arg(&input)
+                .arg(&command)
+                .arg(&packed)
+                .output()
+                .unwrap(),
+        );
+        let output = check(
+            Command::new(&packed)
+                .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+                .output()
+                .unwrap(),
+        );
+        output.trim().to_string()
+    };
+
+    // derived from the unpack directory name and independent of the version
+    let first = pack("first", &["--version-string", "1"]);
+    assert_eq!(first, pack("second", &["--version-string", "2"]));
+    assert_eq!(first.len(), 36);
+    assert_eq!(&first[14..15], "8");
+    assert_ne!(first, pack("renamed", &["--unpack-directory", "renamed"]));
+    assert_eq!(
+        pack("supplied", &[
+            "--app-id",
+            "0123456789ABCDEF0123456789abcdef"
+        ]),
+        "01234567-89ab-cdef-0123-456789abcdef"
+    );
+
+    let invalid = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["--show-information", "none", "--app-id", "0123-4567"])
+        .arg(&input)
+        .arg(&command)
+        .arg(root.join("invalid"))
+        .output()
+        .unwrap();
+    assert!(!invalid.status.success());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn split_runner_finds_pack_file_next_to_it() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("分割 packed");
+    let pack = root.join("分割 packed.pack");
+    create_input(&input);
+    write(
+        input.join("sub dir 子").join("data 📄.txt"),
+        "原始".repeat(4096),
+    )
+    .unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--split"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(pack.is_file());
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("原始原始"), "{}", output);
+    let listed = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["list", "--plain"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(listed.contains("data 📄.txt"), "{}", listed);
+
+    // updates only replace the pack file
+    let runner = std::fs::read(&packed).unwrap();
+    write(root.join("new.txt"), "更新").unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args([
+                "update",
+                "--plain",
+                "--version-string",
+                "2",
+                "--add",
+                "sub dir 子/data 📄.txt",
+            ])
+            .arg(root.join("new.txt"))
+            .arg(&pack)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(std::fs::read(&packed).unwrap(), runner);
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("更新更新"), "{}", output);
+
+    remove_file(&pack).unwrap();
+    assert!(!Command::new(&packed).output().unwrap().status.success());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn doctor_reports_unusable_unpack_root() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--version-string", "诊断"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let doctor = |unpack_root: &Path| {
+        Command::new(&packed)
+            .arg("--wrappe-doctor")
+            .env("WRAPPE_UNPACK_ROOT", unpack_root)
+            .output()
+            .unwrap()
+    };
+
+    let unpack_root = root.join("unpack 目录");
+    check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let report = check(doctor(&unpack_root));
+    assert!(
+        report.contains("ok      WRAPPE_UNPACK_ROOT (selected)"),
+        "{}",
+        report
+    );
+    assert!(report.contains("version 诊断 (current)"), "{}", report);
+
+    // files can't be created below a regular file
+    let file = root.join("file");
+    write(&file, "").unwrap();
+    let output = doctor(&file.join("unpack"));
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        report.contains("error   WRAPPE_UNPACK_ROOT (selected)"),
+        "{}",
+        report
+    );
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn several_runners_share_one_payload() {
+    let root = test_dir();
+    let input = root.join("input");
+    let config = root.join("config");
+    let runners = config.join("wrappe").join("runners");
+    let unpack_root = root.join("unpack");
+    create_input(&input);
+
+    // the thin runner of a split output is used as an additional runner
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--split"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(root.join("thin"))
+            .output()
+            .unwrap(),
+    );
+    create_dir_all(&runners).unwrap();
+    std::fs::rename(root.join("thin"), runners.join("x86_64-plugin-linux-gnu")).unwrap();
+
+    let packed = root.join("多个 packed");
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "-r", "native"])
+            .args(["-r", "x86_64-plugin-linux-gnu", "-r", "native"])
+            .env("XDG_CONFIG_HOME", &config)
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let native = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .arg("--list-runners")
+        .output()
+        .unwrap();
+    let native = String::from_utf8(native.stdout).unwrap();
+    let native = native
+        .lines()
+        .find(|line| line.ends_with("(default)"))
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string();
+    for runner in [native.as_str(), "x86_64-plugin-linux-gnu"] {
+        let packed = root.join(format!("多个 packed-{}", runner));
+        let output = check(
+            Command::new(&packed)
+                .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+                .output()
+                .unwrap(),
+        );
+        assert!(output.contains("contentscontents"), "{}", output);
+    }
+    assert!(!packed.exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn backoff_switches_strategy_after_failed_extractions() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack 目录");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--backoff"])
+            .args(["--unpack-directory", "回退"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let failures = unpack_root.join("回退").join("._wrappe_failures_");
+    let run = || {
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap()
+    };
+
+    // successful extractions with the default strategy reset the counter
+    create_dir_all(failures.parent().unwrap()).unwrap();
+    write(&failures, "1").unwrap();
+    let output = run();
+    check(output.clone());
+    assert!(String::from_utf8_lossy(&output.stderr).is_empty());
+    assert!(!failures.exists());
+
+    // the reduced strategy is kept after it succeeded
+    remove_dir_all(&unpack_root).unwrap();
+    create_dir_all(failures.parent().unwrap()).unwrap();
+    write(&failures, "2").unwrap();
+    let output = run();
+    check(output.clone());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unpacking failed 2 times before, unpacking without prefetching"),
+        "{}",
+        stderr
+    );
+    assert_eq!(read_to_string(&failures).unwrap(), "2");
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn unpack_directory_fields_are_expanded_by_the_runner() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--versioning", "replace"])
+            .args(["--version-string", "1.0/测试"])
+            .args(["--unpack-directory", "公司/{name}-{version}-{target}"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let expected = unpack_root.join("公司").join(format!(
+        "run 程序-1.0_测试-{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    ));
+    assert_eq!(output.lines().next(), expected.to_str(), "{}", output);
+
+    let rejected = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["--show-information", "none", "--unpack-directory", "{user}"])
+        .arg(&input)
+        .arg(input.join("run 程序.sh"))
+        .arg(root.join("rejected"))
+        .output()
+        .unwrap();
+    assert!(!rejected.status.success());
+
+    // braces in names inferred from the input directory are kept as they are
+    let input = root.join("输入{version}");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--versioning", "replace"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let expected = unpack_root.join("输入{version}");
+    assert_eq!(output.lines().next(), expected.to_str(), "{}", output);
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn default_desktop_entry_is_installed_without_packed_files() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let data_home = root.join("data");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--desktop-entry"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = Command::new(&packed)
+        .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+        .env("XDG_DATA_HOME", &data_home)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("desktop entry"), "{}", stderr);
+    check(output);
+
+    let entry = read_to_string(data_home.join("applications").join("input.desktop")).unwrap();
+    assert!(entry.starts_with("[Desktop Entry]\n"), "{}", entry);
+    assert!(entry.contains("Name=input\n"), "{}", entry);
+    assert!(
+        entry.contains(&format!("Exec=\"{}\"", packed.display())),
+        "{}",
+        entry
+    );
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn verify_flag_checks_payload_without_unpacking() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    let corrupted = root.join("corrupted");
+    create_input(&input);
+    let marker = "校验 verified contents ".repeat(64);
+    write(input.join("stored.txt"), &marker).unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--store", "*.txt"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let verify = |packed: &Path| {
+        Command::new(packed)
+            .arg("--wrappe-verify")
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap()
+    };
+
+    let output = check(verify(&packed));
+    assert!(output.contains("payload hash: ok"), "{}", output);
+    assert!(
+        output.contains("payload signature: not signed"),
+        "{}",
+        output
+    );
+    assert!(!unpack_root.exists());
+
+    let mut contents = read(&packed).unwrap();
+    let position = contents
+        .windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+        .unwrap();
+    contents[position] ^= 1;
+    write(&corrupted, contents).unwrap();
+    set_permissions(&corrupted, PermissionsExt::from_mode(0o755)).unwrap();
+    let output = verify(&corrupted);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("payload hash differs"), "{}", stderr);
+    assert!(!unpack_root.exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn corrupted_payload_is_rejected_before_unpacking() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_input(&input);
+    let marker = "损坏 corrupted contents ".repeat(64);
+    write(input.join("stored.txt"), &marker).unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--store", "*.txt"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    // a single flipped byte in the last file makes the whole payload fail, not only that file
+    let mut contents = read(&packed).unwrap();
+    let position = contents
+        .windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+        .unwrap();
+    contents[position + marker.len() - 1] ^= 1;
+    write(&packed, contents).unwrap();
+    let output = Command::new(&packed)
+        .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", stderr);
+    assert!(stderr.contains("payload hash differs"), "{}", stderr);
+    // only the lock of the unpack directory is created before the payload is checked
+    for unpack_dir in read_dir(unpack_root.join("input")).unwrap() {
+        let entries = read_dir(unpack_dir.unwrap().path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert!(
+            entries.iter().all(|name| name == "._wrappe_lock_"),
+            "{:?}",
+            entries
+        );
+    }
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,229 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, set_permissions, write},
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    process::Command,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn content_filters_transform_line_endings() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_input(&input);
+    write(
+        input.join("windows 窗口.txt"),
+        "\u{feff}one\r\ntwo\rthree\n",
+    )
+    .unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .args(["--filter", "*.txt=strip-bom,lf", "--store", "windows*"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap(),
+    );
+    let unpacked = PathBuf::from(output.lines().next().unwrap());
+    assert_eq!(
+        read_to_string(unpacked.join("windows 窗口.txt")).unwrap(),
+        "one\ntwo\nthree\n"
+    );
+    assert_eq!(
+        read_to_string(unpacked.join("sub dir 子").join("data 📄.txt")).unwrap(),
+        "contents"
+    );
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["verify", "--plain"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn shebangs_are_pointed_at_the_unpack_directory() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_dir_all(input.join("bin")).unwrap();
+    let interpreter = input.join("bin").join("解释器");
+    write(&interpreter, "#!/bin/sh\nprintf '%s\\n' \"$0\" \"$@\"\n").unwrap();
+    let script = input.join("工具");
+    write(
+        &script,
+        format!("#!{} --flag\necho\n", interpreter.display()),
+    )
+    .unwrap();
+    for path in [&interpreter, &script] {
+        set_permissions(path, PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--shebangs", "unpack"])
+            .args(["--verification", "checksum"])
+            .arg(&input)
+            .arg(&script)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    // the second run verifies the checksum of the rewritten script
+    for _ in 0..2 {
+        let output = Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap();
+        assert!(
+            !String::from_utf8_lossy(&output.stderr).contains("verification failed"),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let output = check(output);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(
+            lines[0].starts_with(unpack_root.to_str().unwrap())
+                && lines[0].ends_with("/bin/解释器"),
+            "{}",
+            output
+        );
+        assert_eq!(lines[1], "--flag");
+        assert!(lines[2].ends_with("/工具"), "{}", output);
+    }
+
+    // env rewrites are stored in the payload
+    let extracted = root.join("extracted");
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--shebangs", "env"])
+            .arg(&input)
+            .arg(&script)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["extract", "--plain"])
+            .arg(&packed)
+            .arg(&extracted)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(
+        read_to_string(extracted.join("工具")).unwrap(),
+        "#!/usr/bin/env -S 解释器 --flag\necho\n"
+    );
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn venv_paths_are_relocated_to_the_unpack_directory() {
+    let root = test_dir();
+    let input = root.join("环境");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_dir_all(input.join("bin")).unwrap();
+    write(
+        input.join("pyvenv.cfg"),
+        format!(
+            "home = /usr/bin\ncommand = /usr/bin/python3 -m venv {0}\nother = {0}-other\n",
+            input.display()
+        ),
+    )
+    .unwrap();
+    write(
+        input.join("bin").join("activate"),
+        format!("VIRTUAL_ENV=\"{}\"\n", input.display()),
+    )
+    .unwrap();
+    let interpreter = input.join("bin").join("python");
+    write(
+        &interpreter,
+        "#!/bin/sh\ncat \"$(dirname \"$0\")/../pyvenv.cfg\" \"$(dirname \"$0\")/activate\"\n",
+    )
+    .unwrap();
+    let script = input.join("bin").join("工具");
+    write(&script, format!("#!{}\n", interpreter.display())).unwrap();
+    for path in [&interpreter, &script] {
+        set_permissions(path, PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--venv"])
+            .args(["--verification", "checksum"])
+            .arg(&input)
+            .arg(&script)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    for _ in 0..2 {
+        let output = Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap();
+        assert!(
+            !String::from_utf8_lossy(&output.stderr).contains("verification failed"),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let output = check(output);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "home = /usr/bin");
+        assert!(
+            lines[1].starts_with(&format!(
+                "command = /usr/bin/python3 -m venv {}",
+                unpack_root.display()
+            )),
+            "{}",
+            output
+        );
+        // paths only sharing a prefix with the input are kept
+        assert_eq!(lines[2], format!("other = {}-other", input.display()));
+        assert!(
+            lines[3].starts_with(&format!("VIRTUAL_ENV=\"{}", unpack_root.display())),
+            "{}",
+            output
+        );
+    }
+
+    // inputs without pyvenv.cfg are rejected
+    remove_file(input.join("pyvenv.cfg")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["--show-information", "none", "--venv"])
+        .arg(&input)
+        .arg(&script)
+        .arg(&packed)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("pyvenv.cfg")
+            || String::from_utf8_lossy(&output.stderr).contains("pyvenv.cfg")
+    );
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,118 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{create_dir_all, remove_dir_all, set_permissions, write},
+    os::unix::fs::PermissionsExt,
+    process::Command,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn extra_sections_are_available_to_the_command() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    let section = root.join("license 许可.txt");
+    let extracted = root.join("extracted");
+    create_dir_all(&input).unwrap();
+    write(&section, "许可 license").unwrap();
+    let command = input.join("run.sh");
+    write(
+        &command,
+        "#!/bin/sh\ncat \"$WRAPPE_SECTIONS_DIR/license\"\n",
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--extra-section"])
+            .arg(format!("license={}", section.display()))
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(output, "许可 license");
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["inspect", "--extract-section", "license", "-o"])
+            .arg(&extracted)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(std::fs::read_to_string(&extracted).unwrap(), "许可 license");
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn file_manifest_is_embedded() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    let section = root.join("section");
+    create_input(&input);
+    write(&section, "section").unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--extra-section"])
+            .arg(format!("license={}", section.display()))
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .args(["--", "argument"])
+            .output()
+            .unwrap(),
+    );
+
+    let manifest = check(
+        Command::new(&packed)
+            .arg("--wrappe-manifest")
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert!(!unpack_root.exists());
+    assert!(manifest.contains("\"path\": \"sub dir 子/data 📄.txt\""));
+    // sha256 of "contents"
+    assert!(manifest.contains(
+        "\"sha256\": \"d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8\""
+    ));
+    assert!(manifest.contains("\"path\": \"link 🔗.txt\""));
+
+    let inspected = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["inspect", "--plain", "--manifest"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(inspected.ends_with(&manifest));
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.ends_with("argument\n"));
+
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,45 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs::remove_dir_all, process::Command};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn input_named_like_subcommand_is_packed_as_path() {
+    let root = test_dir();
+    let input = root.join("update");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .current_dir(&root)
+            .args(["--show-information", "none", "./update"])
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("contentscontents"), "{}", output);
+
+    // without a path, the name selects the subcommand
+    let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .current_dir(&root)
+        .args(["update", "--plain"])
+        .arg(input.join("run 程序.sh"))
+        .arg(&packed)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.contains("reading payload of"), "{}", stdout);
+
+    remove_dir_all(&root).unwrap();
+}
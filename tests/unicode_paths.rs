@@ -0,0 +1,147 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{create_dir_all, remove_dir_all, set_permissions, write},
+    os::unix::fs::{PermissionsExt, symlink},
+    path::Path,
+    process::Command,
+};
+
+use common::{NAMES, check, create_input, test_dir};
+
+#[test]
+fn unpack_paths_with_spaces_and_unicode() {
+    for name in NAMES {
+        let root = test_dir();
+        let input = root.join(format!("input {}", name));
+        let unpack_root = root.join(format!("unpack {}", name));
+        let packed = root.join(format!("packed {}", name));
+        create_input(&input);
+
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args([
+                    "--verification",
+                    "checksum",
+                    "--once",
+                    "--show-information",
+                    "none",
+                ])
+                .arg(&input)
+                .arg(input.join("run 程序.sh"))
+                .arg(&packed)
+                .args(["--", "baked 参数"])
+                .output()
+                .unwrap(),
+        );
+
+        // the second run verifies the unpacked files and symlinks
+        for _ in 0..2 {
+            let output = check(
+                Command::new(&packed)
+                    .arg("forwarded 🚀")
+                    .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+                    .output()
+                    .unwrap(),
+            );
+            let lines = output.lines().collect::<Vec<_>>();
+            assert!(
+                Path::new(lines[0]).starts_with(unpack_root.join(format!("input {}", name))),
+                "{}",
+                output
+            );
+            assert_eq!(lines[1..], [
+                "contentscontents",
+                "baked 参数",
+                "forwarded 🚀"
+            ]);
+        }
+
+        remove_dir_all(&root).unwrap();
+    }
+}
+
+#[test]
+fn baked_arguments_and_version_are_preserved() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    create_input(&input);
+    // baked arguments aren't limited to a fixed size
+    let long = format!("--flag={}", "值".repeat(400));
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            // the version field is padded by bytes, not by characters
+            .args(["--version-string", "版本版本a", "--show-information", "none"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .args(["--", " spaced ", "", "中文", &long])
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(
+        Path::new(lines[0]).ends_with(Path::new("input").join("版本版本a")),
+        "{}",
+        output
+    );
+    assert_eq!(lines[2..], [" spaced ", "", "中文", &long]);
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn names_longer_than_128_bytes_are_preserved() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("packed");
+    let directory = format!("{}目录", "d".repeat(200));
+    let file = format!("{}文件.txt", "f".repeat(230));
+    let link = format!("{}🔗", "l".repeat(150));
+    create_dir_all(input.join(&directory)).unwrap();
+    write(input.join(&directory).join(&file), "contents").unwrap();
+    symlink(Path::new(&directory).join(&file), input.join(&link)).unwrap();
+    let command = input.join("run.sh");
+    write(
+        &command,
+        format!(
+            "#!/bin/sh\ncat \"$WRAPPE_UNPACK_DIR/{}/{}\" \"$WRAPPE_UNPACK_DIR/{}\"\n",
+            directory, file, link
+        ),
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(output, "contentscontents");
+
+    remove_dir_all(&root).unwrap();
+}
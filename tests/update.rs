@@ -0,0 +1,223 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs::{read, remove_dir_all, set_permissions, write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use common::{check, create_input, test_dir};
+
+#[test]
+fn update_replaces_adds_and_removes_files() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    write(root.join("new.txt"), "更新").unwrap();
+    let output = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["update", "--plain", "--add", "sub dir 子/data 📄.txt"])
+            .arg(root.join("new.txt"))
+            .args(["--add", "added 新/file.txt"])
+            .arg(root.join("new.txt"))
+            .args(["--remove", "linked dir 🔗"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("validated output"), "{}", output);
+
+    let output = check(
+        Command::new(&packed)
+            .env("WRAPPE_UNPACK_ROOT", root.join("unpack"))
+            .output()
+            .unwrap(),
+    );
+    let unpacked = PathBuf::from(output.lines().next().unwrap());
+    assert_eq!(
+        read(unpacked.join("sub dir 子").join("data 📄.txt")).unwrap(),
+        "更新".as_bytes()
+    );
+    assert_eq!(
+        read(unpacked.join("link 🔗.txt")).unwrap(),
+        "更新".as_bytes()
+    );
+    assert_eq!(
+        read(unpacked.join("added 新").join("file.txt")).unwrap(),
+        "更新".as_bytes()
+    );
+    assert!(!unpacked.join("linked dir 🔗").exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn update_of_split_runner_rewrites_pack_file() {
+    let root = test_dir();
+    let input = root.join("input");
+    let unpack_root = root.join("unpack");
+    let packed = root.join("分割 packed");
+    let pack = root.join("分割 packed.pack");
+    create_input(&input);
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none", "--split"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let runner = std::fs::read(&packed).unwrap();
+    write(root.join("new.txt"), "更新").unwrap();
+    let update = |output: Option<&Path>| {
+        check(
+            Command::new(env!("CARGO_BIN_EXE_wrappe"))
+                .args(["update", "--plain", "--add", "sub dir 子/data 📄.txt"])
+                .arg(root.join("new.txt"))
+                .arg(&packed)
+                .args(output)
+                .output()
+                .unwrap(),
+        )
+    };
+    let run = |packed: &Path| {
+        check(
+            Command::new(packed)
+                .env("WRAPPE_UNPACK_ROOT", &unpack_root)
+                .output()
+                .unwrap(),
+        )
+    };
+
+    update(None);
+    assert_eq!(std::fs::read(&packed).unwrap(), runner);
+    assert!(pack.is_file());
+    let output = run(&packed);
+    assert!(output.contains("更新更新"), "{}", output);
+
+    // the runner is copied next to the pack file written to another path
+    let copied = root.join("复制 packed");
+    update(Some(&copied));
+    assert_eq!(std::fs::read(&copied).unwrap(), runner);
+    assert!(root.join("复制 packed.pack").is_file());
+    let output = run(&copied);
+    assert!(output.contains("更新更新"), "{}", output);
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn health_check_timeout_rolls_back_update() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("检查 packed");
+    create_input(&input);
+    let command = input.join("hang 程序.sh");
+    write(
+        &command,
+        "#!/bin/sh\n[ \"$1\" = hang ] && sleep 30\nexit 0\n",
+    )
+    .unwrap();
+    set_permissions(&command, PermissionsExt::from_mode(0o755)).unwrap();
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--show-information", "none"])
+            .arg(&input)
+            .arg(&command)
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    let previous = std::fs::read(&packed).unwrap();
+    write(root.join("new.txt"), "更新").unwrap();
+
+    let started = SystemTime::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["update", "--plain", "--add", "new.txt"])
+        .arg(root.join("new.txt"))
+        .args(["--health-check=hang", "--health-check-timeout", "1"])
+        .arg(&packed)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.contains("timed out"), "{}", stdout);
+    assert!(stdout.contains("rolled back"), "{}", stdout);
+    assert!(started.elapsed().unwrap().as_secs() < 30);
+    assert_eq!(std::fs::read(&packed).unwrap(), previous);
+
+    // executables exiting within the timeout pass the health check
+    check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["update", "--plain", "--add", "new.txt"])
+            .arg(root.join("new.txt"))
+            .args(["--health-check", "--health-check-timeout", "30"])
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert_ne!(std::fs::read(&packed).unwrap(), previous);
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn corrupted_file_data_fails_output_validation() {
+    let root = test_dir();
+    let input = root.join("input");
+    let packed = root.join("packed");
+    let corrupted = root.join("corrupted");
+    let updated = root.join("updated");
+    create_input(&input);
+    let marker = "存储 stored contents ".repeat(64);
+    write(input.join("stored.txt"), &marker).unwrap();
+    let output = check(
+        Command::new(env!("CARGO_BIN_EXE_wrappe"))
+            .args(["--plain", "--store", "*.txt"])
+            .arg(&input)
+            .arg(input.join("run 程序.sh"))
+            .arg(&packed)
+            .output()
+            .unwrap(),
+    );
+    assert!(output.contains("files sampled"), "{}", output);
+
+    // files that aren't replaced are copied into the updated payload as they are
+    let mut contents = read(&packed).unwrap();
+    let position = contents
+        .windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+        .unwrap();
+    contents[position] ^= 1;
+    write(&corrupted, contents).unwrap();
+    write(root.join("new.txt"), "new").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_wrappe"))
+        .args(["update", "--plain", "--add", "new.txt"])
+        .arg(root.join("new.txt"))
+        .arg(&corrupted)
+        .arg(&updated)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.contains("output validation failed"), "{}", stdout);
+    assert!(stdout.contains("stored.txt"), "{}", stdout);
+    assert!(!updated.exists());
+
+    remove_dir_all(&root).unwrap();
+}